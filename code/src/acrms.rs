@@ -0,0 +1,105 @@
+// AcRms
+// AC measurement mode: true RMS voltage and current, real and apparent power and the frequency
+// from zero-crossings over a window of fast samples, e.g. for a load on a rectified supply.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+// Hysteresis around the crossing level, relative to the AC part of the current
+const CROSSING_HYSTERESIS: f32 = 0.5;
+// Smaller AC part of the current is regarded as noise, no crossings are counted (A)
+const MIN_AC_CURRENT: f32 = 0.001;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct AcReading {
+    pub vrms: f32,              // V
+    pub irms: f32,              // A
+    pub apparent_power: f32,    // VA
+    pub frequency: f32,         // Hz, 0 if there is no AC component
+}
+
+// Result of a window: mean voltage, mean current and real power with the RMS values
+pub struct AcResult {
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+    pub reading: AcReading,
+}
+
+pub struct AcWindow {
+    sum_v: f64,
+    sum_i: f64,
+    sum_v2: f64,
+    sum_i2: f64,
+    sum_p: f64,
+    count: u32,
+    crossings: u32,
+    level: f32,         // crossing level, mean current of the previous window
+    band: f32,          // hysteresis, from the previous window
+    above: Option<bool>,
+}
+
+impl AcWindow {
+    pub fn new() -> AcWindow {
+        AcWindow { sum_v: 0.0, sum_i: 0.0, sum_v2: 0.0, sum_i2: 0.0, sum_p: 0.0, count: 0,
+            crossings: 0, level: 0.0, band: 0.0, above: None }
+    }
+
+    pub fn add(&mut self, voltage: f32, current: f32)
+    {
+        let (v, i) = (voltage as f64, current as f64);
+        self.sum_v += v;
+        self.sum_i += i;
+        self.sum_v2 += v * v;
+        self.sum_i2 += i * i;
+        self.sum_p += v * i;
+        self.count += 1;
+
+        // Count crossings of the mean current once it is known
+        if self.band > 0.0 {
+            let side = if current > self.level + self.band {
+                Some(true)
+            }
+            else if current < self.level - self.band {
+                Some(false)
+            }
+            else {
+                None
+            };
+            if let Some(side) = side {
+                if self.above.is_some() && self.above != Some(side) {
+                    self.crossings += 1;
+                }
+                self.above = Some(side);
+            }
+        }
+    }
+
+    // Compute the window and start the next one, None if there were no samples
+    pub fn finish(&mut self, secs: f32) -> Option<AcResult> {
+        if self.count == 0 {
+            return None;
+        }
+        let n = self.count as f64;
+        let mean_v = self.sum_v / n;
+        let mean_i = self.sum_i / n;
+        let vrms = (self.sum_v2 / n).sqrt() as f32;
+        let irms = (self.sum_i2 / n).sqrt() as f32;
+        let frequency = if secs > 0.0 { self.crossings as f32 / 2.0 / secs } else { 0.0 };
+        // AC part of the current for the hysteresis of the next window
+        let iac = (self.sum_i2 / n - mean_i * mean_i).max(0.0).sqrt() as f32;
+        let result = AcResult {
+            voltage: mean_v as f32,
+            current: mean_i as f32,
+            power: (self.sum_p / n) as f32,
+            reading: AcReading {
+                vrms: vrms,
+                irms: irms,
+                apparent_power: vrms * irms,
+                frequency: frequency,
+            },
+        };
+        let band = if iac >= MIN_AC_CURRENT { iac * CROSSING_HYSTERESIS } else { 0.0 };
+        *self = AcWindow { level: mean_i as f32, band: band, ..AcWindow::new() };
+        Some(result)
+    }
+}
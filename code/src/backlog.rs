@@ -0,0 +1,183 @@
+// Backlog
+// Record batches the server did not take after repeated failures, kept in the "backlog" flash partition
+// instead of being dropped and replayed oldest first once uploads succeed again. The batches are NVS blobs
+// of up to BATCH_RECORDS records in a ring of MAX_BATCHES slots, the oldest is overwritten when it is full.
+// Only records are kept, the other points (health, markers, events) of a failed request are dropped.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use esp_idf_svc::nvs::{EspNvs, EspCustomNvsPartition, NvsCustom};
+use crate::CurrentLog;
+use crate::acrms::AcReading;
+use crate::currentlogs::SensorReading;
+
+const BACKLOG_PARTITION: &str = "backlog";
+const BACKLOG_NAMESPACE: &str = "backlog";
+// Slots of the ring, a batch of BATCH_RECORDS with all fields takes about 4KB of the 64KB partition
+pub const MAX_BATCHES: u32 = 10;
+pub const BATCH_RECORDS: usize = 64;
+// seq u32, clock u64, voltage, current, power, battery f32, crc u32, flags u8
+const RECORD_SIZE: usize = 33;
+const FLAG_CLOCK_STEP: u8 = 0x01;
+const FLAG_SOC: u8 = 0x02;
+const FLAG_AC: u8 = 0x04;
+const FLAG_SECOND: u8 = 0x08;
+
+pub struct Backlog {
+    nvs: EspNvs<NvsCustom>,
+    head: u32,      // id of the oldest batch
+    next: u32,      // id of the next batch to store
+}
+
+impl Backlog {
+    pub fn new() -> anyhow::Result<Backlog> {
+        let partition = EspCustomNvsPartition::take(BACKLOG_PARTITION)?;
+        let nvs = EspNvs::new(partition, BACKLOG_NAMESPACE, true)?;
+        let head = nvs.get_u32("head").ok().flatten().unwrap_or(0);
+        let next = nvs.get_u32("next").ok().flatten().unwrap_or(0);
+        let backlog = Backlog { nvs: nvs, head: head, next: next.max(head) };
+        info!("Upload backlog: {} batches stored", backlog.len());
+        Ok(backlog)
+    }
+
+    // Batches waiting for the replay
+    pub fn len(&self) -> usize {
+        self.next.wrapping_sub(self.head) as usize
+    }
+
+    // Store the records in batches of BATCH_RECORDS, returns the number of batches overwritten to make room
+    pub fn push(&mut self, records: &[CurrentLog]) -> u32 {
+        let mut overwritten = 0;
+        for chunk in records.chunks(BATCH_RECORDS) {
+            if self.len() >= MAX_BATCHES as usize {
+                self.remove(self.head);
+                self.head = self.head.wrapping_add(1);
+                overwritten += 1;
+            }
+            let key = format!("b{}", self.next % MAX_BATCHES);
+            if let Err(e) = self.nvs.set_blob(&key, &encode(chunk)) {
+                info!("Failed to store a batch of {} records: {:?}", chunk.len(), e);
+                break;
+            }
+            self.next = self.next.wrapping_add(1);
+        }
+        self.save();
+        info!("Upload backlog: {} records stored, {} batches", records.len(), self.len());
+        overwritten
+    }
+
+    // Records of the oldest batch, None if the backlog is empty. A batch that cannot be read is removed.
+    pub fn oldest(&mut self) -> Option<Vec<CurrentLog>> {
+        let mut buf = vec![0u8; BATCH_RECORDS * (RECORD_SIZE + 4 * 8)];
+        while self.len() > 0 {
+            if let Ok(Some(data)) = self.nvs.get_blob(&format!("b{}", self.head % MAX_BATCHES), &mut buf) {
+                let records = decode(data);
+                if !records.is_empty() {
+                    return Some(records);
+                }
+            }
+            info!("Upload backlog: batch {} is unreadable, removed", self.head);
+            self.pop();
+        }
+        None
+    }
+
+    // Remove the oldest batch after it was sent
+    pub fn pop(&mut self)
+    {
+        if self.len() == 0 {
+            return;
+        }
+        self.remove(self.head);
+        self.head = self.head.wrapping_add(1);
+        self.save();
+    }
+
+    fn remove(&mut self, id: u32)
+    {
+        let _ = self.nvs.remove(&format!("b{}", id % MAX_BATCHES));
+    }
+
+    fn save(&mut self)
+    {
+        if let Err(e) = self.nvs.set_u32("head", self.head).and_then(|_| self.nvs.set_u32("next", self.next)) {
+            info!("Failed to save the backlog position: {:?}", e);
+        }
+    }
+}
+
+// The fields of the CRC and the flags, then the optional values that are flagged
+fn encode(records: &[CurrentLog]) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::with_capacity(records.len() * RECORD_SIZE);
+    for it in records {
+        buf.extend_from_slice(&it.seq.to_le_bytes());
+        buf.extend_from_slice(&(it.clock as u64).to_le_bytes());
+        for value in [it.voltage, it.current, it.power, it.battery] {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        buf.extend_from_slice(&it.crc.to_le_bytes());
+        let mut flags = 0;
+        if it.clock_step { flags |= FLAG_CLOCK_STEP; }
+        if it.soc.is_some() { flags |= FLAG_SOC; }
+        if it.ac.is_some() { flags |= FLAG_AC; }
+        if it.second.is_some() { flags |= FLAG_SECOND; }
+        buf.push(flags);
+        if let Some(soc) = it.soc {
+            buf.extend_from_slice(&soc.to_le_bytes());
+        }
+        if let Some(ac) = it.ac {
+            for value in [ac.vrms, ac.irms, ac.apparent_power, ac.frequency] {
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        if let Some(s) = it.second {
+            for value in [s.voltage, s.current, s.power] {
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+    buf
+}
+
+// Records with a matching CRC, decoding stops at a truncated record
+fn decode(data: &[u8]) -> Vec<CurrentLog> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    let f32_at = |pos: usize| f32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+    while pos + RECORD_SIZE <= data.len() {
+        let b = &data[pos..pos + RECORD_SIZE];
+        let mut it = CurrentLog::default();
+        it.seq = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        it.clock = u64::from_le_bytes([b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11]]) as u128;
+        it.voltage = f32_at(pos + 12);
+        it.current = f32_at(pos + 16);
+        it.power = f32_at(pos + 20);
+        it.battery = f32_at(pos + 24);
+        it.crc = u32::from_le_bytes([b[28], b[29], b[30], b[31]]);
+        let flags = b[32];
+        let optional = [(FLAG_SOC, 1), (FLAG_AC, 4), (FLAG_SECOND, 3)].iter()
+            .filter(|(flag, _)| flags & flag != 0).map(|(_, count)| count * 4).sum::<usize>();
+        pos += RECORD_SIZE;
+        if pos + optional > data.len() {
+            break;
+        }
+        it.clock_step = flags & FLAG_CLOCK_STEP != 0;
+        if flags & FLAG_SOC != 0 {
+            it.soc = Some(f32_at(pos));
+            pos += 4;
+        }
+        if flags & FLAG_AC != 0 {
+            it.ac = Some(AcReading { vrms: f32_at(pos), irms: f32_at(pos + 4), apparent_power: f32_at(pos + 8), frequency: f32_at(pos + 12) });
+            pos += 16;
+        }
+        if flags & FLAG_SECOND != 0 {
+            it.second = Some(SensorReading { voltage: f32_at(pos), current: f32_at(pos + 4), power: f32_at(pos + 8) });
+            pos += 12;
+        }
+        if it.calc_crc() == it.crc {
+            records.push(it);
+        }
+    }
+    records
+}
@@ -0,0 +1,103 @@
+// BufferAlert
+// Alerts when the record buffer passes the configured fill levels (e.g. 80% and 95%) during a long
+// offline capture: a display banner, an event point sent when the server is reachable and an optional output pin.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::time::SystemTime;
+use esp_idf_hal::gpio::{PinDriver, AnyOutputPin, Output};
+
+// A level is re-armed when the buffer drains this far below it (%)
+const REARM_HYSTERESIS: u32 = 5;
+
+pub struct BufferEvent {
+    pub level: u32,         // % alert level passed
+    pub fill: u32,          // % buffer fill
+    pub records: usize,
+    pub capacity: usize,
+    pub clock: u128,
+}
+
+pub struct BufferAlert {
+    levels: Vec<u32>,       // ascending
+    level: Option<u32>,     // highest level passed and not re-armed yet
+    pin: Option<PinDriver<'static, AnyOutputPin, Output>>,     // high while a level is passed
+    pending: Option<BufferEvent>,   // newest event not sent yet
+}
+
+// Comma separated levels in %, e.g. "80,95". Empty disables the alerts.
+pub fn parse_levels(text: &str) -> Option<Vec<u32>> {
+    let mut levels = Vec::new();
+    for it in text.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        match it.parse::<u32>() {
+            Ok(level) if level >= 1 && level <= 100 && levels.last().map_or(true, |last| level > *last) => levels.push(level),
+            _ => return None,
+        }
+    }
+    Some(levels)
+}
+
+impl BufferAlert {
+    pub fn new(levels: Vec<u32>, pin: Option<PinDriver<'static, AnyOutputPin, Output>>) -> BufferAlert {
+        if !levels.is_empty() {
+            info!("Buffer alert levels: {:?}%", levels);
+        }
+        BufferAlert { levels: levels, level: None, pin: pin, pending: None }
+    }
+
+    // Called every loop with the buffer fill, returns the level when a higher one is passed
+    pub fn update(&mut self, records: usize, capacity: usize) -> Option<u32> {
+        let fill = (records.min(capacity) * 100 / capacity.max(1)) as u32;
+        if let Some(level) = self.level {
+            if fill + REARM_HYSTERESIS < level {
+                self.level = self.levels.iter().rev().find(|l| fill + REARM_HYSTERESIS >= **l).copied();
+                if self.level.is_none() {
+                    info!("Buffer alert cleared: {}% full", fill);
+                    self.set_output(false);
+                }
+            }
+        }
+        let passed = self.levels.iter().rev().find(|l| fill >= **l).copied();
+        match passed {
+            Some(level) if self.level.map_or(true, |current| level > current) => {
+                info!("Buffer alert: {}% full ({}/{}), passed {}%", fill, records, capacity, level);
+                self.level = Some(level);
+                self.set_output(true);
+                self.pending = Some(BufferEvent {
+                    level: level,
+                    fill: fill,
+                    records: records,
+                    capacity: capacity,
+                    clock: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+                });
+                Some(level)
+            },
+            _ => None,
+        }
+    }
+
+    fn set_output(&mut self, on: bool)
+    {
+        if let Some(ref mut pin) = self.pin {
+            let result = if on { pin.set_high() } else { pin.set_low() };
+            if let Err(e) = result {
+                info!("Buffer alert output failed: {:?}", e);
+            }
+        }
+    }
+
+    // Highest alert level currently passed
+    pub fn level(&self) -> Option<u32> {
+        self.level
+    }
+
+    pub fn pending(&self) -> Option<&BufferEvent> {
+        self.pending.as_ref()
+    }
+
+    pub fn sent(&mut self)
+    {
+        self.pending = None;
+    }
+}
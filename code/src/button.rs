@@ -0,0 +1,105 @@
+// Button
+// The button on GPIO9, polled every loop, which also debounces it. A press of 2 seconds starts the
+// calibration, a hold of 1 to 2 seconds toggles the big digits page and short presses in a row count
+// up to a sequence that is reported once no further press follows.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use esp_idf_hal::gpio::{Gpio9, Input, PinDriver, Pull};
+
+const LONG_PRESS_TIME_MS: u64 = 2000;       // 2 seconds for calibration
+const DOUBLE_PRESS_TIME_MS: u64 = 500;      // next press within 500ms continues the sequence
+const BIG_DIGITS_PRESS_TIME_MS: u64 = 1000; // 1 to 2 seconds toggles the big digits page
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonEvent {
+    Pressed,
+    LongPress,
+    Hold,
+    Presses(u32),   // short presses in the sequence
+}
+
+pub struct Button {
+    pin: PinDriver<'static, Gpio9, Input>,
+    last_state: bool,       // high when released
+    press_start: u64,       // ms
+    long_press: bool,       // long press of this press reported
+    wake_press: bool,       // press that only turned the display on
+    release_time: u64,      // release of the last short press of a pending sequence (ms)
+    count: u32,             // short presses in the pending sequence
+}
+
+impl Button {
+    pub fn new(pin: Gpio9) -> anyhow::Result<Button> {
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+        Ok(Button {
+            pin: pin,
+            last_state: true,
+            press_start: 0,
+            long_press: false,
+            wake_press: false,
+            release_time: 0,
+            count: 0,
+        })
+    }
+
+    // The present press has no other function, e.g. it turned the display on
+    pub fn ignore_press(&mut self)
+    {
+        self.wake_press = true;
+    }
+
+    // Called every loop with the time in ms, at most one event per call
+    pub fn poll(&mut self, now: u64) -> Option<ButtonEvent> {
+        let state = self.pin.is_high();
+        let last_state = self.last_state;
+        self.last_state = state;
+        if last_state && !state {
+            self.press_start = now;
+            self.long_press = false;
+            self.wake_press = false;
+            return Some(ButtonEvent::Pressed);
+        }
+        if !state {
+            if !self.long_press && !self.wake_press && now.saturating_sub(self.press_start) >= LONG_PRESS_TIME_MS {
+                self.long_press = true;
+                return Some(ButtonEvent::LongPress);
+            }
+            return None;
+        }
+        if !last_state {
+            // Released
+            let duration = now.saturating_sub(self.press_start);
+            let (long_press, wake_press) = (self.long_press, self.wake_press);
+            self.long_press = false;
+            self.wake_press = false;
+            info!("Button released after {}ms", duration);
+            if wake_press || long_press || duration >= LONG_PRESS_TIME_MS {
+                return None;
+            }
+            if duration >= BIG_DIGITS_PRESS_TIME_MS {
+                self.release_time = 0;
+                self.count = 0;
+                return Some(ButtonEvent::Hold);
+            }
+            if self.release_time > 0 && now.saturating_sub(self.release_time) < DOUBLE_PRESS_TIME_MS {
+                self.count += 1;
+            }
+            else {
+                self.count = 1;
+            }
+            self.release_time = now;
+            return None;
+        }
+        // Sequence confirmed when no further press followed
+        if self.release_time > 0 && now.saturating_sub(self.release_time) >= DOUBLE_PRESS_TIME_MS {
+            self.release_time = 0;
+            let presses = self.count;
+            self.count = 0;
+            return Some(ButtonEvent::Presses(presses));
+        }
+        None
+    }
+}
@@ -0,0 +1,95 @@
+// Buzzer
+// Optional passive piezo buzzer driven by LEDC PWM: a click on button presses and distinct tone
+// patterns for the current limit alarm, buffer full, WiFi lost and calibration done.
+// Patterns are played by a thread, so the main loop never waits for a tone.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::thread;
+use std::time::Duration;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use esp_idf_hal::gpio::AnyOutputPin;
+use esp_idf_hal::ledc::{LedcDriver, LedcTimerDriver, config::TimerConfig, Resolution, CHANNEL0, TIMER0};
+use esp_idf_hal::units::Hertz;
+use crate::tasks;
+
+// Patterns waiting for the buzzer thread, a new one is dropped when full
+const BUZZER_QUEUE_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Pattern {
+    Click,
+    Alarm,
+    BufferFull,
+    #[cfg(feature = "wifi")]
+    WifiLost,
+    CalibrationDone,
+}
+
+// Frequency (Hz), tone length and the silence after it (ms)
+struct Tone(u32, u64, u64);
+
+impl Pattern {
+    fn tones(&self) -> &'static [Tone] {
+        match self {
+            Pattern::Click => &[Tone(4000, 10, 0)],
+            Pattern::Alarm => &[Tone(3000, 150, 50), Tone(2000, 150, 50), Tone(3000, 150, 50), Tone(2000, 150, 50), Tone(3000, 150, 50), Tone(2000, 150, 0)],
+            Pattern::BufferFull => &[Tone(2500, 400, 200), Tone(2500, 400, 0)],
+            #[cfg(feature = "wifi")]
+            Pattern::WifiLost => &[Tone(1500, 200, 50), Tone(1000, 300, 0)],
+            Pattern::CalibrationDone => &[Tone(2000, 80, 40), Tone(2500, 80, 40), Tone(3000, 150, 0)],
+        }
+    }
+}
+
+pub struct Buzzer {
+    tx: Option<SyncSender<Pattern>>,    // None without a buzzer
+    enabled: bool,
+}
+
+impl Buzzer {
+    pub fn start(timer: TIMER0, channel: CHANNEL0, pin: Option<AnyOutputPin>, enabled: bool) -> anyhow::Result<Buzzer> {
+        let pin = match pin {
+            Some(pin) => pin,
+            None => return Ok(Buzzer { tx: None, enabled: enabled }),
+        };
+        let (tx, rx) = sync_channel::<Pattern>(BUZZER_QUEUE_SIZE);
+        let timer = LedcTimerDriver::new(timer, &TimerConfig::default().frequency(Hertz(2000)).resolution(Resolution::Bits10))?;
+        let _th = tasks::spawn(&tasks::BUZZER, move || {
+            info!("Start Buzzer Thread.");
+            let mut driver = match LedcDriver::new(channel, &timer, pin) {
+                Ok(driver) => driver,
+                Err(e) => {
+                    info!("Buzzer start failed: {:?}", e);
+                    return;
+                }
+            };
+            let half_duty = driver.get_max_duty() / 2;
+            for pattern in rx {
+                for tone in pattern.tones() {
+                    unsafe {
+                        esp_idf_sys::ledc_set_freq(esp_idf_sys::ledc_mode_t_LEDC_LOW_SPEED_MODE, esp_idf_sys::ledc_timer_t_LEDC_TIMER_0, tone.0);
+                    }
+                    let _ = driver.set_duty(half_duty);
+                    thread::sleep(Duration::from_millis(tone.1));
+                    let _ = driver.set_duty(0);
+                    thread::sleep(Duration::from_millis(tone.2));
+                }
+            }
+        });
+        Ok(Buzzer { tx: Some(tx), enabled: enabled })
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool)
+    {
+        self.enabled = enabled;
+    }
+
+    pub fn play(&mut self, pattern: Pattern)
+    {
+        if let (Some(tx), true) = (&self.tx, self.enabled) {
+            let _ = tx.try_send(pattern);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// Capture
+// Capture sessions of a fixed length ("record for 2 hours, then stop"), started from the button menu or with
+// POST /capture of the data API. Logging runs for the wall-clock duration with the remaining time on the
+// display, then stops; the buffered records are uploaded and a summary of the session is sent after them.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::time::{Duration, Instant, SystemTime};
+use crate::stats::SessionSummary;
+
+// Durations of the button menu (seconds), the first entry ends a running capture
+pub const MENU_DURATIONS: [u64; 6] = [0, 600, 3600, 7200, 28800, 86400];
+// Longest capture, 7 days
+pub const MAX_DURATION_SECS: u64 = 604800;
+
+pub struct Capture {
+    duration: Duration,
+    start: Instant,
+    start_seq: u32,         // last record before the capture
+    start_dropped: u32,
+    start_energy: f32,      // Wh
+}
+
+impl Capture {
+    // Counters of the meter at the start, the summary is the difference
+    pub fn new(duration_secs: u64, last_seq: u32, dropped: u32, energy_wh: f32) -> Capture {
+        Capture {
+            duration: Duration::from_secs(duration_secs),
+            start: Instant::now(),
+            start_seq: last_seq,
+            start_dropped: dropped,
+            start_energy: energy_wh,
+        }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.duration.saturating_sub(self.start.elapsed())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.start.elapsed() >= self.duration
+    }
+
+    // Summary of the records, the energy and the peaks since the start
+    pub fn summary(&self, last_seq: u32, dropped: u32, energy_wh: f32, peak_current: f32, peak_power: f32) -> SessionSummary {
+        SessionSummary {
+            event: "capture",
+            uptime: self.start.elapsed().as_secs(),
+            records: last_seq.wrapping_sub(self.start_seq),
+            dropped: dropped.wrapping_sub(self.start_dropped),
+            energy: energy_wh - self.start_energy,
+            peak_current: peak_current,
+            peak_power: peak_power,
+            clock: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+        }
+    }
+}
+
+// Seconds of "7200", "90m", "2h" or "1d", None if invalid or longer than MAX_DURATION_SECS
+pub fn parse_duration(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (number, unit) = match text.char_indices().last() {
+        Some((i, 's')) => (&text[..i], 1),
+        Some((i, 'm')) => (&text[..i], 60),
+        Some((i, 'h')) => (&text[..i], 3600),
+        Some((i, 'd')) => (&text[..i], 86400),
+        _ => (text, 1),
+    };
+    number.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(unit))
+        .filter(|secs| *secs <= MAX_DURATION_SECS)
+}
+
+// Duration of the menu, "10m", "2h" or "24h"
+pub fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 3600 && s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s >= 60 && s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+// Countdown of the display, "1h59m" or "59:30"
+pub fn format_remaining(secs: u64) -> String {
+    match secs {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s => format!("{}:{:02}", s / 60, s % 60),
+    }
+}
@@ -0,0 +1,127 @@
+// ConfigCheck
+// Validation of cfg.toml values at boot: collects every error with a code for the display
+// and falls back to a safe default instead of panicking.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::fmt::Display;
+use std::str::FromStr;
+use crate::locale::{Msg, tr_args};
+
+// Errors shown on one screen, the rest is only logged
+pub const CONFIG_ERROR_LINES: usize = 4;
+
+pub struct ConfigError {
+    pub code: u8,
+    pub key: &'static str,
+}
+
+pub struct ConfigCheck {
+    errors: Vec<ConfigError>,
+}
+
+impl ConfigCheck {
+    pub fn new() -> ConfigCheck {
+        ConfigCheck { errors: Vec::new() }
+    }
+
+    fn error(&mut self, code: u8, key: &'static str, reason: &str)
+    {
+        info!("Config error E{:02}: {} {}", code, key, reason);
+        self.errors.push(ConfigError { code: code, key: key });
+    }
+
+    // Parse a number in [min, max], the default is used when it is unparsable or out of range
+    pub fn number<T>(&mut self, code: u8, key: &'static str, value: &str, default: T, min: T, max: T) -> T
+        where T: FromStr + PartialOrd + Display + Copy
+    {
+        match value.trim().parse::<T>() {
+            Ok(v) if v >= min && v <= max => v,
+            Ok(v) => {
+                self.error(code, key, &format!("{} out of range {}..{}, using {}", v, min, max, default));
+                default
+            },
+            Err(_) => {
+                self.error(code, key, &format!("'{}' is not a number, using {}", value, default));
+                default
+            }
+        }
+    }
+
+    pub fn flag(&mut self, code: u8, key: &'static str, value: &str, default: bool) -> bool {
+        match value.trim().parse::<bool>() {
+            Ok(v) => v,
+            Err(_) => {
+                self.error(code, key, &format!("'{}' is not true or false, using {}", value, default));
+                default
+            }
+        }
+    }
+
+    // Optional GPIO number, empty disables it
+    pub fn gpio(&mut self, code: u8, key: &'static str, value: &str) -> Option<i32> {
+        if value.trim().is_empty() {
+            return None;
+        }
+        match value.trim().parse::<i32>() {
+            Ok(pin) => Some(pin),
+            Err(_) => {
+                self.error(code, key, &format!("'{}' is not a GPIO number, disabled", value));
+                None
+            }
+        }
+    }
+
+    // Optional I2C address in [min, max], decimal or hex with 0x, empty disables it
+    pub fn i2c_address(&mut self, code: u8, key: &'static str, value: &str, min: u8, max: u8) -> Option<u8> {
+        let text = value.trim();
+        if text.is_empty() {
+            return None;
+        }
+        let parsed = match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => text.parse::<u8>(),
+        };
+        match parsed {
+            Ok(address) if address >= min && address <= max => Some(address),
+            _ => {
+                self.error(code, key, &format!("'{}' is not an address in 0x{:02X}..0x{:02X}", value, min, max));
+                None
+            }
+        }
+    }
+
+    // Measurement name or tag value for the line protocol, the default is used when it cannot be written
+    pub fn name(&mut self, code: u8, key: &'static str, value: &str, default: &str) -> String {
+        if crate::transfer::valid_name(value) {
+            return value.to_string();
+        }
+        self.error(code, key, &format!("'{}' must be 1-{} characters without control characters or backslashes, using {}",
+            value, crate::transfer::NAME_MAX_LENGTH, default));
+        default.to_string()
+    }
+
+    // Record an error when a condition between values does not hold
+    pub fn require(&mut self, code: u8, key: &'static str, ok: bool, reason: &str)
+    {
+        if !ok {
+            self.error(code, key, reason);
+        }
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    // Text for the display, one error per line
+    pub fn summary(&self) -> String {
+        let mut lines: Vec<String> = self.errors.iter().take(CONFIG_ERROR_LINES)
+            .map(|e| format!("E{:02} {}", e.code, e.key))
+            .collect();
+        if self.errors.len() > CONFIG_ERROR_LINES {
+            lines.push(tr_args(Msg::MoreErrors, &[&(self.errors.len() - CONFIG_ERROR_LINES).to_string()]));
+        }
+        lines.join("\n")
+    }
+}
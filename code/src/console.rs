@@ -0,0 +1,149 @@
+// Console
+// Line-based command shell over the USB serial console.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::{thread, sync::Arc, sync::Mutex};
+use std::io::Read;
+use std::time::Duration;
+use crate::tasks;
+
+pub enum ConsoleCommand {
+    Help,
+    Status,
+    ConfigGet(String),
+    ConfigSet(String, String),
+    ConfigExport,
+    ConfigImport(String),
+    Calibrate,
+    CalibrateConfirm,
+    CalibrateUndo,
+    DumpCsv,
+    WifiScan,
+    Diagnostics,
+    Ripple,
+    Reboot,
+    Shutdown,
+    Stream(String),
+    Measure,
+    Journal,
+    Iot,
+    IotReset,
+    Soc,
+    SocReset(Option<f32>),
+    Energy,
+    EnergyReset,
+    BatteryCalibrate(Option<f32>),
+}
+
+pub const HELP: &str = "commands: status | config get [key] | config set <key> <value> | config export | config import <json> | cal [confirm|undo] | dump csv | wifi scan | diag | ripple | journal | reboot | shutdown | stream <off|csv|scpi> | MEAS? | iot [reset] | soc [reset [percent]] | energy [reset] | battery cal <volts|reset>";
+
+// Long enough for an exported configuration
+const MAX_LINE_LENGTH: usize = 2048;
+
+pub struct Console {
+    commands: Arc<Mutex<Vec<ConsoleCommand>>>,
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console { commands: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    pub fn start(&mut self)
+    {
+        let commands = self.commands.clone();
+        let _th = tasks::spawn(&tasks::CONSOLE, move || {
+            info!("Start console thread.");
+            let mut line = String::new();
+            let mut buf = [0u8; 64];
+            loop {
+                // stdin of the USB serial console is non-blocking
+                let n = match std::io::stdin().read(&mut buf) {
+                    Ok(n) => n,
+                    Err(_) => 0,
+                };
+                if n == 0 {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+                for b in &buf[..n] {
+                    match *b {
+                        b'\r' | b'\n' => {
+                            if !line.trim().is_empty() {
+                                match parse_command(&line) {
+                                    Ok(cmd) => commands.lock().unwrap().push(cmd),
+                                    Err(e) => println!("error: {}", e),
+                                }
+                            }
+                            line.clear();
+                        },
+                        c if line.len() < MAX_LINE_LENGTH => line.push(c as char),
+                        _ => {},
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn poll(&mut self) -> Vec<ConsoleCommand> {
+        std::mem::take(&mut *self.commands.lock().unwrap())
+    }
+}
+
+fn parse_command(text: &str) -> Result<ConsoleCommand, String> {
+    let args: Vec<&str> = text.trim().split_whitespace().collect();
+    match args.as_slice() {
+        ["help"] => Ok(ConsoleCommand::Help),
+        ["status"] => Ok(ConsoleCommand::Status),
+        ["config", "get"] => Ok(ConsoleCommand::ConfigGet("".to_string())),
+        ["config", "get", key] => Ok(ConsoleCommand::ConfigGet(key.to_string())),
+        // The value is the rest of the line and may contain spaces, e.g. a schedule. Empty clears a text setting.
+        ["config", "set", key, ..] => Ok(ConsoleCommand::ConfigSet(key.to_string(), rest_of(text, 3))),
+        // The JSON document may contain spaces
+        ["config", "import", ..] => Ok(ConsoleCommand::ConfigImport(rest_of(text, 2))),
+        ["config", "export"] => Ok(ConsoleCommand::ConfigExport),
+        ["cal"] => Ok(ConsoleCommand::Calibrate),
+        ["cal", "confirm"] => Ok(ConsoleCommand::CalibrateConfirm),
+        ["cal", "undo"] => Ok(ConsoleCommand::CalibrateUndo),
+        ["dump", "csv"] => Ok(ConsoleCommand::DumpCsv),
+        ["wifi", "scan"] => Ok(ConsoleCommand::WifiScan),
+        ["diag"] => Ok(ConsoleCommand::Diagnostics),
+        ["ripple"] => Ok(ConsoleCommand::Ripple),
+        ["reboot"] => Ok(ConsoleCommand::Reboot),
+        ["shutdown"] => Ok(ConsoleCommand::Shutdown),
+        ["stream", mode] => Ok(ConsoleCommand::Stream(mode.to_string())),
+        ["MEAS?"] | ["meas?"] => Ok(ConsoleCommand::Measure),
+        ["journal"] => Ok(ConsoleCommand::Journal),
+        ["iot"] => Ok(ConsoleCommand::Iot),
+        ["iot", "reset"] => Ok(ConsoleCommand::IotReset),
+        ["soc"] => Ok(ConsoleCommand::Soc),
+        ["soc", "reset"] => Ok(ConsoleCommand::SocReset(None)),
+        ["soc", "reset", percent] => {
+            match percent.parse::<f32>() {
+                Ok(p) if p >= 0.0 && p <= 100.0 => Ok(ConsoleCommand::SocReset(Some(p))),
+                _ => Err("soc reset requires a percentage 0-100".to_string()),
+            }
+        },
+        ["energy"] => Ok(ConsoleCommand::Energy),
+        ["energy", "reset"] => Ok(ConsoleCommand::EnergyReset),
+        ["battery", "cal", "reset"] => Ok(ConsoleCommand::BatteryCalibrate(None)),
+        ["battery", "cal", volts] => {
+            match volts.parse::<f32>() {
+                Ok(v) if v > 0.0 => Ok(ConsoleCommand::BatteryCalibrate(Some(v))),
+                _ => Err("battery cal requires the measured battery voltage".to_string()),
+            }
+        },
+        _ => Err(format!("unknown command '{}', {}", text.trim(), HELP)),
+    }
+}
+
+// The text after the first words, with the spaces inside kept
+fn rest_of(text: &str, words: usize) -> String {
+    let mut rest = text.trim();
+    for _ in 0..words {
+        rest = rest.split_once(char::is_whitespace).map_or("", |(_, rest)| rest.trim_start());
+    }
+    rest.to_string()
+}
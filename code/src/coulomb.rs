@@ -0,0 +1,82 @@
+// Coulomb
+// Coulomb counter for a battery under test: integrates the charge in and out of the DUT
+// and tracks state-of-charge, charge efficiency and equivalent full cycles.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+//
+// Positive current discharges the battery (current flows into the load),
+// negative current charges it.
+
+use log::*;
+use std::time::Instant;
+
+pub struct CoulombCounter {
+    capacity: f32,          // rated capacity in C
+    initial_soc: f32,       // %
+    charge_out: f32,        // C discharged since reset
+    charge_in: f32,         // C charged since reset
+    last: Option<Instant>,
+}
+
+impl CoulombCounter {
+    pub fn new(capacity_mah: f32, initial_soc: f32) -> CoulombCounter {
+        info!("Coulomb counter: capacity {:.0}mAh, initial SoC {:.1}%", capacity_mah, initial_soc);
+        CoulombCounter {
+            capacity: capacity_mah * 3.6,
+            initial_soc: initial_soc.clamp(0.0, 100.0),
+            charge_out: 0.0,
+            charge_in: 0.0,
+            last: None,
+        }
+    }
+
+    // Integrate the current up to the sample time
+    pub fn update(&mut self, current: f32, instant: Instant)
+    {
+        if let Some(last) = self.last {
+            let dt = instant.saturating_duration_since(last).as_secs_f32();
+            if current > 0.0 {
+                self.charge_out += current * dt;
+            }
+            else {
+                self.charge_in += -current * dt;
+            }
+        }
+        self.last = Some(instant);
+    }
+
+    // State-of-charge in %
+    pub fn soc(&self) -> f32 {
+        let net = (self.charge_in - self.charge_out) / self.capacity * 100.0;
+        (self.initial_soc + net).clamp(0.0, 100.0)
+    }
+
+    // Discharged / charged in %, None until the battery has been charged
+    pub fn efficiency(&self) -> Option<f32> {
+        if self.charge_in <= 0.0 {
+            return None;
+        }
+        Some(self.charge_out / self.charge_in * 100.0)
+    }
+
+    // Equivalent full discharge cycles
+    pub fn cycles(&self) -> f32 {
+        self.charge_out / self.capacity
+    }
+
+    pub fn charge_out_mah(&self) -> f32 {
+        self.charge_out / 3.6
+    }
+
+    pub fn charge_in_mah(&self) -> f32 {
+        self.charge_in / 3.6
+    }
+
+    pub fn reset(&mut self, initial_soc: f32)
+    {
+        info!("Coulomb counter reset, SoC {:.1}%", initial_soc);
+        self.initial_soc = initial_soc.clamp(0.0, 100.0);
+        self.charge_out = 0.0;
+        self.charge_in = 0.0;
+    }
+}
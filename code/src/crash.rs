@@ -0,0 +1,181 @@
+// Crash
+// Panic hook that stores a crash marker, the last log lines and the logging state into NVS.
+// The report is taken on the next boot and sent as a "crash" event.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+
+const CRASH_NAMESPACE: &str = "crash";
+const LOG_LINES: usize = 8;
+const LOG_LINE_LEN: usize = 120;
+const MESSAGE_LEN: usize = 256;
+
+#[derive(Clone, Copy)]
+struct CrashState {
+    channel: u8,
+    logging: bool,
+    buffer: u32,
+    seq: u32,
+    clock: u64,     // ns
+}
+
+pub struct CrashReport {
+    pub message: String,
+    pub log: String,
+    pub channel: u8,
+    pub logging: bool,
+    pub buffer: u32,
+    pub seq: u32,
+    pub clock: u128,
+}
+
+static STATE: Mutex<CrashState> = Mutex::new(CrashState { channel: 0, logging: false, buffer: 0, seq: 0, clock: 0 });
+static LOG_RING: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+// Logger keeping the last lines in memory for the crash report
+struct RingLogger {
+    inner: EspLogger,
+}
+
+static LOGGER: RingLogger = RingLogger { inner: EspLogger::new() };
+
+impl Log for RingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record)
+    {
+        self.inner.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        #[cfg(feature = "wifi")]
+        crate::syslog::forward(record);
+        // Never block the caller, a line may be lost while the panic hook holds the ring
+        if let Ok(mut ring) = LOG_RING.try_lock() {
+            let mut line = format!("{} {}", record.level(), record.args());
+            if line.len() > LOG_LINE_LEN {
+                let mut end = LOG_LINE_LEN;
+                while !line.is_char_boundary(end) {
+                    end -= 1;
+                }
+                line.truncate(end);
+            }
+            if ring.len() >= LOG_LINES {
+                ring.pop_front();
+            }
+            ring.push_back(line);
+        }
+    }
+
+    fn flush(&self)
+    {
+        self.inner.flush();
+    }
+}
+
+// Replaces EspLogger::initialize_default()
+pub fn init_logger()
+{
+    match log::set_logger(&LOGGER) {
+        Ok(_) => log::set_max_level(LevelFilter::Info),
+        Err(e) => println!("Failed to set logger: {:?}", e),
+    }
+}
+
+// Key state saved with the crash marker, updated every loop
+pub fn update_state(channel: u8, logging: bool, buffer: u32, seq: u32)
+{
+    let clock = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as u64;
+    let mut state = STATE.lock().unwrap();
+    *state = CrashState { channel: channel, logging: logging, buffer: buffer, seq: seq, clock: clock };
+}
+
+pub fn install(partition: EspNvsPartition<NvsDefault>)
+{
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        let mut message = panic_info.to_string();
+        if message.len() > MESSAGE_LEN {
+            let mut end = MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        let state = match STATE.try_lock() {
+            Ok(state) => *state,
+            Err(_) => CrashState { channel: 0, logging: false, buffer: 0, seq: 0, clock: 0 },
+        };
+        let log = match LOG_RING.try_lock() {
+            Ok(ring) => ring.iter().cloned().collect::<Vec<String>>().join("\n"),
+            Err(_) => String::new(),
+        };
+        let mut blob = [0u8; 18];
+        blob[0] = state.channel;
+        blob[1] = state.logging as u8;
+        blob[2..6].copy_from_slice(&state.buffer.to_le_bytes());
+        blob[6..10].copy_from_slice(&state.seq.to_le_bytes());
+        blob[10..18].copy_from_slice(&state.clock.to_le_bytes());
+        match EspNvs::new(partition.clone(), CRASH_NAMESPACE, true) {
+            Ok(mut nvs) => {
+                let _ = nvs.set_str("message", &message);
+                let _ = nvs.set_str("log", &log);
+                let _ = nvs.set_blob("state", &blob);
+            },
+            Err(e) => {
+                println!("Failed to store crash marker: {:?}", e);
+            }
+        }
+        default_hook(panic_info);
+    }));
+}
+
+// Take the crash report stored by the previous boot, if any
+pub fn take_report(partition: EspNvsPartition<NvsDefault>) -> Option<CrashReport> {
+    let mut nvs = match EspNvs::new(partition, CRASH_NAMESPACE, true) {
+        Ok(nvs) => nvs,
+        Err(e) => {
+            info!("Failed to open crash namespace: {:?}", e);
+            return None;
+        }
+    };
+    let mut blob = [0u8; 18];
+    let state = match nvs.get_blob("state", &mut blob) {
+        Ok(Some(data)) if data.len() == 18 => {
+            let mut s = [0u8; 18];
+            s.copy_from_slice(data);
+            s
+        },
+        _ => return None,
+    };
+    let mut message_buf = [0u8; MESSAGE_LEN + 1];
+    let mut log_buf = [0u8; LOG_LINES * (LOG_LINE_LEN + 1) + 1];
+    let message = nvs.get_str("message", &mut message_buf).ok().flatten().unwrap_or("").to_string();
+    let log = nvs.get_str("log", &mut log_buf).ok().flatten().unwrap_or("").to_string();
+    let _ = nvs.remove("state");
+    let _ = nvs.remove("message");
+    let _ = nvs.remove("log");
+    let report = CrashReport {
+        message: message,
+        log: log,
+        channel: state[0],
+        logging: state[1] != 0,
+        buffer: u32::from_le_bytes([state[2], state[3], state[4], state[5]]),
+        seq: u32::from_le_bytes([state[6], state[7], state[8], state[9]]),
+        clock: u64::from_le_bytes([state[10], state[11], state[12], state[13], state[14], state[15], state[16], state[17]]) as u128,
+    };
+    info!("Previous boot crashed: {}", report.message);
+    info!("Crash state: channel={} logging={} buffer={} seq={}", report.channel, report.logging, report.buffer, report.seq);
+    for line in report.log.lines() {
+        info!("  {}", line);
+    }
+    Some(report)
+}
@@ -5,64 +5,331 @@
 // Copyright (c) 2024 Hiroshi Nakajima
 
 use log::*;
+use std::collections::VecDeque;
+use std::collections::vec_deque::Drain;
+use std::time::Instant;
+use crate::acrms::AcReading;
 
+// Free heap always left for WiFi, TLS and the HTTP client when sizing the buffer
+const HEAP_RESERVE: usize = 32 * 1024;
+// Smallest buffer the adaptive sizing shrinks to while the buffer is empty
+const MIN_CAPACITY: usize = 64;
+// Difference between wall clock and monotonic elapsed time regarded as a clock step (1s)
+const CLOCK_STEP_THRESHOLD_NS: i128 = 1_000_000_000;
+// Input power below which the efficiency is not computed (W)
+const MIN_EFFICIENCY_POWER: f32 = 0.001;
+
+#[derive(Clone, Copy)]
 pub struct CurrentLog {
     pub voltage: f32,
     pub current: f32,
     pub power: f32,
     pub clock: u128,
     pub battery: f32,
+    pub clock_step: bool,   // true if the timestamp was re-based after a clock step
+    pub seq: u32,           // monotonically increasing sequence number since boot
+    pub crc: u32,           // CRC-32 of seq, clock, voltage, current, power and battery
+    pub soc: Option<f32>,   // state-of-charge of the battery under test in %, coulomb counter mode only
+    pub ac: Option<AcReading>,  // RMS values of the window, AC mode only (voltage/current are the means)
+    pub second: Option<SensorReading>,  // second sensor, if configured
+}
+
+// Reading of the second INA228, e.g. on the other side of a regulator
+#[derive(Clone, Copy)]
+pub struct SensorReading {
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+}
+
+// Derived channel of the first sensor minus the second, e.g. charger input vs. battery output
+#[derive(Clone, Copy)]
+pub struct Differential {
+    pub current: f32,   // A
+    pub loss: f32,      // W
+    pub efficiency: Option<f32>,    // %, None below MIN_EFFICIENCY_POWER at the input
 }
 
 impl CurrentLog {
     pub fn default() -> Self {
-        CurrentLog { voltage: 0.0, current: 0.0, power: 0.0, clock: 0, battery: 0.0 }
+        CurrentLog { voltage: 0.0, current: 0.0, power: 0.0, clock: 0, battery: 0.0, clock_step: false, seq: 0, crc: 0, soc: None, ac: None, second: None }
+    }
+
+    // CRC-32 over the little-endian record fields (seq u32, clock u64, voltage, current, power, battery f32)
+    pub fn calc_crc(&self) -> u32 {
+        let mut buf = [0u8; 28];
+        buf[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        buf[4..12].copy_from_slice(&(self.clock as u64).to_le_bytes());
+        buf[12..16].copy_from_slice(&self.voltage.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.current.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.power.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.battery.to_le_bytes());
+        crc32(&buf)
+    }
+
+    // Difference of the two sensors, None without a second sensor reading
+    pub fn differential(&self) -> Option<Differential> {
+        let second = self.second?;
+        let efficiency = match self.power.abs() >= MIN_EFFICIENCY_POWER {
+            true => Some(second.power.abs() / self.power.abs() * 100.0),
+            false => None,
+        };
+        Some(Differential {
+            current: self.current - second.current,
+            loss: self.power - second.power,
+            efficiency: efficiency,
+        })
+    }
+}
+
+// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            }
+            else {
+                crc >>= 1;
+            }
+        }
     }
+    !crc
 }
 
 
 pub struct CurrentRecord {
-    rec: Vec<CurrentLog>,
+    rec: VecDeque<CurrentLog>,  // pre-allocated ring buffer, never grows on push
+    last_clock: u128,
+    last_instant: Option<Instant>,
+    next_seq: u32,
+    first_seq: u32,         // first record of this boot, older ones were restored and keep their timestamps
+    retention_ns: u128,     // 0: keep all records until the buffer is full
+    dropped: u32,           // records dropped by the retention policy
+    max_records: usize,     // configured upper limit
+    capacity: usize,        // effective capacity, adapted to the free heap
+    heap_percent: usize,    // share of the free heap the buffer may grow into, 0: fixed max_records
 }
 
 #[allow(dead_code)]
 impl CurrentRecord {
     pub fn new() -> CurrentRecord {
-        CurrentRecord { rec: Vec::new() }
+        CurrentRecord { rec: VecDeque::new(), last_clock: 0, last_instant: None, next_seq: 0, first_seq: 0, retention_ns: 0, dropped: 0,
+            max_records: 1023, capacity: 1023, heap_percent: 0 }
+    }
+
+    pub fn set_capacity(&mut self, max_records: usize, heap_percent: usize)
+    {
+        self.max_records = max_records;
+        self.capacity = max_records;
+        self.heap_percent = heap_percent;
+        if heap_percent == 0 {
+            self.rec.reserve_exact(max_records.saturating_sub(self.rec.len()));
+        }
+        else {
+            self.update_capacity();
+        }
+    }
+
+    // Grow the capacity up to max_records while there is free heap, shrink it under memory pressure.
+    // The buffer is re-allocated only in steps of 25% so that pushes never allocate.
+    pub fn update_capacity(&mut self) -> usize {
+        if self.heap_percent == 0 {
+            return self.capacity;
+        }
+        let free = unsafe { esp_idf_sys::esp_get_free_heap_size() } as usize;
+        let size = std::mem::size_of::<CurrentLog>();
+        let capacity = if free < HEAP_RESERVE {
+            // Keep what is buffered, but no more and release the spare allocation
+            self.rec.shrink_to(self.rec.len());
+            self.rec.len()
+        }
+        else {
+            // The allocated buffer counts as free for its own share of the heap
+            let allocated = self.rec.capacity() * size;
+            let target = ((free + allocated - HEAP_RESERVE) * self.heap_percent / 100 / size)
+                .clamp(MIN_CAPACITY.min(self.max_records), self.max_records);
+            let allocated = self.rec.capacity();
+            if target > allocated && (target >= allocated + allocated / 4 || target == self.max_records) {
+                self.rec.reserve_exact(target - self.rec.len());
+            }
+            target.min(self.rec.capacity())
+        };
+        if capacity.abs_diff(self.capacity) > self.capacity / 10 {
+            info!("Record buffer capacity: {} (free heap {} bytes)", capacity, free);
+        }
+        self.capacity = capacity;
+        capacity
+    }
+
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.rec.len() >= self.capacity
     }
 
     pub fn record(&mut self, data: CurrentLog)
     {
-        self.rec.push(data);
+        self.record_at(data, Instant::now());
+    }
+
+    // Record a sample taken at the given monotonic time
+    pub fn record_at(&mut self, mut data: CurrentLog, now: Instant)
+    {
+        match self.last_instant {
+            // Taken before the last clock check, put it on the time base of that check
+            Some(last) if now < last => {
+                let clock = self.last_clock as i128 - last.duration_since(now).as_nanos() as i128;
+                if (data.clock as i128 - clock).abs() > CLOCK_STEP_THRESHOLD_NS {
+                    data.clock = clock.max(0) as u128;
+                    data.clock_step = true;
+                }
+            },
+            _ => self.check_clock(data.clock, now),
+        }
+        data.seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        data.crc = data.calc_crc();
+        self.rec.push_back(data);
+    }
+
+    // Compare the wall clock progress with the monotonic timer to detect SNTP steps.
+    // Also called when the time is synchronized, so that the records taken before are back-filled.
+    pub fn check_clock(&mut self, clock: u128, now: Instant)
+    {
+        if let Some(last) = self.last_instant {
+            let mono_ns = now.duration_since(last).as_nanos() as i128;
+            let wall_ns = clock as i128 - self.last_clock as i128;
+            let step = wall_ns - mono_ns;
+            if step.abs() > CLOCK_STEP_THRESHOLD_NS {
+                info!("Clock step detected: {}ms, re-basing {} buffered records", step / 1_000_000, self.rec.len());
+                self.rebase(step);
+            }
+        }
+        self.last_clock = clock;
+        self.last_instant = Some(now);
+    }
+
+    // Shift buffered timestamps onto the new time base and mark them
+    fn rebase(&mut self, step: i128)
+    {
+        let first_seq = self.first_seq;
+        for it in self.rec.iter_mut().filter(|it| it.seq >= first_seq) {
+            it.clock = (it.clock as i128 + step).max(0) as u128;
+            it.clock_step = true;
+            it.crc = it.calc_crc();
+        }
     }
 
     pub fn dump(&self)
     {
-        info!("time,voltage,current,power,battery");
+        info!("seq,time,voltage,current,power,battery,crc");
         for it in &self.rec {
-           info!("{},{},{},{},{}", it.clock, it.voltage, it.current, it.power, it.battery);
+           info!("{},{},{},{},{},{},{:08x}", it.seq, it.clock, it.voltage, it.current, it.power, it.battery, it.crc);
         } 
     }
 
+    // Print buffered records as CSV to the console
+    pub fn dump_csv(&self)
+    {
+        println!("seq,time,voltage,current,power,battery");
+        for it in &self.rec {
+            println!("{},{},{:.5},{:.5},{:.5},{:.2}", it.seq, it.clock, it.voltage, it.current, it.power, it.battery);
+        }
+    }
+
     pub fn clear(&mut self)
     {
         self.rec.clear()
     }
 
+    // Keep only records newer than the given age, preferring recent data over stopping the logging
+    pub fn set_retention(&mut self, secs: u64)
+    {
+        self.retention_ns = secs as u128 * 1_000_000_000;
+    }
+
+    pub fn has_retention(&self) -> bool {
+        self.retention_ns > 0
+    }
+
+    // Drop records older than the retention period, returns the number of records dropped
+    pub fn expire(&mut self, now: u128) -> usize {
+        if self.retention_ns == 0 || now < self.retention_ns {
+            return 0;
+        }
+        let limit = now - self.retention_ns;
+        let num = self.rec.iter().take_while(|it| it.clock < limit).count();
+        self.drop_oldest(num);
+        num
+    }
+
+    // Drop the oldest records to make room for new ones
+    pub fn drop_oldest(&mut self, size: usize)
+    {
+        let num = size.min(self.rec.len());
+        if num > 0 {
+            self.rec.drain(0..num);
+            self.dropped = self.dropped.wrapping_add(num as u32);
+        }
+    }
+
+    pub fn get_dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    // Sequence number of the last recorded sample
+    pub fn last_seq(&self) -> u32 {
+        self.next_seq.wrapping_sub(1)
+    }
+
     pub fn get_size(&self) -> usize {
         self.rec.len()    
     }
 
-    pub fn get_all_data(&self) -> &Vec<CurrentLog> {
-        &self.rec
+    // Up to size newest records, oldest first
+    pub fn newest(&self, size: usize) -> impl Iterator<Item = &CurrentLog> {
+        self.rec.iter().skip(self.rec.len().saturating_sub(size))
+    }
+
+    // Up to size records after the given sequence number (all if None) with a timestamp of at least since, oldest first
+    pub fn records_after(&self, after: Option<u32>, since: u128, size: usize) -> Vec<CurrentLog> {
+        self.rec.iter()
+            .filter(|it| after.map_or(true, |seq| it.seq > seq) && it.clock >= since)
+            .take(size)
+            .cloned()
+            .collect()
+    }
+
+    // Remove the records up to and including the given sequence number, returns the number removed
+    pub fn remove_through(&mut self, seq: u32) -> usize {
+        let num = self.rec.iter().take_while(|it| it.seq <= seq).count();
+        self.rec.drain(0..num);
+        num
+    }
+
+    // Put back records recovered from a previous boot, keeping their sequence numbers and CRCs.
+    // New records are numbered after them, uploads skip sequence numbers already queued.
+    pub fn restore(&mut self, data: Vec<CurrentLog>)
+    {
+        for it in data {
+            if self.rec.len() >= self.capacity {
+                break;
+            }
+            self.next_seq = self.next_seq.max(it.seq.wrapping_add(1));
+            self.rec.push_back(it);
+        }
+        self.first_seq = self.next_seq;
     }
 
-    pub fn remove_data(&mut self, size : usize){
-        let mut num = size;
-        if self.rec.len() < size {
-            num = self.rec.len();
-        }       
-        let _ = &self.rec.drain(0..num);
+    // Remove and return up to size oldest records for transfer
+    pub fn take_batch(&mut self, size: usize) -> Drain<'_, CurrentLog> {
+        let num = size.min(self.rec.len());
+        self.rec.drain(0..num)
     }
 
 }
@@ -0,0 +1,280 @@
+// DataApi
+// HTTP endpoint to download the buffered records from the device, for users without a database:
+//   GET /data?format=csv|json&since=<ns>&clear=true
+//   GET /status (live readings, logging state, buffer, network and upload counters as JSON)
+//   GET /journal (event log as JSON)
+//   POST /config?key=<key> (store a setting like config set on the console, the value is the body)
+//   GET /capture, POST /capture?duration=2h (capture session of a fixed length, duration=0 ends it)
+//   GET /events (Server-Sent Events with the live samples) and GET / (live chart page)
+// The buffer is owned by the main loop, so the handler fetches the records in chunks through a channel
+// and streams them to the client. With clear=true the downloaded records are removed afterwards.
+// The live samples are kept in a short ring shared with the main loop. The HTTP server runs one handler
+// at a time, so an event stream ends after a few seconds and the browser reconnects with Last-Event-ID,
+// continuing without a gap while downloads are served in between.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::thread;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+use embedded_svc::http::{Headers, Method};
+use embedded_svc::io::{Read, Write};
+use esp_idf_svc::http::server::{EspHttpServer, Configuration};
+use crate::CurrentLog;
+use crate::capture;
+
+// Records per chunk handed over by the main loop
+pub const DATA_CHUNK_RECORDS: usize = 256;
+// The main loop answers within a loop period, longer means it is busy (e.g. calibrating)
+const DATA_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+// Live samples kept for the event streams, enough to bridge a reconnect at 100 samples/s
+const LIVE_BUFFER_SIZE: usize = 1024;
+// Length of one event stream, how often it sends new samples and the reconnect delay of the browser
+const SSE_SESSION_TIME: Duration = Duration::from_secs(5);
+const SSE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+const SSE_RETRY_MS: u32 = 200;
+// Longest setting value accepted by POST /config
+const CONFIG_VALUE_SIZE: usize = 512;
+
+const CHART_PAGE: &str = include_str!("./web/chart.html");
+
+// Sample of the event stream
+#[derive(Clone, Copy)]
+struct LiveSample {
+    clock: u128,
+    voltage: f32,
+    current: f32,
+    power: f32,
+}
+
+pub enum DataRequest {
+    // Records after the sequence number (all if None) with a timestamp of at least since (ns)
+    Records(Option<u32>, u128, SyncSender<Vec<CurrentLog>>),
+    // Remove the records up to and including the sequence number
+    Clear(u32),
+    // Status JSON built by the main loop
+    Status(SyncSender<String>),
+    // Event log JSON
+    Journal(SyncSender<String>),
+    // Start a capture of the seconds (0: end it) or None to read its state, replied as JSON
+    Capture(Option<u64>, SyncSender<String>),
+    // Store a setting, replied with whether it was applied at once or an error
+    Config(String, String, SyncSender<Result<bool, String>>),
+}
+
+pub struct DataApi {
+    _server: EspHttpServer<'static>,
+    rx: Receiver<DataRequest>,
+    live: Arc<Mutex<VecDeque<LiveSample>>>,
+    samples: Vec<LiveSample>,      // pushed since the last flush
+}
+
+impl DataApi {
+    pub fn start(port: u16) -> anyhow::Result<DataApi> {
+        let (tx, rx) = sync_channel::<DataRequest>(4);
+        let mut server = EspHttpServer::new(&Configuration {
+            http_port: port,
+            ..Default::default()
+        })?;
+        let live = Arc::new(Mutex::new(VecDeque::<LiveSample>::with_capacity(LIVE_BUFFER_SIZE)));
+        server.fn_handler("/", Method::Get, move |req| -> anyhow::Result<()> {
+            let mut resp = req.into_response(200, Some("OK"), &[("Content-Type", "text/html")])?;
+            resp.write_all(CHART_PAGE.as_bytes())?;
+            Ok(())
+        })?;
+        let events_live = live.clone();
+        server.fn_handler("/events", Method::Get, move |req| -> anyhow::Result<()> {
+            // Continue after the last sample of the previous stream, otherwise start with the newest one
+            let mut last = req.header("Last-Event-ID").and_then(|id| id.parse::<u128>().ok())
+                .or_else(|| events_live.lock().unwrap().back().map(|s| s.clock))
+                .unwrap_or(0);
+            let mut resp = req.into_response(200, Some("OK"), &[("Content-Type", "text/event-stream"), ("Cache-Control", "no-cache")])?;
+            resp.write_all(format!("retry: {}\n\n", SSE_RETRY_MS).as_bytes())?;
+            let end = Instant::now() + SSE_SESSION_TIME;
+            while Instant::now() < end {
+                let samples: Vec<LiveSample> = {
+                    let live = events_live.lock().unwrap();
+                    // The clock was stepped back (NTP sync), continue with the newest sample
+                    if live.back().map_or(false, |s| s.clock < last) {
+                        last = live.back().unwrap().clock;
+                    }
+                    live.iter().filter(|s| s.clock > last).copied().collect()
+                };
+                if let Some(newest) = samples.last() {
+                    last = newest.clock;
+                    let items: Vec<String> = samples.iter()
+                        .map(|s| format!("{{\"ts\":{},\"voltage\":{:.5},\"current\":{:.5},\"power\":{:.5}}}", s.clock, s.voltage, s.current, s.power))
+                        .collect();
+                    // An error means the browser has gone
+                    resp.write_all(format!("id: {}\ndata: [{}]\n\n", last, items.join(",")).as_bytes())?;
+                    resp.flush()?;
+                }
+                thread::sleep(SSE_POLL_INTERVAL);
+            }
+            Ok(())
+        })?;
+        let status_tx = tx.clone();
+        server.fn_handler("/status", Method::Get, move |req| -> anyhow::Result<()> {
+            let (reply_tx, reply_rx) = sync_channel::<String>(1);
+            status_tx.send(DataRequest::Status(reply_tx)).map_err(|_| anyhow::anyhow!("main loop has gone"))?;
+            let body = reply_rx.recv_timeout(DATA_REPLY_TIMEOUT)?;
+            let mut resp = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+            resp.write_all(body.as_bytes())?;
+            Ok(())
+        })?;
+        let journal_tx = tx.clone();
+        server.fn_handler("/journal", Method::Get, move |req| -> anyhow::Result<()> {
+            let (reply_tx, reply_rx) = sync_channel::<String>(1);
+            journal_tx.send(DataRequest::Journal(reply_tx)).map_err(|_| anyhow::anyhow!("main loop has gone"))?;
+            let body = reply_rx.recv_timeout(DATA_REPLY_TIMEOUT)?;
+            let mut resp = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+            resp.write_all(body.as_bytes())?;
+            Ok(())
+        })?;
+        let capture_tx = tx.clone();
+        server.fn_handler("/capture", Method::Get, move |req| -> anyhow::Result<()> {
+            let (reply_tx, reply_rx) = sync_channel::<String>(1);
+            capture_tx.send(DataRequest::Capture(None, reply_tx)).map_err(|_| anyhow::anyhow!("main loop has gone"))?;
+            let body = reply_rx.recv_timeout(DATA_REPLY_TIMEOUT)?;
+            let mut resp = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+            resp.write_all(body.as_bytes())?;
+            Ok(())
+        })?;
+        let capture_tx = tx.clone();
+        server.fn_handler("/capture", Method::Post, move |req| -> anyhow::Result<()> {
+            let duration = req.uri().split_once('?')
+                .and_then(|(_, query)| query.split('&').find_map(|param| param.strip_prefix("duration=")))
+                .and_then(capture::parse_duration);
+            let secs = match duration {
+                Some(secs) => secs,
+                None => {
+                    let mut resp = req.into_response(400, Some("Bad Request"), &[("Content-Type", "text/plain")])?;
+                    resp.write_all(format!("duration must be seconds or e.g. 90m, 2h, up to {}s\n", capture::MAX_DURATION_SECS).as_bytes())?;
+                    return Ok(());
+                }
+            };
+            let (reply_tx, reply_rx) = sync_channel::<String>(1);
+            capture_tx.send(DataRequest::Capture(Some(secs), reply_tx)).map_err(|_| anyhow::anyhow!("main loop has gone"))?;
+            let body = reply_rx.recv_timeout(DATA_REPLY_TIMEOUT)?;
+            let mut resp = req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+            resp.write_all(body.as_bytes())?;
+            Ok(())
+        })?;
+        let config_tx = tx.clone();
+        server.fn_handler("/config", Method::Post, move |mut req| -> anyhow::Result<()> {
+            let key = req.uri().split_once('?')
+                .and_then(|(_, query)| query.split('&').find_map(|param| param.strip_prefix("key=")))
+                .unwrap_or("").to_string();
+            let mut body = [0u8; CONFIG_VALUE_SIZE];
+            let mut len = 0;
+            while len < body.len() {
+                match req.read(&mut body[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            if len == body.len() {
+                let mut resp = req.into_response(400, Some("Bad Request"), &[("Content-Type", "text/plain")])?;
+                resp.write_all(format!("error: the value is longer than {} bytes\n", CONFIG_VALUE_SIZE - 1).as_bytes())?;
+                return Ok(());
+            }
+            let value = std::str::from_utf8(&body[..len]).unwrap_or("").trim().to_string();
+            let (reply_tx, reply_rx) = sync_channel::<Result<bool, String>>(1);
+            config_tx.send(DataRequest::Config(key.clone(), value, reply_tx)).map_err(|_| anyhow::anyhow!("main loop has gone"))?;
+            let (status, text) = match reply_rx.recv_timeout(DATA_REPLY_TIMEOUT)? {
+                Ok(true) => (200, "ok\n".to_string()),
+                Ok(false) => (200, format!("ok, {} takes effect after reboot\n", key)),
+                Err(e) => (400, format!("error: {}\n", e)),
+            };
+            let mut resp = req.into_response(status, None, &[("Content-Type", "text/plain")])?;
+            resp.write_all(text.as_bytes())?;
+            Ok(())
+        })?;
+        server.fn_handler("/data", Method::Get, move |req| -> anyhow::Result<()> {
+            let (json, since, clear) = parse_query(req.uri());
+            let content_type = if json { "application/json" } else { "text/csv" };
+            let mut resp = req.into_response(200, Some("OK"), &[("Content-Type", content_type)])?;
+            resp.write_all(if json { b"[" } else { b"seq,time,voltage,current,power,battery\n" })?;
+            let mut last_seq: Option<u32> = None;
+            let mut count = 0;
+            loop {
+                let (reply_tx, reply_rx) = sync_channel::<Vec<CurrentLog>>(1);
+                tx.send(DataRequest::Records(last_seq, since, reply_tx)).map_err(|_| anyhow::anyhow!("main loop has gone"))?;
+                let records = reply_rx.recv_timeout(DATA_REPLY_TIMEOUT)?;
+                if records.is_empty() {
+                    break;
+                }
+                let mut body = String::with_capacity(records.len() * 64);
+                for it in &records {
+                    if json {
+                        body.push_str(&format!("{}{{\"ts\":{},\"seq\":{},\"voltage\":{:.5},\"current\":{:.5},\"power\":{:.5},\"battery\":{:.2}}}",
+                            if count > 0 { "," } else { "" }, it.clock, it.seq, it.voltage, it.current, it.power, it.battery));
+                    }
+                    else {
+                        body.push_str(&format!("{},{},{:.5},{:.5},{:.5},{:.2}\n", it.seq, it.clock, it.voltage, it.current, it.power, it.battery));
+                    }
+                    count += 1;
+                }
+                resp.write_all(body.as_bytes())?;
+                last_seq = records.last().map(|it| it.seq);
+            }
+            if json {
+                resp.write_all(b"]")?;
+            }
+            info!("Data API: {} records downloaded", count);
+            if let (true, Some(seq)) = (clear, last_seq) {
+                tx.send(DataRequest::Clear(seq)).map_err(|_| anyhow::anyhow!("main loop has gone"))?;
+            }
+            Ok(())
+        })?;
+        info!("Data API on port {}: GET /data, GET /status, GET /journal, /capture, POST /config, GET /events, live chart on /", port);
+        Ok(DataApi { _server: server, rx: rx, live: live, samples: Vec::new() })
+    }
+
+    // Called for every sample
+    pub fn push(&mut self, data: &CurrentLog)
+    {
+        self.samples.push(LiveSample { clock: data.clock, voltage: data.voltage, current: data.current, power: data.power });
+    }
+
+    // Hand the samples pushed since the last call to the event streams
+    pub fn flush(&mut self)
+    {
+        if self.samples.is_empty() {
+            return;
+        }
+        let mut live = self.live.lock().unwrap();
+        for sample in self.samples.drain(..) {
+            if live.len() >= LIVE_BUFFER_SIZE {
+                live.pop_front();
+            }
+            live.push_back(sample);
+        }
+    }
+
+    // Requests of the HTTP handler since the last call
+    pub fn poll(&mut self) -> Vec<DataRequest> {
+        self.rx.try_iter().collect()
+    }
+}
+
+// format=json, since=<ns> and clear=true of the query string
+fn parse_query(uri: &str) -> (bool, u128, bool) {
+    let mut json = false;
+    let mut since = 0;
+    let mut clear = false;
+    if let Some((_, query)) = uri.split_once('?') {
+        for param in query.split('&') {
+            match param.split_once('=') {
+                Some(("format", value)) => json = value == "json",
+                Some(("since", value)) => since = value.parse::<u128>().unwrap_or(0),
+                Some(("clear", value)) => clear = value == "true",
+                _ => {},
+            }
+        }
+    }
+    (json, since, clear)
+}
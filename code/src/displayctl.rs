@@ -1,21 +1,35 @@
 use log::*;
-use std::{thread, time::Duration, sync::Arc, sync::Mutex};
+use std::{thread, time::Duration, time::Instant, sync::Arc, sync::Mutex};
 use esp_idf_hal::i2c;
-use ssd1306::{I2CDisplayInterface, prelude::*, Ssd1306};
+use ssd1306::{I2CDisplayInterface, prelude::*, Ssd1306, mode::BufferedGraphicsMode};
+use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
 use embedded_graphics::{
     mono_font::{ascii::{FONT_10X20, FONT_5X8, FONT_6X10}, MonoTextStyle, MonoTextStyleBuilder},
     pixelcolor::BinaryColor,
-    text::{Text},
+    text::{Text, Alignment},
     geometry::{Point, Size},
     prelude::*,
     image::Image,
     primitives::{Rectangle, PrimitiveStyle},
 };
 use tinybmp::Bmp;
+use profont::PROFONT_24_POINT;
+use u8g2_fonts::{U8g2TextStyle, fonts::u8g2_font_b10_t_japanese1};
+use crate::transfer::TransferStatus;
+use crate::filter::Span;
+use crate::jitter::JitterSummary;
+use crate::ripple::RippleResult;
+use crate::pulse::PulseStats;
+use crate::iotprofile::IotSummary;
+use crate::softi2c::SoftI2c;
+use crate::locale::{Msg, tr, tr_args};
+use crate::capture;
+use crate::tasks;
 
 pub enum LoggingStatus {
     Start,
     Stop,
+    Armed,  // waiting for the trigger start condition
 }
 
 pub enum WifiStatus {
@@ -24,6 +38,177 @@ pub enum WifiStatus {
     Connected,
 }
 
+// Quantity of the big digits page
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BigValue {
+    Current,
+    Voltage,
+    Power,
+}
+
+impl BigValue {
+    pub fn from_str(text: &str) -> Option<BigValue> {
+        match text.trim() {
+            "current" => Some(BigValue::Current),
+            "voltage" => Some(BigValue::Voltage),
+            "power" => Some(BigValue::Power),
+            _ => None,
+        }
+    }
+}
+
+// Power mode of the OLED for headless and battery-powered installs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisplayMode {
+    On,
+    Off,        // panel off and no display thread
+    Button,     // on for display_on_time after boot and after each button press
+}
+
+impl DisplayMode {
+    pub fn from_str(text: &str) -> Option<DisplayMode> {
+        match text.trim() {
+            "on" => Some(DisplayMode::On),
+            "off" => Some(DisplayMode::Off),
+            "button" => Some(DisplayMode::Button),
+            _ => None,
+        }
+    }
+}
+
+// Quantity of an active alarm, blinked while the display is inverted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlarmQuantity {
+    Current,    // current limit trip
+    Buffer,     // buffer alert level passed
+}
+
+// SSD1306 display off command and the inverse and normal display commands
+const SSD1306_DISPLAY_OFF: u8 = 0xAE;
+const SSD1306_INVERSE: u8 = 0xA7;
+const SSD1306_NORMAL: u8 = 0xA6;
+
+// Kana and common kanji of the Japanese texts, 10 pixels high
+const JAPANESE_FONT: u8g2_font_b10_t_japanese1 = u8g2_font_b10_t_japanese1;
+
+// Fastest I2C clock of the SSD1306 (fast mode)
+pub const SSD1306_MAX_I2C_KHZ: u32 = 400;
+
+// SPI clock of the panel, the SSD1306 takes up to 10MHz
+#[cfg(feature = "display-spi")]
+const SPI_BAUDRATE_MHZ: u32 = 10;
+
+// Bus of the panel, the shared I2C bus or SPI (display-spi feature)
+pub type PanelInterface = Box<dyn WriteOnlyDataCommand + Send>;
+
+// The driver and the inverse display command, which is not part of the driver, share the interface
+struct SharedInterface(Arc<Mutex<PanelInterface>>);
+
+impl WriteOnlyDataCommand for SharedInterface {
+    fn send_commands(&mut self, cmd: DataFormat<'_>) -> Result<(), DisplayError> {
+        self.0.lock().unwrap().send_commands(cmd)
+    }
+
+    fn send_data(&mut self, buf: DataFormat<'_>) -> Result<(), DisplayError> {
+        self.0.lock().unwrap().send_data(buf)
+    }
+}
+
+type Panel = Ssd1306<SharedInterface, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>;
+
+const FRAME_WIDTH: usize = 128;
+const FRAME_HEIGHT: usize = 64;
+// One bit per pixel in the SSD1306 layout, a byte is 8 rows of a column
+const FRAME_BYTES: usize = FRAME_WIDTH * FRAME_HEIGHT / 8;
+
+// Frame drawn by the display thread and the frame on the panel. The changed pixels are written to the
+// driver buffer, which sends the rectangle around them on flush. A page that redraws the same content
+// costs no bus traffic, a changing reading only the rectangle of its digits.
+struct DiffFrame {
+    pixels: [u8; FRAME_BYTES],
+    shown: [u8; FRAME_BYTES],
+}
+
+impl DiffFrame {
+    fn new() -> DiffFrame {
+        DiffFrame { pixels: [0; FRAME_BYTES], shown: [0; FRAME_BYTES] }
+    }
+
+    fn clear(&mut self)
+    {
+        self.pixels = [0; FRAME_BYTES];
+    }
+
+    fn flush(&mut self, panel: &mut Panel) -> Result<(), DisplayError> {
+        let mut changed = false;
+        for (i, (new, old)) in self.pixels.iter().zip(self.shown.iter()).enumerate() {
+            let diff = new ^ old;
+            if diff == 0 {
+                continue;
+            }
+            let (x, row) = ((i % FRAME_WIDTH) as u32, (i / FRAME_WIDTH * 8) as u32);
+            for bit in 0..8 {
+                if diff & (1 << bit) != 0 {
+                    panel.set_pixel(x, row + bit, new & (1 << bit) != 0);
+                }
+            }
+            changed = true;
+        }
+        if !changed {
+            return Ok(());
+        }
+        self.shown = self.pixels;
+        let result = panel.flush();
+        if result.is_err() {
+            // The panel state is unknown, every pixel is sent again with the next frame
+            self.shown.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+        result
+    }
+}
+
+impl OriginDimensions for DiffFrame {
+    fn size(&self) -> Size {
+        Size::new(FRAME_WIDTH as u32, FRAME_HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for DiffFrame {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+        where I: IntoIterator<Item = Pixel<Self::Color>>
+    {
+        for Pixel(point, color) in pixels {
+            if point.x < 0 || point.y < 0 || point.x as usize >= FRAME_WIDTH || point.y as usize >= FRAME_HEIGHT {
+                continue;
+            }
+            let (x, y) = (point.x as usize, point.y as usize);
+            let (index, mask) = (y / 8 * FRAME_WIDTH + x, 1u8 << (y % 8));
+            match color {
+                BinaryColor::On => self.pixels[index] |= mask,
+                BinaryColor::Off => self.pixels[index] &= !mask,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Wrapper of the shared I2C driver that implements the I2C trait of the SSD1306 driver
+struct I2CWrapper {
+    driver: Arc<Mutex<i2c::I2cDriver<'static>>>,
+}
+
+impl embedded_hal_0_2::blocking::i2c::Write for I2CWrapper {
+    type Error = ();
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        let mut driver = self.driver.lock().unwrap();
+        driver.write(address, bytes, esp_idf_hal::delay::BLOCK).map_err(|_| ())
+    }
+}
+
 struct DisplayText {
     voltage: f32,
     current: f32,
@@ -34,12 +219,95 @@ struct DisplayText {
     status: LoggingStatus,
     wifi: WifiStatus,
     buffer_water_mark: u32,
+    buffer_capacity: u32,   // effective record buffer capacity
+    buffer_alert: bool,     // a buffer alert level is passed
+    transfer_status: Option<TransferStatus>,    // None until the first upload
     channel: u32,
+    channel_name: String,   // shown next to the channel number, empty: none
     voltage_range: u8,  // 0=mV, 1=V
     current_range: u8,  // 0=mA, 1=A
     power_range: u8,    // 0=mW, 1=W
+    peak_current: f32,
+    peak_power: f32,
+    tripped: bool,
+    adc_low_range: bool,    // true: 40.96mV, false: 163.84mV
+    filtered: bool,         // display values are smoothed
+    ac_mode: bool,          // RMS values are shown
+    demo: bool,             // readings of the simulated sensor
+    divider: bool,          // bus voltage is measured through an external divider
+    config_error: bool,     // sensor configuration error (wrong SHUNT_CAL)
+    soc: Option<SocInfo>,   // battery under test page, coulomb counter mode only
+    iot: Option<IotSummary>,    // IoT profile page in place of the SoC page, None: profiling disabled
+    differential: bool,     // efficiency is shown in place of the peak power
+    efficiency: Option<f32>,    // %, sensor 2 power over sensor 1 power
+    big_digits: Option<BigValue>,   // big digits page for reading from a distance, None: meter page
+    spans: [Span; 3],       // voltage, current and power over the last display period
+    net_info: Option<NetInfo>,      // network page shown periodically, None: disabled
+    backlog: usize,                 // batches of failed uploads stored in flash
+    capture: Option<u64>,           // seconds left of a capture, shown in place of the logging status
+    scan_results: Vec<(String, i8)>,    // WiFi scan page, shown while not empty
+    scan_until: Option<Instant>,
+    scan_serial: u32,
+    jitter: JitterSummary,      // sample spacing since boot for the diagnostics page
+    diag_until: Option<Instant>,    // diagnostics page, shown until this time
+    ripple: Option<RippleResult>,   // ripple page of the last analysis, shown until ripple_until
+    ripple_until: Option<Instant>,
+    pulse: Option<PulseStats>,      // pulse page shown periodically, None: pulse detection disabled
+    on_time: Option<Duration>,  // panel is turned off this long after the last wake, None: always on
+    wake_time: Instant,
+    alarm: Option<AlarmQuantity>,   // active alarm, the display is inverted until it is acknowledged
+    alarm_acknowledged: bool,
 }
 
+// Upload pipeline for the network page
+#[derive(Clone, Copy, PartialEq)]
+pub struct NetInfo {
+    pub records_sent: u64,
+    pub last_upload: Option<Instant>,
+    pub failed_attempts: u32,
+    pub backlog: usize,     // batches stored for the replay
+}
+
+// Battery under test (coulomb counter mode)
+#[derive(Clone, Copy, PartialEq)]
+pub struct SocInfo {
+    pub soc: f32,           // %
+    pub charge_out: f32,    // mAh
+    pub charge_in: f32,     // mAh
+    pub efficiency: Option<f32>,    // %
+    pub cycles: f32,
+}
+
+// Loops (100ms) of the meter page and the SoC page in coulomb counter mode
+const METER_PAGE_LOOPS: u32 = 50;
+const SOC_PAGE_LOOPS: u32 = 30;
+
+// Loops (100ms) between the network pages and how long one is shown
+const NET_PAGE_INTERVAL_LOOPS: u32 = 300;
+const NET_PAGE_LOOPS: u32 = 30;
+
+// Loops (100ms) between the pulse pages and how long one is shown, halfway between the network pages
+const PULSE_PAGE_INTERVAL_LOOPS: u32 = 300;
+const PULSE_PAGE_LOOPS: u32 = 30;
+const PULSE_PAGE_PHASE_LOOPS: u32 = 150;
+
+// WiFi scan page timeout and number of networks shown
+const SCAN_PAGE_TIME: Duration = Duration::from_secs(10);
+const SCAN_PAGE_LINES: usize = 6;
+
+// Diagnostics page timeout
+const DIAG_PAGE_TIME: Duration = Duration::from_secs(10);
+
+// Ripple page timeout
+const RIPPLE_PAGE_TIME: Duration = Duration::from_secs(10);
+
+// Characters of the big digits font across the panel (16 pixels each)
+const BIG_DIGITS_CHARS: usize = 8;
+// Characters of the big digits page title before TRIPPED, and of the channel name next to CH:n
+const BIG_DIGITS_TITLE_CHARS: usize = 18;
+const CHANNEL_NAME_CHARS: usize = 9;
+
+#[derive(Clone)]
 pub struct DisplayPanel {
     txt: Arc<Mutex<DisplayText>>
 }
@@ -57,41 +325,98 @@ impl DisplayPanel {
                          status: LoggingStatus::Stop,
                          wifi: WifiStatus::Disconnected,
                          buffer_water_mark: 0,
+                         buffer_capacity: 0,
+                         buffer_alert: false,
+                         transfer_status: None,
                          channel: 1, // Default channel
+                         channel_name: String::new(),
                          voltage_range: 1, // Default to V
                          current_range: 1, // Default to A
                          power_range: 1,   // Default to W
+                         peak_current: 0.0,
+                         peak_power: 0.0,
+                         tripped: false,
+                         adc_low_range: true,
+                         filtered: false,
+                         ac_mode: false,
+                         demo: false,
+                         divider: false,
+                         config_error: false,
+                         soc: None,
+                         iot: None,
+                         differential: false,
+                         efficiency: None,
+                         big_digits: None,
+                         spans: [Span::default(); 3],
+                         net_info: None,
+                         backlog: 0,
+                         capture: None,
+                         scan_results: Vec::new(),
+                         scan_until: None,
+                         scan_serial: 0,
+                         jitter: JitterSummary::default(),
+                         diag_until: None,
+                         ripple: None,
+                         ripple_until: None,
+                         pulse: None,
+                         on_time: None,
+                         wake_time: Instant::now(),
+                         alarm: None,
+                         alarm_acknowledged: false,
                      })) }
     }
 
-    pub fn start(&mut self, shared_i2c: Arc<Mutex<i2c::I2cDriver<'static>>>)
+    // The panel on the I2C bus shared with the sensor
+    pub fn i2c_interface(shared_i2c: Arc<Mutex<i2c::I2cDriver<'static>>>) -> PanelInterface {
+        Box::new(I2CDisplayInterface::new(I2CWrapper { driver: shared_i2c }))
+    }
+
+    // The panel on a bit-banged I2C bus of its own, pins are sda and scl
+    pub fn soft_i2c_interface(pins: &[i32]) -> anyhow::Result<PanelInterface> {
+        let bus = SoftI2c::new(pins[0], pins[1])?;
+        info!("Display on its own I2C bus: SDA GPIO{} SCL GPIO{}", pins[0], pins[1]);
+        Ok(Box::new(I2CDisplayInterface::new(bus)))
+    }
+
+    // The panel on SPI2, pins are sclk, mosi, dc, cs and the optional reset
+    #[cfg(feature = "display-spi")]
+    pub fn spi_interface(spi: esp_idf_hal::spi::SPI2, pins: &[i32]) -> anyhow::Result<PanelInterface> {
+        use esp_idf_hal::gpio::{AnyIOPin, AnyOutputPin, PinDriver};
+        use esp_idf_hal::spi::{SpiDeviceDriver, SpiDriverConfig, config::Config};
+        use esp_idf_hal::units::FromValueType;
+        let device = SpiDeviceDriver::new_single(spi,
+            unsafe { AnyOutputPin::new(pins[0]) },
+            unsafe { AnyOutputPin::new(pins[1]) },
+            Option::<AnyIOPin>::None,
+            Some(unsafe { AnyOutputPin::new(pins[3]) }),
+            &SpiDriverConfig::new(),
+            &Config::new().baudrate(SPI_BAUDRATE_MHZ.MHz().into()))?;
+        let dc = PinDriver::output(unsafe { AnyOutputPin::new(pins[2]) })?;
+        if let Some(&pin) = pins.get(4) {
+            let mut reset = PinDriver::output(unsafe { AnyOutputPin::new(pin) })?;
+            reset.set_low()?;
+            thread::sleep(Duration::from_millis(10));
+            reset.set_high()?;
+            // Dropping the driver would release the pin, it stays high while the panel is used
+            std::mem::forget(reset);
+        }
+        info!("Display on SPI: SCLK GPIO{} MOSI GPIO{} DC GPIO{} CS GPIO{}", pins[0], pins[1], pins[2], pins[3]);
+        Ok(Box::new(SPIInterfaceNoCS::new(device, dc)))
+    }
+
+    pub fn start(&mut self, interface: PanelInterface)
     {
         let txt = self.txt.clone();
-        let _th = thread::spawn(move || {
+        let _th = tasks::spawn(&tasks::DISPLAY, move || {
             info!("Start Display Thread.");
-            
-            // Create a simple wrapper that implements the required traits for SSD1306
-            struct I2CWrapper {
-                driver: Arc<Mutex<i2c::I2cDriver<'static>>>,
-            }
-            
-            impl embedded_hal_0_2::blocking::i2c::Write for I2CWrapper {
-                type Error = ();
-                
-                fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
-                    let mut driver = self.driver.lock().unwrap();
-                    driver.write(address, bytes, esp_idf_hal::delay::BLOCK).map_err(|_| ())
-                }
-            }
-            
-            let wrapper = I2CWrapper { driver: shared_i2c };
-            let interface = I2CDisplayInterface::new(wrapper);
-            let mut display = Ssd1306::new(interface, 
+            let interface = Arc::new(Mutex::new(interface));
+            let invert_interface = interface.clone();
+            let mut panel = Ssd1306::new(SharedInterface(interface), 
                 DisplaySize128x64,
                 ssd1306::prelude::DisplayRotation::Rotate0)
                 .into_buffered_graphics_mode();
                 
-            if let Err(e) = display.init() {
+            if let Err(e) = panel.init() {
                 info!("Display init failed: {:?}", e);
                 return;
             }
@@ -103,6 +428,7 @@ impl DisplayPanel {
             //     .background_color(BinaryColor::On)
             //     .build();
             let style_small = MonoTextStyle::new(&FONT_5X8, BinaryColor::On);
+            let style_big = MonoTextStyle::new(&PROFONT_24_POINT, BinaryColor::On);
             let style_middle = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
             let style_middle_inv = MonoTextStyleBuilder::new()
                 .font(&FONT_6X10)
@@ -141,8 +467,10 @@ impl DisplayPanel {
             let usbpwr_img: Image<Bmp<BinaryColor>> = Image::new(&usbpwr, Point::new(bat_x, bat_y));
 
             // Clear display
-            display.clear();
-            display.flush().unwrap();
+            panel.clear();
+            panel.flush().unwrap();
+            // Pages are drawn into this frame, only the pixels that changed are sent to the panel
+            let mut display = DiffFrame::new();
             
             let mut loopcount = 0;
             let mut battery_level = 0;
@@ -158,11 +486,49 @@ impl DisplayPanel {
             let mut prev_wifi_status = WifiStatus::Disconnected;
             let mut prev_wifi_rssi = -999;
             let mut prev_buffer_wm = 999;
+            let mut prev_buffer_capacity = 0;
+            let mut prev_buffer_alert = false;
+            let mut prev_transfer_status: Option<TransferStatus> = None;
+            let mut prev_backlog = 0;
+            let mut prev_capture: Option<u64> = None;
             let mut prev_battery = -1.0;
             let mut prev_battery_level = 999;
             let mut prev_channel = 0;
+            let mut prev_channel_name = String::new();
             let mut prev_message = String::new();
             let mut prev_loopcount_display = 0;
+            let mut prev_peak_current = -1.0;
+            let mut prev_peak_power = -1.0;
+            let mut prev_peak_page = false;
+            let mut peak_count = 0;
+            let mut peak_page = false;  // false: peak current, true: peak power
+            let mut prev_tripped = false;
+            let mut prev_adc_low_range = true;
+            let mut prev_scan_serial = 0;
+            let mut prev_filtered = false;
+            let mut prev_config_error = false;
+            let mut prev_soc: Option<SocInfo> = None;
+            let mut prev_iot: Option<IotSummary> = None;
+            let mut page_count = 0;
+            let mut soc_page = false;
+            let mut prev_soc_page = false;
+            let mut prev_efficiency: Option<f32> = None;
+            let mut prev_big_digits: Option<BigValue> = None;
+            let mut prev_spans = [Span::default(); 3];
+            let mut net_count = 0;
+            let mut net_page = false;
+            let mut prev_net_page = false;
+            let mut pulse_count = PULSE_PAGE_PHASE_LOOPS;
+            let mut pulse_page = false;
+            let mut prev_pulse_page = false;
+            let mut prev_pulse_text = String::new();
+            let mut prev_net_text = String::new();
+            let mut prev_diag_text = String::new();
+            let mut prev_ripple_text = String::new();
+            let mut panel_on = true;
+            let mut redraw = false;
+            let mut inverted = false;
+            let mut prev_blink_off = false;
             
             loop {
                 let mut lck = txt.lock().unwrap();
@@ -170,6 +536,38 @@ impl DisplayPanel {
                 if loopcount > 15 {
                     loopcount = 0;
                 }
+                // Alternate the peak-hold readout between current and power every 2 seconds
+                peak_count += 1;
+                if peak_count >= 20 {
+                    peak_count = 0;
+                    peak_page = !peak_page;
+                }
+
+                // Duty-cycled panel: no I2C traffic while it is off
+                let awake = lck.on_time.map_or(true, |on_time| lck.wake_time.elapsed() < on_time);
+                if awake != panel_on {
+                    if let Err(e) = panel.set_display_on(awake) {
+                        info!("Display power failed: {:?}", e);
+                    }
+                    panel_on = awake;
+                    redraw = awake;
+                }
+                if !panel_on {
+                    drop(lck);
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+
+                // Attention mode: inverted with the alarm quantity blinking until the alarm is acknowledged
+                let attention = lck.alarm.filter(|_| !lck.alarm_acknowledged);
+                if attention.is_some() != inverted {
+                    inverted = attention.is_some();
+                    let command = if inverted { SSD1306_INVERSE } else { SSD1306_NORMAL };
+                    if let Err(e) = invert_interface.lock().unwrap().send_commands(DataFormat::U8(&[command])) {
+                        info!("Display invert failed: {:?}", e);
+                    }
+                }
+                let blink_off = attention.is_some() && loopcount >= 8;
 
                 // Auto-range voltage display with hysteresis
                 let voltage = lck.voltage;
@@ -286,6 +684,93 @@ impl DisplayPanel {
                     }
                 }
 
+                // Alternate the meter page and the SoC page in coulomb counter mode, or the IoT profile page
+                page_count += 1;
+                if lck.soc.is_none() && lck.iot.is_none() {
+                    soc_page = false;
+                    page_count = 0;
+                }
+                else if page_count >= if soc_page { SOC_PAGE_LOOPS } else { METER_PAGE_LOOPS } {
+                    page_count = 0;
+                    soc_page = !soc_page;
+                }
+
+                // Show the network page for a moment every 30 seconds
+                net_count += 1;
+                if lck.net_info.is_none() {
+                    net_page = false;
+                    net_count = 0;
+                }
+                else if net_count >= if net_page { NET_PAGE_LOOPS } else { NET_PAGE_INTERVAL_LOOPS } {
+                    net_count = 0;
+                    net_page = !net_page;
+                }
+                // Text of the network page, the upload age changes every second
+                let net_text = match (net_page, lck.net_info) {
+                    (true, Some(net)) => {
+                        let last = match net.last_upload {
+                            Some(t) => tr_args(Msg::SecondsAgo, &[&t.elapsed().as_secs().to_string()]),
+                            None => tr(Msg::Never).to_string(),
+                        };
+                        let mut text = tr_args(Msg::NetworkText, &[&net.records_sent.to_string(), &last, &net.failed_attempts.to_string(), &lck.buffer_water_mark.to_string()]);
+                        if net.backlog > 0 {
+                            text.push('\n');
+                            text.push_str(&tr_args(Msg::BacklogBatches, &[&net.backlog.to_string()]));
+                        }
+                        text
+                    },
+                    _ => String::new(),
+                };
+
+                // Show the pulse page for a moment every 30 seconds
+                pulse_count += 1;
+                if lck.pulse.is_none() {
+                    pulse_page = false;
+                    pulse_count = PULSE_PAGE_PHASE_LOOPS;
+                }
+                else if pulse_count >= if pulse_page { PULSE_PAGE_LOOPS } else { PULSE_PAGE_INTERVAL_LOOPS } {
+                    pulse_count = 0;
+                    pulse_page = !pulse_page;
+                }
+                let pulse_text = match (pulse_page, lck.pulse) {
+                    (true, Some(pulse)) => tr_args(Msg::PulseText, &[&format!("{:.1}", pulse.duty_cycle), &pulse.count.to_string(),
+                        &format!("{:.1}", pulse.width_ms), &format!("{:.3}", pulse.charge_mc)]),
+                    _ => String::new(),
+                };
+
+                // Close the WiFi scan page after the timeout
+                if let Some(until) = lck.scan_until {
+                    if Instant::now() >= until {
+                        lck.scan_results.clear();
+                        lck.scan_until = None;
+                        lck.scan_serial += 1;
+                    }
+                }
+                // Text of the diagnostics page until its timeout
+                if lck.diag_until.is_some_and(|until| Instant::now() >= until) {
+                    lck.diag_until = None;
+                }
+                let diag_text = match lck.diag_until {
+                    Some(_) => tr_args(Msg::DiagnosticsText, &[&lck.jitter.samples.to_string(), &lck.jitter.mean_us.to_string(),
+                        &lck.jitter.p95_us.to_string(), &lck.jitter.p99_us.to_string(), &lck.jitter.late.to_string(), &lck.jitter.max_us.to_string()]),
+                    None => String::new(),
+                };
+                // Text of the ripple page until its timeout
+                if lck.ripple_until.is_some_and(|until| Instant::now() >= until) {
+                    lck.ripple_until = None;
+                }
+                let ripple_text = match (lck.ripple_until, lck.ripple) {
+                    (Some(_), Some(ripple)) => {
+                        let frequency = match ripple.frequency {
+                            f if f > 0.0 => format!("{:.1}Hz", f),
+                            _ => tr(Msg::NoRipple).to_string(),
+                        };
+                        tr_args(Msg::RippleText, &[&frequency, &format!("{:.3}", ripple.amplitude * 1_000.0),
+                            &format!("{:.3}", ripple.peak_to_peak * 1_000.0), &format!("{:.0}", ripple.sample_rate)])
+                    },
+                    _ => String::new(),
+                };
+
                 // Check if anything has changed that requires display update
                 let wifi_changed = match (&lck.wifi, &prev_wifi_status) {
                     (WifiStatus::Disconnected, WifiStatus::Disconnected) => false,
@@ -297,6 +782,7 @@ impl DisplayPanel {
                 let status_changed = match (&lck.status, &prev_status) {
                     (LoggingStatus::Start, LoggingStatus::Start) => false,
                     (LoggingStatus::Stop, LoggingStatus::Stop) => false,
+                    (LoggingStatus::Armed, LoggingStatus::Armed) => false,
                     _ => true,
                 };
 
@@ -310,9 +796,37 @@ impl DisplayPanel {
                     status_changed ||
                     wifi_changed ||
                     lck.buffer_water_mark != prev_buffer_wm ||
+                    lck.buffer_capacity != prev_buffer_capacity ||
+                    lck.buffer_alert != prev_buffer_alert ||
+                    lck.transfer_status != prev_transfer_status ||
+                    lck.backlog != prev_backlog ||
+                    lck.capture != prev_capture ||
                     lck.battery != prev_battery ||
                     battery_level != prev_battery_level ||
                     lck.channel != prev_channel ||
+                    lck.channel_name != prev_channel_name ||
+                    lck.peak_current != prev_peak_current ||
+                    lck.peak_power != prev_peak_power ||
+                    peak_page != prev_peak_page ||
+                    lck.tripped != prev_tripped ||
+                    lck.adc_low_range != prev_adc_low_range ||
+                    lck.scan_serial != prev_scan_serial ||
+                    lck.filtered != prev_filtered ||
+                    lck.config_error != prev_config_error ||
+                    soc_page != prev_soc_page ||
+                    (soc_page && lck.soc != prev_soc) ||
+                    (soc_page && lck.iot != prev_iot) ||
+                    (lck.differential && lck.efficiency != prev_efficiency) ||
+                    lck.big_digits != prev_big_digits ||
+                    (lck.big_digits.is_some() && lck.spans != prev_spans) ||
+                    net_page != prev_net_page ||
+                    pulse_page != prev_pulse_page ||
+                    pulse_text != prev_pulse_text ||
+                    net_text != prev_net_text ||
+                    diag_text != prev_diag_text ||
+                    ripple_text != prev_ripple_text ||
+                    blink_off != prev_blink_off ||
+                    redraw ||
                     lck.message != prev_message;
 
                 // Only update display if something changed
@@ -330,8 +844,9 @@ impl DisplayPanel {
                         _ => {}
                     }
                     
-                    // Display current with auto-range
-                    match lck.current_range {
+                    // Display current with auto-range, blinking on a current limit alarm
+                    let current_range = if blink_off && attention == Some(AlarmQuantity::Current) { 255 } else { lck.current_range };
+                    match current_range {
                         0 => { // mA
                             Text::new(&format!("I:{:.3}mA", current * 1_000.0), Point::new(1, 15), style_large).draw(&mut display).unwrap();
                         },
@@ -351,14 +866,63 @@ impl DisplayPanel {
                         },
                         _ => {}
                     }
-                                    
-                    // Display logging status
-                    match lck.status {
-                        LoggingStatus::Start => {
-                            Text::new("LOGGING", Point::new(1, 50), style_middle_inv).draw(&mut display).unwrap();
+
+                    // Display shunt voltage range (LO: 40.96mV, HI: 163.84mV)
+                    draw_text(&mut display, tr(if lck.adc_low_range { Msg::RangeLow } else { Msg::RangeHigh }), Point::new(116, 7), style_small, Alignment::Left);
+
+                    // Display divider mark after the voltage when the bus voltage is scaled
+                    if lck.divider {
+                        draw_text(&mut display, tr(Msg::Differential), Point::new(123, 30), style_small, Alignment::Left);
+                    }
+
+                    // Display sensor configuration error mark
+                    if lck.config_error {
+                        draw_text(&mut display, tr(Msg::ConfigError), Point::new(84, 7), style_small, Alignment::Left);
+                    }
+                    // Display demo mark in the same place, a configuration error takes precedence
+                    else if lck.demo {
+                        draw_text(&mut display, tr(Msg::Demo), Point::new(84, 7), style_small, Alignment::Left);
+                    }
+
+                    // Display AC mark when RMS values are shown
+                    if lck.ac_mode {
+                        draw_text(&mut display, tr(Msg::AcMode), Point::new(96, 7), style_small, Alignment::Left);
+                    }
+
+                    // Display filter mark when the readings are smoothed
+                    if lck.filtered {
+                        draw_text(&mut display, tr(Msg::Filtered), Point::new(108, 7), style_small, Alignment::Left);
+                    }
+
+                    // Display peak-hold with PK marker, or the efficiency in differential mode
+                    let peak_text = match (peak_page, lck.differential) {
+                        (false, _) => tr_args(Msg::PeakHold, &[&format_peak(lck.peak_current, "A")]),
+                        (true, false) => tr_args(Msg::PeakHold, &[&format_peak(lck.peak_power, "W")]),
+                        (true, true) => match lck.efficiency {
+                            Some(efficiency) => tr_args(Msg::EfficiencyHold, &[&format!("{:.1}%", efficiency)]),
+                            None => tr_args(Msg::EfficiencyHold, &["--.-%"]),
                         },
-                        LoggingStatus::Stop => {
-                            Text::new("STOPPED", Point::new(1, 50), style_middle).draw(&mut display).unwrap();
+                    };
+                    draw_text(&mut display, &peak_text, Point::new(62, 40), style_small, Alignment::Left);
+                                    
+                    // Display logging status, the countdown of a capture, or the latched current limit trip
+                    if lck.tripped {
+                        draw_text(&mut display, tr(Msg::Tripped), Point::new(1, 50), style_middle_inv, Alignment::Left);
+                    }
+                    else if let (Some(secs), LoggingStatus::Start) = (lck.capture, &lck.status) {
+                        draw_text(&mut display, &capture::format_remaining(secs), Point::new(1, 50), style_middle_inv, Alignment::Left);
+                    }
+                    else {
+                        match lck.status {
+                            LoggingStatus::Start => {
+                                draw_text(&mut display, tr(Msg::Logging), Point::new(1, 50), style_middle_inv, Alignment::Left);
+                            },
+                            LoggingStatus::Stop => {
+                                draw_text(&mut display, tr(Msg::Stopped), Point::new(1, 50), style_middle, Alignment::Left);
+                            },
+                            LoggingStatus::Armed => {
+                                draw_text(&mut display, tr(Msg::Armed), Point::new(1, 50), style_middle, Alignment::Left);
+                            }
                         }
                     }
                     
@@ -381,8 +945,17 @@ impl DisplayPanel {
                             .draw(&mut display).unwrap();
                     }
                     
-                    // Display percentage text next to the bar
-                    Text::new(&format!("{}%", lck.buffer_water_mark), Point::new(65, 60), style_small).draw(&mut display).unwrap();
+                    // Display percentage text next to the bar, alternating with the effective capacity
+                    let buffer_text = match peak_page {
+                        true if lck.buffer_capacity >= 10000 => format!("{}k", lck.buffer_capacity / 1000),
+                        true if lck.buffer_capacity >= 1000 => format!("{:.1}k", lck.buffer_capacity as f32 / 1000.0),
+                        true => format!("{}", lck.buffer_capacity),
+                        false if lck.buffer_alert => format!("!{}%", lck.buffer_water_mark),
+                        false => format!("{}%", lck.buffer_water_mark),
+                    };
+                    if !(blink_off && attention == Some(AlarmQuantity::Buffer)) {
+                        Text::new(&buffer_text, Point::new(65, 60), style_small).draw(&mut display).unwrap();
+                    }
                                                     
                     // Battery status
                     Text::new(&format!("{:.1}V", battery_voltage), Point::new(86, 60), style_small).draw(&mut display).unwrap();
@@ -456,25 +1029,123 @@ impl DisplayPanel {
                                 _ => {
                                 },
                             }
-                            if lck.wifi_rssi != 0 {
+                            // Upload problems, or else the channel name, alternate with the RSSI
+                            let transfer_text = match lck.transfer_status {
+                                Some(TransferStatus::Retrying(retry, code)) => Some(tr_args(Msg::Retrying, &[&retry.to_string(), &code.to_string()])),
+                                Some(TransferStatus::Failed(code)) => Some(tr_args(Msg::Failed, &[&code.to_string()])),
+                                _ if lck.backlog > 0 => Some(tr_args(Msg::Backlog, &[&lck.backlog.to_string()])),
+                                _ if !lck.channel_name.is_empty() => Some(lck.channel_name.chars().take(CHANNEL_NAME_CHARS).collect()),
+                                _ => None,
+                            };
+                            if let (Some(text), true) = (transfer_text, peak_page) {
+                                draw_text(&mut display, &text, Point::new(81, 52), style_small, Alignment::Left);
+                            }
+                            else if lck.wifi_rssi != 0 {
                                 Text::new(&format!("{:+02}dBm", lck.wifi_rssi), Point::new(81, 52), style_small).draw(&mut display).unwrap();
                             }
                             else {
-                                Text::new("NO SIG", Point::new(81, 52), style_small).draw(&mut display).unwrap();
+                                draw_text(&mut display, tr(Msg::NoSignal), Point::new(81, 52), style_small, Alignment::Left);
                             }
                         },
                     }    
                     
                     // Display Channel
-                    Text::new(&format!("CH:{}", lck.channel), Point::new(50, 50), style_middle).draw(&mut display).unwrap();
+                    draw_text(&mut display, &tr_args(Msg::Channel, &[&lck.channel.to_string()]), Point::new(50, 50), style_middle, Alignment::Left);
 
                     // Error message if any
                     if !lck.message.is_empty() {
                         display.clear();
-                        Text::new(&lck.message, Point::new(1, 8), style_small).draw(&mut display).unwrap();
+                        draw_text(&mut display, &lck.message, Point::new(1, 8), style_small, Alignment::Left);
+                    }
+                    // WiFi scan page, strongest networks first
+                    else if !lck.scan_results.is_empty() {
+                        display.clear();
+                        draw_text(&mut display, &tr_args(Msg::WifiScan, &[&lck.scan_results.len().to_string()]), Point::new(1, 7), style_small, Alignment::Left);
+                        for (i, (ssid, rssi)) in lck.scan_results.iter().take(SCAN_PAGE_LINES).enumerate() {
+                            let name: String = ssid.chars().take(19).collect();
+                            draw_text(&mut display, &format!("{:>4} {}", rssi, name), Point::new(1, 17 + 9 * i as i32), style_small, Alignment::Left);
+                        }
+                    }
+                    // Diagnostics page, sample spacing since boot
+                    else if !diag_text.is_empty() {
+                        display.clear();
+                        draw_text(&mut display, tr(Msg::Diagnostics), Point::new(1, 8), style_middle, Alignment::Left);
+                        draw_text(&mut display, &diag_text, Point::new(1, 24), style_small, Alignment::Left);
+                    }
+                    // Ripple page, dominant component of the last analysis
+                    else if !ripple_text.is_empty() {
+                        display.clear();
+                        draw_text(&mut display, tr(Msg::Ripple), Point::new(1, 8), style_middle, Alignment::Left);
+                        draw_text(&mut display, &ripple_text, Point::new(1, 24), style_small, Alignment::Left);
+                    }
+                    // Network page, uploads of this session
+                    else if net_page {
+                        display.clear();
+                        let rssi = match lck.wifi {
+                            WifiStatus::Connected if lck.wifi_rssi != 0 => format!("{}dBm", lck.wifi_rssi),
+                            _ => tr(Msg::Offline).to_string(),
+                        };
+                        draw_text(&mut display, &tr_args(Msg::Network, &[&rssi]), Point::new(1, 8), style_middle, Alignment::Left);
+                        draw_text(&mut display, &net_text, Point::new(1, 24), style_small, Alignment::Left);
+                    }
+                    // Pulse page, statistics of the last report interval
+                    else if !pulse_text.is_empty() {
+                        display.clear();
+                        draw_text(&mut display, tr(Msg::Pulse), Point::new(1, 8), style_middle, Alignment::Left);
+                        draw_text(&mut display, &pulse_text, Point::new(1, 24), style_small, Alignment::Left);
+                    }
+                    // Big digits page, the selected value only
+                    else if let Some(value) = lck.big_digits {
+                        display.clear();
+                        let (name, text, span, scale, unit) = match value {
+                            BigValue::Current if lck.current_range == 0 => (tr(Msg::Current), fit_digits(current * 1_000.0, "mA"), lck.spans[1], 1_000.0, "mA"),
+                            BigValue::Current => (tr(Msg::Current), fit_digits(current, "A"), lck.spans[1], 1.0, "A"),
+                            BigValue::Voltage if lck.voltage_range == 0 => (tr(Msg::Voltage), fit_digits(voltage * 1_000.0, "mV"), lck.spans[0], 1_000.0, "mV"),
+                            BigValue::Voltage => (tr(Msg::Voltage), fit_digits(voltage, "V"), lck.spans[0], 1.0, "V"),
+                            BigValue::Power if lck.power_range == 0 => (tr(Msg::Power), fit_digits(power * 1_000.0, "mW"), lck.spans[2], 1_000.0, "mW"),
+                            BigValue::Power => (tr(Msg::Power), fit_digits(power, "W"), lck.spans[2], 1.0, "W"),
+                        };
+                        let title: String = format!("{} {} {}", name, tr_args(Msg::Channel, &[&lck.channel.to_string()]), lck.channel_name).chars().take(BIG_DIGITS_TITLE_CHARS).collect();
+                        draw_text(&mut display, title.trim_end(), Point::new(1, 7), style_small, Alignment::Left);
+                        if lck.tripped {
+                            draw_text(&mut display, tr(Msg::Tripped), Point::new(92, 7), style_small, Alignment::Left);
+                        }
+                        Text::with_alignment(&text, Point::new(127, 44), style_big, Alignment::Right).draw(&mut display).unwrap();
+                        // Range of the samples behind the shown mean
+                        draw_text(&mut display, &tr_args(Msg::Minimum, &[&fit_digits(span.min * scale, unit)]), Point::new(1, 60), style_small, Alignment::Left);
+                        draw_text(&mut display, &tr_args(Msg::Maximum, &[&fit_digits(span.max * scale, unit)]), Point::new(127, 60), style_small, Alignment::Right);
+                    }
+                    // Battery under test page
+                    else if soc_page {
+                        if let Some(soc) = lck.soc {
+                            display.clear();
+                            Text::new(&format!("SoC:{:.1}%", soc.soc), Point::new(1, 15), style_large).draw(&mut display).unwrap();
+                            Text::new(&format!("I:{:.4}A", current), Point::new(1, 30), style_middle).draw(&mut display).unwrap();
+                            draw_text(&mut display, &tr_args(Msg::SocCharge, &[&format!("{:.1}", soc.charge_out), &format!("{:.1}", soc.charge_in)]), Point::new(1, 42), style_small, Alignment::Left);
+                            let efficiency = match soc.efficiency {
+                                Some(e) => format!("{:.1}%", e),
+                                None => "-".to_string(),
+                            };
+                            draw_text(&mut display, &tr_args(Msg::SocCycles, &[&efficiency, &format!("{:.2}", soc.cycles)]), Point::new(1, 52), style_small, Alignment::Left);
+                        }
+                        // IoT profile page, time share and charge of each band
+                        else if let Some(iot) = lck.iot {
+                            display.clear();
+                            draw_text(&mut display, &tr_args(Msg::IotAverage, &[&fit_digits(iot.average_current * 1_000.0, "mA")]), Point::new(1, 8), style_middle, Alignment::Left);
+                            for (i, name) in [Msg::Sleep, Msg::Idle, Msg::Active].iter().enumerate() {
+                                let line = format!("{:<6} {:>5.1}% {:.3}mAh", tr(*name), iot.time_percent(i), iot.charge_mah(i));
+                                draw_text(&mut display, &line, Point::new(1, 24 + 9 * i as i32), style_small, Alignment::Left);
+                            }
+                            let life = match iot.battery_life {
+                                Some(h) if h >= 48.0 => tr_args(Msg::Days, &[&format!("{:.1}", h / 24.0)]),
+                                Some(h) => tr_args(Msg::Hours, &[&format!("{:.1}", h)]),
+                                None => "-".to_string(),
+                            };
+                            draw_text(&mut display, &tr_args(Msg::BatteryLife, &[&life]), Point::new(1, 54), style_small, Alignment::Left);
+                        }
                     }
 
-                    match display.flush() {                  
+                    match display.flush(&mut panel) {
                         Ok(_) => {},
                         Err(_) => {},
                     }
@@ -489,6 +1160,7 @@ impl DisplayPanel {
                     prev_status = match lck.status {
                         LoggingStatus::Start => LoggingStatus::Start,
                         LoggingStatus::Stop => LoggingStatus::Stop,
+                        LoggingStatus::Armed => LoggingStatus::Armed,
                     };
                     prev_wifi_status = match lck.wifi {
                         WifiStatus::Disconnected => WifiStatus::Disconnected,
@@ -497,11 +1169,39 @@ impl DisplayPanel {
                     };
                     prev_wifi_rssi = lck.wifi_rssi;
                     prev_buffer_wm = lck.buffer_water_mark;
+                    prev_buffer_capacity = lck.buffer_capacity;
+                    prev_buffer_alert = lck.buffer_alert;
+                    prev_transfer_status = lck.transfer_status;
+                    prev_backlog = lck.backlog;
+                    prev_capture = lck.capture;
                     prev_battery = lck.battery;
                     prev_battery_level = battery_level;
                     prev_channel = lck.channel;
+                    prev_channel_name = lck.channel_name.clone();
+                    prev_blink_off = blink_off;
                     prev_message = lck.message.clone();
                     prev_loopcount_display = loopcount;
+                    prev_peak_current = lck.peak_current;
+                    prev_peak_power = lck.peak_power;
+                    prev_peak_page = peak_page;
+                    prev_tripped = lck.tripped;
+                    prev_adc_low_range = lck.adc_low_range;
+                    prev_scan_serial = lck.scan_serial;
+                    prev_filtered = lck.filtered;
+                    prev_config_error = lck.config_error;
+                    prev_soc = lck.soc;
+                    prev_iot = lck.iot;
+                    prev_soc_page = soc_page;
+                    prev_efficiency = lck.efficiency;
+                    prev_big_digits = lck.big_digits;
+                    prev_spans = lck.spans;
+                    prev_net_page = net_page;
+                    prev_pulse_page = pulse_page;
+                    prev_pulse_text = pulse_text;
+                    prev_net_text = net_text;
+                    prev_diag_text = diag_text;
+                    prev_ripple_text = ripple_text;
+                    redraw = false;
                 }
                 drop(lck);                
                 thread::sleep(Duration::from_millis(100));
@@ -509,12 +1209,38 @@ impl DisplayPanel {
         });
     }
 
-    pub fn set_voltage(&mut self, vol: f32, cur: f32, power: f32)
+    // Display mode off: the panel keeps its state over a reset, so it is switched off once
+    // without starting the display thread
+    pub fn power_off(mut interface: PanelInterface)
+    {
+        if let Err(e) = interface.send_commands(DataFormat::U8(&[SSD1306_DISPLAY_OFF])) {
+            info!("Display off failed: {:?}", e);
+        }
+    }
+
+    // Button display mode, None keeps the panel on
+    pub fn set_on_time(&mut self, on_time: Option<Duration>)
     {
         let mut lck = self.txt.lock().unwrap();
-        lck.voltage = vol;
-        lck.current = cur;
-        lck.power = power;
+        lck.on_time = on_time;
+    }
+
+    // Restart the on time, returns false if the panel was off
+    pub fn wake(&mut self) -> bool {
+        let mut lck = self.txt.lock().unwrap();
+        let awake = lck.on_time.map_or(true, |on_time| lck.wake_time.elapsed() < on_time);
+        lck.wake_time = Instant::now();
+        awake
+    }
+
+    // Decimated readings, the mean is shown on the meter page
+    pub fn set_readings(&mut self, voltage: Span, current: Span, power: Span)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.voltage = voltage.mean;
+        lck.current = current.mean;
+        lck.power = power.mean;
+        lck.spans = [voltage, current, power];
     }
 
     pub fn set_current_status(&mut self, status: LoggingStatus)
@@ -535,6 +1261,19 @@ impl DisplayPanel {
         lck.message = msg;
     }
 
+    pub fn set_buffer_capacity(&mut self, capacity: u32)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.buffer_capacity = capacity;
+    }
+
+    #[cfg(feature = "influx")]
+    pub fn set_transfer_status(&mut self, status: TransferStatus)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.transfer_status = Some(status);
+    }
+
     pub fn set_battery(&mut self, bat: f32)
     {
         let mut lck = self.txt.lock().unwrap();
@@ -547,15 +1286,237 @@ impl DisplayPanel {
         lck.buffer_water_mark = wm;
     }
 
+    pub fn set_buffer_alert(&mut self, alert: bool)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.buffer_alert = alert;
+    }
+
+    #[cfg(feature = "wifi")]
     pub fn set_wifi_rssi(&mut self, rssi: i32)
     {
         let mut lck = self.txt.lock().unwrap();
         lck.wifi_rssi = rssi;
     }
 
-    pub fn set_channel(&mut self, channel: u32)
+    pub fn set_channel(&mut self, channel: u32, name: &str)
     {
         let mut lck = self.txt.lock().unwrap();
         lck.channel = channel;
+        lck.channel_name = name.to_string();
+    }
+
+    // A new alarm inverts the display and wakes the panel
+    pub fn set_alarm(&mut self, alarm: Option<AlarmQuantity>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        if alarm != lck.alarm {
+            if alarm.is_some() {
+                lck.wake_time = Instant::now();
+            }
+            lck.alarm = alarm;
+            lck.alarm_acknowledged = false;
+        }
+    }
+
+    // Returns false if there was no alarm to acknowledge
+    pub fn acknowledge_alarm(&mut self) -> bool {
+        let mut lck = self.txt.lock().unwrap();
+        if lck.alarm.is_none() || lck.alarm_acknowledged {
+            return false;
+        }
+        lck.alarm_acknowledged = true;
+        true
+    }
+
+    pub fn set_tripped(&mut self, tripped: bool)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.tripped = tripped;
+    }
+
+    pub fn set_adc_range(&mut self, low_range: bool)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.adc_low_range = low_range;
+    }
+
+    // Show the WiFi scan page for a while, sorted by signal strength
+    pub fn set_scan_results(&mut self, results: Vec<(String, i8)>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.scan_results = results;
+        lck.scan_until = Some(Instant::now() + SCAN_PAGE_TIME);
+        lck.scan_serial += 1;
+    }
+
+    // Close the WiFi scan page, returns true if it was shown
+    pub fn clear_scan_results(&mut self) -> bool {
+        let mut lck = self.txt.lock().unwrap();
+        if lck.scan_results.is_empty() {
+            return false;
+        }
+        lck.scan_results.clear();
+        lck.scan_until = None;
+        lck.scan_serial += 1;
+        true
+    }
+
+    pub fn set_jitter(&mut self, jitter: JitterSummary)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.jitter = jitter;
+    }
+
+    // Show the diagnostics page for a while
+    pub fn show_diagnostics(&mut self)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.diag_until = Some(Instant::now() + DIAG_PAGE_TIME);
+    }
+
+    // Close the diagnostics page, returns true if it was shown
+    pub fn clear_diagnostics(&mut self) -> bool {
+        let mut lck = self.txt.lock().unwrap();
+        lck.diag_until.take().is_some()
+    }
+
+    // Show the ripple page of an analysis for a while
+    pub fn show_ripple(&mut self, ripple: RippleResult)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.ripple = Some(ripple);
+        lck.ripple_until = Some(Instant::now() + RIPPLE_PAGE_TIME);
+    }
+
+    // Close the ripple page, returns true if it was shown
+    pub fn clear_ripple(&mut self) -> bool {
+        let mut lck = self.txt.lock().unwrap();
+        lck.ripple_until.take().is_some()
+    }
+
+    pub fn set_filtered(&mut self, filtered: bool)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.filtered = filtered;
+    }
+
+    pub fn set_ac_mode(&mut self, ac_mode: bool)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.ac_mode = ac_mode;
+    }
+
+    pub fn set_demo(&mut self, demo: bool)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.demo = demo;
+    }
+
+    pub fn set_divider(&mut self, divider: bool)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.divider = divider;
+    }
+
+    pub fn set_config_error(&mut self, error: bool)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.config_error = error;
+    }
+
+    pub fn set_soc(&mut self, soc: Option<SocInfo>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.soc = soc;
+    }
+
+    pub fn set_iot(&mut self, iot: Option<IotSummary>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.iot = iot;
+    }
+
+    pub fn set_peak(&mut self, current: f32, power: f32)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.peak_current = current;
+        lck.peak_power = power;
+    }
+
+    // Differential mode: efficiency alternates with the peak current, None below the minimum input power
+    pub fn set_efficiency(&mut self, efficiency: Option<f32>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.differential = true;
+        lck.efficiency = efficiency;
+    }
+
+    pub fn set_big_digits(&mut self, value: Option<BigValue>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.big_digits = value;
+    }
+
+    pub fn set_pulse(&mut self, pulse: Option<PulseStats>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.pulse = pulse;
+    }
+
+    pub fn set_backlog(&mut self, batches: usize)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.backlog = batches;
+    }
+
+    // Seconds left of the running capture, None: no capture
+    pub fn set_capture(&mut self, remaining: Option<u64>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.capture = remaining;
+    }
+
+    pub fn set_net_info(&mut self, info: Option<NetInfo>)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.net_info = info;
+    }
+}
+
+// Text in the mono font of the style, or in the Japanese font if it has other than ASCII characters.
+// The Japanese font has no background, the text box is filled first for an inverted style.
+fn draw_text(display: &mut DiffFrame, text: &str, position: Point, style: MonoTextStyle<'_, BinaryColor>, alignment: Alignment)
+{
+    if text.is_ascii() {
+        let _ = Text::with_alignment(text, position, style, alignment).draw(display);
+        return;
+    }
+    let japanese = U8g2TextStyle::new(JAPANESE_FONT, style.text_color.unwrap_or(BinaryColor::On));
+    let text = Text::with_alignment(text, position, japanese, alignment);
+    if let Some(background) = style.background_color {
+        let _ = text.bounding_box().into_styled(PrimitiveStyle::with_fill(background)).draw(display);
+    }
+    let _ = text.draw(display);
+}
+
+// Format a value with as many decimals as fit the big digits page
+fn fit_digits(value: f32, unit: &str) -> String {
+    for decimals in (0..=4).rev() {
+        let text = format!("{:.*}{}", decimals, value, unit);
+        if text.len() <= BIG_DIGITS_CHARS {
+            return text;
+        }
+    }
+    format!("{:.0}{}", value, unit)
+}
+
+// Format a peak value to fit the small font area (max 7 characters)
+fn format_peak(value: f32, unit: &str) -> String {
+    if value.abs() < 1.0 {
+        format!("{:.1}m{}", value * 1_000.0, unit)
+    }
+    else {
+        format!("{:.3}{}", value, unit)
     }
 }
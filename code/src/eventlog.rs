@@ -0,0 +1,179 @@
+// EventLog
+// Journal of significant events (boot, crash, WiFi up/down, calibration, alarms, buffer full, shutdown)
+// in a small circular store in NVS for the post-mortem of long unattended runs. The entries survive
+// reboots and power losses, and can be read on the console or over HTTP and mirrored to InfluxDB.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use crate::transfer::json_escape;
+
+const EVENTLOG_NAMESPACE: &str = "eventlog";
+// Entries kept, the oldest is overwritten
+pub const JOURNAL_ENTRIES: u32 = 64;
+const DETAIL_LEN: usize = 48;
+// seq u32, clock u64, kind u8, detail
+const HEADER_SIZE: usize = 13;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Boot,
+    Crash,
+    WifiUp,
+    WifiDown,
+    Calibration,
+    CurrentLimit,
+    BufferAlert,
+    BufferFull,
+    Shutdown,
+    Reboot,
+}
+
+const KINDS: [EventKind; 10] = [EventKind::Boot, EventKind::Crash, EventKind::WifiUp, EventKind::WifiDown, EventKind::Calibration,
+    EventKind::CurrentLimit, EventKind::BufferAlert, EventKind::BufferFull, EventKind::Shutdown, EventKind::Reboot];
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Boot => "boot",
+            EventKind::Crash => "crash",
+            EventKind::WifiUp => "wifi_up",
+            EventKind::WifiDown => "wifi_down",
+            EventKind::Calibration => "calibration",
+            EventKind::CurrentLimit => "current_limit",
+            EventKind::BufferAlert => "buffer_alert",
+            EventKind::BufferFull => "buffer_full",
+            EventKind::Shutdown => "shutdown",
+            EventKind::Reboot => "reboot",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JournalEntry {
+    pub seq: u32,
+    pub clock: u128,        // ns, time since boot before the clock is synchronized
+    pub kind: EventKind,
+    pub detail: String,
+}
+
+impl JournalEntry {
+    pub fn to_json(&self) -> String {
+        format!("{{\"seq\":{},\"ts\":{},\"event\":\"{}\",\"detail\":\"{}\"}}", self.seq, self.clock, self.kind.name(), json_escape(&self.detail))
+    }
+}
+
+pub struct EventLog {
+    nvs: Option<EspNvs<NvsDefault>>,    // None: journal in RAM only
+    next_seq: u32,
+    entries: VecDeque<JournalEntry>,    // newest JOURNAL_ENTRIES, oldest first
+    mirror: bool,
+    pending: VecDeque<JournalEntry>,    // to be mirrored to InfluxDB
+}
+
+impl EventLog {
+    pub fn new(partition: EspNvsPartition<NvsDefault>, mirror: bool) -> EventLog {
+        let nvs = match EspNvs::new(partition, EVENTLOG_NAMESPACE, true) {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                info!("Event log is not persistent: {:?}", e);
+                None
+            }
+        };
+        let mut log = EventLog { nvs: nvs, next_seq: 0, entries: VecDeque::new(), mirror: mirror, pending: VecDeque::new() };
+        log.load();
+        log
+    }
+
+    // Entries of the previous boots
+    fn load(&mut self)
+    {
+        let nvs = match self.nvs {
+            Some(ref nvs) => nvs,
+            None => return,
+        };
+        self.next_seq = nvs.get_u32("next").ok().flatten().unwrap_or(0);
+        let mut buf = [0u8; HEADER_SIZE + DETAIL_LEN];
+        for seq in self.next_seq.saturating_sub(JOURNAL_ENTRIES)..self.next_seq {
+            let data = match nvs.get_blob(&format!("e{}", seq % JOURNAL_ENTRIES), &mut buf) {
+                Ok(Some(data)) if data.len() >= HEADER_SIZE => data,
+                _ => continue,
+            };
+            let entry_seq = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            let kind = match KINDS.get(data[12] as usize) {
+                Some(kind) if entry_seq == seq => *kind,
+                _ => continue,
+            };
+            let mut clock = [0u8; 8];
+            clock.copy_from_slice(&data[4..12]);
+            self.entries.push_back(JournalEntry {
+                seq: seq,
+                clock: u64::from_le_bytes(clock) as u128,
+                kind: kind,
+                detail: String::from_utf8_lossy(&data[HEADER_SIZE..]).to_string(),
+            });
+        }
+        info!("Event log: {} entries, next {}", self.entries.len(), self.next_seq);
+    }
+
+    pub fn record(&mut self, kind: EventKind, detail: &str)
+    {
+        let mut detail = detail.to_string();
+        if detail.len() > DETAIL_LEN {
+            let mut end = DETAIL_LEN;
+            while !detail.is_char_boundary(end) {
+                end -= 1;
+            }
+            detail.truncate(end);
+        }
+        let entry = JournalEntry {
+            seq: self.next_seq,
+            clock: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+            kind: kind,
+            detail: detail,
+        };
+        info!("Event {}: {} {}", entry.seq, kind.name(), entry.detail);
+        self.next_seq = self.next_seq.wrapping_add(1);
+        if let Some(ref mut nvs) = self.nvs {
+            let mut blob: Vec<u8> = Vec::with_capacity(HEADER_SIZE + entry.detail.len());
+            blob.extend_from_slice(&entry.seq.to_le_bytes());
+            blob.extend_from_slice(&(entry.clock as u64).to_le_bytes());
+            blob.push(KINDS.iter().position(|k| *k == kind).unwrap() as u8);
+            blob.extend_from_slice(entry.detail.as_bytes());
+            if let Err(e) = nvs.set_blob(&format!("e{}", entry.seq % JOURNAL_ENTRIES), &blob).and_then(|_| nvs.set_u32("next", self.next_seq)) {
+                info!("Failed to store event: {:?}", e);
+            }
+        }
+        if self.entries.len() >= JOURNAL_ENTRIES as usize {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry.clone());
+        if self.mirror {
+            if self.pending.len() >= JOURNAL_ENTRIES as usize {
+                self.pending.pop_front();
+            }
+            self.pending.push_back(entry);
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &JournalEntry> {
+        self.entries.iter()
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self.entries.iter().map(|e| e.to_json()).collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    pub fn pending(&self) -> Option<&JournalEntry> {
+        self.pending.front()
+    }
+
+    pub fn sent(&mut self)
+    {
+        self.pending.pop_front();
+    }
+}
@@ -0,0 +1,128 @@
+// Filter
+// Smoothing of measured values: exponential moving average or median of the last N samples,
+// and the decimation of the samples for the display.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+const MAX_MEDIAN_SIZE: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    Off,
+    Ema(f32),       // smoothing factor alpha (0.0 - 1.0], 1.0 = no smoothing
+    Median(usize),  // window size
+}
+
+impl FilterMode {
+    // "off", "ema:<alpha>" or "median:<n>"
+    pub fn from_str(mode: &str) -> Option<FilterMode> {
+        match mode.split_once(':') {
+            None if mode == "off" || mode.is_empty() => Some(FilterMode::Off),
+            Some(("ema", alpha)) => {
+                match alpha.parse::<f32>() {
+                    Ok(a) if a > 0.0 && a <= 1.0 => Some(FilterMode::Ema(a)),
+                    _ => None,
+                }
+            },
+            Some(("median", n)) => {
+                match n.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= MAX_MEDIAN_SIZE => Some(FilterMode::Median(n)),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+pub struct Filter {
+    mode: FilterMode,
+    value: Option<f32>,
+    window: VecDeque<f32>,
+}
+
+impl Filter {
+    pub fn new(mode: FilterMode) -> Filter {
+        Filter { mode: mode, value: None, window: VecDeque::new() }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.mode != FilterMode::Off
+    }
+
+    // Add a sample and return the filtered value
+    pub fn update(&mut self, sample: f32) -> f32 {
+        match self.mode {
+            FilterMode::Off => sample,
+            FilterMode::Ema(alpha) => {
+                let value = match self.value {
+                    Some(prev) => prev + alpha * (sample - prev),
+                    None => sample,
+                };
+                self.value = Some(value);
+                value
+            },
+            FilterMode::Median(size) => {
+                if self.window.len() >= size {
+                    self.window.pop_front();
+                }
+                self.window.push_back(sample);
+                let mut sorted: Vec<f32> = self.window.iter().cloned().collect();
+                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+                sorted[sorted.len() / 2]
+            },
+        }
+    }
+}
+
+// Mean, minimum and maximum of one quantity over a display period
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+// Feeds the display at most once per period with the aggregate of the samples in between,
+// so a fast sampling rate does not update the display state for every sample
+pub struct Decimator {
+    period: Duration,
+    last: Instant,
+    sum: [f64; 3],
+    min: [f32; 3],
+    max: [f32; 3],
+    count: u32,
+}
+
+impl Decimator {
+    pub fn new(period: Duration) -> Decimator {
+        Decimator { period: period, last: Instant::now(), sum: [0.0; 3], min: [f32::MAX; 3], max: [f32::MIN; 3], count: 0 }
+    }
+
+    // Voltage, current and power of one sample
+    pub fn push(&mut self, values: [f32; 3])
+    {
+        for (i, v) in values.iter().enumerate() {
+            self.sum[i] += *v as f64;
+            self.min[i] = self.min[i].min(*v);
+            self.max[i] = self.max[i].max(*v);
+        }
+        self.count += 1;
+    }
+
+    // The aggregates since the last call once the period has elapsed and a sample was pushed
+    pub fn take(&mut self) -> Option<[Span; 3]> {
+        if self.count == 0 || self.last.elapsed() < self.period {
+            return None;
+        }
+        let mut spans = [Span::default(); 3];
+        for i in 0..3 {
+            spans[i] = Span { mean: (self.sum[i] / self.count as f64) as f32, min: self.min[i], max: self.max[i] };
+        }
+        *self = Decimator::new(self.period);
+        Some(spans)
+    }
+}
@@ -0,0 +1,254 @@
+// Health
+// Device health telemetry: free heap, uptime, reset reason, WiFi RSSI, reconnects and reconnect attempts,
+// the upload pipeline and sample spacing statistics over the health interval, the stack high-water
+// mark of each task and the drift of the RTC. A heartbeat with the device status is sent as well, also
+// while nothing is logged, so a dashboard can tell a dead meter from a load that is off.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(feature = "wifi")]
+use crate::wifi::{self, WifiStats};
+use crate::transfer::TransferStats;
+use crate::jitter::{JitterStats, JitterSummary};
+use crate::tasks;
+use crate::rtc::RtcDrift;
+
+pub struct HealthLog {
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub uptime: u64,        // seconds since boot
+    pub rssi: i32,
+    pub reconnects: u32,
+    pub reconnect_attempts: u32,
+    pub dropped_records: u32,
+    pub config_error: bool,
+    pub upload_batch: usize,    // records per upload
+    pub upload_interval: u64,   // ms between uploads
+    pub upload_latency: u32,    // averaged request latency in ms
+    pub transfer: TransferStats,    // upload pipeline counts since the previous health record
+    pub jitter: JitterSummary,      // sample spacing since the previous health record
+    pub window_secs: u64,           // time since the previous health record
+    pub stacks: Vec<(&'static str, u32)>,   // least free stack of each task since boot (bytes)
+    pub rtc: Option<RtcDrift>,      // RTC against NTP at the last sync
+    pub reset_reason: &'static str,
+    pub clock: u128,
+}
+
+pub struct HeartbeatLog {
+    pub logging: bool,
+    pub tripped: bool,
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+    pub battery: f32,
+    pub buffer: usize,      // records waiting for upload
+    pub rssi: i32,
+    pub uptime: u64,        // seconds since boot
+    pub clock: u128,
+}
+
+pub struct Heartbeat {
+    interval: Duration,
+    last: Option<Instant>,
+    pending: Option<HeartbeatLog>,
+}
+
+impl Heartbeat {
+    pub fn new(interval_secs: u64) -> Heartbeat {
+        Heartbeat { interval: Duration::from_secs(interval_secs), last: None, pending: None }
+    }
+
+    // A new heartbeat is due, the previous one has been sent
+    pub fn is_due(&self) -> bool {
+        !self.interval.is_zero() && self.pending.is_none() && self.last.map_or(true, |last| last.elapsed() >= self.interval)
+    }
+
+    pub fn set(&mut self, log: HeartbeatLog)
+    {
+        self.last = Some(Instant::now());
+        self.pending = Some(log);
+    }
+
+    // Kept until sent() is called
+    pub fn pending(&self) -> Option<&HeartbeatLog> {
+        self.pending.as_ref()
+    }
+
+    pub fn sent(&mut self)
+    {
+        self.pending = None;
+    }
+}
+
+pub struct Health {
+    interval: Duration,
+    last: Option<Instant>,
+    #[cfg(feature = "wifi")]
+    wifi: WifiStats,
+    dropped_records: u32,
+    config_error: bool,
+    upload: (usize, u64, u32),
+    transfer: TransferStats,
+    transfer_base: TransferStats,   // counters at the previous health record
+    jitter: JitterStats,
+    jitter_base: JitterStats,
+    rtc: Option<RtcDrift>,
+    reset_reason: &'static str,
+    pending: Option<HealthLog>,
+}
+
+impl Health {
+    pub fn new(interval_secs: u64) -> Health {
+        let reset_reason = get_reset_reason();
+        info!("Last reset reason: {}", reset_reason);
+        Health {
+            interval: Duration::from_secs(interval_secs),
+            last: None,
+            #[cfg(feature = "wifi")]
+            wifi: WifiStats::default(),
+            dropped_records: 0,
+            config_error: false,
+            upload: (0, 0, 0),
+            transfer: TransferStats::default(),
+            transfer_base: TransferStats::default(),
+            jitter: JitterStats::default(),
+            jitter_base: JitterStats::default(),
+            rtc: None,
+            reset_reason: reset_reason,
+            pending: None,
+        }
+    }
+
+    // Reconnects and attempts since boot from the WiFi supervisor
+    #[cfg(feature = "wifi")]
+    pub fn set_wifi_stats(&mut self, stats: WifiStats)
+    {
+        self.wifi = stats;
+    }
+
+    #[cfg(feature = "wifi")]
+    pub fn reconnects(&self) -> u32 {
+        self.wifi.reconnects
+    }
+
+    #[cfg(not(feature = "wifi"))]
+    pub fn reconnects(&self) -> u32 {
+        0
+    }
+
+    // Returns the health record to send when the interval has elapsed.
+    // The record is kept until sent() is called.
+    pub fn poll(&mut self) -> Option<&HealthLog> {
+        if self.interval.is_zero() {
+            return None;
+        }
+        let due = match self.last {
+            Some(last) => last.elapsed() >= self.interval,
+            None => true,
+        };
+        if due && self.pending.is_none() {
+            let window_secs = match self.last {
+                Some(last) => last.elapsed().as_secs(),
+                None => unsafe { esp_idf_sys::esp_timer_get_time() as u64 / 1_000_000 },
+            };
+            self.last = Some(Instant::now());
+            self.pending = Some(self.sample(window_secs));
+            self.transfer_base = self.transfer;
+            self.jitter_base = self.jitter;
+        }
+        self.pending.as_ref()
+    }
+
+    pub fn set_dropped_records(&mut self, dropped: u32)
+    {
+        self.dropped_records = dropped;
+    }
+
+    // Sensor configuration error (POWER register does not match V x I)
+    pub fn set_config_error(&mut self, error: bool)
+    {
+        self.config_error = error;
+    }
+
+    // Adaptive upload parameters: records per upload, interval in ms and latency in ms
+    pub fn set_upload_stats(&mut self, stats: (usize, u64, u32))
+    {
+        self.upload = stats;
+    }
+
+    // Upload pipeline counters since boot
+    pub fn set_transfer_stats(&mut self, stats: TransferStats)
+    {
+        self.transfer = stats;
+    }
+
+    // Sample spacing counters since boot
+    pub fn set_jitter_stats(&mut self, stats: JitterStats)
+    {
+        self.jitter = stats;
+    }
+
+    // Offset and drift of the RTC measured at the last NTP sync
+    pub fn set_rtc_drift(&mut self, drift: Option<RtcDrift>)
+    {
+        self.rtc = drift;
+    }
+
+    pub fn sent(&mut self)
+    {
+        self.pending = None;
+    }
+
+    fn sample(&self, window_secs: u64) -> HealthLog {
+        let (free_heap, min_free_heap, uptime_us) = unsafe {
+            (esp_idf_sys::esp_get_free_heap_size(),
+             esp_idf_sys::esp_get_minimum_free_heap_size(),
+             esp_idf_sys::esp_timer_get_time())
+        };
+        HealthLog {
+            free_heap: free_heap,
+            min_free_heap: min_free_heap,
+            uptime: (uptime_us / 1_000_000) as u64,
+            #[cfg(feature = "wifi")]
+            rssi: wifi::get_rssi(),
+            #[cfg(not(feature = "wifi"))]
+            rssi: 0,
+            reconnects: self.reconnects(),
+            #[cfg(feature = "wifi")]
+            reconnect_attempts: self.wifi.attempts,
+            #[cfg(not(feature = "wifi"))]
+            reconnect_attempts: 0,
+            dropped_records: self.dropped_records,
+            config_error: self.config_error,
+            upload_batch: self.upload.0,
+            upload_interval: self.upload.1,
+            upload_latency: self.upload.2,
+            transfer: self.transfer.since(&self.transfer_base),
+            jitter: self.jitter.since(&self.jitter_base).summary(),
+            window_secs: window_secs,
+            stacks: tasks::stack_high_water_marks(),
+            rtc: self.rtc,
+            reset_reason: self.reset_reason,
+            clock: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+        }
+    }
+}
+
+pub fn get_reset_reason() -> &'static str {
+    let reason = unsafe { esp_idf_sys::esp_reset_reason() };
+    match reason {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "poweron",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "int_wdt",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task_wdt",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "wdt",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deepsleep",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
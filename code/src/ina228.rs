@@ -0,0 +1,452 @@
+// INA228 sensor driver
+// 20-bit power monitor on the shared I2C bus, with automatic shunt voltage range switching.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::{sync::Arc, sync::Mutex};
+use std::time::{Duration, Instant};
+use esp_idf_hal::i2c;
+use esp_idf_hal::delay::BLOCK;
+use crate::simulator::Simulator;
+
+// Default address with A0 and A1 tied to GND, the strapping selects 0x40-0x4F
+pub const INA228_ADDR: u8 = 0x40;
+pub const INA228_ADDR_MAX: u8 = 0x4F;
+// Fastest I2C clock without the high-speed mode master code (fast mode plus)
+pub const INA228_MAX_I2C_KHZ: u32 = 1000;
+
+const REG_CONFIG: u8 = 0x00;
+const REG_ADC_CONFIG: u8 = 0x01;
+const REG_SHUNT_CAL: u8 = 0x02;
+const REG_SHUNT_TEMPCO: u8 = 0x03;
+const REG_VSHUNT: u8 = 0x04;
+const REG_VBUS: u8 = 0x05;
+const REG_DIETEMP: u8 = 0x06;
+const REG_CURRENT: u8 = 0x07;
+const REG_POWER: u8 = 0x08;
+const REG_DIAG_ALRT: u8 = 0x0B;
+
+// Auto-range thresholds of the shunt voltage with hysteresis
+const RANGE_UP_THRESHOLD: f32 = 0.036864;   // 90% of 40.96mV, switch to 163.84mV range
+const RANGE_DOWN_THRESHOLD: f32 = 0.030;    // switch back to 40.96mV range
+// VBUS input range, higher buses need an external divider
+pub const BUS_FULL_SCALE_V: f32 = 85.0;
+// Cross-check of the POWER register with V x I: relative tolerance and absolute floor (W)
+const POWER_CHECK_TOLERANCE: f32 = 0.05;
+const POWER_CHECK_FLOOR: f32 = 0.001;
+// Conversion time (us) and averaging count of the ADC_CONFIG field values
+const CONVERSION_TIME_US: [u64; 8] = [50, 84, 150, 280, 540, 1052, 2074, 4120];
+const AVERAGING_COUNT: [u64; 8] = [1, 4, 16, 64, 128, 256, 512, 1024];
+
+// Result registers of one conversion
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Measurement {
+    pub voltage: f32,       // V
+    pub current: f32,       // A
+    pub power: f32,         // W
+}
+
+impl Measurement {
+    // The POWER register must match the computed V x I, a mismatch means SHUNT_CAL
+    // does not fit the current LSB (e.g. a shunt_resistance out of the SHUNT_CAL range)
+    pub fn power_mismatch(&self) -> bool {
+        let computed = self.voltage * self.current.abs();
+        (self.power - computed).abs() > POWER_CHECK_TOLERANCE * self.power.max(computed) + POWER_CHECK_FLOOR
+    }
+}
+
+// ADC_CONFIG field values of the continuous bus voltage, shunt voltage and temperature mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdcConfig {
+    pub vbus_ct: u16,
+    pub vshunt_ct: u16,
+    pub vtemp_ct: u16,
+    pub avg: u16,
+}
+
+impl AdcConfig {
+    // Fields of an ADC_CONFIG register value
+    pub fn from_register(value: u16) -> AdcConfig {
+        AdcConfig { vbus_ct: (value >> 9) & 7, vshunt_ct: (value >> 6) & 7, vtemp_ct: (value >> 3) & 7, avg: value & 7 }
+    }
+
+    // Time until a new averaged result is available
+    pub fn conversion_period_ms(&self) -> u64 {
+        (self.conversion_period_us() / 1000).max(1)
+    }
+
+    pub fn conversion_period_us(&self) -> u64 {
+        let ct = CONVERSION_TIME_US[(self.vbus_ct & 7) as usize] + CONVERSION_TIME_US[(self.vshunt_ct & 7) as usize]
+            + CONVERSION_TIME_US[(self.vtemp_ct & 7) as usize];
+        ct * AVERAGING_COUNT[(self.avg & 7) as usize]
+    }
+}
+
+pub struct Ina228 {
+    i2c: Arc<Mutex<i2c::I2cDriver<'static>>>,
+    address: u8,
+    shunt_resistance: f32,
+    low_range: bool,        // true: 40.96mV, false: 163.84mV
+    auto_range: bool,
+    current_lsb: f32,
+    settle_until: Option<Instant>,
+    adc_config: AdcConfig,
+    invert_current: bool,   // shunt wired backwards, the sign of current, shunt voltage and charge is flipped
+    vbus_divider: f32,      // ratio of an external divider on VBUS, scales voltage, power and energy
+    simulator: Option<Mutex<Simulator>>,    // demo mode, the registers are simulated instead of read over I2C
+}
+
+impl Ina228 {
+    pub fn new(i2c: Arc<Mutex<i2c::I2cDriver<'static>>>, address: u8, shunt_resistance: f32, low_range: bool, auto_range: bool) -> Self {
+        Ina228 {
+            i2c: i2c,
+            address: address,
+            shunt_resistance: shunt_resistance,
+            low_range: low_range,
+            auto_range: auto_range,
+            current_lsb: 0.0,
+            settle_until: None,
+            // VBUSCT: 0x5 = 1052us, VSHCT: 0x7 = 4120us, VTCT: 0x5 = 1052us, AVG: 0x6 = 512 samples
+            adc_config: AdcConfig { vbus_ct: 0x5, vshunt_ct: 0x7, vtemp_ct: 0x5, avg: 0x6 },
+            invert_current: false,
+            vbus_divider: 1.0,
+            simulator: None,
+        }
+    }
+
+    // Demo mode: all register accesses go to the simulator, the bus is not used
+    pub fn simulate(&mut self, simulator: Simulator)
+    {
+        info!("INA228 at 0x{:02X} is simulated", self.address);
+        self.simulator = Some(Mutex::new(simulator));
+    }
+
+    pub fn is_simulated(&self) -> bool {
+        self.simulator.is_some()
+    }
+
+    // External resistive divider on VBUS for buses above 85V, e.g. 10.0 for 900k/100k
+    pub fn set_vbus_divider(&mut self, ratio: f32)
+    {
+        if ratio != 1.0 {
+            info!("INA228 VBUS divider ratio {:.4}, full scale {:.0}V", ratio, BUS_FULL_SCALE_V * ratio);
+        }
+        self.vbus_divider = ratio;
+    }
+
+    pub fn set_invert_current(&mut self, invert: bool)
+    {
+        if invert != self.invert_current {
+            info!("INA228 current polarity {}", if invert { "inverted" } else { "normal" });
+        }
+        self.invert_current = invert;
+    }
+
+    pub fn is_current_inverted(&self) -> bool {
+        self.invert_current
+    }
+
+    fn polarity(&self) -> f32 {
+        if self.invert_current { -1.0 } else { 1.0 }
+    }
+
+    pub fn init(&mut self, shunt_temp_coefficient: u16) -> anyhow::Result<()> {
+        self.set_range(self.low_range)?;
+
+        // INA228 ADC Config
+        let read_adc_config = self.read_reg16(REG_ADC_CONFIG)?;
+        info!("INA228 ADC Config Read: {:04x}", read_adc_config);
+        self.set_adc_config(self.adc_config)?;
+
+        // Shunt Temperature Coefficient
+        info!("Shunt Temperature Coefficient: {:?}", shunt_temp_coefficient);
+        self.write_reg16(REG_SHUNT_TEMPCO, shunt_temp_coefficient)?;
+        let read_shunt_temp_coefficient = self.read_reg16(REG_SHUNT_TEMPCO)?;
+        info!("INA228 SHUNT_TEMP_COEFFICIENT Set to: {:04x}", read_shunt_temp_coefficient);
+
+        // Temperature Measurement
+        let temperature: f32 = self.read_reg16(REG_DIETEMP)? as f32 * 7.8125;
+        info!("Initial Temperature Read: {:.2}°C", temperature / 1000.0);
+        Ok(())
+    }
+
+    // Set ADCRANGE and the matching SHUNT_CAL
+    pub fn set_range(&mut self, low_range: bool) -> anyhow::Result<()> {
+        match low_range {
+            true => self.write_reg16(REG_CONFIG, 0x0030)?, // Bit4: ADCRANGE=1(40.96mV), Bit5 Enables temperature compensation
+            false => self.write_reg16(REG_CONFIG, 0x0020)?, // Bit4: ADCRANGE=0(163.84mV), Bit5 Enables temperature compensation
+        }
+        let read_value = self.read_reg16(REG_CONFIG)?;
+        info!("INA228 Config Set to: {:04x}", read_value);
+
+        // SHUNT_CAL
+        let current_lsb = match low_range {
+            true => {
+                // 40.96mV range
+                40.96 / 524_288.0
+            },
+            false => {
+                // 163.84mV range
+                163.84 / 524_288.0
+            }
+        };
+        let shunt_cal_val = match low_range {
+            true => 13107.2 * current_lsb * 1000_000.0 * self.shunt_resistance * 4.0, // 40.96mV range
+            false => 13107.2 * current_lsb * 1000_000.0 * self.shunt_resistance, // 163.84mV range
+        };
+        let shunt_cal = shunt_cal_val as u16;
+        info!("current_lsb={:?} shunt_cal_val={:?} shunt_cal={:?}", current_lsb, shunt_cal_val, shunt_cal);
+        self.write_reg16(REG_SHUNT_CAL, shunt_cal)?;
+        let read_shunt_cal = self.read_reg16(REG_SHUNT_CAL)?;
+        info!("INA228 SHUNT_CAL Set to: {:04x}", read_shunt_cal);
+
+        self.low_range = low_range;
+        self.current_lsb = current_lsb;
+        Ok(())
+    }
+
+    pub fn set_adc_config(&mut self, config: AdcConfig) -> anyhow::Result<()> {
+        // Mode: 0xF = Continuous bus voltage, shunt voltage and temperature
+        // VBUSCT, VSHCT, VTCT: conversion times for VBUS, shunt voltage and temperature
+        // AVG: ADC sample averaging count
+        let write_adc_config : u16 = (0xF << 12) | ((config.vbus_ct & 7) << 9) | ((config.vshunt_ct & 7) << 6)
+            | ((config.vtemp_ct & 7) << 3) | (config.avg & 7);
+        self.write_reg16(REG_ADC_CONFIG, write_adc_config)?;
+        let read_adc_config = self.read_reg16(REG_ADC_CONFIG)?;
+        info!("INA228 ADC Config Set to: {:04x} ({}ms per result)", read_adc_config, config.conversion_period_ms());
+        self.adc_config = config;
+        Ok(())
+    }
+
+    // Switch the range when the shunt voltage (V) approaches full-scale or gets small.
+    // Returns true if the range was changed.
+    fn update_range(&mut self, vshunt: f32) -> anyhow::Result<bool> {
+        if let Some(until) = self.settle_until {
+            if Instant::now() < until {
+                return Ok(false);
+            }
+            self.settle_until = None;
+        }
+        if !self.auto_range {
+            return Ok(false);
+        }
+        let vshunt = vshunt.abs();
+        let next_low_range = match self.low_range {
+            true => vshunt < RANGE_UP_THRESHOLD,
+            false => vshunt < RANGE_DOWN_THRESHOLD,
+        };
+        if next_low_range == self.low_range {
+            return Ok(false);
+        }
+        info!("Shunt voltage {:.3}mV, switching to {} range", vshunt * 1000.0,
+            if next_low_range { "40.96mV" } else { "163.84mV" });
+        self.switch_range(next_low_range)?;
+        Ok(true)
+    }
+
+    // Change the range while converting, e.g. to calibrate the other range
+    pub fn switch_range(&mut self, low_range: bool) -> anyhow::Result<()> {
+        self.set_range(low_range)?;
+        // Readings are stale until the next averaged conversion completes
+        self.settle_until = Some(Instant::now() + Duration::from_millis(self.adc_config.conversion_period_ms()));
+        Ok(())
+    }
+
+    // true while the result registers still hold data converted in the previous range
+    pub fn is_settling(&self) -> bool {
+        self.settle_until.is_some()
+    }
+
+    pub fn is_low_range(&self) -> bool {
+        self.low_range
+    }
+
+    pub fn is_auto_range(&self) -> bool {
+        self.auto_range
+    }
+
+    // Interval of the conversion-ready alert
+    pub fn conversion_period_us(&self) -> u64 {
+        self.adc_config.conversion_period_us()
+    }
+
+    pub fn read_current(&self) -> anyhow::Result<f32> {
+        match self.read_reg24(REG_CURRENT) {
+            Ok(raw) => {
+                Ok(self.polarity() * self.current_lsb * decode_signed20(raw))
+            },
+            Err(e) => {
+                info!("{:?}", e);
+                Err(anyhow::anyhow!("Current Read Error"))
+            }
+        }
+    }
+
+    pub fn read_voltage(&self) -> anyhow::Result<f32> {
+        match self.read_reg24(REG_VBUS) {
+            Ok(raw) => {
+                let vbus = ((raw >> 4) as f32 * 195.3125) / 1000_000.0;
+                Ok(vbus * self.vbus_divider)
+            },
+            Err(e) => {
+                info!("{:?}", e);
+                Err(anyhow::anyhow!("Voltage Read Error"))
+            }
+        }
+    }
+
+    // AC mode: continuous shunt and bus voltage, 150us conversions without averaging.
+    // The 163.84mV range is fixed, the peaks must not clip and auto-ranging would switch within a cycle.
+    pub fn set_fast_conversion(&mut self) -> anyhow::Result<()> {
+        // Mode: 0xB = Continuous bus voltage and shunt voltage, VBUSCT: 0x2 = 150us, VSHCT: 0x2 = 150us, AVG: 0x0 = 1 sample
+        let write_adc_config : u16 = (0xB << 12) | (0x2 << 9) | (0x2 << 6) | (0x0 << 3) | 0x0;
+        self.write_reg16(REG_ADC_CONFIG, write_adc_config)?;
+        info!("INA228 ADC Config Set to: {:04x} (fast conversion)", self.read_reg16(REG_ADC_CONFIG)?);
+        self.auto_range = false;
+        self.set_range(false)
+    }
+
+    // Ripple analysis: currents of back-to-back fast conversions and the time they took.
+    // Unless the sensor is already in fast conversion (AC mode), the averaged configuration,
+    // the range and auto-ranging are restored afterwards.
+    pub fn capture_fast(&mut self, count: usize, restore: bool) -> anyhow::Result<(Vec<f32>, Duration)> {
+        let (config, low_range, auto_range) = (self.adc_config, self.low_range, self.auto_range);
+        if restore {
+            self.set_fast_conversion()?;
+            // First conversion with the new configuration
+            std::thread::sleep(Duration::from_micros(300));
+        }
+        let mut currents = Vec::with_capacity(count);
+        let start = Instant::now();
+        let mut result = Ok(());
+        while currents.len() < count {
+            match self.read_fast() {
+                Ok((_, current)) => currents.push(current),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        if restore {
+            self.set_adc_config(config)?;
+            self.set_range(low_range)?;
+            self.auto_range = auto_range;
+            self.settle_until = Some(Instant::now() + Duration::from_millis(config.conversion_period_ms()));
+        }
+        result.map(|_| (currents, elapsed))
+    }
+
+    // Bus voltage and current only
+    pub fn read_fast(&self) -> anyhow::Result<(f32, f32)> {
+        let voltage = self.vbus_divider * ((self.read_reg24(REG_VBUS)? >> 4) as f32 * 195.3125) / 1000_000.0;
+        let current = self.polarity() * self.current_lsb * decode_signed20(self.read_reg24(REG_CURRENT)?);
+        Ok((voltage, current))
+    }
+
+    // Assert the ALERT pin (latched, active low) when an averaged conversion completes
+    pub fn enable_conversion_ready(&self) -> anyhow::Result<()> {
+        // Bit15: ALATCH=1, Bit14: CNVR=1
+        self.write_reg16(REG_DIAG_ALRT, 0xC000)?;
+        info!("INA228 DIAG_ALRT Set to: {:04x}", self.read_reg16(REG_DIAG_ALRT)?);
+        Ok(())
+    }
+
+    // Read and clear the conversion-ready flag (CNVRF), releasing the ALERT pin
+    pub fn conversion_ready(&self) -> anyhow::Result<bool> {
+        Ok(self.read_reg16(REG_DIAG_ALRT)? & 0x0002 != 0)
+    }
+
+    // Bus voltage, current and power of one sample, then the auto-ranging from its shunt voltage.
+    // A range switch applies from the next conversion, this sample was converted in the old range.
+    pub fn read_all(&mut self) -> anyhow::Result<Measurement> {
+        let [vshunt, vbus, current, power] = self.read_results()?;
+        let lsb_nv = match self.low_range {
+            true => 78.125,
+            false => 312.5,
+        };
+        let measurement = Measurement {
+            voltage: self.vbus_divider * ((vbus >> 4) as f32 * 195.3125) / 1000_000.0,
+            current: self.polarity() * self.current_lsb * decode_signed20(current),
+            power: self.vbus_divider * 3.2 * self.current_lsb * power as f32,
+        };
+        if let Err(e) = self.update_range(decode_signed20(vshunt) * lsb_nv / 1000_000_000.0) {
+            info!("{:?}", e);
+        }
+        Ok(measurement)
+    }
+
+    // VSHUNT, VBUS, CURRENT and POWER one by one with the bus held, so no other transaction comes in
+    // between and the registers belong to the same conversion. The INA228 does not advance the register
+    // pointer, a read past the end of a register returns it again, so they cannot be read in one burst.
+    fn read_results(&self) -> anyhow::Result<[u32; 4]> {
+        let mut results = [0u32; 4];
+        let mut data = [0u8; 3];
+        let regs = [REG_VSHUNT, REG_VBUS, REG_CURRENT, REG_POWER];
+        if let Some(ref simulator) = self.simulator {
+            let mut simulator = simulator.lock().unwrap();
+            for (result, reg) in results.iter_mut().zip(regs) {
+                simulator.read(reg, &mut data);
+                *result = ((data[0] as u32) << 16) | ((data[1] as u32) << 8) | (data[2] as u32);
+            }
+            return Ok(results);
+        }
+        let mut i2c = self.i2c.lock().unwrap();
+        for (result, reg) in results.iter_mut().zip(regs) {
+            i2c.write(self.address, &[reg; 1], BLOCK)?;
+            i2c.read(self.address, &mut data, BLOCK)?;
+            *result = ((data[0] as u32) << 16) | ((data[1] as u32) << 8) | (data[2] as u32);
+        }
+        Ok(results)
+    }
+
+    fn write_reg16(&self, reg: u8, value: u16) -> anyhow::Result<()> {
+        let mut config = [0u8; 3];
+        config[0] = reg;
+        config[1] = (value >> 8) as u8;
+        config[2] = value as u8;
+        if let Some(ref simulator) = self.simulator {
+            simulator.lock().unwrap().write(reg, value);
+            return Ok(());
+        }
+        let mut i2c = self.i2c.lock().unwrap();
+        i2c.write(self.address, &config, BLOCK)?;
+        Ok(())
+    }
+
+    fn read_reg16(&self, reg: u8) -> anyhow::Result<u16> {
+        let mut data = [0u8; 2];
+        self.read_reg(reg, &mut data)?;
+        Ok(((data[0] as u16) << 8) | (data[1] as u16))
+    }
+
+    fn read_reg24(&self, reg: u8) -> anyhow::Result<u32> {
+        let mut data = [0u8; 3];
+        self.read_reg(reg, &mut data)?;
+        Ok(((data[0] as u32) << 16) | ((data[1] as u32) << 8) | (data[2] as u32))
+    }
+
+    // Register pointer write, then the read in a second transaction
+    fn read_reg(&self, reg: u8, data: &mut [u8]) -> anyhow::Result<()> {
+        if let Some(ref simulator) = self.simulator {
+            simulator.lock().unwrap().read(reg, data);
+            return Ok(());
+        }
+        let mut i2c = self.i2c.lock().unwrap();
+        i2c.write(self.address, &[reg; 1], BLOCK)?;
+        i2c.read(self.address, data, BLOCK)?;
+        Ok(())
+    }
+}
+
+// 20-bit two's complement value left-aligned in a 24-bit register
+fn decode_signed20(raw: u32) -> f32 {
+    if raw & 0x800000 == 0x800000 {
+        (0x100000 - (raw >> 4)) as f32 * -1.0
+    }
+    else {
+        (raw >> 4) as f32
+    }
+}
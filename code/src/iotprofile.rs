@@ -0,0 +1,132 @@
+// IotProfile
+// Sleep-current profiling of an IoT device under test: the samples are classified into sleep, idle and
+// active bands by current level, with the time and charge of each band and the battery life they imply.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const BAND_NAMES: [&str; 3] = ["sleep", "idle", "active"];
+
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct BandTotals {
+    pub time: f32,          // s
+    pub charge: f32,        // C
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct IotSummary {
+    pub bands: [BandTotals; 3],
+    pub average_current: f32,   // A over the whole profile
+    pub battery_life: Option<f32>,  // hours on the configured battery, None without a capacity or current
+    pub clock: u128,
+}
+
+impl IotSummary {
+    // Share of the profile time in %
+    pub fn time_percent(&self, band: usize) -> f32 {
+        let total: f32 = self.bands.iter().map(|b| b.time).sum();
+        if total > 0.0 { self.bands[band].time / total * 100.0 } else { 0.0 }
+    }
+
+    pub fn charge_mah(&self, band: usize) -> f32 {
+        self.bands[band].charge / 3.6
+    }
+
+    pub fn to_json(&self) -> String {
+        let bands: Vec<String> = BAND_NAMES.iter().enumerate().map(|(i, name)|
+            format!("\"{}\":{{\"time\":{:.1},\"time_percent\":{:.2},\"charge_mah\":{:.6}}}", name, self.bands[i].time, self.time_percent(i), self.charge_mah(i))).collect();
+        format!("{{{},\"average_current\":{:.7},\"battery_life_h\":{}}}",
+            bands.join(","), self.average_current, self.battery_life.map_or("null".to_string(), |h| format!("{:.1}", h)))
+    }
+}
+
+// Upper limits of the sleep and idle bands in A, e.g. "0.0001,0.005". Empty disables the profiling.
+pub fn parse_bands(text: &str) -> Option<Option<(f32, f32)>> {
+    let limits: Vec<&str> = text.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).collect();
+    match limits.as_slice() {
+        [] => Some(None),
+        [sleep, idle] => match (sleep.parse::<f32>(), idle.parse::<f32>()) {
+            (Ok(sleep), Ok(idle)) if sleep > 0.0 && idle > sleep => Some(Some((sleep, idle))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub struct IotProfiler {
+    sleep_max: f32,         // A
+    idle_max: f32,          // A
+    battery_mah: f32,       // 0: no battery life estimate
+    bands: [BandTotals; 3],
+    last: Option<Instant>,
+    interval: Duration,     // between the summary points
+    last_report: Instant,
+    pending: Option<IotSummary>,    // summary not sent yet
+}
+
+impl IotProfiler {
+    pub fn new(sleep_max: f32, idle_max: f32, battery_mah: f32, interval_secs: u64) -> IotProfiler {
+        info!("IoT profile: sleep < {:.6}A, idle < {:.6}A, battery {:.0}mAh", sleep_max, idle_max, battery_mah);
+        IotProfiler {
+            sleep_max: sleep_max,
+            idle_max: idle_max,
+            battery_mah: battery_mah,
+            bands: [BandTotals::default(); 3],
+            last: None,
+            interval: Duration::from_secs(interval_secs),
+            last_report: Instant::now(),
+            pending: None,
+        }
+    }
+
+    // Add the time up to the sample to the band of its current
+    pub fn update(&mut self, current: f32, instant: Instant)
+    {
+        if let Some(last) = self.last {
+            let dt = instant.saturating_duration_since(last).as_secs_f32();
+            let band = if current < self.sleep_max { 0 } else if current < self.idle_max { 1 } else { 2 };
+            self.bands[band].time += dt;
+            self.bands[band].charge += current.max(0.0) * dt;
+        }
+        self.last = Some(instant);
+        if instant.saturating_duration_since(self.last_report) >= self.interval {
+            self.pending = Some(self.summary());
+            self.last_report = instant;
+        }
+    }
+
+    pub fn summary(&self) -> IotSummary {
+        let time: f32 = self.bands.iter().map(|b| b.time).sum();
+        let charge: f32 = self.bands.iter().map(|b| b.charge).sum();
+        let average_current = if time > 0.0 { charge / time } else { 0.0 };
+        let battery_life = match average_current {
+            a if a > 0.0 && self.battery_mah > 0.0 => Some(self.battery_mah / 1_000.0 / a),
+            _ => None,
+        };
+        IotSummary {
+            bands: self.bands,
+            average_current: average_current,
+            battery_life: battery_life,
+            clock: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+        }
+    }
+
+    pub fn reset(&mut self)
+    {
+        info!("IoT profile reset");
+        self.bands = [BandTotals::default(); 3];
+        self.last = None;
+        self.last_report = Instant::now();
+    }
+
+    pub fn pending(&self) -> Option<&IotSummary> {
+        self.pending.as_ref()
+    }
+
+    pub fn sent(&mut self)
+    {
+        self.pending = None;
+    }
+}
@@ -0,0 +1,94 @@
+// Jitter
+// Spacing of the samples: every interval between two samples is compared with the target interval
+// (the sampling period, or the conversion time with the conversion-ready alert), so energy figures
+// can be trusted to come from evenly spaced samples while WiFi competes for the CPU.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+// Width of one histogram bucket of the deviation from the target interval (us)
+const BUCKET_US: u32 = 100;
+// Deviations of up to 12.8ms are resolved, the last bucket takes the larger ones
+const BUCKETS: usize = 128;
+
+// Sample spacing counters since boot, the health telemetry sends the difference over its interval
+#[derive(Clone, Copy)]
+pub struct JitterStats {
+    pub samples: u32,           // intervals measured
+    pub deviation_us: u64,      // total deviation from the target interval
+    pub late: u32,              // intervals over twice the target, a sample slot was missed
+    pub max_us: u32,            // largest deviation since boot
+    histogram: [u32; BUCKETS + 1],
+}
+
+impl Default for JitterStats {
+    fn default() -> Self {
+        JitterStats { samples: 0, deviation_us: 0, late: 0, max_us: 0, histogram: [0; BUCKETS + 1] }
+    }
+}
+
+// Percentiles of the deviation for the health records and the diagnostics page
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct JitterSummary {
+    pub samples: u32,
+    pub mean_us: u32,
+    pub p95_us: u32,
+    pub p99_us: u32,
+    pub late: u32,
+    pub max_us: u32,
+}
+
+impl JitterStats {
+    pub fn add(&mut self, interval_us: u64, target_us: u64)
+    {
+        let deviation = interval_us.abs_diff(target_us).min(u32::MAX as u64) as u32;
+        self.samples = self.samples.wrapping_add(1);
+        self.deviation_us = self.deviation_us.wrapping_add(deviation as u64);
+        if interval_us > target_us * 2 {
+            self.late = self.late.wrapping_add(1);
+        }
+        self.max_us = self.max_us.max(deviation);
+        self.histogram[((deviation / BUCKET_US) as usize).min(BUCKETS)] += 1;
+    }
+
+    // Counts since an earlier snapshot, the maximum stays the one since boot
+    pub fn since(&self, base: &JitterStats) -> JitterStats {
+        let mut histogram = self.histogram;
+        for (count, base) in histogram.iter_mut().zip(base.histogram.iter()) {
+            *count = count.wrapping_sub(*base);
+        }
+        JitterStats {
+            samples: self.samples.wrapping_sub(base.samples),
+            deviation_us: self.deviation_us.wrapping_sub(base.deviation_us),
+            late: self.late.wrapping_sub(base.late),
+            max_us: self.max_us,
+            histogram: histogram,
+        }
+    }
+
+    // Percentiles are the upper edge of their histogram bucket, never more than the maximum
+    pub fn summary(&self) -> JitterSummary {
+        JitterSummary {
+            samples: self.samples,
+            mean_us: (self.deviation_us / (self.samples.max(1) as u64)) as u32,
+            p95_us: self.percentile(95),
+            p99_us: self.percentile(99),
+            late: self.late,
+            max_us: self.max_us,
+        }
+    }
+
+    fn percentile(&self, percent: u64) -> u32 {
+        if self.samples == 0 {
+            return 0;
+        }
+        let rank = (self.samples as u64 * percent).div_ceil(100);
+        let mut count = 0;
+        for (i, n) in self.histogram.iter().enumerate() {
+            count += *n as u64;
+            if count >= rank {
+                return ((i as u32 + 1) * BUCKET_US).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+}
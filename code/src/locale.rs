@@ -0,0 +1,219 @@
+// Locale
+// Texts of the display in English and Japanese, selected by display_language. The display draws text
+// with other than ASCII characters in a Japanese bitmap font, numbers, units and key names stay ASCII.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    En,
+    Ja,
+}
+
+impl Language {
+    pub fn from_str(text: &str) -> Option<Language> {
+        match text.trim() {
+            "en" => Some(Language::En),
+            "ja" => Some(Language::Ja),
+            _ => None,
+        }
+    }
+}
+
+static LANGUAGE: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_language(language: Language)
+{
+    LANGUAGE.store(language as u8, Ordering::Relaxed);
+}
+
+pub fn language() -> Language {
+    match LANGUAGE.load(Ordering::Relaxed) {
+        1 => Language::Ja,
+        _ => Language::En,
+    }
+}
+
+// Texts in the order of TEXTS, "{}" are replaced by the arguments of tr_args.
+// The texts of the display pages and the WiFi messages stay in a build without them, as the order is the index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(all(feature = "wifi", feature = "display")), allow(dead_code))]
+pub enum Msg {
+    // Messages of the main loop
+    RecoveredRecords,
+    ConfigErrors,
+    MoreErrors,
+    Calibrating,
+    PressToConfirm,
+    CalibrationOk,
+    CalibrationFailed,
+    CalibrationCancelled,
+    ProfileName,
+    ScanningWifi,
+    WifiScanFailed,
+    WifiNotAvailable,
+    AccessPoint,
+    PeakCleared,
+    BufferAlert,
+    NegativeCurrent,
+    ServerCheck,
+    ServerUnreachable,
+    BadToken,
+    BucketMissing,
+    ClockSkew,
+    HttpStatus,
+    Flushing,
+    SafeToPowerOff,
+    CaptureMenu,
+    CaptureStop,
+    CaptureStarted,
+    CaptureDone,
+    // Meter page
+    RangeLow,
+    RangeHigh,
+    Differential,
+    ConfigError,
+    Demo,
+    AcMode,
+    Filtered,
+    PeakHold,
+    EfficiencyHold,
+    Tripped,
+    Logging,
+    Stopped,
+    Armed,
+    Retrying,
+    Failed,
+    Backlog,
+    NoSignal,
+    Channel,
+    // Other pages
+    WifiScan,
+    Diagnostics,
+    DiagnosticsText,
+    Ripple,
+    RippleText,
+    NoRipple,
+    Network,
+    NetworkText,
+    BacklogBatches,
+    Offline,
+    SecondsAgo,
+    Never,
+    Pulse,
+    PulseText,
+    Current,
+    Voltage,
+    Power,
+    Minimum,
+    Maximum,
+    SocCharge,
+    SocCycles,
+    IotAverage,
+    Sleep,
+    Idle,
+    Active,
+    BatteryLife,
+    Days,
+    Hours,
+}
+
+// English and Japanese text of each message
+const TEXTS: [(&str, &str); 74] = [
+    ("Recovered {} records\nfrom last power loss", "停電から {}件の\n記録を復元"),
+    ("Config errors:\n{}", "設定エラー:\n{}"),
+    ("+{} more", "ほか{}件"),
+    ("Calibrating...", "校正中..."),
+    ("Press to Confirm", "押して確定"),
+    ("Calibration OK", "校正完了"),
+    ("Calibration Failed", "校正失敗"),
+    ("Calibration Cancelled", "校正キャンセル"),
+    ("Profile:\n{}", "プロファイル:\n{}"),
+    ("Scanning WiFi...", "WiFiスキャン中..."),
+    ("WiFi Scan Failed", "WiFiスキャン失敗"),
+    ("WiFi Not Available", "WiFiなし"),
+    ("WiFi AP {}\n{}", "アクセスポイント {}\n{}"),
+    ("Peak Cleared", "ピーク クリア"),
+    ("BUFFER {}% FULL\nData loss imminent", "バッファ {}%\nデータ欠落のおそれ"),
+    ("Negative current\ninvert_current={}?", "負の電流\ninvert_current={}?"),
+    ("Server check:\n{}", "サーバ確認:\n{}"),
+    ("server unreachable", "接続できません"),
+    ("{} bad token", "{} トークン不正"),
+    ("bucket missing", "バケットがありません"),
+    ("clock skew {}s", "時刻のずれ {}s"),
+    ("HTTP status {}", "HTTP ステータス {}"),
+    ("Flushing\n{} records", "送信中\n{}件"),
+    ("SAFE TO\nPOWER OFF", "電源を\n切れます"),
+    ("Capture: {}\nPress: next\nHold: start", "記録時間: {}\n押す: 次へ\n長押し: 開始"),
+    ("stop", "停止"),
+    ("Capture {}", "記録 {}"),
+    ("Capture done\n{} records", "記録終了\n{}件"),
+    ("LO", "LO"),
+    ("HI", "HI"),
+    ("D", "D"),
+    ("CFG!", "CFG!"),
+    ("DEMO", "デモ"),
+    ("AC", "AC"),
+    ("F", "F"),
+    ("PK{}", "PK{}"),
+    ("EF{}", "EF{}"),
+    ("TRIPPED", "トリップ"),
+    ("LOGGING", "記録中"),
+    ("STOPPED", "停止"),
+    ("ARMED", "待機中"),
+    ("RTRY{} {}", "再送{} {}"),
+    ("FAIL {}", "失敗 {}"),
+    ("BKLG {}", "未送 {}"),
+    ("NO SIG", "圏外"),
+    ("CH:{}", "CH:{}"),
+    ("WiFi scan: {} APs", "WiFiスキャン: {}件"),
+    ("DIAGNOSTICS", "診断"),
+    ("Samples: {}\nJitter: {}us avg\np95/p99: {}/{}us\nLate: {} Max: {}us", "サンプル: {}\nジッタ平均: {}us\np95/p99: {}/{}us\n遅延: {} 最大: {}us"),
+    ("RIPPLE", "リップル"),
+    ("Freq: {}\nAmpl: {}mA\nP-P:  {}mA\nRate: {}S/s", "周波数: {}\n振幅: {}mA\nP-P: {}mA\nレート: {}S/s"),
+    ("none", "なし"),
+    ("NETWORK {}", "ネットワーク {}"),
+    ("Sent:   {} records\nLast:   {}\nFailed: {}\nBuffer: {}%", "送信: {}件\n最終: {}\n失敗: {}\nバッファ: {}%"),
+    ("Backlog: {} batches", "未送信: {}バッチ"),
+    ("offline", "オフライン"),
+    ("{}s ago", "{}秒前"),
+    ("never", "なし"),
+    ("PULSE", "パルス"),
+    ("Duty:   {}%\nPulses: {}\nWidth:  {}ms\nCharge: {}mC", "デューティ: {}%\nパルス数: {}\n幅: {}ms\n電荷: {}mC"),
+    ("CURRENT", "電流"),
+    ("VOLTAGE", "電圧"),
+    ("POWER", "電力"),
+    ("min {}", "最小 {}"),
+    ("max {}", "最大 {}"),
+    ("Out:{}mAh In:{}mAh", "放電:{}mAh 充電:{}mAh"),
+    ("Eff:{} Cycles:{}", "効率:{} サイクル:{}"),
+    ("IOT avg {}", "IoT 平均 {}"),
+    ("Sleep", "スリープ"),
+    ("Idle", "アイドル"),
+    ("Active", "動作"),
+    ("Life: {}", "電池寿命: {}"),
+    ("{} days", "{}日"),
+    ("{} hours", "{}時間"),
+];
+
+// Text of the message in the selected language
+pub fn tr(msg: Msg) -> &'static str {
+    let (en, ja) = TEXTS[msg as usize];
+    match language() {
+        Language::En => en,
+        Language::Ja => ja,
+    }
+}
+
+// Text with each "{}" replaced by the next argument
+pub fn tr_args(msg: Msg, args: &[&str]) -> String {
+    let mut parts = tr(msg).split("{}");
+    let mut text = parts.next().unwrap_or("").to_string();
+    for (i, part) in parts.enumerate() {
+        text.push_str(args.get(i).copied().unwrap_or(""));
+        text.push_str(part);
+    }
+    text
+}
@@ -0,0 +1,81 @@
+// Marker
+// External sync marker input: a falling edge on a GPIO inserts a marker with the time of
+// the edge and a running count, so test equipment can mark the phases of a test run.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
+use esp_idf_hal::gpio::{PinDriver, AnyInputPin, Input, InterruptType, Pull};
+
+// Markers kept while WiFi is down, the oldest is dropped when full
+const MAX_PENDING_MARKERS: usize = 32;
+
+// Written by the interrupt handler: edge count and esp_timer time of the last edge (lower 32 bits of us)
+static EDGE_COUNT: AtomicU32 = AtomicU32::new(0);
+static EDGE_TIME_US: AtomicU32 = AtomicU32::new(0);
+
+pub struct Marker {
+    pub clock: u128,    // ns, time of the edge
+    pub count: u32,     // markers since boot, starting at 1
+}
+
+pub struct MarkerInput {
+    pin: PinDriver<'static, AnyInputPin, Input>,
+    count: u32,
+    pending: VecDeque<Marker>,
+}
+
+impl MarkerInput {
+    pub fn new(pin: i32) -> anyhow::Result<MarkerInput> {
+        let mut input = PinDriver::input(unsafe { AnyInputPin::new(pin) })?;
+        input.set_pull(Pull::Up)?;
+        input.set_interrupt_type(InterruptType::NegEdge)?;
+        unsafe {
+            input.subscribe(|| {
+                EDGE_TIME_US.store(esp_idf_sys::esp_timer_get_time() as u32, Ordering::Relaxed);
+                EDGE_COUNT.fetch_add(1, Ordering::Release);
+            })?;
+        }
+        input.enable_interrupt()?;
+        info!("Marker input on GPIO{}", pin);
+        Ok(MarkerInput { pin: input, count: 0, pending: VecDeque::new() })
+    }
+
+    // Called every loop: queues the marker of a new edge and re-arms the interrupt
+    pub fn poll(&mut self) -> Option<&Marker> {
+        let count = EDGE_COUNT.load(Ordering::Acquire);
+        if count == self.count {
+            return None;
+        }
+        let edge_us = EDGE_TIME_US.load(Ordering::Relaxed);
+        let now_us = unsafe { esp_idf_sys::esp_timer_get_time() } as u32;
+        let age = Duration::from_micros(now_us.wrapping_sub(edge_us) as u64);
+        let clock = (SystemTime::now() - age).duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+        if count.wrapping_sub(self.count) > 1 {
+            info!("{} marker edges merged", count.wrapping_sub(self.count));
+        }
+        self.count = count;
+        // The interrupt is disabled after each edge
+        if let Err(e) = self.pin.enable_interrupt() {
+            info!("Marker interrupt enable failed: {:?}", e);
+        }
+        if self.pending.len() >= MAX_PENDING_MARKERS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(Marker { clock: clock, count: count });
+        self.pending.back()
+    }
+
+    // Oldest marker not sent yet
+    pub fn pending(&self) -> Option<&Marker> {
+        self.pending.front()
+    }
+
+    pub fn sent(&mut self)
+    {
+        self.pending.pop_front();
+    }
+}
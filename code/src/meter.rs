@@ -0,0 +1,2770 @@
+// Meter
+// The meter itself: owns the sensor sampling, the record buffer, the display, the transfer, the settings,
+// the button and the other subsystems, set up by init() from the configuration. tick() runs one pass of
+// the main loop, the button, MQTT, the serial console and the data API each have their own handler.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::{thread, time::Duration};
+use esp_idf_hal::{prelude::*, i2c, gpio::*};
+use esp_idf_hal::peripherals::Peripherals;
+use log::*;
+use std::time::{SystemTime, Instant};
+use esp_idf_hal::adc::ADC1;
+use esp_idf_hal::adc::oneshot::config::AdcChannelConfig;
+use esp_idf_hal::adc::oneshot::config::Calibration;
+use esp_idf_hal::adc::oneshot::*;
+use esp_idf_hal::adc::attenuation::DB_11;
+use esp_idf_hal::gpio::PinDriver;
+#[cfg(feature = "wifi")]
+use esp_idf_svc::sntp::{EspSntp, SyncStatus, SntpConf, OperatingMode, SyncMode};
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+#[cfg(feature = "wifi")]
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use chrono::{DateTime, Utc};
+
+use crate::{CONFIG, ADCRANGE, CALIBRATION_USE, I2C_MAX_KHZ, CUTOFF_GPIO_ALLOWED, LOOP_PERIOD_MS,
+    CALIBRATION_MAX_CURRENT_OFFSET, CALIBRATION_MAX_VOLTAGE_OFFSET, CALIBRATION_MAX_CURRENT_STDDEV, CALIBRATION_MAX_VOLTAGE_STDDEV,
+    CALIBRATION_CONFIRM_MS, CONFIG_ERROR_DISPLAY_MS, POWER_LOSS_DISPLAY_MS, NTP_SYNC_TIMEOUT_MS, BUFFER_ALERT_DISPLAY_MS,
+    SHUTDOWN_DRAIN_TIMEOUT_MS, SERVER_CHECK_MESSAGE_MS, ENERGY_CHECKPOINT_MS, REVERSED_SHUNT_CURRENT, REVERSED_SHUNT_HINT_MS,
+    REVERSED_SHUNT_MESSAGE_MS, CAPTURE_MENU_MS};
+#[cfg(feature = "wifi")]
+use crate::{WIFI_DELAY_START, AP_MESSAGE_MS};
+#[cfg(feature = "display")]
+use crate::DISPLAY_PERIOD_MS;
+use crate::{version, transfer, schedule, crash, timebase, ripple, powerloss, iotprofile, locale, health,
+    console, bufferalert};
+#[cfg(feature = "wifi")]
+use crate::{wifi, network, syslog, dataapi};
+use crate::displayctl::{DisplayPanel, LoggingStatus, WifiStatus, AlarmQuantity};
+#[cfg(feature = "display")]
+use crate::displayctl::{SocInfo, BigValue, NetInfo, DisplayMode, SSD1306_MAX_I2C_KHZ};
+use crate::currentlogs::{CurrentRecord, CurrentLog};
+use crate::transfer::{Transfer, ServerInfo, PayloadFormat};
+use crate::timestamp::{Precision, Timestamp};
+use crate::stats::{PeakHold, SessionSummary, EnergyIntegrator};
+#[cfg(feature = "wifi")]
+use crate::mqtt::{MqttControl, Command};
+use crate::health::{Health, Heartbeat, HeartbeatLog};
+use crate::summary::{Summary, SummaryInterval};
+use crate::protection::Protection;
+use crate::ina228::{Ina228, INA228_ADDR, INA228_ADDR_MAX, INA228_MAX_I2C_KHZ};
+use crate::simulator::Simulator;
+#[cfg(feature = "influx")]
+use crate::backlog::Backlog;
+use crate::ripple::RippleResult;
+use crate::pulse::PulseDetector;
+use crate::iotprofile::IotProfiler;
+use crate::eventlog::{EventLog, EventKind};
+use crate::settings::{Settings, SettingsWatch, SETTING_KEYS, SECRET_KEYS, parse_channel_names};
+use crate::console::{Console, ConsoleCommand};
+use crate::stream::{Streamer, StreamMode};
+use crate::trigger::{Trigger, TriggerEvent};
+use crate::schedule::{Schedule, ScheduleEvent};
+use crate::sampler::{Sampler, Offsets};
+#[cfg(feature = "display")]
+use crate::filter::{Filter, FilterMode, Decimator};
+#[cfg(feature = "wifi")]
+use crate::wspush::WsPush;
+use crate::coulomb::CoulombCounter;
+use crate::configcheck::ConfigCheck;
+use crate::marker::MarkerInput;
+#[cfg(feature = "wifi")]
+use crate::wifi::{ApFallback, EapCredentials, WifiSupervisor};
+use crate::powerloss::PowerMonitor;
+use crate::profile::Profile;
+use crate::bufferalert::BufferAlert;
+use crate::buzzer::{Buzzer, Pattern};
+use crate::statusled::{StatusLed, LedState, LedType};
+#[cfg(feature = "wifi")]
+use crate::dataapi::{DataApi, DataRequest};
+#[cfg(feature = "wifi")]
+use crate::scpi::Scpi;
+use crate::button::{Button, ButtonEvent};
+use crate::crash::CrashReport;
+use crate::locale::{Language, Msg, tr, tr_args};
+use crate::capture::{self, Capture};
+use crate::rtc::{Ds3231, RtcSync, DS3231_ADDR, DS3231_MAX_I2C_KHZ};
+use crate::outputrule::{self, OutputRules};
+
+// Settings applied without a reboot by each part of the meter, the others are read at boot
+const SENSOR_SETTINGS: [&str; 8] = ["current_limit", "current_offset", "current_offset_hi", "voltage_offset", "invert_current", "profile", "schedule", "output_rules"];
+const CHANNEL_SETTINGS: [&str; 2] = ["channel", "channel_names"];
+const TRANSFER_SETTINGS: [&str; 2] = ["device_name", "influxdb_tags"];
+#[cfg(feature = "wifi")]
+const WIFI_SETTINGS: [&str; 2] = ["wifi_ssid", "wifi_psk"];
+const METER_SETTINGS: [&str; 6] = ["sound_enabled", "timezone", "battery_divider", "battery_scale", "trigger_start", "trigger_stop"];
+
+pub struct Meter {
+    // Subsystems
+    dp: DisplayPanel,
+    buzzer: Buzzer,
+    led: StatusLed,
+    journal: EventLog,
+    settings: Settings,
+    sampler: Sampler,
+    clogs: CurrentRecord,
+    txd: Transfer,
+    health: Health,
+    heartbeat: Heartbeat,
+    summary: Summary,
+    button: Button,
+    // Changes of the settings applied at runtime
+    sensor_watch: SettingsWatch,
+    channel_watch: SettingsWatch,
+    transfer_watch: SettingsWatch,
+    #[cfg(feature = "wifi")]
+    wifi_watch: SettingsWatch,
+    meter_watch: SettingsWatch,
+    #[cfg(feature = "wifi")]
+    wifi_device: Option<WifiSupervisor>,
+    #[cfg(feature = "wifi")]
+    ntp: EspSntp<'static>,
+    #[cfg(feature = "wifi")]
+    mqtt_ctl: Option<MqttControl>,
+    #[cfg(feature = "wifi")]
+    data_api: Option<DataApi>,
+    #[cfg(feature = "wifi")]
+    scpi: Option<Scpi>,
+    console: Console,
+    #[cfg(feature = "wifi")]
+    ws_push: Option<WsPush>,
+    streamer: Streamer,
+    power_monitor: Option<PowerMonitor>,
+    adc_pin: AdcChannelDriver<'static, Gpio3, AdcDriver<'static, ADC1>>,
+    trigger_pin: Option<PinDriver<'static, AnyInputPin, Input>>,
+    marker: Option<MarkerInput>,
+    buffer_alert: BufferAlert,
+    trigger: Trigger,
+    log_schedule: Schedule,
+    coulomb: Option<CoulombCounter>,
+    pulse: Option<PulseDetector>,
+    iot: Option<IotProfiler>,
+    peak: PeakHold,         // peak-hold since boot or last clear
+    energy: EnergyIntegrator,
+    #[cfg(feature = "display")]
+    voltage_filter: Filter,
+    #[cfg(feature = "display")]
+    current_filter: Filter,
+    #[cfg(feature = "display")]
+    power_filter: Filter,
+    #[cfg(feature = "display")]
+    display_decimator: Decimator,
+    // Configuration used at runtime
+    max_records: usize,
+    buffer_heap_percent: usize,
+    retention_secs: u64,
+    ripple_interval: u64,
+    battery_divider: f32,
+    coulomb_initial_soc: f32,
+    #[cfg(feature = "display")]
+    big_digits_value: BigValue,
+    ac_mode: bool,
+    differential: bool,
+    #[cfg(feature = "display")]
+    network_page: bool,
+    output_gpio: Vec<i32>,      // GPIOs free for the output rules
+    // State
+    channel: u8,
+    channel_names: Vec<String>,
+    tag: String,
+    profile: Profile,
+    scheduled_profile: Option<Profile>,     // profile of the open window, replacing the configured one
+    offsets: Offsets,
+    pending_calibration: Option<(Offsets, u64)>,    // offsets waiting for confirmation
+    battery_scale: f32,
+    battery_mv: f32,
+    data: CurrentLog,
+    crash_report: Option<CrashReport>,
+    logging_start: bool,
+    logging_stopped_by_buffer_full: bool,   // logging was stopped due to buffer full
+    wifi_enable: bool,
+    #[cfg(feature = "wifi")]
+    ipv6_enabled: bool,
+    #[cfg(feature = "wifi")]
+    access_point: bool,     // the WiFi fallback access point is up
+    #[cfg(feature = "wifi")]
+    ap_ssid: String,
+    time_synced: bool,
+    rtc: Option<RtcSync>,
+    rtc_sync_count: u32,    // NTP syncs the RTC was set at
+    #[cfg(feature = "influx")]
+    server_checked: bool,
+    #[cfg(feature = "display")]
+    big_digits: bool,       // big digits page toggled by a 1-2 second press
+    shutdown_requested: bool,
+    was_tripped: bool,
+    was_full: bool,
+    last_trigger_level: bool,
+    reversed_since: Option<u64>,    // negative current since (ms)
+    reversed_hint_shown: bool,
+    last_ripple: Instant,
+    pending_ripple: Option<RippleResult>,
+    capture: Option<Capture>,       // capture session of a fixed length
+    capture_summary: Option<SessionSummary>,    // sent after the records of the capture
+    capture_menu: Option<usize>,    // entry of MENU_DURATIONS shown by the button menu
+    energy_checkpoint: Instant,
+    message_clear_time: u64,        // the message is cleared at this time (ms), 0 if none
+    start_time: Instant,
+}
+
+impl Meter {
+    // Set up the subsystems from the configuration, the sampling, network and display threads run from here
+    pub fn init(peripherals: Peripherals) -> anyhow::Result<Meter> {
+        // Create display, started on the shared I2C bus or SPI once the display mode is known
+        let mut dp = DisplayPanel::new();
+        info!("Firmware {}", version::summary());
+        dp.set_err_message(version::boot_text());
+
+        // Parse configuration values, errors are shown after all values are checked
+        let mut check = ConfigCheck::new();
+        let max_records = check.number(1, "max_records", CONFIG.max_records, 1023, 1, 100000);
+        let buffer_heap_percent = check.number(17, "buffer_heap_percent", CONFIG.buffer_heap_percent, 50, 0, 90);
+        info!("Max records set to: {}", max_records);
+
+        // Initialize NVS
+        let nvs_default_partition = EspNvsPartition::<NvsDefault>::take().unwrap();
+        let nvs = match EspNvs::new(nvs_default_partition.clone(), "storage", true) {
+            Ok(nvs) => { 
+                info!("NVS storage area initialized"); 
+                nvs 
+            },
+            Err(ref e) => {
+                info!("NVS initialization failed {:?}", e);
+                panic!("NVS initialization failed {:?}", e); 
+            }
+        };
+
+        let mut settings = Settings::new(nvs);
+        let sensor_watch = settings.watch(&SENSOR_SETTINGS);
+        let channel_watch = settings.watch(&CHANNEL_SETTINGS);
+        let transfer_watch = settings.watch(&TRANSFER_SETTINGS);
+        #[cfg(feature = "wifi")]
+        let wifi_watch = settings.watch(&WIFI_SETTINGS);
+        let meter_watch = settings.watch(&METER_SETTINGS);
+
+        // Language of the display texts
+        match Language::from_str(CONFIG.display_language) {
+            Some(language) => locale::set_language(language),
+            None => check.require(61, "display_language", false, &format!("'{}' is not en or ja, using en", CONFIG.display_language)),
+        }
+
+        // The OLED can be switched off or turned on by the button only in headless installs
+        #[cfg(feature = "display")]
+        let display_mode_text = settings.get("display_mode").unwrap_or(CONFIG.display_mode.to_string());
+        #[cfg(feature = "display")]
+        let display_mode = match DisplayMode::from_str(&display_mode_text) {
+            Some(mode) => mode,
+            None => {
+                check.require(39, "display_mode", false, &format!("'{}' is invalid, using on", display_mode_text));
+                DisplayMode::On
+            }
+        };
+        #[cfg(feature = "display")]
+        let display_on_time = check.number(40, "display_on_time", CONFIG.display_on_time, 10, 1, 3600);
+        #[cfg(feature = "display")]
+        if display_mode == DisplayMode::Button {
+            dp.set_on_time(Some(Duration::from_secs(display_on_time)));
+        }
+        // SPI panel "sclk,mosi,dc,cs[,rst]" with the display-spi feature, these GPIOs are not free for the other functions
+        let display_spi_pins: Vec<i32> = match CONFIG.display_spi_pins.trim() {
+            "" => Vec::new(),
+            text => {
+                let pins: Vec<i32> = text.split(',').filter_map(|pin| pin.trim().parse::<i32>().ok()).collect();
+                let valid = cfg!(feature = "display-spi") && pins.len() == text.split(',').count() && (4..=5).contains(&pins.len())
+                    && pins.iter().enumerate().all(|(i, pin)| CUTOFF_GPIO_ALLOWED.contains(pin) && !pins[..i].contains(pin));
+                check.require(49, "display_spi_pins", valid, &format!("'{}' is not 4 or 5 free GPIOs or no display-spi build, using I2C", text));
+                if valid { pins } else { Vec::new() }
+            }
+        };
+        // I2C panel on two free GPIOs "sda,scl" of a bit-banged bus, so the sensors have the I2C controller to themselves
+        let display_i2c_pins: Vec<i32> = match CONFIG.display_i2c_pins.trim() {
+            "" => Vec::new(),
+            text => {
+                let pins: Vec<i32> = text.split(',').filter_map(|pin| pin.trim().parse::<i32>().ok()).collect();
+                let valid = cfg!(feature = "display") && display_spi_pins.is_empty() && pins.len() == 2 && text.split(',').count() == 2
+                    && pins[0] != pins[1] && pins.iter().all(|pin| CUTOFF_GPIO_ALLOWED.contains(pin));
+                check.require(60, "display_i2c_pins", valid, &format!("'{}' is not 2 free GPIOs, no display build or SPI display, using the shared bus", text));
+                if valid { pins } else { Vec::new() }
+            }
+        };
+        // DS3231 RTC on the shared bus
+        let rtc_enabled = check.flag(66, "rtc", CONFIG.rtc, false);
+        // Shared I2C for both SSD1306 display and INA228 sensor, at the clock of the slowest device on the bus
+        #[cfg(feature = "display")]
+        let bus_max_khz = match display_spi_pins.is_empty() && display_i2c_pins.is_empty() {
+            true => I2C_MAX_KHZ.min(INA228_MAX_I2C_KHZ).min(SSD1306_MAX_I2C_KHZ),
+            false => I2C_MAX_KHZ.min(INA228_MAX_I2C_KHZ),
+        };
+        #[cfg(not(feature = "display"))]
+        let bus_max_khz = I2C_MAX_KHZ.min(INA228_MAX_I2C_KHZ);
+        let bus_max_khz = if rtc_enabled { bus_max_khz.min(DS3231_MAX_I2C_KHZ) } else { bus_max_khz };
+        let i2c_clock = check.number(59, "i2c_clock", CONFIG.i2c_clock, 100, 10, bus_max_khz);
+        info!("I2C clock {}kHz", i2c_clock);
+        let i2c = peripherals.i2c0;
+        let scl = peripherals.pins.gpio7;
+        let sda = peripherals.pins.gpio8;
+        let config = i2c::I2cConfig::new().baudrate(i2c_clock.kHz().into());
+        let i2c_driver = i2c::I2cDriver::new(i2c, sda, scl, &config)?;
+
+        // Clone the I2C driver for shared use (using Arc and Mutex for thread safety)
+        use std::sync::{Arc, Mutex};
+        let shared_i2c = Arc::new(Mutex::new(i2c_driver));
+        // The wall clock is set from the RTC before the first sample, NTP sets it again once online
+        let rtc = match rtc_enabled {
+            true => {
+                let rtc = Ds3231::new(shared_i2c.clone());
+                match rtc.read_time() {
+                    Ok(Some(wall)) => {
+                        timebase::set_clock(wall);
+                        let dt_now: DateTime<Utc> = SystemTime::now().into();
+                        info!("Clock set from the RTC: {}", dt_now.format("%Y-%m-%d %H:%M:%S"));
+                        Some(rtc)
+                    },
+                    Ok(None) => {
+                        info!("RTC has lost the time, waiting for NTP");
+                        Some(rtc)
+                    },
+                    Err(e) => {
+                        info!("RTC read failed: {:?}", e);
+                        check.require(66, "rtc", false, &format!("no DS3231 at 0x{:02X}", DS3231_ADDR));
+                        None
+                    }
+                }
+            },
+            false => None,
+        };
+        #[cfg(feature = "display")]
+        let display_i2c = shared_i2c.clone();
+        let free_gpio: Vec<i32> = CUTOFF_GPIO_ALLOWED.iter().copied().filter(|pin| !display_spi_pins.contains(pin) && !display_i2c_pins.contains(pin)).collect();
+        #[cfg(feature = "display-spi")]
+        let panel = match display_spi_pins.is_empty() {
+            true => Some(DisplayPanel::i2c_interface(display_i2c)),
+            false => match DisplayPanel::spi_interface(peripherals.spi2, &display_spi_pins) {
+                Ok(panel) => Some(panel),
+                Err(e) => {
+                    info!("SPI display failed: {:?}", e);
+                    None
+                }
+            },
+        };
+        #[cfg(all(feature = "display", not(feature = "display-spi")))]
+        let panel = Some(DisplayPanel::i2c_interface(display_i2c));
+        // The own bus replaces the shared one, display_i2c_pins is empty with an SPI panel
+        #[cfg(feature = "display")]
+        let panel = match display_i2c_pins.is_empty() {
+            true => panel,
+            false => match DisplayPanel::soft_i2c_interface(&display_i2c_pins) {
+                Ok(panel) => Some(panel),
+                Err(e) => {
+                    info!("Display I2C bus failed: {:?}, using the shared bus", e);
+                    panel
+                }
+            },
+        };
+        #[cfg(feature = "display")]
+        if let Some(panel) = panel {
+            match display_mode {
+                DisplayMode::Off => {
+                    info!("Display off");
+                    DisplayPanel::power_off(panel);
+                },
+                _ => dp.start(panel),
+            }
+        }
+
+        // Crash report of the previous boot, then arm the panic hook for this one
+        let crash_report = crash::take_report(nvs_default_partition.clone());
+        crash::install(nvs_default_partition.clone());
+
+        // Event journal in NVS, optionally mirrored to InfluxDB
+        let mut journal = EventLog::new(nvs_default_partition.clone(), check.flag(57, "eventlog_mirror", CONFIG.eventlog_mirror, false));
+        journal.record(EventKind::Boot, &format!("{} reset={}", version::VERSION, health::get_reset_reason()));
+        if let Some(ref report) = crash_report {
+            journal.record(EventKind::Crash, &report.message);
+        }
+
+        // Emergency flush of the record buffer when the battery voltage collapses
+        let mut power_monitor = match PowerMonitor::new(nvs_default_partition) {
+            Ok(monitor) => Some(monitor),
+            Err(e) => {
+                info!("Power loss monitor disabled: {:?}", e);
+                None
+            }
+        };
+
+        // Load current channel from NVS
+        let channel: u8 = settings.load_channel();
+
+        // Load configuration
+        // Server settings imported into NVS take precedence over cfg.toml
+        let mut server_info = ServerInfo::new(settings.get("influxdb_server").unwrap_or(CONFIG.influxdb_server.to_string()),
+            settings.get("influxdb_api_key").unwrap_or(CONFIG.influxdb_api_key.to_string()),
+            settings.get("influxdb_api").unwrap_or(CONFIG.influxdb_api.to_string()),
+            check.name(44, "influxdb_measurement", CONFIG.influxdb_measurement, "minicurrent"),
+            check.name(45, "influxdb_tag", CONFIG.influxdb_tag, "ch"));
+        check.require(15, "influxdb_server", !server_info.server.is_empty() || server_info.influxdb_api.is_empty(),
+            "is empty but influxdb_api is set, nothing can be sent");
+        server_info.health_measurement = check.name(46, "health_measurement", CONFIG.health_measurement, "meter_health");
+        server_info.summary_measurement = CONFIG.summary_measurement.to_string();
+        server_info.marker_measurement = check.name(47, "marker_measurement", CONFIG.marker_measurement, "meter_marker");
+        server_info.pulse_measurement = check.name(53, "pulse_measurement", CONFIG.pulse_measurement, "meter_pulse");
+        server_info.tags = match transfer::parse_tags(&settings.get("influxdb_tags").unwrap_or(CONFIG.influxdb_tags.to_string())) {
+            Ok(tags) => tags,
+            Err(e) => {
+                check.require(48, "influxdb_tags", false, &format!("{}, ignored", e));
+                Vec::new()
+            }
+        };
+        server_info.payload_format = PayloadFormat::from_str(CONFIG.payload_format);
+        server_info.precision = Precision::from_str(CONFIG.timestamp_precision);
+        server_info.http_user = CONFIG.http_user.to_string();
+        server_info.http_password = CONFIG.http_password.to_string();
+        server_info.http_headers = match transfer::parse_headers(CONFIG.http_headers) {
+            Some(headers) => headers,
+            None => {
+                check.require(22, "http_headers", false, "must be 'Name: value' lines, ignored");
+                Vec::new()
+            }
+        };
+        server_info.device = settings.load_device_name();
+        info!("Device tag: {}", server_info.device);
+        // Remote log output, the device tag is the syslog hostname
+        #[cfg(feature = "wifi")]
+        if !CONFIG.syslog_url.is_empty() {
+            match syslog::parse_url(CONFIG.syslog_url) {
+                Some(target) => syslog::start(target, &server_info.device),
+                None => check.require(58, "syslog_url", false, &format!("'{}' is not a udp:// or http(s):// URL, disabled", CONFIG.syslog_url)),
+            }
+        }
+        let channel_names_text = settings.get("channel_names").unwrap_or(CONFIG.channel_names.to_string());
+        let channel_names = match parse_channel_names(&channel_names_text) {
+            Ok(names) => names,
+            Err(e) => {
+                check.require(43, "channel_names", false, &format!("{}, ignored", e));
+                vec![String::new(); 4]
+            }
+        };
+
+        // Health telemetry (0 disables)
+        let health_interval = check.number(5, "health_interval", CONFIG.health_interval, 60, 0, 86400);
+        let health = Health::new(health_interval);
+        // Heartbeat with the device status, also while not logging (0 disables)
+        let heartbeat_interval = check.number(67, "heartbeat_interval", CONFIG.heartbeat_interval, 0, 0, 86400);
+        let heartbeat = Heartbeat::new(heartbeat_interval);
+
+        // Periodic summary reports by local time (off, hourly or daily)
+        let summary_interval = SummaryInterval::from_str(CONFIG.summary_interval).unwrap_or_else(|| {
+            info!("Invalid summary_interval '{}', summary reports disabled", CONFIG.summary_interval);
+            SummaryInterval::Off
+        });
+        let summary = Summary::new(summary_interval, CONFIG.summary_webhook);
+
+        // Use the shared I2C for INA sensor
+        let sensor_i2c = shared_i2c.clone();
+
+        // Logging profile, the one selected at runtime is stored in NVS
+        let config_profile = match Profile::from_name(CONFIG.profile) {
+            Some(profile) => profile,
+            None => {
+                check.require(21, "profile", false, &format!("'{}' is invalid, using standard", CONFIG.profile));
+                Profile::Standard
+            }
+        };
+        let profile = settings.get("profile").and_then(|name| Profile::from_name(&name)).unwrap_or(config_profile);
+        info!("Logging profile: {}", profile.name());
+
+        // Initialize INA228 sensor
+        let shunt_resistance = check.number(2, "shunt_resistance", CONFIG.shunt_resistance, 0.005, 0.0001, 10.0);
+        let auto_range = check.flag(4, "auto_range", CONFIG.auto_range, false);
+        let sensor_address = check.i2c_address(23, "sensor_address", CONFIG.sensor_address, INA228_ADDR, INA228_ADDR_MAX).unwrap_or(INA228_ADDR);
+        let mut sensor = Ina228::new(sensor_i2c.clone(), sensor_address, shunt_resistance, ADCRANGE, auto_range);
+        let shunt_temp_coefficient = check.number(3, "shunt_temp_coefficient", CONFIG.shunt_temp_coefficient, 50, 0, 16383);
+        // Demo mode: simulated sensors, always ("on") or when no INA228 answers ("auto")
+        let demo_mode = match CONFIG.demo_mode.trim() {
+            mode @ ("off" | "on" | "auto") => mode,
+            _ => {
+                check.require(62, "demo_mode", false, &format!("'{}' is not off, on or auto, using off", CONFIG.demo_mode));
+                "off"
+            }
+        };
+        if demo_mode == "on" {
+            sensor.simulate(Simulator::new(shunt_resistance));
+        }
+        match sensor.init(shunt_temp_coefficient) {
+            Ok(_) => {},
+            Err(e) if demo_mode == "auto" => {
+                info!("No INA228 at 0x{:02X} ({:?}), starting in demo mode", sensor_address, e);
+                sensor.simulate(Simulator::new(shunt_resistance));
+                sensor.init(shunt_temp_coefficient)?;
+            },
+            Err(e) => return Err(e),
+        }
+        let demo = sensor.is_simulated();
+        dp.set_demo(demo);
+        server_info.set_demo(demo);
+        let invert_current = check.flag(36, "invert_current",
+            settings.get("invert_current").as_deref().unwrap_or(CONFIG.invert_current), false);
+        sensor.set_invert_current(invert_current);
+        // External divider on VBUS for buses above 85V, 1.0 without a divider
+        let vbus_divider = check.number(37, "vbus_divider", CONFIG.vbus_divider, 1.0, 1.0, 100.0);
+        sensor.set_vbus_divider(vbus_divider);
+        // AC mode: true RMS over a window of fast conversions
+        let ac_mode = check.flag(18, "ac_mode", CONFIG.ac_mode, false);
+        let ac_window_ms = check.number(19, "ac_window_ms", CONFIG.ac_window_ms, 1000, 100, 10000);
+        if ac_mode {
+            sensor.set_fast_conversion()?;
+        }
+        else {
+            sensor.set_adc_config(profile.adc_config())?;
+        }
+        // Periodic ripple analysis in seconds (0 disables, on demand only)
+        let ripple_interval = check.number(50, "ripple_interval", CONFIG.ripple_interval, 0, 0, 86400);
+
+        // Optional second sensor at another address, logged as current2/voltage2/power2 (not in AC mode)
+        let sensor2_shunt_resistance = check.number(25, "sensor2_shunt_resistance", CONFIG.sensor2_shunt_resistance, 0.005, 0.0001, 10.0);
+        let sensor2 = match check.i2c_address(24, "sensor2_address", CONFIG.sensor2_address, INA228_ADDR, INA228_ADDR_MAX) {
+            Some(address) if address == sensor_address => {
+                check.require(24, "sensor2_address", false, &format!("0x{:02X} is the address of the first sensor", address));
+                None
+            },
+            Some(_) if ac_mode => {
+                info!("Second sensor is not used in AC mode");
+                None
+            },
+            Some(address) => {
+                let mut sensor2 = Ina228::new(sensor_i2c, address, sensor2_shunt_resistance, ADCRANGE, auto_range);
+                if demo {
+                    sensor2.simulate(Simulator::regulator(sensor2_shunt_resistance));
+                }
+                match sensor2.init(shunt_temp_coefficient).and_then(|_| sensor2.set_adc_config(profile.adc_config())) {
+                    Ok(_) => {
+                        info!("Second sensor at 0x{:02X}", address);
+                        Some(sensor2)
+                    },
+                    Err(e) => {
+                        info!("Second sensor at 0x{:02X} not found: {:?}", address, e);
+                        check.require(24, "sensor2_address", false, &format!("no sensor at 0x{:02X}", address));
+                        None
+                    }
+                }
+            },
+            None => None,
+        };
+        // Differential mode: first sensor minus the second, with the efficiency of the second over the first
+        let differential = check.flag(26, "differential", CONFIG.differential, false);
+        check.require(26, "differential", !differential || sensor2.is_some(), "requires the second sensor (not in AC mode)");
+        let differential = differential && sensor2.is_some();
+        server_info.differential = differential;
+        if differential {
+            dp.set_efficiency(None);
+        }
+        dp.set_ac_mode(ac_mode);
+        dp.set_divider(vbus_divider != 1.0);
+        dp.set_adc_range(sensor.is_low_range());
+
+        // Load calibration offsets from NVS
+        let current_offset = settings.load_offset("current_offset");
+        let mut offsets = Offsets {
+            current: current_offset,
+            // A calibration without it applied the offset to both ranges
+            current_hi: settings.get("current_offset_hi").and_then(|v| v.parse::<f32>().ok()).unwrap_or(current_offset),
+            voltage: settings.load_offset("voltage_offset"),
+        };
+
+        // Display loaded calibration info
+        if (offsets.current != 0.0 || offsets.current_hi != 0.0 || offsets.voltage != 0.0) && CALIBRATION_USE {
+            info!("Using stored calibration - Current offset: {:.6}A (163.84mV range {:.6}A), Voltage offset: {:.6}V",
+                  offsets.current, offsets.current_hi, offsets.voltage);
+        } else {
+            info!("No calibration data found - using zero offsets");
+            offsets = Offsets::default();
+        }
+
+        // GPIO9 Button for channel selection (polling method)
+        let button = Button::new(peripherals.pins.gpio9)?;
+
+        // Hard current limit and load cutoff GPIO (driven high when the limit trips)
+        let current_limit = check.number(6, "current_limit",
+            settings.get("current_limit").as_deref().unwrap_or(CONFIG.current_limit), 0.0, 0.0, 100.0);
+        let current_limit_time = check.number(7, "current_limit_time", CONFIG.current_limit_time, 100, 0, 60000);
+        let cutoff_gpio = check.gpio(8, "cutoff_gpio", CONFIG.cutoff_gpio);
+        let trigger_gpio = check.gpio(9, "trigger_gpio", CONFIG.trigger_gpio);
+        let alert_gpio = check.gpio(10, "alert_gpio", CONFIG.alert_gpio);
+        let marker_gpio = check.gpio(20, "marker_gpio", CONFIG.marker_gpio);
+        let buffer_alert_gpio = check.gpio(28, "buffer_alert_gpio", CONFIG.buffer_alert_gpio);
+        let buzzer_gpio = check.gpio(29, "buzzer_gpio", CONFIG.buzzer_gpio);
+        let status_led_gpio = check.gpio(31, "status_led_gpio", CONFIG.status_led_gpio);
+        let protection = Protection::new(current_limit, current_limit_time);
+        let cutoff_pin = match cutoff_gpio {
+            Some(pin) if free_gpio.contains(&pin) => {
+                let mut out = PinDriver::output(unsafe { AnyOutputPin::new(pin) })?;
+                out.set_low()?;
+                info!("Load cutoff output on GPIO{}", pin);
+                Some(out)
+            },
+            Some(pin) => {
+                check.require(8, "cutoff_gpio", false, &format!("GPIO{} cannot be used for load cutoff", pin));
+                None
+            },
+            None => None,
+        };
+        if current_limit > 0.0 {
+            info!("Current limit: {:.4}A for {}ms", current_limit, current_limit_time);
+        }
+
+        // Logging trigger and external trigger input (falling edge)
+        let trigger = Trigger::new(&settings.get("trigger_start").unwrap_or(CONFIG.trigger_start.to_string()),
+            &settings.get("trigger_stop").unwrap_or(CONFIG.trigger_stop.to_string()));
+
+        // Logging windows by local time, applied once the clock is set by SNTP
+        let schedule_text = settings.get("schedule").unwrap_or(CONFIG.schedule.to_string());
+        let log_schedule = match Schedule::parse(&schedule_text) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                check.require(41, "schedule", false, &format!("{}, disabled", e));
+                Schedule::parse("").unwrap()
+            }
+        };
+        let timezone = settings.get("timezone").unwrap_or(CONFIG.timezone.to_string());
+        check.require(42, "timezone", schedule::valid_timezone(&timezone), &format!("'{}' is invalid, using UTC", timezone));
+        schedule::set_timezone(if schedule::valid_timezone(&timezone) { &timezone } else { "" });
+        let trigger_pin = match trigger_gpio {
+            Some(pin) if free_gpio.contains(&pin) && cutoff_gpio != Some(pin) => {
+                let mut input = PinDriver::input(unsafe { AnyInputPin::new(pin) })?;
+                input.set_pull(Pull::Up)?;
+                info!("Trigger input on GPIO{}", pin);
+                Some(input)
+            },
+            Some(pin) => {
+                check.require(9, "trigger_gpio", false, &format!("GPIO{} cannot be used for trigger input", pin));
+                None
+            },
+            None => None,
+        };
+
+        // INA228 ALERT pin as conversion-ready signal (open drain, active low)
+        let alert_pin = match alert_gpio {
+            Some(_) if demo => {
+                info!("Conversion-ready alert is not used in demo mode");
+                None
+            },
+            Some(pin) if free_gpio.contains(&pin) && cutoff_gpio != Some(pin) && trigger_gpio != Some(pin) => {
+                let mut input = PinDriver::input(unsafe { AnyInputPin::new(pin) })?;
+                input.set_pull(Pull::Up)?;
+                sensor.enable_conversion_ready()?;
+                sensor.conversion_ready()?;
+                info!("Conversion-ready alert on GPIO{}", pin);
+                Some(input)
+            },
+            Some(pin) => {
+                check.require(10, "alert_gpio", false, &format!("GPIO{} cannot be used for the alert input", pin));
+                None
+            },
+            None => None,
+        };
+
+        // External sync marker input (falling edge)
+        let marker = match marker_gpio {
+            Some(pin) if free_gpio.contains(&pin)
+                && cutoff_gpio != Some(pin) && trigger_gpio != Some(pin) && alert_gpio != Some(pin) => {
+                match MarkerInput::new(pin) {
+                    Ok(input) => Some(input),
+                    Err(e) => {
+                        info!("Marker input failed: {:?}", e);
+                        None
+                    }
+                }
+            },
+            Some(pin) => {
+                check.require(20, "marker_gpio", false, &format!("GPIO{} cannot be used for the marker input", pin));
+                None
+            },
+            None => None,
+        };
+
+        // Buffer fill alerts and their output (driven high while a level is passed, e.g. a beeper)
+        let buffer_alert_levels = match bufferalert::parse_levels(CONFIG.buffer_alert_levels) {
+            Some(levels) => levels,
+            None => {
+                check.require(27, "buffer_alert_levels", false, &format!("'{}' is invalid, alerts disabled", CONFIG.buffer_alert_levels));
+                Vec::new()
+            }
+        };
+        let buffer_alert_pin = match buffer_alert_gpio {
+            Some(pin) if free_gpio.contains(&pin) && cutoff_gpio != Some(pin) && trigger_gpio != Some(pin)
+                && alert_gpio != Some(pin) && marker_gpio != Some(pin) => {
+                let mut out = PinDriver::output(unsafe { AnyOutputPin::new(pin) })?;
+                out.set_low()?;
+                info!("Buffer alert output on GPIO{}", pin);
+                Some(out)
+            },
+            Some(pin) => {
+                check.require(28, "buffer_alert_gpio", false, &format!("GPIO{} cannot be used for the buffer alert output", pin));
+                None
+            },
+            None => None,
+        };
+        let buffer_alert = BufferAlert::new(buffer_alert_levels, buffer_alert_pin);
+
+        // Piezo buzzer for button clicks and alarms
+        let buzzer_pin = match buzzer_gpio {
+            Some(pin) if free_gpio.contains(&pin) && cutoff_gpio != Some(pin) && trigger_gpio != Some(pin)
+                && alert_gpio != Some(pin) && marker_gpio != Some(pin) && buffer_alert_gpio != Some(pin) => {
+                info!("Buzzer on GPIO{}", pin);
+                Some(unsafe { AnyOutputPin::new(pin) })
+            },
+            Some(pin) => {
+                check.require(29, "buzzer_gpio", false, &format!("GPIO{} cannot be used for the buzzer", pin));
+                None
+            },
+            None => None,
+        };
+        let sound_enabled = check.flag(30, "sound_enabled",
+            settings.get("sound_enabled").as_deref().unwrap_or(CONFIG.sound_enabled), true);
+        let buzzer = Buzzer::start(peripherals.ledc.timer0, peripherals.ledc.channel0, buzzer_pin, sound_enabled)?;
+
+        // Status LED (WS2812 or a simple LED) showing the device state
+        let mut led = StatusLed::new();
+        let led_type = match LedType::from_str(CONFIG.status_led_type) {
+            Some(led_type) => led_type,
+            None => {
+                check.require(32, "status_led_type", false, &format!("'{}' is invalid, using ws2812", CONFIG.status_led_type));
+                LedType::Ws2812
+            }
+        };
+        match status_led_gpio {
+            Some(pin) if free_gpio.contains(&pin) && cutoff_gpio != Some(pin) && trigger_gpio != Some(pin)
+                && alert_gpio != Some(pin) && marker_gpio != Some(pin) && buffer_alert_gpio != Some(pin) && buzzer_gpio != Some(pin) => {
+                info!("Status LED ({:?}) on GPIO{}", led_type, pin);
+                led.start(peripherals.rmt.channel0, unsafe { AnyOutputPin::new(pin) }, led_type)?;
+            },
+            Some(pin) => {
+                check.require(31, "status_led_gpio", false, &format!("GPIO{} cannot be used for the status LED", pin));
+            },
+            None => {},
+        }
+
+        // GPIO outputs following the measurements, on the GPIOs left free by the other functions
+        let output_gpio: Vec<i32> = free_gpio.iter().copied()
+            .filter(|pin| ![cutoff_gpio, trigger_gpio, alert_gpio, marker_gpio, buffer_alert_gpio, buzzer_gpio, status_led_gpio].contains(&Some(*pin)))
+            .collect();
+        let output_rules = settings.get("output_rules").unwrap_or(CONFIG.output_rules.to_string());
+        let mut outputs = OutputRules::new();
+        match outputrule::parse_rules(&output_rules) {
+            Ok(specs) => {
+                if let Err(e) = outputs.set(specs, &output_gpio) {
+                    check.require(68, "output_rules", false, &format!("{}, left out", e));
+                }
+            },
+            Err(e) => check.require(68, "output_rules", false, &format!("{}, disabled", e)),
+        }
+        #[cfg(feature = "wifi")]
+        let data_api_port = check.number(33, "data_api_port", CONFIG.data_api_port, 80u16, 0, 65535);
+        #[cfg(feature = "wifi")]
+        let scpi_port = check.number(69, "scpi_port", CONFIG.scpi_port, 5025u16, 0, 65535);
+        #[cfg(feature = "display")]
+        let big_digits_value = match BigValue::from_str(CONFIG.big_digits_value) {
+            Some(value) => value,
+            None => {
+                check.require(34, "big_digits_value", false, &format!("'{}' is invalid, using current", CONFIG.big_digits_value));
+                BigValue::Current
+            }
+        };
+        #[cfg(feature = "display")]
+        let network_page = cfg!(feature = "influx") && check.flag(35, "network_page", CONFIG.network_page, true);
+        #[cfg(feature = "influx")]
+        let upload_backlog = check.flag(63, "upload_backlog", CONFIG.upload_backlog, true);
+        // Access point for the data download after ap_fallback minutes without WiFi (0: off)
+        #[cfg(feature = "wifi")]
+        let mut ap_fallback = check.number(64, "ap_fallback", CONFIG.ap_fallback, 0u64, 0, 1440);
+        #[cfg(feature = "wifi")]
+        if ap_fallback > 0 && data_api_port == 0 {
+            check.require(64, "ap_fallback", false, "needs the data API but data_api_port is 0, access point disabled");
+            ap_fallback = 0;
+        }
+        #[cfg(feature = "wifi")]
+        let ap_psk = CONFIG.ap_psk;
+        #[cfg(feature = "wifi")]
+        if !ap_psk.is_empty() && (ap_psk.len() < 8 || ap_psk.len() > 63) {
+            check.require(65, "ap_psk", false, "must be 8 to 63 characters, access point disabled");
+            ap_fallback = 0;
+        }
+
+        // Temperature Logs
+        let mut clogs = CurrentRecord::new();
+        clogs.set_capacity(max_records, profile.heap_percent().unwrap_or(buffer_heap_percent));
+        let retention_secs = check.number(11, "retention_secs", CONFIG.retention_secs, 0, 0, 2592000);
+        if retention_secs > 0 {
+            clogs.set_retention(retention_secs);
+            info!("Record retention: {}s", retention_secs);
+        }
+        // Records flushed before the last power loss are uploaded first
+        if let Some(ref mut monitor) = power_monitor {
+            let recovered = monitor.recover();
+            if !recovered.is_empty() {
+                info!("Recovered {} records from the last power loss", recovered.len());
+                dp.set_err_message(tr_args(Msg::RecoveredRecords, &[&recovered.len().to_string()]));
+                clogs.restore(recovered);
+                thread::sleep(Duration::from_millis(POWER_LOSS_DISPLAY_MS));
+                dp.set_err_message(version::boot_text());
+            }
+        }
+
+        // Smoothing of the displayed values, logged samples stay raw
+        #[cfg(feature = "display")]
+        let filter_mode = match FilterMode::from_str(CONFIG.display_filter) {
+            Some(mode) => mode,
+            None => {
+                check.require(14, "display_filter", false, &format!("'{}' is invalid, filtering disabled", CONFIG.display_filter));
+                FilterMode::Off
+            }
+        };
+        #[cfg(feature = "display")]
+        let voltage_filter = Filter::new(filter_mode);
+        #[cfg(feature = "display")]
+        let current_filter = Filter::new(filter_mode);
+        #[cfg(feature = "display")]
+        let power_filter = Filter::new(filter_mode);
+        #[cfg(feature = "display")]
+        dp.set_filtered(voltage_filter.is_active());
+
+        // Coulomb counter mode for a battery under test (disabled if the capacity is 0)
+        let coulomb_initial_soc = check.number(13, "coulomb_initial_soc", CONFIG.coulomb_initial_soc, 100.0, 0.0, 100.0);
+        let coulomb = match check.number(12, "coulomb_capacity", CONFIG.coulomb_capacity, 0.0, 0.0, 1000000.0) {
+            capacity if capacity > 0.0 => Some(CoulombCounter::new(capacity, coulomb_initial_soc)),
+            _ => None,
+        };
+
+        // Pulse statistics of a switched load (disabled if the threshold is 0)
+        let pulse_interval = check.number(52, "pulse_interval", CONFIG.pulse_interval, 60, 1, 86400);
+        let pulse = match check.number(51, "pulse_threshold", CONFIG.pulse_threshold, 0.0, 0.0, 100.0) {
+            threshold if threshold > 0.0 => Some(PulseDetector::new(threshold, pulse_interval)),
+            _ => None,
+        };
+
+        // IoT profile: sleep, idle and active bands of a device under test (disabled without bands)
+        let iot_battery_mah = check.number(55, "iot_battery_mah", CONFIG.iot_battery_mah, 0.0, 0.0, 1000000.0);
+        let iot_interval = check.number(56, "iot_interval", CONFIG.iot_interval, 300, 10, 86400);
+        let iot = match iotprofile::parse_bands(CONFIG.iot_bands) {
+            Some(Some((sleep_max, idle_max))) => Some(IotProfiler::new(sleep_max, idle_max, iot_battery_mah, iot_interval)),
+            Some(None) => None,
+            None => {
+                check.require(54, "iot_bands", false, &format!("'{}' is not two ascending currents in A, profiling disabled", CONFIG.iot_bands));
+                None
+            }
+        };
+
+        let stream_mode = match StreamMode::from_str(CONFIG.stream_mode) {
+            Some(mode) => mode,
+            None => {
+                check.require(16, "stream_mode", false, &format!("'{}' is invalid, streaming disabled", CONFIG.stream_mode));
+                StreamMode::Off
+            }
+        };
+
+        // Show the configuration errors before starting with the safe defaults
+        if check.has_errors() {
+            dp.set_err_message(tr_args(Msg::ConfigErrors, &[&check.summary()]));
+            thread::sleep(Duration::from_millis(CONFIG_ERROR_DISPLAY_MS));
+            dp.set_err_message(version::boot_text());
+        }
+
+        // WiFi
+        // The connection and the NTP sync come up in the background while sampling already runs
+        #[cfg(feature = "wifi")]
+        let mut wifi_device: Option<WifiSupervisor> = None;
+        #[cfg(feature = "wifi")]
+        let ap_ssid = settings.load_device_name();
+        #[cfg(feature = "wifi")]
+        {
+            // Credentials changed at runtime are stored in NVS and take precedence over cfg.toml
+            let (wifi_ssid, wifi_psk) = settings.load_wifi_credentials()
+                .unwrap_or((CONFIG.wifi_ssid.to_string(), CONFIG.wifi_psk.to_string()));
+            // WPA2-Enterprise when a username is set and there is no pre-shared key
+            let eap = EapCredentials {
+                identity: settings.get("wifi_eap_identity").unwrap_or(CONFIG.wifi_eap_identity.to_string()),
+                username: settings.get("wifi_eap_username").unwrap_or(CONFIG.wifi_eap_username.to_string()),
+                password: settings.get("wifi_eap_password").unwrap_or(CONFIG.wifi_eap_password.to_string()),
+                ca_cert: CONFIG.wifi_eap_ca_cert,
+            };
+            let eap = if !eap.username.is_empty() && wifi_psk.is_empty() { Some(eap) } else { None };
+            dp.set_wifi_status(WifiStatus::Connecting);
+            let sys_event_loop = EspSystemEventLoop::take()?;
+            match wifi::wifi_start(peripherals.modem, &sys_event_loop, &wifi_ssid, &wifi_psk, eap.as_ref()) {
+                Ok(wifi) => { 
+                    let supervisor = WifiSupervisor::new(wifi, sys_event_loop);
+                    if ap_fallback > 0 {
+                        supervisor.set_ap_fallback(ApFallback { after: Duration::from_secs(ap_fallback * 60), ssid: ap_ssid.clone(), psk: ap_psk.to_string() });
+                    }
+                    wifi_device = Some(supervisor);
+                },
+                Err(ref e) => { 
+                    info!("{:?}", e); 
+                }
+            }
+        }
+
+        // NTP Server
+        #[cfg(feature = "wifi")]
+        let ntp = {
+            let sntp_conf = SntpConf {
+                servers: ["time.aws.com",
+                            "time.google.com",
+                            "time.cloudflare.com",
+                            "ntp.nict.jp"],
+                operating_mode: OperatingMode::Poll,
+                sync_mode: SyncMode::Immediate,
+            };
+            // Every sync re-anchors the sample timestamps
+            let ntp = EspSntp::new_with_callback(&sntp_conf, |synced| timebase::sync(synced)).unwrap();
+            info!("NTP Sync Start..");
+            ntp
+        };
+        dp.set_err_message("".to_string());
+
+        let mut txd =  Transfer::new(server_info);
+        #[cfg(feature = "influx")]
+        {
+            let mut transfer_dp = dp.clone();
+            let mut transfer_led = led.clone();
+            txd.set_status_callback(move |status| {
+                transfer_dp.set_transfer_status(status);
+                transfer_led.set_transfer_status(status);
+            });
+        }
+        let (min_batch, min_interval) = profile.upload_pacing();
+        txd.set_min_pacing(min_batch, min_interval);
+        // Batches the server does not take are kept in flash and replayed
+        #[cfg(feature = "influx")]
+        if upload_backlog {
+            match Backlog::new() {
+                Ok(backlog) => txd.set_backlog(backlog),
+                Err(e) => info!("Upload backlog is not available: {:?}", e),
+            }
+        }
+        // The upload task applies the server and tag settings itself
+        #[cfg(feature = "influx")]
+        txd.set_settings_watch(settings.watch(&transfer::TASK_SETTINGS));
+        // The WiFi supervisor and the uploads share the network thread
+        #[cfg(feature = "influx")]
+        let transfer = Some(txd.start());
+        #[cfg(all(feature = "wifi", not(feature = "influx")))]
+        let transfer = None;
+        #[cfg(feature = "wifi")]
+        network::start(wifi_device.clone(), transfer)?;
+
+        // MQTT command channel (disabled if mqtt_url is empty)
+        #[cfg(feature = "wifi")]
+        let mqtt_ctl = match CONFIG.mqtt_url {
+            "" => None,
+            url => match MqttControl::new(url, "mini-current-meter", CONFIG.mqtt_command_topic, CONFIG.mqtt_status_topic) {
+                Ok(mut ctl) => {
+                    info!("MQTT command channel started: {}", CONFIG.mqtt_command_topic);
+                    if CONFIG.ha_discovery == "true" {
+                        ctl.enable_discovery(CONFIG.ha_discovery_prefix);
+                    }
+                    Some(ctl)
+                },
+                Err(e) => {
+                    info!("MQTT start failed: {:?}", e);
+                    None
+                }
+            },
+        };
+
+        // HTTP download of the buffered records (disabled if data_api_port is 0)
+        #[cfg(feature = "wifi")]
+        let data_api = match data_api_port {
+            0 => None,
+            port => match DataApi::start(port) {
+                Ok(api) => Some(api),
+                Err(e) => {
+                    info!("Data API start failed: {:?}", e);
+                    None
+                }
+            },
+        };
+
+        // SCPI commands over TCP for lab automation (disabled if scpi_port is 0)
+        #[cfg(feature = "wifi")]
+        let scpi = match scpi_port {
+            0 => None,
+            port => match Scpi::start(port) {
+                Ok(scpi) => Some(scpi),
+                Err(e) => {
+                    info!("SCPI server start failed: {:?}", e);
+                    None
+                }
+            },
+        };
+
+        // Serial console command shell
+        let mut console = Console::new();
+        console.start();
+
+        // USB serial streaming (off, csv or scpi)
+        // Real-time WebSocket push
+        #[cfg(feature = "wifi")]
+        let ws_push = match CONFIG.ws_url {
+            "" => None,
+            url => Some(WsPush::new(url)),
+        };
+        let streamer = Streamer::new(stream_mode);
+
+        // ADC GPIO0
+        let adc = AdcDriver::new(peripherals.adc1)?;
+        let adc_config = AdcChannelConfig {
+            attenuation: DB_11,
+            calibration: Calibration::Curve, // Use curve calibration for better accuracy
+            ..Default::default()
+        };
+        let adc_pin = AdcChannelDriver::new(adc, peripherals.pins.gpio3, &adc_config)?;
+        // Battery voltage = ADC mV x divider ratio x one-point calibration scale
+        let battery_divider = check.number(38, "battery_divider",
+            settings.get("battery_divider").as_deref().unwrap_or(CONFIG.battery_divider), 2.0, 1.0, 20.0);
+        let battery_scale = settings.get("battery_scale").and_then(|v| v.parse::<f32>().ok()).unwrap_or(1.0);
+        if battery_scale != 1.0 {
+            info!("Battery calibration scale {:.4}", battery_scale);
+        }
+
+        let mut logging_start = !trigger.is_enabled();  // wait for the trigger if configured
+        if let Some(ref report) = crash_report {
+            if CONFIG.crash_resume == "true" {
+                logging_start = report.logging;
+                info!("Resuming session after crash: logging={}", logging_start);
+            }
+        }
+        #[cfg(feature = "wifi")]
+        if WIFI_DELAY_START > 0 {
+            wifi_device.as_mut().map(|wifi| {
+                wifi.set_paused(true);
+                wifi::stop_wifi(&mut wifi.lock()).unwrap();
+            });
+        }
+        // Sensor sampling runs in its own thread from here
+        let sampler = Sampler::start(sensor, sensor2, protection, cutoff_pin, outputs, alert_pin,
+            offsets, profile.sampling_period_ms(), if ac_mode { ac_window_ms } else { 0 });
+        let mut energy = EnergyIntegrator::new();
+        let (energy_total, charge_total) = settings.load_totals();
+        energy.restore_totals(energy_total, charge_total);
+        info!("Energy total: {:.6}Wh {:.6}Ah", energy_total, charge_total);
+
+        let mut meter = Meter {
+            dp: dp,
+            buzzer: buzzer,
+            led: led,
+            journal: journal,
+            settings: settings,
+            sampler: sampler,
+            clogs: clogs,
+            txd: txd,
+            health: health,
+            heartbeat: heartbeat,
+            summary: summary,
+            button: button,
+            sensor_watch: sensor_watch,
+            channel_watch: channel_watch,
+            transfer_watch: transfer_watch,
+            #[cfg(feature = "wifi")]
+            wifi_watch: wifi_watch,
+            meter_watch: meter_watch,
+            #[cfg(feature = "wifi")]
+            wifi_device: wifi_device,
+            #[cfg(feature = "wifi")]
+            ntp: ntp,
+            #[cfg(feature = "wifi")]
+            mqtt_ctl: mqtt_ctl,
+            #[cfg(feature = "wifi")]
+            data_api: data_api,
+            #[cfg(feature = "wifi")]
+            scpi: scpi,
+            console: console,
+            #[cfg(feature = "wifi")]
+            ws_push: ws_push,
+            streamer: streamer,
+            power_monitor: power_monitor,
+            adc_pin: adc_pin,
+            trigger_pin: trigger_pin,
+            marker: marker,
+            buffer_alert: buffer_alert,
+            trigger: trigger,
+            log_schedule: log_schedule,
+            coulomb: coulomb,
+            pulse: pulse,
+            iot: iot,
+            peak: PeakHold::new(),
+            energy: energy,
+            #[cfg(feature = "display")]
+            voltage_filter: voltage_filter,
+            #[cfg(feature = "display")]
+            current_filter: current_filter,
+            #[cfg(feature = "display")]
+            power_filter: power_filter,
+            #[cfg(feature = "display")]
+            display_decimator: Decimator::new(Duration::from_millis(DISPLAY_PERIOD_MS)),
+            max_records: max_records,
+            buffer_heap_percent: buffer_heap_percent,
+            retention_secs: retention_secs,
+            ripple_interval: ripple_interval,
+            battery_divider: battery_divider,
+            coulomb_initial_soc: coulomb_initial_soc,
+            #[cfg(feature = "display")]
+            big_digits_value: big_digits_value,
+            ac_mode: ac_mode,
+            differential: differential,
+            #[cfg(feature = "display")]
+            network_page: network_page,
+            output_gpio: output_gpio,
+            channel: channel,
+            channel_names: channel_names,
+            tag: String::new(),
+            profile: profile,
+            scheduled_profile: None,
+            offsets: offsets,
+            pending_calibration: None,
+            battery_scale: battery_scale,
+            battery_mv: 0.0,
+            data: CurrentLog::default(),
+            crash_report: crash_report,
+            logging_start: logging_start,
+            logging_stopped_by_buffer_full: false,
+            wifi_enable: false,
+            #[cfg(feature = "wifi")]
+            ipv6_enabled: false,
+            #[cfg(feature = "wifi")]
+            access_point: false,
+            #[cfg(feature = "wifi")]
+            ap_ssid: ap_ssid,
+            time_synced: false,
+            rtc: rtc.map(RtcSync::start),
+            rtc_sync_count: 0,
+            #[cfg(feature = "influx")]
+            server_checked: false,
+            #[cfg(feature = "display")]
+            big_digits: false,
+            shutdown_requested: false,
+            was_tripped: false,
+            was_full: false,
+            last_trigger_level: true,
+            reversed_since: None,
+            reversed_hint_shown: false,
+            last_ripple: Instant::now(),
+            pending_ripple: None,
+            capture: None,
+            capture_summary: None,
+            capture_menu: None,
+            energy_checkpoint: Instant::now(),
+            message_clear_time: 0,
+            start_time: Instant::now(),
+        };
+        // Initialize with loaded channel tag and name, also set on display
+        meter.apply_channel();
+        info!("Using channel {} (tag: {})", meter.channel, meter.tag);
+        Ok(meter)
+    }
+
+    // One pass of the main loop, called every LOOP_PERIOD_MS
+    pub fn tick(&mut self)
+    {
+        self.update_connection();
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+
+        #[cfg(feature = "wifi")]
+        self.update_access_point(now);
+        // Clear message after timeout
+        if self.message_clear_time > 0 && now >= self.message_clear_time {
+            self.dp.set_err_message("".to_string());
+            self.message_clear_time = 0;
+            self.capture_menu = None;
+        }
+        // Stop the capture at the end of its duration, or when the logging was stopped otherwise
+        let remaining = self.capture.as_ref().map(|c| (c.is_done() || !self.logging_start, c.remaining().as_secs()));
+        match remaining {
+            Some((true, _)) => self.end_capture(now),
+            Some((false, secs)) => self.dp.set_capture(Some(secs)),
+            None => {},
+        }
+        // Discard an unconfirmed calibration
+        if let Some((_, deadline)) = self.pending_calibration {
+            if now >= deadline {
+                self.pending_calibration = None;
+                info!("Calibration not confirmed, keeping the previous offsets");
+                self.show_message(tr(Msg::CalibrationCancelled).to_string(), now, 2000);
+            }
+        }
+
+        if let Some(event) = self.button.poll(now) {
+            self.handle_button(event, now);
+        }
+        #[cfg(feature = "wifi")]
+        self.poll_mqtt(now);
+        self.update_logging_state();
+        let tripped = self.update_readings();
+        #[cfg(feature = "wifi")]
+        self.serve_data_api(now);
+        let commands = self.console.poll();
+        for cmd in commands {
+            self.handle_console(cmd, now);
+        }
+        self.apply_settings();
+        self.update_buffer(now, tripped);
+        self.upload(now);
+        if self.shutdown_requested {
+            self.shutdown();
+        }
+    }
+
+    // WiFi state and the NTP sync
+    fn update_connection(&mut self)
+    {
+        #[cfg(feature = "wifi")]
+        self.update_wifi();
+
+        // Records taken before the NTP sync are back-filled onto the synchronized time base
+        if !self.time_synced {
+            #[cfg(feature = "wifi")]
+            let completed = self.ntp.get_sync_status() == SyncStatus::Completed;
+            #[cfg(not(feature = "wifi"))]
+            let completed = false;
+            let from_rtc = !completed && timebase::is_valid();
+            if completed || from_rtc || self.start_time.elapsed() >= Duration::from_millis(NTP_SYNC_TIMEOUT_MS) {
+                let now = SystemTime::now();
+                let dt_now : DateTime<Utc> = now.into();
+                info!("NTP Sync {}: {}", if completed { "Completed" } else if from_rtc { "pending, using the RTC" } else { "Timeout" }, dt_now.format("%Y-%m-%d %H:%M:%S"));
+                self.clogs.check_clock(timebase::now_ns(), Instant::now());
+                self.time_synced = true;
+            }
+        }
+
+        // Set the RTC after each NTP sync, which also measures how far it had drifted
+        if let Some(ref rtc) = self.rtc {
+            let sync_count = timebase::sync_count();
+            if sync_count != self.rtc_sync_count {
+                self.rtc_sync_count = sync_count;
+                rtc.request();
+            }
+            self.health.set_rtc_drift(rtc.last_drift());
+        }
+    }
+
+    // Station state, reconnecting is left to the WiFi supervisor task
+    #[cfg(feature = "wifi")]
+    fn update_wifi(&mut self)
+    {
+        if self.start_time.elapsed().as_secs() < WIFI_DELAY_START {
+            self.wifi_enable = true;
+        }
+        else {
+            if let Some(ref wifi) = self.wifi_device {
+                wifi.set_paused(false);
+            }
+            // Get RSSI, reconnecting is left to the WiFi supervisor task
+            let rssi = wifi::get_rssi();
+            self.dp.set_wifi_rssi(rssi);
+            if rssi == 0 {
+                self.ipv6_enabled = false;
+                if self.wifi_enable {
+                    info!("WiFi connection lost");
+                    self.buzzer.play(Pattern::WifiLost);
+                    self.journal.record(EventKind::WifiDown, "");
+                }
+                self.wifi_enable = false;
+                self.dp.set_wifi_status(if self.wifi_device.is_some() { WifiStatus::Connecting } else { WifiStatus::Disconnected });
+            }
+            else {
+                self.dp.set_wifi_status(WifiStatus::Connected);
+                if !self.wifi_enable {
+                    self.journal.record(EventKind::WifiUp, &format!("rssi={}", rssi));
+                }
+                self.wifi_enable = true;
+                if !self.ipv6_enabled {
+                    if let Some(ref wifi) = self.wifi_device {
+                        wifi::enable_ipv6(&wifi.lock());
+                    }
+                    self.ipv6_enabled = true;
+                }
+            }
+        }
+    }
+
+    // The fallback access point replaces the station until the network is tried again
+    #[cfg(feature = "wifi")]
+    fn update_access_point(&mut self, now: u64)
+    {
+        let access_point = self.wifi_device.as_ref().map_or(false, |wifi| wifi.stats().access_point);
+        if access_point == self.access_point {
+            return;
+        }
+        self.access_point = access_point;
+        if access_point {
+            let ip = wifi::get_ap_ip().map_or(String::new(), |ip| ip.to_string());
+            self.show_message(tr_args(Msg::AccessPoint, &[&self.ap_ssid, &ip]), now, AP_MESSAGE_MS);
+        }
+    }
+
+    fn handle_button(&mut self, event: ButtonEvent, now: u64)
+    {
+        match event {
+            ButtonEvent::Pressed => {
+                let wake = !self.dp.wake();
+                if wake {
+                    self.button.ignore_press();
+                }
+                info!("Button press detected{}", if wake { ", display on" } else { "" });
+                self.buzzer.play(Pattern::Click);
+            },
+            ButtonEvent::LongPress => {
+                // Long press (2+ seconds) - calibration
+                info!("Long press detected - starting calibration...");
+                let _ = self.start_calibration(now);
+            },
+            ButtonEvent::Hold if self.capture_menu.is_some() => {
+                // Hold in the capture menu - start the capture shown or end the running one
+                let secs = capture::MENU_DURATIONS[self.capture_menu.take().unwrap_or(0)];
+                if secs > 0 {
+                    self.start_capture(secs, now);
+                }
+                else if self.capture.is_some() {
+                    self.end_capture(now);
+                }
+                else {
+                    self.dp.set_err_message("".to_string());
+                    self.message_clear_time = 0;
+                }
+            },
+            ButtonEvent::Presses(_) if self.capture_menu.is_some() => {
+                // Press in the capture menu - next duration
+                let index = (self.capture_menu.unwrap_or(0) + 1) % capture::MENU_DURATIONS.len();
+                self.show_capture_menu(index, now);
+            },
+            #[cfg(feature = "display")]
+            ButtonEvent::Hold => {
+                // Hold for 1 second - toggle the big digits page
+                self.big_digits = !self.big_digits;
+                info!("Big digits page {}", if self.big_digits { "on" } else { "off" });
+                self.dp.set_big_digits(if self.big_digits { Some(self.big_digits_value) } else { None });
+            },
+            #[cfg(not(feature = "display"))]
+            ButtonEvent::Hold => {},
+            ButtonEvent::Presses(presses) if presses >= 6 => {
+                // Six presses - capture menu, starting at the stop entry while a capture runs
+                self.show_capture_menu(if self.capture.is_some() { 0 } else { 1 }, now);
+            },
+            ButtonEvent::Presses(5) => {
+                // Five presses - graceful shutdown
+                self.shutdown_requested = true;
+            },
+            ButtonEvent::Presses(4) => {
+                // Four presses - next logging profile
+                self.profile = self.profile.next();
+                self.apply_profile(self.profile);
+                if let Err(e) = self.settings.set("profile", self.profile.name()) {
+                    info!("{}", e);
+                }
+                self.show_message(tr_args(Msg::ProfileName, &[self.profile.name()]), now, 2000);
+            },
+            ButtonEvent::Presses(3) => {
+                // Triple press - WiFi scan page
+                self.dp.set_err_message(tr(Msg::ScanningWifi).to_string());
+                #[cfg(feature = "wifi")]
+                let scan = match self.wifi_device {
+                    Some(ref wifi) => Some(wifi::scan(&mut wifi.lock())),
+                    None => None,
+                };
+                #[cfg(not(feature = "wifi"))]
+                let scan: Option<anyhow::Result<Vec<(String, i8)>>> = None;
+                match scan {
+                    Some(Ok(aps)) => {
+                        info!("WiFi scan found {} networks", aps.len());
+                        self.dp.set_err_message("".to_string());
+                        self.dp.set_scan_results(aps);
+                    },
+                    Some(Err(e)) => {
+                        info!("{:?}", e);
+                        self.show_message(tr(Msg::WifiScanFailed).to_string(), now, 2000);
+                    },
+                    None => {
+                        self.show_message(tr(Msg::WifiNotAvailable).to_string(), now, 2000);
+                    }
+                }
+            },
+            ButtonEvent::Presses(2) => {
+                // Double press - clear peak-hold
+                self.peak.clear();
+                info!("Peak-hold cleared");
+                self.show_message(tr(Msg::PeakCleared).to_string(), now, 1000);
+            },
+            ButtonEvent::Presses(_) => {
+                if self.pending_calibration.is_some() {
+                    // Short press after calibration - confirm and save the offsets
+                    self.confirm_calibration(now);
+                }
+                else if self.dp.clear_scan_results() || self.dp.clear_diagnostics() || self.dp.clear_ripple() {
+                    // Short press on the WiFi scan, diagnostics or ripple page - back to the meter
+                }
+                else if self.sampler.is_tripped() {
+                    // Short press while tripped - re-enable the load
+                    self.sampler.reset_protection();
+                    self.dp.set_tripped(false);
+                }
+                else if self.dp.acknowledge_alarm() {
+                    // Short press during a buffer alert - end the attention mode of the display
+                }
+                else {
+                    // Short press - change channel
+                    self.channel += 1;
+                    if self.channel > 4 {
+                        self.channel = 1;
+                    }
+                    self.apply_channel();
+                    info!("Channel changed to {}", self.tag);
+
+                    // Save current channel to NVS
+                    self.settings.save_channel(self.channel);
+                }
+            },
+        }
+    }
+
+    // Capture menu with the entry of MENU_DURATIONS, closed with the message
+    fn show_capture_menu(&mut self, index: usize, now: u64)
+    {
+        let secs = capture::MENU_DURATIONS[index];
+        let entry = if secs > 0 { capture::format_duration(secs) } else { tr(Msg::CaptureStop).to_string() };
+        self.capture_menu = Some(index);
+        self.show_message(tr_args(Msg::CaptureMenu, &[&entry]), now, CAPTURE_MENU_MS);
+    }
+
+    // Log for secs seconds from now, a running capture is replaced
+    fn start_capture(&mut self, secs: u64, now: u64)
+    {
+        info!("Capture of {}s started", secs);
+        self.logging_start = true;
+        self.logging_stopped_by_buffer_full = false;
+        self.peak.clear();
+        self.capture = Some(Capture::new(secs, self.clogs.last_seq(), self.clogs.get_dropped(), self.energy.energy_wh()));
+        self.dp.set_capture(Some(secs));
+        self.show_message(tr_args(Msg::CaptureStarted, &[&capture::format_duration(secs)]), now, 2000);
+    }
+
+    // Stop the logging of the capture, its summary is sent once its records are uploaded
+    fn end_capture(&mut self, now: u64)
+    {
+        let capture = match self.capture.take() {
+            Some(capture) => capture,
+            None => return,
+        };
+        self.logging_start = false;
+        let summary = capture.summary(self.clogs.last_seq(), self.clogs.get_dropped(), self.energy.energy_wh(), self.peak.current, self.peak.power);
+        info!("Capture done: {}s, {} records, {} dropped, {:.3}Wh", summary.uptime, summary.records, summary.dropped, summary.energy);
+        self.save_totals();
+        self.dp.set_capture(None);
+        self.show_message(tr_args(Msg::CaptureDone, &[&summary.records.to_string()]), now, 5000);
+        #[cfg(feature = "influx")]
+        {
+            self.capture_summary = Some(summary);
+        }
+    }
+
+    // Message on the display, cleared after duration_ms
+    fn show_message(&mut self, message: String, now: u64, duration_ms: u64)
+    {
+        self.dp.set_err_message(message);
+        self.message_clear_time = now + duration_ms;
+    }
+
+    // Measure the offsets, they are applied and saved only after confirmation
+    fn start_calibration(&mut self, now: u64) -> anyhow::Result<Offsets> {
+        self.dp.set_err_message(tr(Msg::Calibrating).to_string());
+        let result = calibration(&mut self.sampler.sensor());
+        match result {
+            Ok(offsets) => {
+                info!("Calibration completed - Current offset: {:.6}A (163.84mV range {:.6}A), Voltage offset: {:.6}V",
+                        offsets.current, offsets.current_hi, offsets.voltage);
+                self.buzzer.play(Pattern::CalibrationDone);
+                self.pending_calibration = Some((offsets, now + CALIBRATION_CONFIRM_MS));
+                self.show_message(tr(Msg::PressToConfirm).to_string(), now, CALIBRATION_CONFIRM_MS);
+                Ok(offsets)
+            },
+            Err(e) => {
+                info!("Calibration failed: {:?}", e);
+                self.show_message(tr(Msg::CalibrationFailed).to_string(), now, 2000);
+                Err(e)
+            }
+        }
+    }
+
+    // Apply and save the offsets waiting for confirmation, None if there are none
+    fn confirm_calibration(&mut self, now: u64) -> Option<Offsets> {
+        let (offsets, _) = self.pending_calibration.take()?;
+        self.set_offsets(offsets);
+        self.settings.save_calibration(offsets);
+        self.journal.record(EventKind::Calibration, &format!("current={:.6} current_hi={:.6} voltage={:.6}", offsets.current, offsets.current_hi, offsets.voltage));
+        self.show_message(tr(Msg::CalibrationOk).to_string(), now, 2000);
+        Some(offsets)
+    }
+
+    // Back to the offsets before the last calibration
+    fn undo_calibration(&mut self) -> Option<Offsets> {
+        let offsets = self.settings.undo_calibration()?;
+        self.set_offsets(offsets);
+        self.journal.record(EventKind::Calibration, &format!("undo current={:.6} current_hi={:.6} voltage={:.6}", offsets.current, offsets.current_hi, offsets.voltage));
+        Some(offsets)
+    }
+
+    fn set_offsets(&mut self, offsets: Offsets)
+    {
+        self.offsets = offsets;
+        self.sampler.set_offsets(offsets);
+    }
+
+    // Ripple analysis on demand, shown on the ripple page and sent with the next upload
+    fn show_ripple(&mut self) -> anyhow::Result<RippleResult> {
+        let result = ripple_analysis(&mut self.sampler.sensor(), self.ac_mode);
+        self.last_ripple = Instant::now();
+        let ripple = result?;
+        self.dp.show_ripple(ripple);
+        self.pending_ripple = Some(ripple);
+        Ok(ripple)
+    }
+
+    // Energy totals to NVS, written at once
+    fn save_totals(&mut self)
+    {
+        let (energy_wh, charge_ah) = self.energy.totals();
+        self.settings.save_totals(energy_wh, charge_ah);
+        self.settings.flush();
+    }
+
+    fn reboot(&mut self, source: &str)
+    {
+        self.journal.record(EventKind::Reboot, source);
+        self.save_totals();
+        thread::sleep(Duration::from_millis(500));
+        unsafe { esp_idf_sys::esp_restart(); }
+    }
+
+    // Apply the settings changed through the console or an import, each subsystem gets the keys it watches
+    fn apply_settings(&mut self)
+    {
+        for (key, value) in self.sensor_watch.changes() {
+            match key.as_str() {
+                "current_limit" => {
+                    self.sampler.set_limit(value.parse::<f32>().unwrap_or(0.0));
+                },
+                "current_offset" => {
+                    self.offsets.current = value.parse::<f32>().unwrap_or(0.0);
+                    self.sampler.set_offsets(self.offsets);
+                },
+                "current_offset_hi" => {
+                    self.offsets.current_hi = value.parse::<f32>().unwrap_or(0.0);
+                    self.sampler.set_offsets(self.offsets);
+                },
+                "voltage_offset" => {
+                    self.offsets.voltage = value.parse::<f32>().unwrap_or(0.0);
+                    self.sampler.set_offsets(self.offsets);
+                },
+                "invert_current" => {
+                    let invert = value == "true";
+                    let mut sensor = self.sampler.sensor();
+                    if invert != sensor.is_current_inverted() {
+                        sensor.set_invert_current(invert);
+                        drop(sensor);
+                        // The stored offsets were measured with the other polarity
+                        self.offsets.current = -self.offsets.current;
+                        self.offsets.current_hi = -self.offsets.current_hi;
+                        self.sampler.set_offsets(self.offsets);
+                        let _ = self.settings.set("current_offset", &format!("{:.6}", self.offsets.current));
+                        let _ = self.settings.set("current_offset_hi", &format!("{:.6}", self.offsets.current_hi));
+                    }
+                    self.reversed_since = None;
+                },
+                "profile" => {
+                    if let Some(p) = Profile::from_name(&value) {
+                        self.profile = p;
+                        self.apply_profile(self.profile);
+                    }
+                },
+                "output_rules" => {
+                    if let Ok(specs) = outputrule::parse_rules(&value) {
+                        if let Err(e) = self.sampler.set_output_rules(specs, &self.output_gpio) {
+                            info!("Output rules: {}", e);
+                        }
+                    }
+                },
+                "schedule" => {
+                    // The new windows are applied at the next loop, with the current state
+                    if let Ok(s) = Schedule::parse(&value) {
+                        self.log_schedule = s;
+                        if self.scheduled_profile.take().is_some() {
+                            self.apply_profile(self.profile);
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+        for (key, value) in self.channel_watch.changes() {
+            match key.as_str() {
+                "channel" => {
+                    self.channel = value.parse::<u8>().unwrap_or(self.channel);
+                },
+                "channel_names" => {
+                    if let Ok(names) = parse_channel_names(&value) {
+                        self.channel_names = names;
+                    }
+                },
+                _ => {},
+            }
+            self.apply_channel();
+        }
+        for (key, value) in self.transfer_watch.changes() {
+            match key.as_str() {
+                "device_name" => self.txd.set_device(value),
+                "influxdb_tags" => self.txd.set_tags(transfer::parse_tags(&value).unwrap_or_default()),
+                _ => {},
+            }
+        }
+        // The SSID and the password are applied together, as stored
+        #[cfg(feature = "wifi")]
+        if !self.wifi_watch.changes().is_empty() {
+            if let Some((ssid, psk)) = self.settings.load_wifi_credentials() {
+                if let Err(e) = self.change_wifi(&ssid, &psk) {
+                    info!("{:?}", e);
+                }
+            }
+        }
+        for (key, value) in self.meter_watch.changes() {
+            match key.as_str() {
+                "sound_enabled" => self.buzzer.set_enabled(value == "true"),
+                "timezone" => schedule::set_timezone(&value),
+                "battery_divider" => self.battery_divider = value.parse::<f32>().unwrap_or(self.battery_divider),
+                "battery_scale" => self.battery_scale = value.parse::<f32>().unwrap_or(self.battery_scale),
+                "trigger_start" | "trigger_stop" => self.update_trigger(),
+                _ => {},
+            }
+        }
+    }
+
+    // New trigger conditions, logging waits for the start condition again as after boot
+    fn update_trigger(&mut self)
+    {
+        self.trigger = Trigger::new(&self.settings.get("trigger_start").unwrap_or(CONFIG.trigger_start.to_string()),
+            &self.settings.get("trigger_stop").unwrap_or(CONFIG.trigger_stop.to_string()));
+        self.logging_start = !self.trigger.is_enabled();
+    }
+
+    // Connect to another access point, sampling continues while WiFi reconnects
+    #[cfg(feature = "wifi")]
+    fn change_wifi(&mut self, ssid: &str, psk: &str) -> anyhow::Result<()> {
+        let wifi = self.wifi_device.as_ref().ok_or(anyhow::anyhow!("WiFi is not available"))?;
+        self.wifi_enable = false;
+        let ret = wifi::change_credentials(&mut wifi.lock(), ssid, psk);
+        wifi.restart_backoff();
+        ret
+    }
+
+    #[cfg(feature = "wifi")]
+    fn ack(&mut self, text: &str)
+    {
+        if let Some(ref mut ctl) = self.mqtt_ctl {
+            ctl.ack(text);
+        }
+    }
+
+    #[cfg(feature = "wifi")]
+    fn poll_mqtt(&mut self, now: u64)
+    {
+        let commands = match self.mqtt_ctl {
+            Some(ref mut ctl) => ctl.poll(),
+            None => Vec::new(),
+        };
+        for cmd in commands {
+            self.handle_command(cmd, now);
+        }
+    }
+
+    // Remote command from MQTT
+    #[cfg(feature = "wifi")]
+    fn handle_command(&mut self, cmd: Command, now: u64)
+    {
+        match cmd {
+            Command::Start => {
+                self.logging_start = true;
+                self.logging_stopped_by_buffer_full = false;
+                self.ack("ok: start");
+            },
+            Command::Stop => {
+                self.logging_start = false;
+                self.logging_stopped_by_buffer_full = false;
+                self.ack("ok: stop");
+            },
+            Command::Channel(ch) => {
+                self.channel = ch;
+                self.apply_channel();
+                info!("Channel changed to {} by remote command", self.tag);
+                self.settings.save_channel(self.channel);
+                self.ack(&format!("ok: channel {}", self.channel));
+            },
+            Command::Tag(new_tag) => {
+                self.tag = new_tag;
+                self.txd.set_tag(self.tag.clone());
+                self.ack(&format!("ok: tag {}", self.tag));
+            },
+            Command::Calibrate => {
+                match self.start_calibration(now) {
+                    Ok(offsets) => {
+                        self.ack(&format!("ok: calibrate current_offset={:.6} current_offset_hi={:.6} voltage_offset={:.6}, send 'calibrate confirm' within {}s",
+                            offsets.current, offsets.current_hi, offsets.voltage, CALIBRATION_CONFIRM_MS / 1000));
+                    },
+                    Err(e) => self.ack(&format!("error: calibration failed: {}", e)),
+                }
+            },
+            Command::CalibrateConfirm => {
+                match self.confirm_calibration(now) {
+                    Some(_) => self.ack("ok: calibrate confirm"),
+                    None => self.ack("error: no calibration to confirm"),
+                }
+            },
+            Command::CalibrateUndo => {
+                match self.undo_calibration() {
+                    Some(offsets) => {
+                        self.ack(&format!("ok: calibrate undo current_offset={:.6} current_offset_hi={:.6} voltage_offset={:.6}", offsets.current, offsets.current_hi, offsets.voltage));
+                    },
+                    None => {
+                        self.ack("error: no previous calibration");
+                    }
+                }
+            },
+            Command::Reboot => {
+                self.ack("ok: reboot");
+                self.reboot("mqtt");
+            },
+            Command::Shutdown => {
+                self.ack("ok: shutdown");
+                self.shutdown_requested = true;
+            },
+            Command::Ripple => {
+                match self.show_ripple() {
+                    Ok(ripple) => {
+                        self.ack(&format!("ok: ripple freq={:.1}Hz amplitude={:.6}A pp={:.6}A rate={:.0}S/s",
+                            ripple.frequency, ripple.amplitude, ripple.peak_to_peak, ripple.sample_rate));
+                    },
+                    Err(e) => self.ack(&format!("error: ripple analysis failed: {}", e)),
+                }
+            },
+            Command::Wifi(ssid, psk) => {
+                // Stored only once they are applied, so credentials the station rejects are not used at the next boot.
+                // Acknowledged after both.
+                let ret = self.change_wifi(&ssid, &psk).map_err(|e| format!("{}", e))
+                    .and_then(|_| self.settings.save_wifi_credentials(&ssid, &psk));
+                match ret {
+                    Ok(_) => self.ack(&format!("ok: wifi {}", ssid)),
+                    Err(e) => {
+                        info!("WiFi change failed: {}", e);
+                        self.ack(&format!("error: {}", e));
+                    },
+                }
+            },
+            Command::Reset => {
+                self.sampler.reset_protection();
+                self.dp.set_tripped(false);
+                self.ack("ok: reset");
+            },
+            Command::EnergyReset => {
+                self.energy.reset_totals();
+                self.settings.save_totals(0.0, 0.0);
+                info!("Energy totals reset");
+                self.ack("ok: energy reset");
+            },
+            Command::BatteryCalibrate(measured) => {
+                match calibrate_battery(&mut self.settings, measured, self.battery_mv, self.battery_divider) {
+                    Ok(scale) => {
+                        self.battery_scale = scale;
+                        self.ack(&format!("ok: battery scale {:.4}", scale));
+                    },
+                    Err(e) => self.ack(&format!("error: {}", e)),
+                }
+            },
+            Command::Threshold(name, value) => {
+                match name.as_str() {
+                    "current_limit" => {
+                        self.sampler.set_limit(value);
+                        self.ack(&format!("ok: threshold current_limit {:.4}", value));
+                    },
+                    _ => {
+                        self.ack(&format!("error: unknown threshold '{}'", name));
+                    }
+                }
+            },
+        }
+    }
+
+    fn update_logging_state(&mut self)
+    {
+        if self.wifi_enable == false{
+            // Still connecting in the background unless WiFi could not be started
+            #[cfg(feature = "wifi")]
+            let connecting = self.wifi_device.is_some();
+            #[cfg(not(feature = "wifi"))]
+            let connecting = false;
+            self.dp.set_wifi_status(if connecting { WifiStatus::Connecting } else { WifiStatus::Disconnected });
+        }
+        else {
+            self.dp.set_wifi_status(WifiStatus::Connected);
+        }
+
+        // Logging schedule: only the opening and closing of a window change the logging state,
+        // so logging started or stopped by hand in between is kept until the next change
+        if self.log_schedule.is_enabled() && timebase::is_valid() {
+            let (weekday, minute) = schedule::local_time();
+            match self.log_schedule.update(weekday, minute) {
+                Some(ScheduleEvent::Start(window_profile)) => {
+                    self.logging_start = true;
+                    if window_profile != self.scheduled_profile {
+                        self.apply_profile(window_profile.unwrap_or(self.profile));
+                        self.scheduled_profile = window_profile;
+                    }
+                },
+                Some(ScheduleEvent::Stop) => {
+                    self.logging_start = false;
+                    if self.scheduled_profile.take().is_some() {
+                        self.apply_profile(self.profile);
+                    }
+                },
+                None => {},
+            }
+        }
+
+        if self.logging_start == true {
+            //startstop_led.set_high()?;
+            self.dp.set_current_status(LoggingStatus::Start);
+        }
+        else if self.trigger.is_enabled() {
+            self.dp.set_current_status(LoggingStatus::Armed);
+        }
+        else {
+            //startstop_led.set_low()?;
+            self.dp.set_current_status(LoggingStatus::Stop);
+        }
+    }
+
+    // Samples taken by the sampling thread since the last loop, returns true while the current limit is tripped
+    fn update_readings(&mut self) -> bool {
+        let samples = self.sampler.poll();
+        let tripped = self.sampler.is_tripped();
+        if tripped {
+            self.dp.set_tripped(true);
+            if !self.was_tripped {
+                self.buzzer.play(Pattern::Alarm);
+                self.journal.record(EventKind::CurrentLimit, &format!("current={:.4}A", self.data.current));
+            }
+        }
+        self.was_tripped = tripped;
+        self.dp.set_adc_range(self.sampler.is_low_range());
+        let config_error = self.sampler.has_config_error();
+        self.dp.set_config_error(config_error);
+        self.health.set_config_error(config_error);
+
+        // battery voltage 
+        self.battery_mv = self.adc_pin.read().unwrap() as f32;
+        let battery = self.battery_mv * self.battery_divider * self.battery_scale / 1000.0;
+        self.dp.set_battery(battery);
+        if let Some(ref mut monitor) = self.power_monitor {
+            if monitor.update(battery) {
+                monitor.flush(self.clogs.newest(powerloss::FLUSH_MAX_RECORDS));
+                let (energy_wh, charge_ah) = self.energy.totals();
+                self.settings.save_totals(energy_wh, charge_ah);
+                self.settings.flush();
+            }
+        }
+        if self.energy_checkpoint.elapsed() >= Duration::from_millis(ENERGY_CHECKPOINT_MS) {
+            let (energy_wh, charge_ah) = self.energy.totals();
+            self.settings.save_totals(energy_wh, charge_ah);
+            self.energy_checkpoint = Instant::now();
+        }
+        self.settings.poll();
+
+        // Logging trigger
+        let trigger_level = match self.trigger_pin {
+            Some(ref pin) => pin.is_high(),
+            None => true,
+        };
+        let mut trigger_edge = self.last_trigger_level && !trigger_level;
+        self.last_trigger_level = trigger_level;
+
+        // External sync marker
+        if let Some(ref mut input) = self.marker {
+            if let Some(m) = input.poll() {
+                info!("Marker {} at {}", m.count, m.clock);
+            }
+        }
+
+        for mut sample in samples {
+            sample.data.battery = battery;
+            self.data = sample.data;
+            // Skip until the first conversion in a new range completes
+            if sample.settling {
+                continue;
+            }
+            self.energy.update(self.data.power, self.data.current, sample.instant);
+            if let Some(ref mut cc) = self.coulomb {
+                cc.update(sample.data.current, sample.instant);
+                sample.data.soc = Some(cc.soc());
+                self.data.soc = sample.data.soc;
+            }
+            if let Some(ref mut detector) = self.pulse {
+                detector.update(self.data.current, sample.instant);
+            }
+            if let Some(ref mut profiler) = self.iot {
+                profiler.update(self.data.current, sample.instant);
+            }
+            self.peak.update(self.data.current, self.data.power);
+            self.summary.update(&self.data);
+            // RMS values are shown in AC mode
+            #[cfg(feature = "display")]
+            let (voltage, current) = match self.data.ac {
+                Some(ac) => (ac.vrms, ac.irms),
+                None => (self.data.voltage, self.data.current),
+            };
+            #[cfg(feature = "display")]
+            self.display_decimator.push([self.voltage_filter.update(voltage), self.current_filter.update(current), self.power_filter.update(self.data.power)]);
+            self.streamer.push(&self.data);
+            #[cfg(feature = "wifi")]
+            self.push_network(sample.instant);
+            match self.trigger.update(&self.data, trigger_edge) {
+                Some(TriggerEvent::Start) => {
+                    self.logging_start = true;
+                },
+                Some(TriggerEvent::Stop) => {
+                    self.logging_start = false;
+                },
+                None => {},
+            }
+            trigger_edge = false;
+            if self.logging_start && self.clogs.is_full() && self.clogs.has_retention() {
+                // Keep logging, the oldest records make room for the new one
+                self.clogs.drop_oldest(self.clogs.get_size() + 1 - self.clogs.get_capacity());
+            }
+            if self.logging_start && !self.clogs.is_full() {
+                self.clogs.record_at(sample.data, sample.instant);
+            }
+        }
+        // info!("voltage={:.2}V current={:.5}A power={:.5}W battery={:.2}V",
+        //     data.voltage, data.current, data.power, data.battery);
+        #[cfg(feature = "wifi")]
+        self.flush_network();
+        #[cfg(feature = "display")]
+        if let Some([voltage, current, power]) = self.display_decimator.take() {
+            self.dp.set_readings(voltage, current, power);
+        }
+        let counters = self.txd.get_session_counters();
+        self.dp.set_backlog(counters.backlog_batches);
+        #[cfg(feature = "display")]
+        if self.network_page {
+            self.dp.set_net_info(Some(NetInfo { records_sent: counters.records_sent, last_upload: counters.last_upload,
+                failed_attempts: counters.failed_attempts, backlog: counters.backlog_batches }));
+        }
+        #[cfg(feature = "display")]
+        self.dp.set_soc(self.coulomb.as_ref().map(|cc| SocInfo {
+            soc: cc.soc(),
+            charge_out: cc.charge_out_mah(),
+            charge_in: cc.charge_in_mah(),
+            efficiency: cc.efficiency(),
+            cycles: cc.cycles(),
+        }));
+        self.dp.set_pulse(self.pulse.as_ref().and_then(|detector| detector.latest()));
+        self.dp.set_iot(self.iot.as_ref().map(|profiler| profiler.summary()));
+        self.dp.set_peak(self.peak.current, self.peak.power);
+        if self.differential {
+            self.dp.set_efficiency(self.data.differential().and_then(|d| d.efficiency));
+        }
+        tripped
+    }
+
+    // Each sample to the WebSocket push, the data API and the SCPI server
+    #[cfg(feature = "wifi")]
+    fn push_network(&mut self, instant: Instant)
+    {
+        if let Some(ref mut ws) = self.ws_push {
+            ws.push(&self.data);
+        }
+        if let Some(ref mut api) = self.data_api {
+            api.push(&self.data);
+        }
+        if let Some(ref mut scpi) = self.scpi {
+            scpi.update(&self.data, &self.peak, self.energy.energy_wh(), instant);
+        }
+    }
+
+    // Once per pass: send the pushed samples and publish the state over MQTT
+    #[cfg(feature = "wifi")]
+    fn flush_network(&mut self)
+    {
+        if let Some(ref mut ws) = self.ws_push {
+            ws.flush(&self.tag);
+        }
+        if let Some(ref mut api) = self.data_api {
+            api.flush();
+        }
+        if let Some(ref mut ctl) = self.mqtt_ctl {
+            ctl.publish_state(self.data.voltage, self.data.current, self.data.power, self.energy.totals().0 as f32, self.data.battery, wifi::get_rssi());
+        }
+    }
+
+    // Download requests of the data API
+    #[cfg(feature = "wifi")]
+    fn serve_data_api(&mut self, now: u64)
+    {
+        let requests = match self.data_api {
+            Some(ref mut api) => api.poll(),
+            None => Vec::new(),
+        };
+        for req in requests {
+            match req {
+                DataRequest::Records(after, since, reply) => {
+                    let _ = reply.send(self.clogs.records_after(after, since, dataapi::DATA_CHUNK_RECORDS));
+                },
+                DataRequest::Clear(seq) => {
+                    let removed = self.clogs.remove_through(seq);
+                    info!("Data API: {} downloaded records cleared", removed);
+                },
+                DataRequest::Journal(reply) => {
+                    let _ = reply.send(self.journal.to_json());
+                },
+                DataRequest::Capture(duration, reply) => {
+                    match duration {
+                        Some(0) => self.end_capture(now),
+                        Some(secs) => self.start_capture(secs, now),
+                        None => {},
+                    }
+                    let remaining = self.capture.as_ref().map(|c| c.remaining().as_secs());
+                    let _ = reply.send(format!("{{\"capture\":{},\"remaining\":{}}}",
+                        remaining.is_some(), remaining.map_or("null".to_string(), |secs| secs.to_string())));
+                },
+                DataRequest::Config(key, value, reply) => {
+                    // The credentials are not changed over plain HTTP
+                    let ret = if SECRET_KEYS.contains(&key.as_str()) {
+                        Err(format!("{} can only be set on the serial console", key))
+                    }
+                    else {
+                        self.settings.set(&key, &value).map(|_| self.settings.is_watched(&key))
+                    };
+                    let _ = reply.send(ret);
+                },
+                DataRequest::Status(reply) => {
+                    let counters = self.txd.get_session_counters();
+                    let _ = reply.send(format!("{{\"voltage\":{:.5},\"current\":{:.5},\"power\":{:.5},\"battery\":{:.2},\"channel\":{},\"tag\":\"{}\",\"channel_name\":\"{}\",\"logging\":{},\"tripped\":{},\"records_sent\":{},\"failed_attempts\":{},\"last_upload\":{},\"last_upload_age\":{},\"last_transfer\":{},\"buffer\":{},\"capacity\":{},\"buffer_fill\":{},\"rssi\":{},\"ip\":{},\"iot_profile\":{},\"capture_remaining\":{},\"uptime\":{},\"firmware\":\"{}\"}}",
+                        self.data.voltage,
+                        self.data.current,
+                        self.data.power,
+                        self.data.battery,
+                        self.channel,
+                        transfer::json_escape(&self.tag),
+                        transfer::json_escape(self.channel_names.get((self.channel as usize).wrapping_sub(1)).map_or("", |n| n.as_str())),
+                        self.logging_start,
+                        self.sampler.is_tripped(),
+                        counters.records_sent,
+                        counters.failed_attempts,
+                        counters.last_upload_clock,
+                        counters.last_upload.map_or("null".to_string(), |t| t.elapsed().as_secs().to_string()),
+                        counters.last_status.map_or("null".to_string(), |s| s.to_json()),
+                        self.clogs.get_size(),
+                        self.clogs.get_capacity(),
+                        self.clogs.get_size() * 100 / self.clogs.get_capacity().max(1),
+                        wifi::get_rssi(),
+                        wifi::get_ip().map_or("null".to_string(), |ip| format!("\"{}\"", ip)),
+                        self.iot.as_ref().map_or("null".to_string(), |profiler| profiler.summary().to_json()),
+                        self.capture.as_ref().map_or("null".to_string(), |c| c.remaining().as_secs().to_string()),
+                        self.start_time.elapsed().as_secs(),
+                        transfer::json_escape(&version::summary())));
+                },
+            }
+        }
+    }
+
+    // Serial console command
+    fn handle_console(&mut self, cmd: ConsoleCommand, now: u64)
+    {
+        match cmd {
+            ConsoleCommand::Help => {
+                println!("{}", console::HELP);
+            },
+            ConsoleCommand::Status => {
+                let uptime = unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000;
+                println!("voltage={:.5}V current={:.5}A power={:.5}W battery={:.2}V", self.data.voltage, self.data.current, self.data.power, self.data.battery);
+                if let Some(s) = self.data.second {
+                    println!("voltage2={:.5}V current2={:.5}A power2={:.5}W", s.voltage, s.current, s.power);
+                }
+                if let (true, Some(d)) = (self.differential, self.data.differential()) {
+                    println!("current_diff={:.5}A loss={:.5}W efficiency={}", d.current, d.loss,
+                        d.efficiency.map_or("-".to_string(), |e| format!("{:.2}%", e)));
+                }
+                let (gaps, gap_time) = self.energy.gaps();
+                println!("energy={:.6}Wh gaps={} ({:.1}s not integrated)", self.energy.energy_wh(), gaps, gap_time.as_secs_f32());
+                #[cfg(feature = "wifi")]
+                let rssi = wifi::get_rssi();
+                #[cfg(not(feature = "wifi"))]
+                let rssi = 0;
+                println!("channel={} tag={} name={} logging={} buffer={}/{} rssi={}dBm wifi={} uptime={}s",
+                    self.channel, self.tag, self.channel_names.get((self.channel as usize).wrapping_sub(1)).map_or("", |n| n.as_str()), self.logging_start, self.clogs.get_size(), self.clogs.get_capacity(), rssi, self.wifi_enable, uptime);
+                let outputs = self.sampler.output_states();
+                if !outputs.is_empty() {
+                    println!("outputs={}", outputs.iter().map(|(gpio, on)| format!("GPIO{}:{}", gpio, if *on { "on" } else { "off" })).collect::<Vec<String>>().join(" "));
+                }
+                if let Some(ref rtc) = self.rtc {
+                    match rtc.last_drift() {
+                        Some(drift) => println!("rtc_offset={}ms rtc_drift={}", drift.offset_ms, drift.drift_ppm.map_or("-".to_string(), |ppm| format!("{:.3}ppm", ppm))),
+                        None => println!("rtc_offset=- (no NTP sync yet)"),
+                    }
+                }
+                println!("firmware={}", version::summary());
+            },
+            ConsoleCommand::ConfigGet(key) => {
+                let show = |key: &str| match self.settings.get(key) {
+                    Some(_) if SECRET_KEYS.contains(&key) => "********".to_string(),
+                    Some(value) => value,
+                    None => "(default)".to_string(),
+                };
+                if key.is_empty() {
+                    for key in SETTING_KEYS {
+                        println!("{}={}", key, show(key));
+                    }
+                }
+                else if SETTING_KEYS.contains(&key.as_str()) {
+                    println!("{}={}", key, show(&key));
+                }
+                else {
+                    println!("error: unknown setting '{}'", key);
+                }
+            },
+            ConsoleCommand::ConfigExport => {
+                println!("{}", self.settings.export_json());
+            },
+            ConsoleCommand::ConfigImport(json) => {
+                match self.settings.import_json(&json) {
+                    Ok(count) => println!("ok: {} settings imported", count),
+                    Err(e) => println!("error: {}", e),
+                }
+            },
+            ConsoleCommand::ConfigSet(key, value) => {
+                match self.settings.set(&key, &value) {
+                    Ok(()) => {
+                        // Settings that no subsystem watches are read at boot
+                        if !self.settings.is_watched(&key) {
+                            println!("{} takes effect after reboot", key);
+                        }
+                        println!("ok");
+                    },
+                    Err(e) => {
+                        println!("error: {}", e);
+                    }
+                }
+            },
+            ConsoleCommand::Calibrate => {
+                match self.start_calibration(now) {
+                    Ok(offsets) => {
+                        println!("ok: current_offset={:.6}A current_offset_hi={:.6}A voltage_offset={:.6}V, type 'cal confirm' within {}s",
+                            offsets.current, offsets.current_hi, offsets.voltage, CALIBRATION_CONFIRM_MS / 1000);
+                    },
+                    Err(e) => println!("error: calibration failed: {}", e),
+                }
+            },
+            ConsoleCommand::CalibrateConfirm => {
+                match self.confirm_calibration(now) {
+                    Some(_) => println!("ok"),
+                    None => println!("error: no calibration to confirm"),
+                }
+            },
+            ConsoleCommand::CalibrateUndo => {
+                match self.undo_calibration() {
+                    Some(offsets) => {
+                        println!("ok: current_offset={:.6}A current_offset_hi={:.6}A voltage_offset={:.6}V", offsets.current, offsets.current_hi, offsets.voltage);
+                    },
+                    None => {
+                        println!("error: no previous calibration");
+                    }
+                }
+            },
+            ConsoleCommand::DumpCsv => {
+                self.clogs.dump_csv();
+            },
+            ConsoleCommand::WifiScan => {
+                #[cfg(feature = "wifi")]
+                let scan = match self.wifi_device {
+                    Some(ref wifi) => Some(wifi::scan(&mut wifi.lock())),
+                    None => None,
+                };
+                #[cfg(not(feature = "wifi"))]
+                let scan: Option<anyhow::Result<Vec<(String, i8)>>> = None;
+                match scan {
+                    Some(Ok(aps)) => {
+                        for (ssid, rssi) in &aps {
+                            println!("{:>4}dBm {}", rssi, ssid);
+                        }
+                        self.dp.set_scan_results(aps);
+                    },
+                    Some(Err(e)) => {
+                        println!("error: {:?}", e);
+                    },
+                    None => {
+                        println!("error: WiFi is not available");
+                    }
+                }
+            },
+            ConsoleCommand::Diagnostics => {
+                let jitter = self.sampler.get_jitter_stats().summary();
+                println!("samples={} jitter_mean={}us jitter_p95={}us jitter_p99={}us late={} jitter_max={}us alert_misses={}",
+                    jitter.samples, jitter.mean_us, jitter.p95_us, jitter.p99_us, jitter.late, jitter.max_us, self.sampler.alert_misses());
+                self.dp.show_diagnostics();
+            },
+            ConsoleCommand::Stream(mode) => {
+                match StreamMode::from_str(&mode) {
+                    Some(mode) => self.streamer.set_mode(mode),
+                    None => println!("error: stream mode must be off, csv or scpi"),
+                }
+            },
+            ConsoleCommand::Measure => {
+                if self.streamer.mode() == StreamMode::Scpi {
+                    self.streamer.respond(&self.data);
+                }
+                else {
+                    println!("error: MEAS? requires stream scpi mode");
+                }
+            },
+            ConsoleCommand::Journal => {
+                for entry in self.journal.entries() {
+                    println!("{:>5} {} {} {}", entry.seq, DateTime::<Utc>::from_timestamp_nanos(entry.clock as i64).format("%Y-%m-%d %H:%M:%S"), entry.kind.name(), entry.detail);
+                }
+            },
+            ConsoleCommand::Iot => {
+                match self.iot {
+                    Some(ref profiler) => {
+                        let summary = profiler.summary();
+                        for (i, name) in iotprofile::BAND_NAMES.iter().enumerate() {
+                            println!("{}: time={:.1}s ({:.2}%) charge={:.6}mAh", name, summary.bands[i].time, summary.time_percent(i), summary.charge_mah(i));
+                        }
+                        println!("avg_current={:.7}A battery_life={}", summary.average_current,
+                            summary.battery_life.map(|h| format!("{:.1}h", h)).unwrap_or("-".to_string()));
+                    },
+                    None => {
+                        println!("error: IoT profile is disabled (iot_bands)");
+                    }
+                }
+            },
+            ConsoleCommand::IotReset => {
+                match self.iot {
+                    Some(ref mut profiler) => {
+                        profiler.reset();
+                        println!("ok");
+                    },
+                    None => {
+                        println!("error: IoT profile is disabled (iot_bands)");
+                    }
+                }
+            },
+            ConsoleCommand::Soc => {
+                match self.coulomb {
+                    Some(ref cc) => {
+                        println!("soc={:.2}% out={:.2}mAh in={:.2}mAh efficiency={} cycles={:.3}",
+                            cc.soc(), cc.charge_out_mah(), cc.charge_in_mah(),
+                            cc.efficiency().map(|e| format!("{:.1}%", e)).unwrap_or("-".to_string()), cc.cycles());
+                    },
+                    None => {
+                        println!("error: coulomb counter mode is disabled (coulomb_capacity)");
+                    }
+                }
+            },
+            ConsoleCommand::SocReset(soc) => {
+                match self.coulomb {
+                    Some(ref mut cc) => {
+                        cc.reset(soc.unwrap_or(self.coulomb_initial_soc));
+                        println!("ok");
+                    },
+                    None => {
+                        println!("error: coulomb counter mode is disabled (coulomb_capacity)");
+                    }
+                }
+            },
+            ConsoleCommand::Energy => {
+                let (energy_wh, charge_ah) = self.energy.totals();
+                println!("energy_total={:.6}Wh charge_total={:.6}Ah session={:.6}Wh", energy_wh, charge_ah, self.energy.energy_wh());
+            },
+            ConsoleCommand::EnergyReset => {
+                self.energy.reset_totals();
+                self.settings.save_totals(0.0, 0.0);
+                info!("Energy totals reset");
+                println!("ok");
+            },
+            ConsoleCommand::BatteryCalibrate(measured) => {
+                match calibrate_battery(&mut self.settings, measured, self.battery_mv, self.battery_divider) {
+                    Ok(scale) => {
+                        self.battery_scale = scale;
+                        println!("ok: battery_scale={:.4}", scale);
+                    },
+                    Err(e) => println!("error: {}", e),
+                }
+            },
+            ConsoleCommand::Reboot => {
+                println!("rebooting");
+                self.reboot("console");
+            },
+            ConsoleCommand::Shutdown => {
+                println!("shutting down, {} records to send", self.clogs.get_size());
+                self.shutdown_requested = true;
+            },
+            ConsoleCommand::Ripple => {
+                match self.show_ripple() {
+                    Ok(ripple) => {
+                        println!("freq={:.1}Hz amplitude={:.6}A pp={:.6}A rate={:.0}S/s",
+                            ripple.frequency, ripple.amplitude, ripple.peak_to_peak, ripple.sample_rate);
+                    },
+                    Err(e) => println!("error: {}", e),
+                }
+            },
+        }
+    }
+
+    // Buffer fill, alarms and the status shown by the display and LED
+    fn update_buffer(&mut self, now: u64, tripped: bool)
+    {
+        // Drop records older than the retention period while they cannot be sent
+        if self.wifi_enable == false && self.clogs.has_retention() {
+            let dropped = self.clogs.expire(self.data.clock);
+            if dropped > 0 {
+                info!("Dropped {} records older than {}s ({} in total)", dropped, self.retention_secs, self.clogs.get_dropped());
+            }
+        }
+        self.health.set_dropped_records(self.clogs.get_dropped());
+        self.health.set_upload_stats(self.txd.get_upload_stats());
+        self.health.set_transfer_stats(self.txd.get_transfer_stats());
+        let jitter = self.sampler.get_jitter_stats();
+        self.health.set_jitter_stats(jitter);
+        self.dp.set_jitter(jitter.summary());
+        #[cfg(feature = "wifi")]
+        if let Some(ref wifi) = self.wifi_device {
+            self.health.set_wifi_stats(wifi.stats());
+        }
+        let current_record = self.clogs.get_size();
+        let capacity = self.clogs.update_capacity();
+        let full = current_record >= capacity;
+        if full && !self.was_full {
+            self.buzzer.play(Pattern::BufferFull);
+            self.journal.record(EventKind::BufferFull, &format!("records={}", current_record));
+        }
+        self.was_full = full;
+        if current_record >= capacity && !self.clogs.has_retention() {
+            self.logging_start = false;  // Auto stop logging if buffer is full.
+            self.logging_stopped_by_buffer_full = true;  // Mark that logging was stopped due to buffer full
+        }
+        
+        // Restart logging if it was stopped due to buffer full and buffer usage drops below 50%
+        if self.logging_stopped_by_buffer_full && !self.logging_start && current_record < capacity / 2 {
+            self.logging_start = true;
+            self.logging_stopped_by_buffer_full = false;
+            info!("Logging restarted: buffer usage dropped below 50% ({}/{})", current_record, capacity);
+        }
+        
+        self.dp.set_buffer_watermark((current_record.min(capacity) as u32) * 100 / capacity.max(1) as u32);
+        self.dp.set_buffer_capacity(capacity as u32);
+        if let Some(level) = self.buffer_alert.update(current_record, capacity) {
+            self.journal.record(EventKind::BufferAlert, &format!("level={}%", level));
+            self.show_message(tr_args(Msg::BufferAlert, &[&level.to_string()]), now, BUFFER_ALERT_DISPLAY_MS);
+        }
+        self.dp.set_buffer_alert(self.buffer_alert.level().is_some());
+
+        // Sustained negative current without a battery under test usually means a reversed shunt,
+        // suggested once per boot
+        if !self.reversed_hint_shown && self.coulomb.is_none() && !self.ac_mode {
+            if self.data.current < REVERSED_SHUNT_CURRENT {
+                let since = *self.reversed_since.get_or_insert(now);
+                if now.saturating_sub(since) >= REVERSED_SHUNT_HINT_MS {
+                    let invert = !self.sampler.sensor().is_current_inverted();
+                    info!("Negative current {:.4}A for {}s, shunt may be reversed: try invert_current = {}",
+                        self.data.current, REVERSED_SHUNT_HINT_MS / 1000, invert);
+                    self.show_message(tr_args(Msg::NegativeCurrent, &[&invert.to_string()]), now, REVERSED_SHUNT_MESSAGE_MS);
+                    self.reversed_hint_shown = true;
+                }
+            }
+            else {
+                self.reversed_since = None;
+            }
+        }
+
+        // Attention mode of the display until a short press acknowledges the alarm
+        self.dp.set_alarm(if tripped {
+            Some(AlarmQuantity::Current)
+        }
+        else if self.buffer_alert.level().is_some() {
+            Some(AlarmQuantity::Buffer)
+        }
+        else {
+            None
+        });
+
+        // Status LED, the alarm takes precedence over the connection and logging state
+        self.led.set_state(if tripped || self.buffer_alert.level().is_some() {
+            LedState::Alarm
+        }
+        else if !self.wifi_enable {
+            LedState::WifiDown
+        }
+        else if self.logging_start {
+            LedState::Logging
+        }
+        else {
+            LedState::Idle
+        });
+        crash::update_state(self.channel, self.logging_start, current_record as u32, self.clogs.last_seq());
+    }
+
+    // Points queued for the transfer thread, uploads start when WiFi is up and the clock is synchronized.
+    // Until the clock holds a valid time the records wait in the buffer, they are re-based when it is set.
+    fn upload(&mut self, now: u64)
+    {
+        #[cfg(feature = "influx")]
+        let uploading = self.wifi_enable && self.time_synced && Timestamp::valid(timebase::now_ns()).is_some();
+        #[cfg(not(feature = "influx"))]
+        let uploading = false;
+
+        // Check the server once before the first upload
+        #[cfg(feature = "influx")]
+        if uploading && !self.server_checked {
+            self.txd.request_probe();
+            self.server_checked = true;
+        }
+        if let Some(result) = self.txd.take_probe_result() {
+            match result.message() {
+                Some(msg) => {
+                    info!("Server check failed: {:?}", result);
+                    self.show_message(tr_args(Msg::ServerCheck, &[&msg]), now, SERVER_CHECK_MESSAGE_MS);
+                },
+                None => info!("Server check passed"),
+            }
+        }
+
+        // Nothing is uploaded with timestamps before the NTP sync
+        if uploading && self.crash_report.is_some() {
+            if self.txd.set_crash_data(self.crash_report.as_ref().unwrap()) {
+                self.crash_report = None;
+            }
+        }
+
+        if uploading {
+            if let Some(ref mut input) = self.marker {
+                if let Some(m) = input.pending() {
+                    if self.txd.set_marker_data(m) {
+                        input.sent();
+                    }
+                }
+            }
+        }
+
+        // Periodic ripple analysis, the result is sent with the next upload
+        if self.ripple_interval > 0 && self.last_ripple.elapsed() >= Duration::from_secs(self.ripple_interval) {
+            match ripple_analysis(&mut self.sampler.sensor(), self.ac_mode) {
+                Ok(ripple) => {
+                    info!("Ripple: {:.1}Hz {:.6}A pp={:.6}A", ripple.frequency, ripple.amplitude, ripple.peak_to_peak);
+                    self.pending_ripple = Some(ripple);
+                },
+                Err(e) => info!("Ripple analysis failed: {:?}", e),
+            }
+            self.last_ripple = Instant::now();
+        }
+
+        if uploading {
+            if let Some(ref mut detector) = self.pulse {
+                if let Some(stats) = detector.pending() {
+                    if self.txd.set_pulse_data(stats) {
+                        detector.sent();
+                    }
+                }
+            }
+        }
+
+        if uploading {
+            if let Some(event) = self.journal.pending() {
+                if self.txd.set_journal_data(event) {
+                    self.journal.sent();
+                }
+            }
+        }
+
+        if uploading {
+            if let Some(ref mut profiler) = self.iot {
+                if let Some(summary) = profiler.pending() {
+                    if self.txd.set_iot_data(summary) {
+                        profiler.sent();
+                    }
+                }
+            }
+        }
+
+        if uploading {
+            if let Some(ripple) = self.pending_ripple {
+                if self.txd.set_ripple_data(&ripple) {
+                    self.pending_ripple = None;
+                }
+            }
+        }
+
+        if uploading {
+            if let Some(event) = self.buffer_alert.pending() {
+                if self.txd.set_buffer_alert_data(event) {
+                    self.buffer_alert.sent();
+                }
+            }
+        }
+
+        if uploading {
+            if let Some(health_log) = self.health.poll() {
+                if self.txd.set_health_data(health_log) {
+                    self.health.sent();
+                }
+            }
+        }
+
+        // Summary report of the period that has ended, kept until it can be sent
+        if let Some(report) = self.summary.poll(self.energy.energy_wh(), self.sampler.read_errors(), self.health.reconnects()) {
+            if uploading && self.txd.set_summary_data(report) {
+                self.summary.sent();
+            }
+        }
+
+        #[cfg(feature = "wifi")]
+        let rssi = wifi::get_rssi();
+        #[cfg(not(feature = "wifi"))]
+        let rssi = 0;
+        if uploading {
+            if self.heartbeat.is_due() {
+                self.heartbeat.set(HeartbeatLog {
+                    logging: self.logging_start,
+                    tripped: self.sampler.is_tripped(),
+                    voltage: self.data.voltage,
+                    current: self.data.current,
+                    power: self.data.power,
+                    battery: self.data.battery,
+                    buffer: self.clogs.get_size(),
+                    rssi: rssi,
+                    uptime: self.start_time.elapsed().as_secs(),
+                    clock: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+                });
+            }
+            if let Some(beat) = self.heartbeat.pending() {
+                if self.txd.set_heartbeat_data(beat) {
+                    self.heartbeat.sent();
+                }
+            }
+        }
+
+        self.txd.adapt(rssi);
+        if uploading && self.clogs.get_size() > 0 && self.txd.is_ready() {
+            self.txd.set_transfer_data(self.clogs.take_batch(self.txd.get_batch_size()));
+        }
+        // The summary of a finished capture follows its records
+        else if uploading && self.clogs.get_size() == 0 && self.capture_summary.is_some() {
+            if self.txd.set_session_data(self.capture_summary.as_ref().unwrap()) {
+                self.capture_summary = None;
+            }
+        }
+    }
+
+    // Graceful shutdown: send everything buffered and the session summary, then stop WiFi
+    fn shutdown(&mut self)
+    {
+        info!("Shutdown: stopping sampling, {} records buffered", self.clogs.get_size());
+        self.journal.record(EventKind::Shutdown, &format!("records={}", self.clogs.get_size()));
+        self.save_totals();
+        self.sampler.stop();
+        self.logging_start = false;
+        self.dp.set_current_status(LoggingStatus::Stop);
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_DRAIN_TIMEOUT_MS);
+        let mut summary_sent = false;
+        #[cfg(feature = "influx")]
+        let drain = self.wifi_enable;
+        #[cfg(not(feature = "influx"))]
+        let drain = false;
+        while drain && Timestamp::valid(timebase::now_ns()).is_some() && Instant::now() < deadline {
+            if !self.txd.is_busy() {
+                if self.clogs.get_size() > 0 {
+                    self.dp.set_err_message(tr_args(Msg::Flushing, &[&self.clogs.get_size().to_string()]));
+                    self.txd.set_transfer_data(self.clogs.take_batch(self.txd.get_batch_size()));
+                }
+                else if !summary_sent {
+                    let uptime = self.start_time.elapsed().as_secs();
+                    summary_sent = self.txd.set_session_data(&SessionSummary {
+                        event: "shutdown",
+                        uptime: uptime,
+                        records: self.clogs.last_seq().wrapping_add(1),
+                        dropped: self.clogs.get_dropped(),
+                        energy: self.energy.energy_wh(),
+                        peak_current: self.peak.current,
+                        peak_power: self.peak.power,
+                        clock: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+                    });
+                }
+                else {
+                    break;
+                }
+            }
+            thread::sleep(Duration::from_millis(LOOP_PERIOD_MS));
+        }
+        // Whatever could not be sent is kept in NVS for the next boot
+        let unsent = self.clogs.get_size();
+        if unsent > 0 {
+            info!("Shutdown: {} records not sent", unsent);
+            if let Some(ref mut monitor) = self.power_monitor {
+                monitor.flush(self.clogs.newest(powerloss::FLUSH_MAX_RECORDS));
+            }
+        }
+        #[cfg(feature = "wifi")]
+        if let Some(ref wifi) = self.wifi_device {
+            wifi.set_paused(true);
+            if let Err(e) = wifi::stop_wifi(&mut wifi.lock()) {
+                info!("{:?}", e);
+            }
+        }
+        self.dp.set_wifi_status(WifiStatus::Disconnected);
+        info!("Shutdown complete, safe to power off");
+        self.dp.set_err_message(tr(Msg::SafeToPowerOff).to_string());
+        loop {
+            thread::sleep(Duration::from_millis(1000));
+        }
+    }
+
+    // Show the channel and set its tag and name for the records
+    fn apply_channel(&mut self)
+    {
+        self.tag = format!("ch{}", self.channel);
+        let name = self.channel_names.get((self.channel as usize).wrapping_sub(1)).cloned().unwrap_or_default();
+        self.dp.set_channel(self.channel as u32, &name);
+        self.txd.set_tag(self.tag.clone());
+        self.txd.set_channel_name(name);
+    }
+
+    // Switch the logging profile while running, the sensor is not reconfigured in AC mode
+    fn apply_profile(&mut self, profile: Profile)
+    {
+        info!("Logging profile: {}", profile.name());
+        self.sampler.set_period(profile.sampling_period_ms());
+        if !self.ac_mode {
+            if let Err(e) = self.sampler.sensor().set_adc_config(profile.adc_config()) {
+                info!("{:?}", e);
+            }
+            if let Some(mut sensor2) = self.sampler.sensor2() {
+                if let Err(e) = sensor2.set_adc_config(profile.adc_config()) {
+                    info!("{:?}", e);
+                }
+            }
+        }
+        self.clogs.set_capacity(self.max_records, profile.heap_percent().unwrap_or(self.buffer_heap_percent));
+        let (min_batch, min_interval) = profile.upload_pacing();
+        self.txd.set_min_pacing(min_batch, min_interval);
+    }
+}
+
+// One-point battery calibration: the scale that makes the last ADC reading match the measured voltage,
+// stored in NVS. None goes back to the divider ratio alone.
+fn calibrate_battery(settings: &mut Settings, measured: Option<f32>, battery_mv: f32, divider: f32) -> Result<f32, String> {
+    let scale = match measured {
+        Some(volts) => {
+            if battery_mv <= 0.0 {
+                return Err("no battery reading".to_string());
+            }
+            volts / (battery_mv * divider / 1000.0)
+        },
+        None => 1.0,
+    };
+    settings.set("battery_scale", &format!("{:.4}", scale))?;
+    info!("Battery calibration scale {:.4}", scale);
+    Ok(scale)
+}
+
+// Capture a burst of fast conversions and find the dominant ripple of the current.
+// Sampling pauses for the burst, the sensor is locked.
+fn ripple_analysis(sensor: &mut Ina228, ac_mode: bool) -> anyhow::Result<RippleResult> {
+    let (currents, elapsed) = sensor.capture_fast(ripple::FFT_SIZE, !ac_mode)?;
+    let sample_rate = currents.len() as f32 / elapsed.as_secs_f32();
+    let clock = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+    ripple::analyze(&currents, sample_rate, clock).ok_or(anyhow::anyhow!("Too few samples"))
+}
+
+// Offsets of both shunt voltage ranges. With auto_range the other range is switched to and measured as well,
+// otherwise it is never used and gets the same current offset.
+fn calibration(sensor: &mut Ina228) -> anyhow::Result<Offsets> {
+    let low_range = sensor.is_low_range();
+    let (current_offset, voltage_offset) = calibration_pass(sensor)?;
+    let mut other_current_offset = current_offset;
+    if sensor.is_auto_range() {
+        info!("Calibrating the {} range", if low_range { "163.84mV" } else { "40.96mV" });
+        sensor.switch_range(!low_range)?;
+        // The first conversion in the other range
+        thread::sleep(Duration::from_micros(sensor.conversion_period_us()));
+        let result = calibration_pass(sensor);
+        sensor.switch_range(low_range)?;
+        other_current_offset = result?.0;
+    }
+    Ok(match low_range {
+        true => Offsets { current: current_offset, current_hi: other_current_offset, voltage: voltage_offset },
+        false => Offsets { current: other_current_offset, current_hi: current_offset, voltage: voltage_offset },
+    })
+}
+
+fn calibration_pass(sensor: &Ina228) -> anyhow::Result<(f32, f32)> {
+    // INA228 Calibration
+    // Take 300 samples to calculate average offset for current and voltage
+    let mut average_current_offset = 0.0;
+    let mut average_voltage_offset = 0.0;
+    let mut current_square_sum = 0.0;
+    let mut voltage_square_sum = 0.0;
+    
+    info!("Starting calibration - taking 300 samples over 3 seconds...");
+    
+    for i in 0..300 {
+        match sensor.read_current() {
+            Ok(current) => {
+                average_current_offset += current;
+                current_square_sum += current * current;
+            },
+            Err(e) => {
+                return Err(anyhow::anyhow!("Current read error during calibration: {:?}", e));
+            }
+        }
+        
+        match sensor.read_voltage() {
+            Ok(voltage) => {
+                average_voltage_offset += voltage;
+                voltage_square_sum += voltage * voltage;
+            },
+            Err(e) => {
+                return Err(anyhow::anyhow!("Voltage read error during calibration: {:?}", e));
+            }
+        }
+        
+        // Log progress every 50 samples
+        if i % 50 == 0 {
+            info!("Calibration progress: {}/300 samples", i + 1);
+        }
+        
+        thread::sleep(Duration::from_millis(10));
+    }
+    
+    average_current_offset /= 300.0;
+    average_voltage_offset /= 300.0;
+    let current_stddev = (current_square_sum / 300.0 - average_current_offset * average_current_offset).max(0.0).sqrt();
+    let voltage_stddev = (voltage_square_sum / 300.0 - average_voltage_offset * average_voltage_offset).max(0.0).sqrt();
+    
+    info!("Calibration completed - Average Current Offset: {:.6}A (sd {:.6}A), Voltage Offset: {:.6}V (sd {:.6}V)", 
+          average_current_offset, current_stddev, average_voltage_offset, voltage_stddev);
+
+    // Reject offsets that look like a connected load
+    if current_stddev > CALIBRATION_MAX_CURRENT_STDDEV || voltage_stddev > CALIBRATION_MAX_VOLTAGE_STDDEV {
+        return Err(anyhow::anyhow!("readings are not stable, disconnect the load"));
+    }
+    if average_current_offset.abs() > CALIBRATION_MAX_CURRENT_OFFSET || average_voltage_offset.abs() > CALIBRATION_MAX_VOLTAGE_OFFSET {
+        return Err(anyhow::anyhow!("offset out of range, disconnect the load"));
+    }
+    
+    Ok((average_current_offset, average_voltage_offset))
+}
@@ -0,0 +1,298 @@
+// MQTT command channel for remote control
+// Subscribes to a command topic and acknowledges each command on a status topic.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::{sync::Arc, sync::Mutex};
+use std::time::{Duration, Instant};
+use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration, EventPayload, QoS};
+use crate::settings;
+use crate::transfer;
+use crate::tasks;
+
+pub enum Command {
+    Start,
+    Stop,
+    Channel(u8),
+    Tag(String),
+    Calibrate,
+    CalibrateConfirm,
+    CalibrateUndo,
+    Reboot,
+    Shutdown,
+    Reset,
+    Threshold(String, f32),
+    Wifi(String, String),
+    EnergyReset,
+    BatteryCalibrate(Option<f32>),
+    Ripple,
+}
+
+struct MqttState {
+    commands: Vec<Command>,
+    errors: Vec<String>,
+    connected: bool,
+    subscribed: bool,
+    announced: bool,    // Home Assistant discovery published since the last connection
+}
+
+// Home Assistant sensor state interval
+const HA_STATE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Home Assistant sensors: key, name, unit, device_class, state_class, diagnostic
+const HA_SENSORS: [(&str, &str, &str, &str, &str, bool); 6] = [
+    ("current", "Current", "A", "current", "measurement", false),
+    ("voltage", "Voltage", "V", "voltage", "measurement", false),
+    ("power", "Power", "W", "power", "measurement", false),
+    ("energy", "Energy", "Wh", "energy", "total_increasing", false),
+    ("battery", "Battery", "V", "voltage", "measurement", true),
+    ("rssi", "RSSI", "dBm", "signal_strength", "measurement", true),
+];
+
+pub struct MqttControl {
+    client: EspMqttClient<'static>,
+    state: Arc<Mutex<MqttState>>,
+    command_topic: String,
+    status_topic: String,
+    discovery_prefix: Option<String>,
+    device_id: String,
+    last_state: Option<Instant>,
+}
+
+impl MqttControl {
+    pub fn new(url: &str, client_id: &str, command_topic: &str, status_topic: &str) -> anyhow::Result<Self> {
+        let conf = MqttClientConfiguration {
+            client_id: Some(client_id),
+            ..Default::default()
+        };
+        let (client, mut connection) = EspMqttClient::new(url, &conf)?;
+        let state = Arc::new(Mutex::new(MqttState { commands: Vec::new(), errors: Vec::new(), connected: false, subscribed: false, announced: false }));
+        let event_state = state.clone();
+        let _th = tasks::spawn(&tasks::MQTT, move || {
+            info!("Start MQTT event thread.");
+            while let Ok(event) = connection.next() {
+                match event.payload() {
+                    EventPayload::Connected(_) => {
+                        info!("MQTT connected");
+                        let mut lck = event_state.lock().unwrap();
+                        lck.connected = true;
+                        lck.subscribed = false;
+                        lck.announced = false;
+                    },
+                    EventPayload::Disconnected => {
+                        info!("MQTT disconnected");
+                        event_state.lock().unwrap().connected = false;
+                    },
+                    EventPayload::Received { data, .. } => {
+                        let text = String::from_utf8_lossy(data).to_string();
+                        info!("MQTT command received: {}", redact(&text));
+                        match parse_command(&text) {
+                            Ok(cmd) => event_state.lock().unwrap().commands.push(cmd),
+                            Err(e) => {
+                                info!("MQTT command rejected: {}", e);
+                                event_state.lock().unwrap().errors.push(e);
+                            },
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            info!("MQTT event thread stopped.");
+        });
+        Ok(MqttControl {
+            client: client,
+            state: state,
+            command_topic: command_topic.to_string(),
+            status_topic: status_topic.to_string(),
+            discovery_prefix: None,
+            device_id: settings::mac_id(),
+            last_state: None,
+        })
+    }
+
+    // Announce the sensors to Home Assistant on every (re)connection
+    pub fn enable_discovery(&mut self, prefix: &str)
+    {
+        info!("Home Assistant discovery enabled: {}/sensor/{}", prefix, self.device_id);
+        self.discovery_prefix = Some(prefix.to_string());
+    }
+
+    fn state_topic(&self) -> String {
+        format!("mini-current-meter/{}/state", self.device_id)
+    }
+
+    fn announce(&mut self) -> bool {
+        let prefix = match self.discovery_prefix {
+            Some(ref prefix) => prefix.clone(),
+            None => return true,
+        };
+        let state_topic = self.state_topic();
+        for (key, name, unit, device_class, state_class, diagnostic) in HA_SENSORS {
+            let topic = format!("{}/sensor/{}/{}/config", prefix, self.device_id, key);
+            let payload = format!("{{\"name\":\"{}\",\"unique_id\":\"{}_{}\",\"state_topic\":\"{}\",\"value_template\":\"{{{{ value_json.{} }}}}\",\"unit_of_measurement\":\"{}\",\"device_class\":\"{}\",\"state_class\":\"{}\"{},\"device\":{{\"identifiers\":[\"{}\"],\"name\":\"Mini Current Meter\",\"model\":\"Mini Current Meter\",\"manufacturer\":\"hnz1102\"}}}}",
+                name, self.device_id, key, state_topic, key, unit, device_class, state_class,
+                if diagnostic { ",\"entity_category\":\"diagnostic\"" } else { "" },
+                self.device_id);
+            if let Err(e) = self.client.enqueue(&topic, QoS::AtLeastOnce, true, payload.as_bytes()) {
+                info!("MQTT discovery publish failed: {:?}", e);
+                return false;
+            }
+        }
+        info!("Home Assistant discovery published");
+        true
+    }
+
+    // Sensor state for Home Assistant, published every HA_STATE_INTERVAL
+    pub fn publish_state(&mut self, voltage: f32, current: f32, power: f32, energy_wh: f32, battery: f32, rssi: i32)
+    {
+        if self.discovery_prefix.is_none() || !self.state.lock().unwrap().connected {
+            return;
+        }
+        if let Some(last) = self.last_state {
+            if last.elapsed() < HA_STATE_INTERVAL {
+                return;
+            }
+        }
+        self.last_state = Some(Instant::now());
+        let payload = format!("{{\"current\":{:.5},\"voltage\":{:.5},\"power\":{:.5},\"energy\":{:.4},\"battery\":{:.2},\"rssi\":{}}}",
+            current, voltage, power, energy_wh, battery, rssi);
+        if let Err(e) = self.client.enqueue(&self.state_topic(), QoS::AtMostOnce, false, payload.as_bytes()) {
+            info!("MQTT publish failed: {:?}", e);
+        }
+    }
+
+    // Subscribe after (re)connection, acknowledge rejected commands and take the received ones.
+    // Subscribing and publishing is done here, not in the event thread, to avoid blocking the MQTT task.
+    pub fn poll(&mut self) -> Vec<Command> {
+        let need_announce = {
+            let lck = self.state.lock().unwrap();
+            lck.connected && !lck.announced
+        };
+        if need_announce {
+            let announced = self.announce();
+            self.state.lock().unwrap().announced = announced;
+        }
+        let mut lck = self.state.lock().unwrap();
+        if lck.connected && !lck.subscribed {
+            match self.client.subscribe(&self.command_topic, QoS::AtLeastOnce) {
+                Ok(_) => {
+                    info!("MQTT subscribed to {}", self.command_topic);
+                    lck.subscribed = true;
+                },
+                Err(e) => {
+                    info!("MQTT subscribe failed: {:?}", e);
+                }
+            }
+        }
+        for e in std::mem::take(&mut lck.errors) {
+            if let Err(e) = self.client.enqueue(&self.status_topic, QoS::AtMostOnce, false, format!("error: {}", e).as_bytes()) {
+                info!("MQTT publish failed: {:?}", e);
+            }
+        }
+        std::mem::take(&mut lck.commands)
+    }
+
+    pub fn ack(&mut self, msg: &str) {
+        if !self.state.lock().unwrap().connected {
+            return;
+        }
+        if let Err(e) = self.client.enqueue(&self.status_topic, QoS::AtMostOnce, false, msg.as_bytes()) {
+            info!("MQTT publish failed: {:?}", e);
+        }
+    }
+}
+
+// Commands whose arguments are secrets, logged without them (the log also goes to syslog)
+const SECRET_COMMANDS: [&str; 1] = ["wifi"];
+
+fn redact(text: &str) -> String {
+    let text = text.trim();
+    let cmd = text.split_whitespace().next().unwrap_or("");
+    if SECRET_COMMANDS.contains(&cmd.to_lowercase().as_str()) && cmd.len() < text.len() {
+        format!("{} <redacted>", cmd)
+    }
+    else {
+        text.to_string()
+    }
+}
+
+// Command format (text payload):
+//   start | stop | channel <1-4> | tag <name> | calibrate [confirm|undo] | reboot | shutdown | reset | threshold <name> <value>
+//   ripple
+//   wifi <ssid> [password]          the SSID may be "quoted", the password is the rest of the payload
+pub fn parse_command(text: &str) -> Result<Command, String> {
+    let mut args = text.trim().split_whitespace();
+    let cmd = args.next().unwrap_or("").to_lowercase();
+    match cmd.as_str() {
+        "start" => Ok(Command::Start),
+        "stop" => Ok(Command::Stop),
+        "calibrate" => {
+            match args.next() {
+                None => Ok(Command::Calibrate),
+                Some("confirm") => Ok(Command::CalibrateConfirm),
+                Some("undo") => Ok(Command::CalibrateUndo),
+                Some(_) => Err("calibrate accepts confirm or undo".to_string()),
+            }
+        },
+        "reboot" => Ok(Command::Reboot),
+        "shutdown" => Ok(Command::Shutdown),
+        "reset" => Ok(Command::Reset),
+        "ripple" => Ok(Command::Ripple),
+        "energy" => {
+            match args.next() {
+                Some("reset") => Ok(Command::EnergyReset),
+                _ => Err("energy accepts reset".to_string()),
+            }
+        },
+        "battery" => {
+            match (args.next(), args.next()) {
+                (Some("cal"), Some("reset")) => Ok(Command::BatteryCalibrate(None)),
+                (Some("cal"), Some(volts)) => {
+                    match volts.parse::<f32>() {
+                        Ok(v) if v > 0.0 => Ok(Command::BatteryCalibrate(Some(v))),
+                        _ => Err("battery cal requires the measured battery voltage".to_string()),
+                    }
+                },
+                _ => Err("battery accepts cal <volts> or cal reset".to_string()),
+            }
+        },
+        "channel" => {
+            match args.next().and_then(|v| v.parse::<u8>().ok()) {
+                Some(ch) if ch >= 1 && ch <= 4 => Ok(Command::Channel(ch)),
+                _ => Err("channel must be 1-4".to_string()),
+            }
+        },
+        "tag" => {
+            match args.next() {
+                Some(tag) if transfer::valid_name(tag) => Ok(Command::Tag(tag.to_string())),
+                Some(_) => Err("tag must not contain control characters or backslashes".to_string()),
+                None => Err("tag requires a name".to_string()),
+            }
+        },
+        "threshold" => {
+            let name = args.next();
+            let value = args.next().and_then(|v| v.parse::<f32>().ok());
+            match (name, value) {
+                (Some(name), Some(value)) => Ok(Command::Threshold(name.to_string(), value)),
+                _ => Err("threshold requires <name> <value>".to_string()),
+            }
+        },
+        "wifi" => {
+            // The SSID may be quoted to contain spaces, the password is the rest of the payload
+            let rest = text.trim().split_once(char::is_whitespace).map_or("", |(_, rest)| rest.trim_start());
+            let (ssid, pass) = match rest.strip_prefix('"') {
+                Some(quoted) => match quoted.split_once('"') {
+                    Some((ssid, pass)) => (ssid, pass.trim_start()),
+                    None => return Err("wifi SSID is missing the closing quote".to_string()),
+                },
+                None => rest.split_once(char::is_whitespace).map_or((rest, ""), |(ssid, pass)| (ssid, pass.trim_start())),
+            };
+            match ssid {
+                ssid if !ssid.is_empty() && ssid.len() <= 32 && pass.len() <= 64 => Ok(Command::Wifi(ssid.to_string(), pass.to_string())),
+                _ => Err("wifi requires <ssid> [password]".to_string()),
+            }
+        },
+        _ => Err(format!("unknown command '{}'", text.trim())),
+    }
+}
@@ -0,0 +1,49 @@
+// Network
+// One thread for the network path: the WiFi supervisor and the uploads run as async tasks on one executor,
+// so HTTP requests, retry timers and WiFi events are handled cooperatively without a thread each.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use esp_idf_hal::task::block_on;
+use esp_idf_svc::io::vfs::MountedEventfs;
+use esp_idf_svc::timer::EspTaskTimerService;
+use embassy_futures::join::join;
+use anyhow::Result;
+use crate::wifi::WifiSupervisor;
+use crate::transfer::TransferTask;
+use crate::tasks;
+
+// Sockets of the async HTTP client are polled through eventfd
+const EVENTFD_MAX_FDS: usize = 5;
+
+// Start the network thread with the tasks that are enabled
+pub fn start(wifi: Option<WifiSupervisor>, transfer: Option<TransferTask>) -> Result<()>
+{
+    if wifi.is_none() && transfer.is_none() {
+        return Ok(());
+    }
+    let eventfs = MountedEventfs::mount(EVENTFD_MAX_FDS)?;
+    let timer_service = EspTaskTimerService::new()?;
+    let _th = tasks::spawn(&tasks::NETWORK, move || {
+        info!("Start network thread.");
+        let _eventfs = eventfs;
+        block_on(join(
+            async {
+                if let Some(wifi) = wifi {
+                    if let Err(e) = wifi.run(&timer_service).await {
+                        info!("WiFi supervisor stopped: {:?}", e);
+                    }
+                }
+            },
+            async {
+                if let Some(transfer) = transfer {
+                    if let Err(e) = transfer.run(&timer_service).await {
+                        info!("Transfer task stopped: {:?}", e);
+                    }
+                }
+            },
+        ));
+    });
+    Ok(())
+}
@@ -0,0 +1,173 @@
+// No display
+// Stand-in for displayctl.rs in builds without the display feature: the meter sets the same state,
+// nothing is drawn and there is no display thread.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#[cfg(feature = "influx")]
+use crate::transfer::TransferStatus;
+use crate::jitter::JitterSummary;
+use crate::ripple::RippleResult;
+use crate::pulse::PulseStats;
+use crate::iotprofile::IotSummary;
+
+pub enum LoggingStatus {
+    Start,
+    Stop,
+    Armed,  // waiting for the trigger start condition
+}
+
+pub enum WifiStatus {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+// Quantity of an active alarm
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlarmQuantity {
+    Current,    // current limit trip
+    Buffer,     // buffer alert level passed
+}
+
+#[derive(Clone)]
+pub struct DisplayPanel;
+
+impl DisplayPanel {
+    pub fn new() -> DisplayPanel {
+        DisplayPanel
+    }
+
+    // There is no panel to switch off, so a press is never taken to wake it
+    pub fn wake(&mut self) -> bool {
+        true
+    }
+
+    pub fn set_current_status(&mut self, _status: LoggingStatus)
+    {
+    }
+
+    pub fn set_wifi_status(&mut self, _status: WifiStatus)
+    {
+    }
+
+    pub fn set_err_message(&mut self, _msg: String)
+    {
+    }
+
+    pub fn set_buffer_capacity(&mut self, _capacity: u32)
+    {
+    }
+
+    #[cfg(feature = "influx")]
+    pub fn set_transfer_status(&mut self, _status: TransferStatus)
+    {
+    }
+
+    pub fn set_battery(&mut self, _bat: f32)
+    {
+    }
+
+    pub fn set_buffer_watermark(&mut self, _wm: u32)
+    {
+    }
+
+    pub fn set_buffer_alert(&mut self, _alert: bool)
+    {
+    }
+
+    #[cfg(feature = "wifi")]
+    pub fn set_wifi_rssi(&mut self, _rssi: i32)
+    {
+    }
+
+    pub fn set_channel(&mut self, _channel: u32, _name: &str)
+    {
+    }
+
+    pub fn set_alarm(&mut self, _alarm: Option<AlarmQuantity>)
+    {
+    }
+
+    // No alarm is shown, so there is none to acknowledge
+    pub fn acknowledge_alarm(&mut self) -> bool {
+        false
+    }
+
+    pub fn set_tripped(&mut self, _tripped: bool)
+    {
+    }
+
+    pub fn set_adc_range(&mut self, _low_range: bool)
+    {
+    }
+
+    pub fn set_scan_results(&mut self, _results: Vec<(String, i8)>)
+    {
+    }
+
+    // The pages are never shown, a press is not taken to close one
+    pub fn clear_scan_results(&mut self) -> bool {
+        false
+    }
+
+    pub fn set_jitter(&mut self, _jitter: JitterSummary)
+    {
+    }
+
+    pub fn show_diagnostics(&mut self)
+    {
+    }
+
+    pub fn clear_diagnostics(&mut self) -> bool {
+        false
+    }
+
+    pub fn show_ripple(&mut self, _ripple: RippleResult)
+    {
+    }
+
+    pub fn clear_ripple(&mut self) -> bool {
+        false
+    }
+
+    pub fn set_ac_mode(&mut self, _ac_mode: bool)
+    {
+    }
+
+    pub fn set_demo(&mut self, _demo: bool)
+    {
+    }
+
+    pub fn set_divider(&mut self, _divider: bool)
+    {
+    }
+
+    pub fn set_config_error(&mut self, _error: bool)
+    {
+    }
+
+    pub fn set_iot(&mut self, _iot: Option<IotSummary>)
+    {
+    }
+
+    pub fn set_peak(&mut self, _current: f32, _power: f32)
+    {
+    }
+
+    pub fn set_efficiency(&mut self, _efficiency: Option<f32>)
+    {
+    }
+
+    pub fn set_pulse(&mut self, _pulse: Option<PulseStats>)
+    {
+    }
+
+    pub fn set_backlog(&mut self, _batches: usize)
+    {
+    }
+
+    pub fn set_capture(&mut self, _remaining: Option<u64>)
+    {
+    }
+}
@@ -0,0 +1,144 @@
+// OutputRule
+// GPIO outputs following a measurement condition, e.g. a fan switched on above 5W. The rules are
+// evaluated on every sample in the sampling thread, a condition has to hold for the hold time before
+// an output changes and the output is released only past the threshold minus the hysteresis.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+//
+// Rule format (cfg.toml output_rules or config set output_rules), up to 4 rules separated by ';':
+//   4=power>5           GPIO4 high while the power is above 5W (quantity: current, voltage, power; op: > or <)
+//   4=power>5~0.5       ... and low again below 4.5W
+//   5=current<0.001:500 GPIO5 high after the current is below 1mA for 500ms, low after 500ms above it
+
+use log::*;
+use std::time::{Duration, Instant};
+use esp_idf_hal::gpio::{PinDriver, AnyOutputPin, Output};
+use crate::CurrentLog;
+use crate::threshold::{self, Threshold};
+
+pub const MAX_RULES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RuleSpec {
+    pub gpio: i32,
+    threshold: Threshold,
+    hysteresis: f32,
+    hold: Duration,
+}
+
+impl RuleSpec {
+    // Condition to drive the output high
+    fn is_met(&self, measured: f32) -> bool {
+        self.threshold.is_met(measured)
+    }
+
+    // Condition to release it, past the threshold by the hysteresis
+    fn is_released(&self, measured: f32) -> bool {
+        let threshold = &self.threshold;
+        if threshold.above { measured < threshold.value - self.hysteresis } else { measured > threshold.value + self.hysteresis }
+    }
+}
+
+struct Rule {
+    spec: RuleSpec,
+    pin: PinDriver<'static, AnyOutputPin, Output>,
+    active: bool,
+    since: Option<Instant>,     // the condition to change the output holds since
+}
+
+pub struct OutputRules {
+    rules: Vec<Rule>,
+}
+
+impl OutputRules {
+    pub fn new() -> OutputRules {
+        OutputRules { rules: Vec::new() }
+    }
+
+    // Replace the rules, the outputs of the previous ones are driven low and released first.
+    // Rules on a GPIO that is not in allowed are left out and reported.
+    pub fn set(&mut self, specs: Vec<RuleSpec>, allowed: &[i32]) -> Result<(), String> {
+        for mut rule in self.rules.drain(..) {
+            let _ = rule.pin.set_low();
+        }
+        let mut errors: Vec<String> = Vec::new();
+        for spec in specs {
+            if !allowed.contains(&spec.gpio) {
+                errors.push(format!("GPIO{} cannot be used for an output rule", spec.gpio));
+                continue;
+            }
+            let pin = PinDriver::output(unsafe { AnyOutputPin::new(spec.gpio) })
+                .and_then(|mut pin| pin.set_low().map(|_| pin));
+            match pin {
+                Ok(pin) => {
+                    info!("Output rule on GPIO{}: {:?}", spec.gpio, spec);
+                    self.rules.push(Rule { spec: spec, pin: pin, active: false, since: None });
+                },
+                Err(e) => errors.push(format!("GPIO{}: {:?}", spec.gpio, e)),
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join(", ")),
+        }
+    }
+
+    // Called with every sample
+    pub fn update(&mut self, data: &CurrentLog)
+    {
+        for rule in self.rules.iter_mut() {
+            let measured = rule.spec.threshold.quantity.measure(data);
+            let change = match rule.active {
+                false => rule.spec.is_met(measured),
+                true => rule.spec.is_released(measured),
+            };
+            if !change {
+                rule.since = None;
+                continue;
+            }
+            let since = *rule.since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= rule.spec.hold {
+                rule.active = !rule.active;
+                rule.since = None;
+                let _ = if rule.active { rule.pin.set_high() } else { rule.pin.set_low() };
+                info!("Output GPIO{} {} at {:.5}", rule.spec.gpio, if rule.active { "on" } else { "off" }, measured);
+            }
+        }
+    }
+
+    // GPIO and output state of each rule
+    pub fn states(&self) -> Vec<(i32, bool)> {
+        self.rules.iter().map(|rule| (rule.spec.gpio, rule.active)).collect()
+    }
+}
+
+// Rules separated by ';', an empty text has none
+pub fn parse_rules(text: &str) -> Result<Vec<RuleSpec>, String> {
+    let mut specs: Vec<RuleSpec> = Vec::new();
+    for rule in text.split(';').map(|rule| rule.trim()).filter(|rule| !rule.is_empty()) {
+        let spec = parse_rule(rule).ok_or(format!("'{}' is not GPIO=quantity>value[~hysteresis][:hold_ms]", rule))?;
+        if specs.iter().any(|s| s.gpio == spec.gpio) {
+            return Err(format!("GPIO{} has more than one rule", spec.gpio));
+        }
+        specs.push(spec);
+    }
+    if specs.len() > MAX_RULES {
+        return Err(format!("more than {} rules", MAX_RULES));
+    }
+    Ok(specs)
+}
+
+fn parse_rule(text: &str) -> Option<RuleSpec> {
+    let (gpio, cond) = text.split_once('=')?;
+    let (cond, hold) = threshold::split_hold(cond)?;
+    let (cond, hysteresis) = match cond.split_once('~') {
+        Some((cond, hysteresis)) => (cond, hysteresis.trim().parse::<f32>().ok().filter(|h| *h >= 0.0)?),
+        None => (cond, 0.0),
+    };
+    Some(RuleSpec {
+        gpio: gpio.trim().parse::<i32>().ok()?,
+        threshold: threshold::parse(cond)?,
+        hysteresis: hysteresis,
+        hold: hold,
+    })
+}
@@ -0,0 +1,144 @@
+// PowerLoss
+// Detects an impending power loss from the battery voltage (low or dropping fast) and flushes
+// the newest buffered records to NVS before the 3.3V rail collapses. They are recovered on the next boot.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::collections::VecDeque;
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use crate::CurrentLog;
+
+const POWERLOSS_NAMESPACE: &str = "powerloss";
+// Battery voltage below which the regulator drops out (V)
+const POWER_LOSS_VOLTAGE: f32 = 3.3;
+// Voltage drop within POWER_LOSS_WINDOW readings regarded as a power loss (V)
+const POWER_LOSS_DROP: f32 = 0.3;
+const POWER_LOSS_WINDOW: usize = 10;
+// The flushed records are discarded when the voltage recovers above this (V)
+const POWER_RECOVERED_VOLTAGE: f32 = 3.5;
+// Records are written in blobs of FLUSH_CHUNK records, at most FLUSH_MAX_RECORDS of the newest
+const FLUSH_CHUNK: usize = 64;
+pub const FLUSH_MAX_RECORDS: usize = 128;
+// seq u32, clock u64, voltage, current, power, battery f32, crc u32
+const RECORD_SIZE: usize = 32;
+
+pub struct PowerMonitor {
+    nvs: EspNvs<NvsDefault>,
+    history: VecDeque<f32>,
+    flushed: bool,
+}
+
+impl PowerMonitor {
+    pub fn new(partition: EspNvsPartition<NvsDefault>) -> anyhow::Result<PowerMonitor> {
+        let nvs = EspNvs::new(partition, POWERLOSS_NAMESPACE, true)?;
+        Ok(PowerMonitor { nvs: nvs, history: VecDeque::new(), flushed: false })
+    }
+
+    // Called with every battery reading, true once when the power is about to fail
+    pub fn update(&mut self, battery: f32) -> bool {
+        if self.history.len() >= POWER_LOSS_WINDOW {
+            self.history.pop_front();
+        }
+        self.history.push_back(battery);
+        if self.flushed {
+            if battery >= POWER_RECOVERED_VOLTAGE {
+                // False alarm, the records are still in RAM
+                info!("Battery recovered: {:.2}V, discarding the flushed records", battery);
+                self.discard();
+                self.flushed = false;
+            }
+            return false;
+        }
+        let max = self.history.iter().cloned().fold(0.0, f32::max);
+        if battery < POWER_LOSS_VOLTAGE || (self.history.len() >= POWER_LOSS_WINDOW && max - battery > POWER_LOSS_DROP) {
+            info!("Power loss detected: battery {:.2}V (max {:.2}V)", battery, max);
+            return true;
+        }
+        false
+    }
+
+    // Write the given records (oldest first) to NVS, returns the number written
+    pub fn flush<'a, I>(&mut self, records: I) -> usize
+        where I: Iterator<Item = &'a CurrentLog>
+    {
+        self.flushed = true;
+        let mut count = 0;
+        let mut chunk: Vec<u8> = Vec::with_capacity(FLUSH_CHUNK * RECORD_SIZE);
+        for (i, it) in records.take(FLUSH_MAX_RECORDS).enumerate() {
+            chunk.extend_from_slice(&it.seq.to_le_bytes());
+            chunk.extend_from_slice(&(it.clock as u64).to_le_bytes());
+            chunk.extend_from_slice(&it.voltage.to_le_bytes());
+            chunk.extend_from_slice(&it.current.to_le_bytes());
+            chunk.extend_from_slice(&it.power.to_le_bytes());
+            chunk.extend_from_slice(&it.battery.to_le_bytes());
+            chunk.extend_from_slice(&it.crc.to_le_bytes());
+            if chunk.len() == FLUSH_CHUNK * RECORD_SIZE || i + 1 == FLUSH_MAX_RECORDS {
+                if !self.write_chunk(count / FLUSH_CHUNK, &chunk) {
+                    return count;
+                }
+                count += chunk.len() / RECORD_SIZE;
+                chunk.clear();
+            }
+        }
+        if !chunk.is_empty() && self.write_chunk(count / FLUSH_CHUNK, &chunk) {
+            count += chunk.len() / RECORD_SIZE;
+        }
+        if let Err(e) = self.nvs.set_u32("count", count as u32) {
+            info!("Failed to save the flushed record count: {:?}", e);
+            return 0;
+        }
+        info!("{} records flushed to NVS", count);
+        count
+    }
+
+    fn write_chunk(&mut self, index: usize, chunk: &[u8]) -> bool {
+        match self.nvs.set_blob(&format!("rec{}", index), chunk) {
+            Ok(_) => true,
+            Err(e) => {
+                info!("Failed to flush records to NVS: {:?}", e);
+                false
+            }
+        }
+    }
+
+    // Records flushed before the last power loss, removed from NVS
+    pub fn recover(&mut self) -> Vec<CurrentLog> {
+        let count = match self.nvs.get_u32("count") {
+            Ok(Some(count)) => count as usize,
+            _ => return Vec::new(),
+        };
+        let mut records = Vec::with_capacity(count);
+        let mut buffer = vec![0u8; FLUSH_CHUNK * RECORD_SIZE];
+        for index in 0..(count + FLUSH_CHUNK - 1) / FLUSH_CHUNK {
+            let data = match self.nvs.get_blob(&format!("rec{}", index), &mut buffer) {
+                Ok(Some(data)) => data,
+                _ => break,
+            };
+            for b in data.chunks_exact(RECORD_SIZE) {
+                let mut data = CurrentLog::default();
+                data.seq = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                data.clock = u64::from_le_bytes([b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11]]) as u128;
+                data.voltage = f32::from_le_bytes([b[12], b[13], b[14], b[15]]);
+                data.current = f32::from_le_bytes([b[16], b[17], b[18], b[19]]);
+                data.power = f32::from_le_bytes([b[20], b[21], b[22], b[23]]);
+                data.battery = f32::from_le_bytes([b[24], b[25], b[26], b[27]]);
+                data.crc = u32::from_le_bytes([b[28], b[29], b[30], b[31]]);
+                // Skip records damaged by the power loss
+                if data.calc_crc() == data.crc {
+                    records.push(data);
+                }
+            }
+        }
+        self.discard();
+        records
+    }
+
+    fn discard(&mut self)
+    {
+        let _ = self.nvs.remove("count");
+        for index in 0..(FLUSH_MAX_RECORDS + FLUSH_CHUNK - 1) / FLUSH_CHUNK {
+            let _ = self.nvs.remove(&format!("rec{}", index));
+        }
+    }
+}
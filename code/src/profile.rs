@@ -0,0 +1,80 @@
+// Profile
+// Named logging profiles bundling the sampling interval, the INA228 conversion time and averaging,
+// the record buffer share of the heap and the upload cadence, so only one setting has to be chosen.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use crate::ina228::AdcConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    Standard,
+    Precision,
+    FastTransient,
+    LongBatteryLife,
+}
+
+pub const PROFILES: [Profile; 4] = [Profile::Standard, Profile::Precision, Profile::FastTransient, Profile::LongBatteryLife];
+
+impl Profile {
+    pub fn from_name(name: &str) -> Option<Profile> {
+        match name.trim() {
+            "standard" => Some(Profile::Standard),
+            "precision" => Some(Profile::Precision),
+            "fast_transient" => Some(Profile::FastTransient),
+            "long_battery_life" => Some(Profile::LongBatteryLife),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::Standard => "standard",
+            Profile::Precision => "precision",
+            Profile::FastTransient => "fast_transient",
+            Profile::LongBatteryLife => "long_battery_life",
+        }
+    }
+
+    // Next profile for the button menu
+    pub fn next(&self) -> Profile {
+        let index = PROFILES.iter().position(|p| p == self).unwrap_or(0);
+        PROFILES[(index + 1) % PROFILES.len()]
+    }
+
+    // Sampling period of the sampler thread without the conversion-ready alert (ms)
+    pub fn sampling_period_ms(&self) -> u64 {
+        match self {
+            Profile::Standard => 100,
+            Profile::Precision => 1000,
+            Profile::FastTransient => 10,
+            Profile::LongBatteryLife => 10000,
+        }
+    }
+
+    // Conversion times: 0x2 = 150us, 0x5 = 1052us, 0x7 = 4120us. Averaging: 0x1 = 4, 0x6 = 512, 0x7 = 1024 samples
+    pub fn adc_config(&self) -> AdcConfig {
+        match self {
+            Profile::Standard => AdcConfig { vbus_ct: 0x5, vshunt_ct: 0x7, vtemp_ct: 0x5, avg: 0x6 },
+            Profile::Precision => AdcConfig { vbus_ct: 0x5, vshunt_ct: 0x7, vtemp_ct: 0x5, avg: 0x7 },
+            Profile::FastTransient => AdcConfig { vbus_ct: 0x2, vshunt_ct: 0x2, vtemp_ct: 0x2, avg: 0x1 },
+            Profile::LongBatteryLife => AdcConfig { vbus_ct: 0x5, vshunt_ct: 0x7, vtemp_ct: 0x5, avg: 0x7 },
+        }
+    }
+
+    // Share of the heap for the record buffer, None: buffer_heap_percent of cfg.toml
+    pub fn heap_percent(&self) -> Option<usize> {
+        match self {
+            Profile::Standard | Profile::Precision => None,
+            Profile::FastTransient | Profile::LongBatteryLife => Some(80),
+        }
+    }
+
+    // Smallest upload batch and interval (ms), the link quality may make them larger
+    pub fn upload_pacing(&self) -> (usize, u64) {
+        match self {
+            Profile::Standard | Profile::Precision | Profile::FastTransient => (0, 0),
+            Profile::LongBatteryLife => (64, 300000),
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Protection
+// Hard current limit: trips when the current exceeds the limit for the hold time,
+// latched until reset by a button press or a remote command.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::time::{Duration, Instant};
+
+pub struct Protection {
+    limit: f32,             // A, 0.0 disables the protection
+    hold: Duration,
+    over_since: Option<Instant>,
+    tripped: bool,
+}
+
+impl Protection {
+    pub fn new(limit: f32, hold_ms: u64) -> Protection {
+        Protection { limit: limit, hold: Duration::from_millis(hold_ms), over_since: None, tripped: false }
+    }
+
+    // Returns true only when the protection trips on this sample
+    pub fn check(&mut self, current: f32) -> bool {
+        if self.limit <= 0.0 || self.tripped {
+            return false;
+        }
+        if current.abs() > self.limit {
+            let since = *self.over_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= self.hold {
+                info!("Current limit tripped: {:.4}A > {:.4}A", current, self.limit);
+                self.tripped = true;
+                self.over_since = None;
+                return true;
+            }
+        }
+        else {
+            self.over_since = None;
+        }
+        false
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.tripped
+    }
+
+    pub fn reset(&mut self)
+    {
+        info!("Current limit trip reset");
+        self.tripped = false;
+        self.over_since = None;
+    }
+
+    pub fn set_limit(&mut self, limit: f32)
+    {
+        info!("Current limit set to {:.4}A", limit);
+        self.limit = limit;
+    }
+}
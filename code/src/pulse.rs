@@ -0,0 +1,121 @@
+// Pulse
+// Pulse statistics of a switched load, e.g. a LoRa radio or a motor: on/off transitions at a current
+// threshold, counted over a report interval with the average pulse width, duty cycle and charge per pulse.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::time::{Duration, Instant, SystemTime};
+
+// The load is off again below this part of the threshold
+const OFF_HYSTERESIS: f32 = 0.9;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct PulseStats {
+    pub count: u32,         // pulses ended in the interval
+    pub width_ms: f32,      // average pulse width, 0 without pulses
+    pub duty_cycle: f32,    // % of the interval the load was on
+    pub charge_mc: f32,     // average charge per pulse in mC, 0 without pulses
+    pub clock: u128,        // ns, end of the interval
+}
+
+pub struct PulseDetector {
+    threshold: f32,         // A
+    interval: Duration,
+    start: Instant,         // of the interval
+    last: Option<Instant>,
+    on_since: Option<Instant>,  // start of the running pulse
+    pulse_charge: f32,      // C of the running pulse
+    count: u32,
+    width_sum: f32,         // s of the pulses ended in the interval
+    charge_sum: f32,        // C of the pulses ended in the interval
+    on_time: f32,           // s the load was on in the interval
+    latest: Option<PulseStats>,     // last interval for the display
+    pending: Option<PulseStats>,    // last interval not sent yet
+}
+
+impl PulseDetector {
+    pub fn new(threshold: f32, interval_secs: u64) -> PulseDetector {
+        info!("Pulse detection: threshold {:.4}A, interval {}s", threshold, interval_secs);
+        PulseDetector {
+            threshold: threshold,
+            interval: Duration::from_secs(interval_secs),
+            start: Instant::now(),
+            last: None,
+            on_since: None,
+            pulse_charge: 0.0,
+            count: 0,
+            width_sum: 0.0,
+            charge_sum: 0.0,
+            on_time: 0.0,
+            latest: None,
+            pending: None,
+        }
+    }
+
+    // Track the load state up to the sample time, an interval is completed every report interval
+    pub fn update(&mut self, current: f32, instant: Instant)
+    {
+        if let (Some(last), Some(_)) = (self.last, self.on_since) {
+            let dt = instant.saturating_duration_since(last).as_secs_f32();
+            self.on_time += dt;
+            self.pulse_charge += current * dt;
+        }
+        self.last = Some(instant);
+
+        match self.on_since {
+            None if current >= self.threshold => {
+                self.on_since = Some(instant);
+                self.pulse_charge = 0.0;
+            },
+            Some(since) if current < self.threshold * OFF_HYSTERESIS => {
+                // A pulse across intervals is counted in the one it ends
+                self.count += 1;
+                self.width_sum += instant.saturating_duration_since(since).as_secs_f32();
+                self.charge_sum += self.pulse_charge;
+                self.on_since = None;
+            },
+            _ => {},
+        }
+
+        let elapsed = instant.saturating_duration_since(self.start);
+        if elapsed >= self.interval {
+            self.finish(elapsed.as_secs_f32());
+            self.start = instant;
+        }
+    }
+
+    fn finish(&mut self, secs: f32)
+    {
+        let (width_ms, charge_mc) = match self.count {
+            0 => (0.0, 0.0),
+            n => (self.width_sum / n as f32 * 1_000.0, self.charge_sum / n as f32 * 1_000.0),
+        };
+        let stats = PulseStats {
+            count: self.count,
+            width_ms: width_ms,
+            duty_cycle: if secs > 0.0 { (self.on_time / secs * 100.0).min(100.0) } else { 0.0 },
+            charge_mc: charge_mc,
+            clock: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+        };
+        self.count = 0;
+        self.width_sum = 0.0;
+        self.charge_sum = 0.0;
+        self.on_time = 0.0;
+        self.latest = Some(stats);
+        self.pending = Some(stats);
+    }
+
+    pub fn latest(&self) -> Option<PulseStats> {
+        self.latest
+    }
+
+    pub fn pending(&self) -> Option<&PulseStats> {
+        self.pending.as_ref()
+    }
+
+    pub fn sent(&mut self)
+    {
+        self.pending = None;
+    }
+}
@@ -0,0 +1,103 @@
+// Ripple
+// Ripple analysis of the load current: a burst of fast conversions is transformed with a 256-point FFT
+// for the dominant ripple frequency and its amplitude, e.g. of a switching regulator or a PWM load.
+// The burst rate is limited by the I2C reads to several hundred samples/s, faster ripple is aliased.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::f32::consts::PI;
+
+pub const FFT_SIZE: usize = 256;
+// Smaller components are regarded as noise, no ripple frequency is reported (A)
+const MIN_RIPPLE_AMPLITUDE: f32 = 0.0001;
+
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct RippleResult {
+    pub frequency: f32,     // Hz of the strongest component, 0 if there is no ripple
+    pub amplitude: f32,     // A, peak of the strongest component
+    pub peak_to_peak: f32,  // A, of the captured current
+    pub sample_rate: f32,   // samples/s of the burst
+    pub clock: u128,        // ns, end of the burst
+}
+
+// Analyze the last FFT_SIZE currents sampled at sample_rate, None if there are too few
+pub fn analyze(currents: &[f32], sample_rate: f32, clock: u128) -> Option<RippleResult> {
+    if currents.len() < FFT_SIZE || sample_rate <= 0.0 {
+        return None;
+    }
+    let samples = &currents[currents.len() - FFT_SIZE..];
+    let mean = samples.iter().sum::<f32>() / FFT_SIZE as f32;
+    let max = samples.iter().cloned().fold(f32::MIN, f32::max);
+    let min = samples.iter().cloned().fold(f32::MAX, f32::min);
+
+    // Hann window on the AC part, the window sum scales the bins back to amplitudes
+    let mut re = [0.0f32; FFT_SIZE];
+    let mut im = [0.0f32; FFT_SIZE];
+    let mut window_sum = 0.0;
+    for (i, current) in samples.iter().enumerate() {
+        let w = 0.5 - 0.5 * (2.0 * PI * i as f32 / (FFT_SIZE - 1) as f32).cos();
+        re[i] = (current - mean) * w;
+        window_sum += w;
+    }
+    fft(&mut re, &mut im);
+
+    // Strongest bin below Nyquist, DC excluded
+    let magnitude = |k: usize| (re[k] * re[k] + im[k] * im[k]).sqrt();
+    let peak = (1..FFT_SIZE / 2).max_by(|&a, &b| magnitude(a).total_cmp(&magnitude(b))).unwrap();
+    let amplitude = 2.0 * magnitude(peak) / window_sum;
+    // Parabolic interpolation between the neighbouring bins
+    let offset = if peak > 1 && peak < FFT_SIZE / 2 - 1 {
+        let (l, c, r) = (magnitude(peak - 1), magnitude(peak), magnitude(peak + 1));
+        let d = l - 2.0 * c + r;
+        if d != 0.0 { 0.5 * (l - r) / d } else { 0.0 }
+    }
+    else {
+        0.0
+    };
+    let frequency = if amplitude >= MIN_RIPPLE_AMPLITUDE { (peak as f32 + offset) * sample_rate / FFT_SIZE as f32 } else { 0.0 };
+    Some(RippleResult {
+        frequency: frequency,
+        amplitude: amplitude,
+        peak_to_peak: max - min,
+        sample_rate: sample_rate,
+        clock: clock,
+    })
+}
+
+// In-place iterative radix-2 FFT, the length must be a power of two
+fn fft(re: &mut [f32], im: &mut [f32])
+{
+    let n = re.len();
+    // Bit-reversed order
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    // Butterflies
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (s, c) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * c - im[b] * s;
+                let ti = re[b] * s + im[b] * c;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+}
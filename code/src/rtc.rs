@@ -0,0 +1,200 @@
+// RTC
+// DS3231 real-time clock on the shared I2C bus. Without WiFi the records would carry the time since
+// 1970 of an unset clock; with the RTC the wall clock is set from it at boot, and every NTP sync sets
+// the RTC again and measures how far it had drifted since the previous one. Setting it waits for second
+// edges, so after boot the RTC is handed to its own thread (RtcSync).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::{sync::Arc, sync::Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use chrono::{Datelike, NaiveDate, Timelike, DateTime};
+use esp_idf_hal::i2c;
+use esp_idf_hal::delay::BLOCK;
+use crate::tasks;
+
+// Fixed address of the DS3231
+pub const DS3231_ADDR: u8 = 0x68;
+// Fast mode, the DS3231 has no fast mode plus
+pub const DS3231_MAX_I2C_KHZ: u32 = 400;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_STATUS: u8 = 0x0F;
+const STATUS_OSF: u8 = 0x80;        // oscillator stopped, the time is not valid
+const CENTURY: u8 = 0x80;           // month register, years 2100-2199
+// The seconds register is polled for its change to get the start of a second
+const EDGE_POLL_MS: u64 = 2;
+const EDGE_TIMEOUT_MS: u64 = 1100;
+
+// RTC against the NTP time at a sync
+#[derive(Debug, Clone, Copy)]
+pub struct RtcDrift {
+    pub offset_ms: i64,             // RTC minus NTP time before it was set again
+    pub drift_ppm: Option<f32>,     // rate error since the previous sync of this boot
+}
+
+pub struct Ds3231 {
+    i2c: Arc<Mutex<i2c::I2cDriver<'static>>>,
+    last_set: Option<u128>,     // wall clock when the RTC was last set (ns)
+    last_drift: Option<RtcDrift>,
+}
+
+impl Ds3231 {
+    pub fn new(i2c: Arc<Mutex<i2c::I2cDriver<'static>>>) -> Ds3231 {
+        Ds3231 { i2c: i2c, last_set: None, last_drift: None }
+    }
+
+    // Unix time at the start of the next second of the RTC, None if the oscillator has stopped
+    // (no backup battery or never set). Waits up to one second.
+    pub fn read_time(&self) -> anyhow::Result<Option<Duration>> {
+        let mut status = [0u8; 1];
+        self.read_reg(REG_STATUS, &mut status)?;
+        if status[0] & STATUS_OSF != 0 {
+            return Ok(None);
+        }
+        let (secs, _) = self.next_second()?;
+        Ok(Some(Duration::from_secs(secs)))
+    }
+
+    // Compare with the NTP time and set the RTC to it, called after each sync
+    pub fn discipline(&mut self) -> anyhow::Result<Option<RtcDrift>> {
+        let mut status = [0u8; 1];
+        self.read_reg(REG_STATUS, &mut status)?;
+        let mut drift = None;
+        if status[0] & STATUS_OSF == 0 {
+            let (secs, wall_ns) = self.next_second()?;
+            let offset_ms = (secs as i128 * 1000 - (wall_ns / 1_000_000) as i128) as i64;
+            let drift_ppm = self.last_set.filter(|set| wall_ns > *set)
+                .map(|set| offset_ms as f32 * 1e12 / (wall_ns - set) as f32);
+            drift = Some(RtcDrift { offset_ms: offset_ms, drift_ppm: drift_ppm });
+        }
+        // Written at the start of a wall clock second, the RTC starts the second at the write
+        let now_ns = wall_now_ns();
+        let next = now_ns / 1_000_000_000 + 1;
+        thread::sleep(Duration::from_nanos((next * 1_000_000_000 - now_ns) as u64));
+        self.set_time(next as u64)?;
+        self.last_set = Some(next * 1_000_000_000);
+        self.last_drift = drift;
+        Ok(drift)
+    }
+
+    // Result of the last sync, None before it or when the RTC had lost the time
+    pub fn last_drift(&self) -> Option<RtcDrift> {
+        self.last_drift
+    }
+
+    // Seconds since the epoch, also clears the oscillator stop flag
+    fn set_time(&self, secs: u64) -> anyhow::Result<()> {
+        let time = DateTime::from_timestamp(secs as i64, 0).ok_or(anyhow::anyhow!("invalid time {}", secs))?.naive_utc();
+        let century = if time.year() >= 2100 { CENTURY } else { 0 };
+        let data = [
+            REG_SECONDS,
+            to_bcd(time.second()),
+            to_bcd(time.minute()),
+            to_bcd(time.hour()),        // 24 hour mode
+            time.weekday().number_from_monday() as u8,
+            to_bcd(time.day()),
+            to_bcd(time.month()) | century,
+            to_bcd((time.year() % 100) as u32),
+        ];
+        let mut i2c = self.i2c.lock().unwrap();
+        i2c.write(DS3231_ADDR, &data, BLOCK)?;
+        i2c.write(DS3231_ADDR, &[REG_STATUS, 0x00], BLOCK)?;
+        Ok(())
+    }
+
+    // Wait for the seconds register to change, returns the new time and the wall clock at the change (ns)
+    fn next_second(&self) -> anyhow::Result<(u64, u128)> {
+        let first = self.read_time_regs()?;
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(EDGE_TIMEOUT_MS) {
+            thread::sleep(Duration::from_millis(EDGE_POLL_MS));
+            let wall_ns = wall_now_ns();
+            let regs = self.read_time_regs()?;
+            if regs[0] != first[0] {
+                let secs = decode_time(&regs).ok_or(anyhow::anyhow!("invalid RTC time {:02X?}", regs))?;
+                return Ok((secs, wall_ns));
+            }
+        }
+        Err(anyhow::anyhow!("RTC seconds did not advance"))
+    }
+
+    fn read_time_regs(&self) -> anyhow::Result<[u8; 7]> {
+        let mut regs = [0u8; 7];
+        self.read_reg(REG_SECONDS, &mut regs)?;
+        Ok(regs)
+    }
+
+    fn read_reg(&self, reg: u8, data: &mut [u8]) -> anyhow::Result<()> {
+        let mut i2c = self.i2c.lock().unwrap();
+        i2c.write_read(DS3231_ADDR, &[reg; 1], data, BLOCK)?;
+        Ok(())
+    }
+}
+
+// Sets the RTC after an NTP sync from its own thread, discipline() takes up to two seconds and
+// would stall the main loop
+pub struct RtcSync {
+    tx: Sender<()>,
+    last_drift: Arc<Mutex<Option<RtcDrift>>>,
+}
+
+impl RtcSync {
+    pub fn start(mut rtc: Ds3231) -> RtcSync {
+        let (tx, rx) = mpsc::channel::<()>();
+        let last_drift = Arc::new(Mutex::new(None));
+        let result = last_drift.clone();
+        let _th = tasks::spawn(&tasks::RTC, move || {
+            info!("Start RTC thread.");
+            for _ in rx {
+                match rtc.discipline() {
+                    Ok(Some(drift)) => info!("RTC set from NTP, it was {}ms off{}", drift.offset_ms,
+                        drift.drift_ppm.map_or(String::new(), |ppm| format!(", drift {:.3}ppm", ppm))),
+                    Ok(None) => info!("RTC set from NTP, it had lost the time"),
+                    Err(e) => info!("RTC not set: {:?}", e),
+                }
+                *result.lock().unwrap() = rtc.last_drift();
+            }
+        });
+        RtcSync { tx: tx, last_drift: last_drift }
+    }
+
+    // Set the RTC from the wall clock, returns at once
+    pub fn request(&self)
+    {
+        let _ = self.tx.send(());
+    }
+
+    // Result of the last sync, see Ds3231::last_drift()
+    pub fn last_drift(&self) -> Option<RtcDrift> {
+        *self.last_drift.lock().unwrap()
+    }
+}
+
+fn wall_now_ns() -> u128 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos()
+}
+
+fn to_bcd(value: u32) -> u8 {
+    ((value / 10) << 4 | value % 10) as u8
+}
+
+fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0x0F) as u32
+}
+
+// Seconds, minutes, hours, weekday, date, month/century and year registers to the unix time
+fn decode_time(regs: &[u8; 7]) -> Option<u64> {
+    let hour = match regs[2] & 0x40 {
+        // 12 hour mode, bit 5 is PM
+        0x40 => from_bcd(regs[2] & 0x1F) % 12 + if regs[2] & 0x20 != 0 { 12 } else { 0 },
+        _ => from_bcd(regs[2] & 0x3F),
+    };
+    let year = 2000 + from_bcd(regs[6]) as i32 + if regs[5] & CENTURY != 0 { 100 } else { 0 };
+    let time = NaiveDate::from_ymd_opt(year, from_bcd(regs[5] & 0x1F), from_bcd(regs[4] & 0x3F))?
+        .and_hms_opt(hour, from_bcd(regs[1] & 0x7F), from_bcd(regs[0] & 0x7F))?;
+    u64::try_from(time.and_utc().timestamp()).ok()
+}
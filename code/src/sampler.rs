@@ -0,0 +1,400 @@
+// Sampler
+// Dedicated sensor sampling thread feeding the main loop through a bounded channel,
+// so WiFi reconnects, NVS writes and button handling never cause missed samples.
+// The current limit and the output rules are checked here on every sample.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::thread;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+use esp_idf_hal::gpio::{PinDriver, AnyInputPin, AnyOutputPin, Input, Output};
+use crate::CurrentLog;
+use crate::currentlogs::SensorReading;
+use crate::ina228::{Ina228, Measurement};
+use crate::protection::Protection;
+use crate::outputrule::{OutputRules, RuleSpec};
+use crate::acrms::AcWindow;
+use crate::jitter::JitterStats;
+use crate::timebase;
+use crate::tasks;
+
+// Samples waiting for the main loop, new samples are dropped when full
+const SAMPLE_QUEUE_SIZE: usize = 256;
+// Fast conversions read back-to-back in AC mode before yielding to the other tasks
+const AC_BURST_SIZE: usize = 64;
+// Consecutive POWER vs V x I mismatches (or matches) to set (or clear) the configuration error
+const POWER_CHECK_COUNT: u32 = 10;
+
+// Calibration offsets, the current offset is measured in each shunt voltage range
+#[derive(Clone, Copy, Default)]
+pub struct Offsets {
+    pub current: f32,       // A, 40.96mV range
+    pub current_hi: f32,    // A, 163.84mV range
+    pub voltage: f32,       // V
+}
+
+impl Offsets {
+    pub fn current_in(&self, low_range: bool) -> f32 {
+        if low_range { self.current } else { self.current_hi }
+    }
+}
+
+pub struct Sample {
+    pub data: CurrentLog,
+    pub instant: Instant,   // monotonic time of the sample
+    pub settling: bool,     // converted in the previous range, not to be recorded
+}
+
+struct SamplerState {
+    offsets: Offsets,
+    protection: Protection,
+    cutoff_pin: Option<PinDriver<'static, AnyOutputPin, Output>>,
+    outputs: OutputRules,
+    low_range: bool,
+    period_ms: u64,         // sampling period without the conversion-ready alert
+    dropped: u32,
+    read_errors: u32,
+    alert_misses: u32,      // conversion-ready alerts not seen within a sampling period
+    power_check_count: u32,
+    config_error: bool,     // POWER register does not match V x I, SHUNT_CAL is wrong
+    stopped: bool,          // the thread exits at the next sample
+    jitter: JitterStats,    // intervals between the samples, not measured in AC mode
+}
+
+impl SamplerState {
+    // Fast-path current limit check
+    fn check_limit(&mut self, current: f32)
+    {
+        if self.protection.check(current) {
+            if let Some(ref mut pin) = self.cutoff_pin {
+                let _ = pin.set_high();
+            }
+        }
+    }
+
+    // Returns false when the main loop has gone
+    fn send(&mut self, tx: &SyncSender<Sample>, sample: Sample) -> bool {
+        match tx.try_send(sample) {
+            Ok(_) => {},
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped % 100 == 1 {
+                    info!("Sample queue full, {} samples dropped", self.dropped);
+                }
+            },
+            Err(TrySendError::Disconnected(_)) => return false,
+        }
+        true
+    }
+
+    fn missed_alert(&mut self)
+    {
+        self.alert_misses += 1;
+        if self.alert_misses % 100 == 1 {
+            info!("No conversion-ready alert within the sampling period, {} missed", self.alert_misses);
+        }
+    }
+
+    fn check_power(&mut self, m: &Measurement)
+    {
+        // Count towards the opposite of the current state
+        if m.power_mismatch() != self.config_error {
+            self.power_check_count += 1;
+        }
+        else {
+            self.power_check_count = 0;
+        }
+        if self.power_check_count >= POWER_CHECK_COUNT {
+            self.power_check_count = 0;
+            self.config_error = !self.config_error;
+            match self.config_error {
+                true => info!("POWER register does not match V x I ({:.5}W vs {:.5}W), check shunt_resistance",
+                    m.power, m.voltage * m.current.abs()),
+                false => info!("POWER register matches V x I again"),
+            }
+        }
+    }
+}
+
+pub struct Sampler {
+    sensor: Arc<Mutex<Ina228>>,
+    sensor2: Option<Arc<Mutex<Ina228>>>,
+    state: Arc<Mutex<SamplerState>>,
+    rx: Receiver<Sample>,
+}
+
+impl Sampler {
+    pub fn start(sensor: Ina228, sensor2: Option<Ina228>, protection: Protection,
+        cutoff_pin: Option<PinDriver<'static, AnyOutputPin, Output>>, outputs: OutputRules,
+        alert_pin: Option<PinDriver<'static, AnyInputPin, Input>>,
+        offsets: Offsets, period_ms: u64, ac_window_ms: u64) -> Sampler
+    {
+        let low_range = sensor.is_low_range();
+        let sensor = Arc::new(Mutex::new(sensor));
+        let sensor2 = sensor2.map(|s| Arc::new(Mutex::new(s)));
+        let state = Arc::new(Mutex::new(SamplerState {
+            offsets: offsets,
+            protection: protection,
+            cutoff_pin: cutoff_pin,
+            outputs: outputs,
+            low_range: low_range,
+            period_ms: period_ms,
+            dropped: 0,
+            read_errors: 0,
+            alert_misses: 0,
+            power_check_count: 0,
+            config_error: false,
+            stopped: false,
+            jitter: JitterStats::default(),
+        }));
+        let (tx, rx) = sync_channel::<Sample>(SAMPLE_QUEUE_SIZE);
+        let th_sensor = sensor.clone();
+        let th_sensor2 = sensor2.clone();
+        let th_state = state.clone();
+        let _th = tasks::spawn(&tasks::SAMPLER, move || {
+            info!("Start sampler thread.");
+            if ac_window_ms > 0 {
+                Self::run_ac(th_sensor, th_state, tx, ac_window_ms);
+                return;
+            }
+            // Samples are taken on a fixed schedule of the esp_timer and stamped with the scheduled time
+            let mut next_us = timebase::timer_us();
+            let mut last_us: Option<i64> = None;   // when the previous sample was read
+            loop {
+                // Wait for the conversion-ready alert or the next sampling period
+                let sample_us = match alert_pin {
+                    Some(ref pin) => {
+                        // At most one sampling period, then the sample is taken without the alert
+                        let period_us = th_state.lock().unwrap().period_ms as i64 * 1000;
+                        let wait_us = timebase::timer_us();
+                        while pin.is_high() && timebase::timer_us() - wait_us < period_us {
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                        if pin.is_high() {
+                            th_state.lock().unwrap().missed_alert();
+                        }
+                        timebase::timer_us()
+                    },
+                    None => {
+                        let period_us = th_state.lock().unwrap().period_ms as i64 * 1000;
+                        next_us += period_us;
+                        let now_us = timebase::timer_us();
+                        if next_us > now_us {
+                            thread::sleep(Duration::from_micros((next_us - now_us) as u64));
+                        }
+                        else if now_us - next_us > period_us {
+                            // Fell behind by more than a period (e.g. calibration), restart the schedule
+                            next_us = now_us;
+                        }
+                        next_us
+                    },
+                };
+                let read_us = timebase::timer_us();
+                let mut data = CurrentLog::default();
+                let instant = Instant::now();
+                // set clock in ns
+                data.clock = timebase::wall_ns(sample_us);
+
+                let (measurement, settling, low_range, conversion_us) = {
+                    let mut sensor = th_sensor.lock().unwrap();
+                    // With the shunt voltage auto-ranging
+                    let measurement = sensor.read_all();
+                    if alert_pin.is_some() {
+                        // Release the latched ALERT pin for the next conversion
+                        let _ = sensor.conversion_ready();
+                    }
+                    (measurement, sensor.is_settling(), sensor.is_low_range(), sensor.conversion_period_us())
+                };
+                if let Some(ref sensor2) = th_sensor2 {
+                    data.second = Self::read_second(&mut sensor2.lock().unwrap());
+                }
+
+                let mut state = th_state.lock().unwrap();
+                if state.stopped {
+                    break;
+                }
+                state.low_range = low_range;
+                // The alert comes with every conversion, otherwise samples are due every period
+                if let Some(last_us) = last_us {
+                    let target_us = match alert_pin {
+                        Some(_) => conversion_us,
+                        None => state.period_ms * 1000,
+                    };
+                    state.jitter.add((read_us - last_us) as u64, target_us);
+                }
+                last_us = Some(read_us);
+                match measurement {
+                    Ok(m) => {
+                        data.voltage = m.voltage - state.offsets.voltage;
+                        data.current = m.current - state.offsets.current_in(low_range);
+                        data.power = m.power;
+                        if !settling {
+                            state.check_power(&m);
+                        }
+                    },
+                    Err(e) => {
+                        info!("{:?}", e);
+                        state.read_errors += 1;
+                        continue;
+                    }
+                }
+                state.check_limit(data.current);
+                state.outputs.update(&data);
+                if !state.send(&tx, Sample { data: data, instant: instant, settling: settling }) {
+                    break;
+                }
+            }
+        });
+        Sampler { sensor: sensor, sensor2: sensor2, state: state, rx: rx }
+    }
+
+    // Second sensor without offsets or current limit, None while it settles in a new range
+    fn read_second(sensor: &mut Ina228) -> Option<SensorReading> {
+        match sensor.read_all() {
+            Ok(_) if sensor.is_settling() => None,
+            Ok(m) => Some(SensorReading { voltage: m.voltage, current: m.current, power: m.power }),
+            Err(e) => {
+                info!("{:?}", e);
+                None
+            }
+        }
+    }
+
+    // AC mode: bursts of fast conversions, one sample with the RMS values per window
+    fn run_ac(sensor: Arc<Mutex<Ina228>>, state: Arc<Mutex<SamplerState>>, tx: SyncSender<Sample>, window_ms: u64)
+    {
+        info!("AC mode: {}ms window", window_ms);
+        let mut window = AcWindow::new();
+        let mut start = Instant::now();
+        loop {
+            {
+                let sensor = sensor.lock().unwrap();
+                let mut state = state.lock().unwrap();
+                if state.stopped {
+                    break;
+                }
+                for _ in 0..AC_BURST_SIZE {
+                    match sensor.read_fast() {
+                        Ok((voltage, current)) => {
+                            let current = current - state.offsets.current_in(sensor.is_low_range());
+                            window.add(voltage - state.offsets.voltage, current);
+                            state.check_limit(current);
+                        },
+                        Err(e) => {
+                            info!("{:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            // Let the other tasks run between the bursts
+            thread::sleep(Duration::from_millis(1));
+
+            let elapsed = start.elapsed();
+            if elapsed < Duration::from_millis(window_ms) {
+                continue;
+            }
+            let instant = Instant::now();
+            start = instant;
+            if let Some(result) = window.finish(elapsed.as_secs_f32()) {
+                let mut data = CurrentLog::default();
+                data.clock = timebase::now_ns();
+                data.voltage = result.voltage;
+                data.current = result.current;
+                data.power = result.power;
+                data.ac = Some(result.reading);
+                let mut state = state.lock().unwrap();
+                state.outputs.update(&data);
+                if !state.send(&tx, Sample { data: data, instant: instant, settling: false }) {
+                    break;
+                }
+            }
+        }
+    }
+
+    // Samples taken since the last call
+    pub fn poll(&mut self) -> Vec<Sample> {
+        self.rx.try_iter().collect()
+    }
+
+    // Exclusive access to the sensor, sampling pauses while it is held
+    pub fn sensor(&self) -> MutexGuard<'_, Ina228> {
+        self.sensor.lock().unwrap()
+    }
+
+    pub fn sensor2(&self) -> Option<MutexGuard<'_, Ina228>> {
+        self.sensor2.as_ref().map(|s| s.lock().unwrap())
+    }
+
+    pub fn set_offsets(&mut self, offsets: Offsets)
+    {
+        self.state.lock().unwrap().offsets = offsets;
+    }
+
+    // Stop the sampling thread for a shutdown, it cannot be restarted
+    pub fn stop(&mut self)
+    {
+        self.state.lock().unwrap().stopped = true;
+        info!("Sampler stopped");
+    }
+
+    pub fn set_period(&mut self, period_ms: u64)
+    {
+        self.state.lock().unwrap().period_ms = period_ms;
+    }
+
+    pub fn set_limit(&mut self, limit: f32)
+    {
+        self.state.lock().unwrap().protection.set_limit(limit);
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.state.lock().unwrap().protection.is_tripped()
+    }
+
+    // Clear the trip and release the load cutoff output
+    pub fn reset_protection(&mut self)
+    {
+        let mut state = self.state.lock().unwrap();
+        state.protection.reset();
+        if let Some(ref mut pin) = state.cutoff_pin {
+            let _ = pin.set_low();
+        }
+    }
+
+    // Replace the output rules, see OutputRules::set
+    pub fn set_output_rules(&mut self, specs: Vec<RuleSpec>, allowed: &[i32]) -> Result<(), String> {
+        self.state.lock().unwrap().outputs.set(specs, allowed)
+    }
+
+    // GPIO and state of each output rule
+    pub fn output_states(&self) -> Vec<(i32, bool)> {
+        self.state.lock().unwrap().outputs.states()
+    }
+
+    pub fn has_config_error(&self) -> bool {
+        self.state.lock().unwrap().config_error
+    }
+
+    pub fn is_low_range(&self) -> bool {
+        self.state.lock().unwrap().low_range
+    }
+
+    // Failed sensor reads since boot
+    pub fn read_errors(&self) -> u32 {
+        self.state.lock().unwrap().read_errors
+    }
+
+    // Conversion-ready alerts missed since boot
+    pub fn alert_misses(&self) -> u32 {
+        self.state.lock().unwrap().alert_misses
+    }
+
+    // Sample spacing counters since boot
+    pub fn get_jitter_stats(&self) -> JitterStats {
+        self.state.lock().unwrap().jitter
+    }
+}
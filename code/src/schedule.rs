@@ -0,0 +1,172 @@
+// Schedule
+// Logging by local time of day and day of week for long-term installations with a known duty cycle.
+// The local time also sets the periods of the summary reports.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+//
+// Schedule format (cfg.toml schedule / config set schedule), windows separated by ';':
+//   mon-fri 08:00-18:00            log from 8:00 to 18:00 on weekdays
+//   sat,sun 10:00-12:00 precision  log with the precision profile on weekend mornings
+//   daily 22:00-06:00              a window may pass midnight, it belongs to the day it starts on
+// Logging starts when a window opens and stops when it closes. A window with a profile switches to it
+// while it is open, the configured profile is restored afterwards.
+
+use log::*;
+use std::ffi::CString;
+use crate::profile::Profile;
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const ALL_DAYS: u8 = 0x7F;
+
+#[derive(Clone, Copy)]
+struct Window {
+    days: u8,           // bit 0: Sunday ... bit 6: Saturday
+    start: u16,         // minutes since midnight
+    end: u16,
+    profile: Option<Profile>,
+}
+
+impl Window {
+    fn is_open(&self, weekday: u32, minute: u16) -> bool {
+        let today = self.days & (1 << weekday) != 0;
+        let yesterday = self.days & (1 << ((weekday + 6) % 7)) != 0;
+        if self.start < self.end {
+            today && minute >= self.start && minute < self.end
+        }
+        else {
+            // Passes midnight
+            (today && minute >= self.start) || (yesterday && minute < self.end)
+        }
+    }
+}
+
+pub enum ScheduleEvent {
+    Start(Option<Profile>),     // a window opened, with its profile
+    Stop,
+}
+
+pub struct Schedule {
+    windows: Vec<Window>,
+    started: bool,                  // the first update has reported the state
+    open: Option<Option<Profile>>,  // profile of the open window, None while closed
+}
+
+impl Schedule {
+    // An empty text disables the schedule
+    pub fn parse(text: &str) -> Result<Schedule, String> {
+        let mut windows = Vec::new();
+        for entry in text.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            windows.push(parse_window(entry)?);
+        }
+        Ok(Schedule { windows: windows, started: false, open: None })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.windows.is_empty()
+    }
+
+    // Called with the local time, returns an event when a window opens or closes.
+    // The first call reports the current state.
+    pub fn update(&mut self, weekday: u32, minute: u16) -> Option<ScheduleEvent> {
+        if self.windows.is_empty() {
+            return None;
+        }
+        let open = self.windows.iter().find(|w| w.is_open(weekday, minute)).map(|w| w.profile);
+        if self.started && self.open == open {
+            return None;
+        }
+        self.started = true;
+        self.open = open;
+        match open {
+            Some(profile) => {
+                info!("Schedule: window open{}", profile.map_or(String::new(), |p| format!(", profile {}", p.name())));
+                Some(ScheduleEvent::Start(profile))
+            },
+            None => {
+                info!("Schedule: window closed");
+                Some(ScheduleEvent::Stop)
+            },
+        }
+    }
+}
+
+// "<days> <HH:MM>-<HH:MM> [profile]"
+fn parse_window(entry: &str) -> Result<Window, String> {
+    let parts: Vec<&str> = entry.split_whitespace().collect();
+    let (days, time, profile) = match parts.as_slice() {
+        [days, time] => (*days, *time, None),
+        [days, time, name] => match Profile::from_name(name) {
+            Some(profile) => (*days, *time, Some(profile)),
+            None => return Err(format!("unknown profile '{}'", name)),
+        },
+        _ => return Err(format!("'{}' is not '<days> <HH:MM>-<HH:MM> [profile]'", entry)),
+    };
+    let (start, end) = time.split_once('-').ok_or(format!("'{}' is not <HH:MM>-<HH:MM>", time))?;
+    let (start, end) = (parse_time(start)?, parse_time(end)?);
+    if start == end {
+        return Err(format!("window {} is empty", time));
+    }
+    Ok(Window { days: parse_days(days)?, start: start, end: end, profile: profile })
+}
+
+// "daily", "mon", "mon-fri", "fri-mon" or "sat,sun"
+fn parse_days(text: &str) -> Result<u8, String> {
+    if text == "daily" {
+        return Ok(ALL_DAYS);
+    }
+    let day = |name: &str| DAY_NAMES.iter().position(|d| *d == name).ok_or(format!("unknown day '{}'", name));
+    let mut days = 0u8;
+    for item in text.split(',') {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let (mut d, last) = (day(first)?, day(last)?);
+                loop {
+                    days |= 1 << d;
+                    if d == last {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            },
+            None => days |= 1 << day(item)?,
+        }
+    }
+    Ok(days)
+}
+
+// "HH:MM" to minutes since midnight, "24:00" is the end of the day
+fn parse_time(text: &str) -> Result<u16, String> {
+    let invalid = || format!("'{}' is not a time HH:MM", text);
+    let (hour, minute) = text.split_once(':').ok_or_else(invalid)?;
+    let hour = hour.parse::<u16>().map_err(|_| invalid())?;
+    let minute = minute.parse::<u16>().map_err(|_| invalid())?;
+    match (hour, minute) {
+        (0..=23, 0..=59) | (24, 0) => Ok(hour * 60 + minute),
+        _ => Err(invalid()),
+    }
+}
+
+// POSIX TZ string, e.g. "JST-9" or "CET-1CEST,M3.5.0,M10.5.0/3". Empty is UTC.
+pub fn valid_timezone(tz: &str) -> bool {
+    tz.is_empty() || (tz.len() <= 64 && tz.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '<')
+        && tz.chars().all(|c| c.is_ascii_graphic()))
+}
+
+pub fn set_timezone(tz: &str)
+{
+    let tz = CString::new(if tz.is_empty() { "UTC0" } else { tz }).unwrap();
+    unsafe {
+        esp_idf_sys::setenv(b"TZ\0".as_ptr() as *const _, tz.as_ptr(), 1);
+        esp_idf_sys::tzset();
+    }
+}
+
+// Local (weekday, minutes since midnight), weekday 0 is Sunday
+pub fn local_time() -> (u32, u16) {
+    unsafe {
+        let now: esp_idf_sys::time_t = esp_idf_sys::time(std::ptr::null_mut());
+        let mut tm: esp_idf_sys::tm = std::mem::zeroed();
+        esp_idf_sys::localtime_r(&now, &mut tm);
+        (tm.tm_wday as u32, (tm.tm_hour * 60 + tm.tm_min) as u16)
+    }
+}
@@ -0,0 +1,189 @@
+// Scpi
+// SCPI command subset on TCP port 5025 (scpi_port), so lab automation such as pyvisa or LabVIEW can
+// query the meter like a bench instrument. The main loop shares the latest sample, the peak-hold and
+// the energy; the server thread answers from them without waiting for the loop. One client is served
+// at a time and is dropped after a minute without a command.
+//   *IDN?                      manufacturer,model,serial (MAC id),firmware version
+//   *CLS                       clear the error queue
+//   MEAS:CURR[:DC]?            current (A)
+//   MEAS:VOLT[:DC]?            voltage (V)
+//   MEAS:POW?                  power (W)
+//   MEAS?                      voltage,current,power
+//   MEAS:CURR:PEAK?            peak-hold current (A), MEAS:POW:PEAK? power (W)
+//   MEAS:ENER?                 energy of this session (Wh)
+//   SYST:ERR?                  oldest error of the queue, 0,"No error" when empty
+// Keywords are case-insensitive in the short or the long form (MEASure:CURRent?), several commands on
+// one line are separated by ';' and their replies too. Replies end with a newline.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::CurrentLog;
+use crate::stats::PeakHold;
+use crate::{tasks, version, settings};
+
+// An idle client is dropped so the next one can connect
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_LINE_LENGTH: u64 = 256;
+// Readings older than this are reported as stale, e.g. while calibrating
+const STALE_TIME: Duration = Duration::from_secs(2);
+const ERROR_QUEUE_SIZE: usize = 10;
+// SCPI "not a number"
+const NAN_VALUE: &str = "9.91E37";
+
+// Long forms of the keywords and their short forms
+const KEYWORDS: [(&str, &str); 7] = [("MEASURE", "MEAS"), ("CURRENT", "CURR"), ("VOLTAGE", "VOLT"), ("POWER", "POW"),
+    ("ENERGY", "ENER"), ("SYSTEM", "SYST"), ("ERROR", "ERR")];
+
+#[derive(Clone, Copy, Default)]
+struct Readings {
+    voltage: f32,
+    current: f32,
+    power: f32,
+    peak_current: f32,
+    peak_power: f32,
+    energy: f32,        // Wh
+    instant: Option<Instant>,   // time of the sample, None before the first
+}
+
+pub struct Scpi {
+    readings: Arc<Mutex<Readings>>,
+}
+
+impl Scpi {
+    pub fn start(port: u16) -> anyhow::Result<Scpi> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        let readings = Arc::new(Mutex::new(Readings::default()));
+        let th_readings = readings.clone();
+        let _th = tasks::spawn(&tasks::SCPI, move || {
+            info!("Start SCPI server on port {}", port);
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = serve(stream, &th_readings) {
+                            info!("SCPI client: {:?}", e);
+                        }
+                    },
+                    Err(e) => info!("SCPI accept failed: {:?}", e),
+                }
+            }
+        });
+        Ok(Scpi { readings: readings })
+    }
+
+    // Called for every sample
+    pub fn update(&mut self, data: &CurrentLog, peak: &PeakHold, energy_wh: f32, instant: Instant)
+    {
+        let mut readings = self.readings.lock().unwrap();
+        *readings = Readings {
+            voltage: data.voltage,
+            current: data.current,
+            power: data.power,
+            peak_current: peak.current,
+            peak_power: peak.power,
+            energy: energy_wh,
+            instant: Some(instant),
+        };
+    }
+}
+
+// Commands of one client until it disconnects or is idle for CLIENT_TIMEOUT
+fn serve(stream: TcpStream, readings: &Mutex<Readings>) -> anyhow::Result<()> {
+    info!("SCPI client {} connected", stream.peer_addr()?);
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut errors: VecDeque<(i32, &'static str)> = VecDeque::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if (&mut reader).take(MAX_LINE_LENGTH).read_line(&mut line)? == 0 {
+            break;
+        }
+        if !line.ends_with('\n') && line.len() as u64 >= MAX_LINE_LENGTH {
+            // The rest of the line is read as the next one and fails as well
+            push_error(&mut errors, -112, "Program mnemonic too long");
+            continue;
+        }
+        let snapshot = *readings.lock().unwrap();
+        let replies: Vec<String> = line.split(';')
+            .map(|cmd| cmd.trim())
+            .filter(|cmd| !cmd.is_empty())
+            .filter_map(|cmd| execute(cmd, &snapshot, &mut errors))
+            .collect();
+        if !replies.is_empty() {
+            writer.write_all(format!("{}\n", replies.join(";")).as_bytes())?;
+        }
+    }
+    info!("SCPI client disconnected");
+    Ok(())
+}
+
+// Reply of a query, None for a command or an error
+fn execute(cmd: &str, readings: &Readings, errors: &mut VecDeque<(i32, &'static str)>) -> Option<String> {
+    let (header, query) = match cmd.strip_suffix('?') {
+        Some(header) => (header, true),
+        None => (cmd, false),
+    };
+    let header: Vec<String> = header.trim_start_matches(':').split(':').map(short_form).collect();
+    match (header.join(":").as_str(), query) {
+        ("*IDN", true) => Some(format!("HNZ1102,mini-current-meter,{},{}", settings::mac_id(), version::VERSION)),
+        ("*CLS", false) => {
+            errors.clear();
+            None
+        },
+        ("SYST:ERR", true) | ("SYST:ERR:NEXT", true) => {
+            let (code, message) = errors.pop_front().unwrap_or((0, "No error"));
+            Some(format!("{},\"{}\"", code, message))
+        },
+        ("MEAS", true) => Some(measurement(&[readings.voltage, readings.current, readings.power], readings, errors)),
+        ("MEAS:CURR", true) | ("MEAS:CURR:DC", true) => Some(measurement(&[readings.current], readings, errors)),
+        ("MEAS:VOLT", true) | ("MEAS:VOLT:DC", true) => Some(measurement(&[readings.voltage], readings, errors)),
+        ("MEAS:POW", true) => Some(measurement(&[readings.power], readings, errors)),
+        ("MEAS:CURR:PEAK", true) => Some(measurement(&[readings.peak_current], readings, errors)),
+        ("MEAS:POW:PEAK", true) => Some(measurement(&[readings.peak_power], readings, errors)),
+        ("MEAS:ENER", true) => Some(measurement(&[readings.energy], readings, errors)),
+        _ => {
+            push_error(errors, -113, "Undefined header");
+            None
+        }
+    }
+}
+
+// Values in NR3 format separated by commas, NaN with an error while there is no recent sample
+fn measurement(values: &[f32], readings: &Readings, errors: &mut VecDeque<(i32, &'static str)>) -> String {
+    let fresh = readings.instant.map_or(false, |instant| instant.elapsed() < STALE_TIME);
+    if !fresh {
+        push_error(errors, -230, "Data corrupt or stale");
+    }
+    values.iter()
+        .map(|value| if fresh { format!("{:.6E}", value) } else { NAN_VALUE.to_string() })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// The last entry of a full queue is replaced by the overflow error
+fn push_error(errors: &mut VecDeque<(i32, &'static str)>, code: i32, message: &'static str)
+{
+    if errors.len() >= ERROR_QUEUE_SIZE {
+        errors.pop_back();
+        errors.push_back((-350, "Queue overflow"));
+        return;
+    }
+    errors.push_back((code, message));
+}
+
+// Upper case short form of a keyword
+fn short_form(word: &str) -> String {
+    let word = word.trim().to_ascii_uppercase();
+    match KEYWORDS.iter().find(|(long, _)| *long == word) {
+        Some((_, short)) => short.to_string(),
+        None => word,
+    }
+}
@@ -0,0 +1,620 @@
+// Settings
+// Runtime settings stored in NVS, overriding the build-time values of cfg.toml.
+// Shared by the button handler, the MQTT command channel and the serial console.
+// Values that may change often are not written on every change: the writes are coalesced and
+// committed in a batch at most every 30 seconds, to keep the flash wear low.
+// Each subsystem watches the keys it uses and is sent the new value of a key when it is set, so a change
+// takes effect without a reboot.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use crate::transfer::{self, json_escape};
+use crate::profile::Profile;
+use crate::schedule::{self, Schedule};
+use crate::outputrule;
+use crate::trigger;
+use crate::sampler::Offsets;
+
+pub const SETTING_KEYS: [&str; 27] = ["channel", "wifi_ssid", "wifi_psk", "current_limit", "current_offset", "current_offset_hi", "voltage_offset", "device_name",
+    "influxdb_server", "influxdb_api_key", "influxdb_api", "profile", "wifi_eap_identity", "wifi_eap_username", "wifi_eap_password",
+    "sound_enabled", "invert_current", "battery_divider", "battery_scale", "display_mode", "schedule", "timezone", "channel_names",
+    "influxdb_tags", "output_rules", "trigger_start", "trigger_stop"];
+// Shown masked by config get, only config export prints them
+pub const SECRET_KEYS: [&str; 3] = ["wifi_psk", "wifi_eap_password", "influxdb_api_key"];
+
+// The only settings that are safe to change frequently (button presses, future counters), their writes are
+// coalesced. Everything else is written immediately and should only change on an explicit user action.
+pub const FREQUENT_KEYS: [&str; 5] = ["channel", "profile", "sound_enabled", "energy_total", "charge_total"];
+// A batch is committed when nothing has changed for SETTLE_TIME, and not more often than COMMIT_INTERVAL
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+const COMMIT_INTERVAL: Duration = Duration::from_secs(30);
+
+// A one-point battery calibration outside this range is a wrong reference voltage, not a resistor tolerance
+pub const BATTERY_SCALE_MIN: f32 = 0.8;
+pub const BATTERY_SCALE_MAX: f32 = 1.2;
+
+// Longest channel name, four of them fit the 64 bytes of a setting
+pub const CHANNEL_NAME_LENGTH: usize = 15;
+
+// Names of the channels 1-4 separated by commas, e.g. "router,3D printer". Always returns four names,
+// an empty name means the channel has none.
+pub fn parse_channel_names(text: &str) -> Result<Vec<String>, String> {
+    let mut names: Vec<String> = match text.trim() {
+        "" => Vec::new(),
+        text => text.split(',').map(|name| name.trim().to_string()).collect(),
+    };
+    if names.len() > 4 {
+        return Err("channel_names has more than 4 names".to_string());
+    }
+    if let Some(name) = names.iter().find(|name| name.len() > CHANNEL_NAME_LENGTH || !name.chars().all(|c| c == ' ' || (c.is_ascii_graphic() && c != '"' && c != '\\'))) {
+        return Err(format!("channel name '{}' must be up to {} characters without quotes or backslashes", name, CHANNEL_NAME_LENGTH));
+    }
+    names.resize(4, String::new());
+    Ok(names)
+}
+
+// Unique device id from the factory MAC address
+pub fn mac_id() -> String {
+    let mut mac = [0u8; 6];
+    unsafe {
+        esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr());
+    }
+    format!("mcm_{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])
+}
+
+// NVS keys are limited to 15 characters
+fn nvs_key(key: &str) -> &str {
+    match key {
+        "influxdb_api_key" => "influx_api_key",
+        "wifi_eap_identity" => "eap_identity",
+        "wifi_eap_username" => "eap_username",
+        "wifi_eap_password" => "eap_password",
+        "current_offset_hi" => "cur_offset_hi",
+        key => key,
+    }
+}
+
+// New values of the watched keys, in the order they were set
+pub struct SettingsWatch {
+    rx: Receiver<(String, String)>,
+}
+
+impl SettingsWatch {
+    // Changes since the previous call
+    pub fn changes(&self) -> Vec<(String, String)> {
+        self.rx.try_iter().collect()
+    }
+}
+
+pub struct Settings {
+    nvs: EspNvs<NvsDefault>,
+    watchers: Vec<(&'static [&'static str], Sender<(String, String)>)>,
+    pending: Vec<(String, String)>,     // frequent settings not committed yet, the latest value per key
+    coalesced: u32,                     // changes replaced before they were committed
+    last_change: Option<Instant>,
+    last_commit: Option<Instant>,
+}
+
+impl Settings {
+    pub fn new(nvs: EspNvs<NvsDefault>) -> Self {
+        Settings { nvs: nvs, watchers: Vec::new(), pending: Vec::new(), coalesced: 0, last_change: None, last_commit: None }
+    }
+
+    // Notify the watch of the changes of the keys, the watch can be moved to another thread
+    pub fn watch(&mut self, keys: &'static [&'static str]) -> SettingsWatch {
+        let (tx, rx) = channel::<(String, String)>();
+        self.watchers.push((keys, tx));
+        SettingsWatch { rx: rx }
+    }
+
+    // A change of the key takes effect without a reboot
+    pub fn is_watched(&self, key: &str) -> bool {
+        self.watchers.iter().any(|(keys, _)| keys.contains(&key))
+    }
+
+    fn notify(&mut self, key: &str, value: &str)
+    {
+        // A watch that was dropped is removed
+        self.watchers.retain(|(keys, tx)| !keys.contains(&key) || tx.send((key.to_string(), value.to_string())).is_ok());
+    }
+
+    // Queue a frequent setting for the next commit, other keys are rejected
+    fn defer(&mut self, key: &str, value: &str) -> Result<(), esp_idf_svc::sys::EspError> {
+        assert!(FREQUENT_KEYS.contains(&key), "{} is not a frequently updated setting", key);
+        match self.pending.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => {
+                *v = value.to_string();
+                self.coalesced += 1;
+            },
+            None => self.pending.push((key.to_string(), value.to_string())),
+        }
+        self.last_change = Some(Instant::now());
+        Ok(())
+    }
+
+    // Called every loop, commits the pending settings when they have settled
+    pub fn poll(&mut self)
+    {
+        if let Some(changed) = self.last_change {
+            if changed.elapsed() >= SETTLE_TIME && self.last_commit.map_or(true, |t| t.elapsed() >= COMMIT_INTERVAL) {
+                self.flush();
+            }
+        }
+    }
+
+    // Commit the pending settings now, before a reboot, shutdown or power loss
+    pub fn flush(&mut self)
+    {
+        if self.pending.is_empty() {
+            return;
+        }
+        for (key, value) in std::mem::take(&mut self.pending) {
+            let ret = match key.as_str() {
+                "channel" => self.nvs.set_u8(&key, value.parse::<u8>().unwrap_or(1)),
+                _ => self.nvs.set_str(nvs_key(&key), &value),
+            };
+            if let Err(e) = ret {
+                info!("Failed to save {} to NVS: {:?}", key, e);
+            }
+        }
+        info!("Settings committed to NVS ({} changes coalesced)", self.coalesced);
+        self.coalesced = 0;
+        self.last_change = None;
+        self.last_commit = Some(Instant::now());
+    }
+
+    // Load current channel from NVS
+    pub fn load_channel(&self) -> u8 {
+        match self.nvs.get_u8("channel") {
+            Ok(Some(ch)) => {
+                info!("Loaded channel {} from NVS", ch);
+                if ch >= 1 && ch <= 4 { ch } else { 1 } // Validate range
+            },
+            Ok(None) => {
+                info!("No channel found in NVS, using default channel 1");
+                1
+            },
+            Err(e) => {
+                info!("Failed to read channel from NVS: {:?}, using default channel 1", e);
+                1
+            }
+        }
+    }
+
+    // Load a calibration offset ("current_offset", "current_offset_hi" or "voltage_offset") from NVS
+    pub fn load_offset(&self, key: &str) -> f32 {
+        let mut buffer = [0u8; 4];
+        match self.nvs.get_blob(nvs_key(key), &mut buffer) {
+            Ok(Some(data)) if data.len() == 4 => {
+                let offset_bytes: [u8; 4] = [data[0], data[1], data[2], data[3]];
+                let offset = f32::from_le_bytes(offset_bytes);
+                info!("Loaded {} from NVS: {:.6}", key, offset);
+                offset
+            },
+            Ok(Some(data)) => {
+                info!("Invalid {} size in NVS (got {} bytes), using default 0.0", key, data.len());
+                0.0
+            },
+            Ok(None) => {
+                info!("No {} found in NVS, using default 0.0", key);
+                0.0
+            },
+            Err(e) => {
+                info!("Failed to read {} from NVS: {:?}, using default 0.0", key, e);
+                0.0
+            }
+        }
+    }
+
+    // Credentials changed at runtime take precedence over cfg.toml
+    #[cfg(feature = "wifi")]
+    pub fn load_wifi_credentials(&self) -> Option<(String, String)> {
+        let mut ssid_buf = [0u8; 33];
+        let mut psk_buf = [0u8; 65];
+        match (self.nvs.get_str("wifi_ssid", &mut ssid_buf), self.nvs.get_str("wifi_psk", &mut psk_buf)) {
+            (Ok(Some(ssid)), Ok(psk)) if !ssid.is_empty() => {
+                info!("Loaded WiFi credentials from NVS (SSID: {})", ssid);
+                Some((ssid.to_string(), psk.unwrap_or("").to_string()))
+            },
+            _ => None,
+        }
+    }
+
+    // Device tag of the InfluxDB points, the MAC based id unless a name is stored in NVS
+    pub fn load_device_name(&self) -> String {
+        let mut buffer = [0u8; 33];
+        match self.nvs.get_str("device_name", &mut buffer) {
+            Ok(Some(name)) if !name.is_empty() => name.to_string(),
+            _ => mac_id(),
+        }
+    }
+
+    pub fn save_channel(&mut self, channel: u8)
+    {
+        let _ = self.defer("channel", &channel.to_string());
+    }
+
+    // Cumulative energy (Wh) and charge (Ah), zero if none are stored
+    pub fn load_totals(&self) -> (f64, f64) {
+        let load = |key: &str| {
+            let mut buffer = [0u8; 32];
+            self.nvs.get_str(key, &mut buffer).ok().flatten().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0)
+        };
+        (load("energy_total"), load("charge_total"))
+    }
+
+    // Checkpoint of the totals, committed with the next batch of frequent settings
+    pub fn save_totals(&mut self, energy_wh: f64, charge_ah: f64)
+    {
+        let _ = self.defer("energy_total", &format!("{:.6}", energy_wh));
+        let _ = self.defer("charge_total", &format!("{:.6}", charge_ah));
+    }
+
+    #[cfg(feature = "wifi")]
+    pub fn save_wifi_credentials(&mut self, ssid: &str, psk: &str) -> Result<(), String> {
+        self.nvs.set_str("wifi_ssid", ssid).and_then(|_| self.nvs.set_str("wifi_psk", psk))
+            .map_err(|e| format!("failed to save the WiFi credentials: {:?}", e))?;
+        info!("WiFi credentials saved to NVS (SSID: {})", ssid);
+        Ok(())
+    }
+
+    // The offsets being replaced are kept for undo_calibration()
+    pub fn save_calibration(&mut self, offsets: Offsets)
+    {
+        let prev_current_offset = self.load_offset("current_offset");
+        let prev_current_offset_hi = self.get("current_offset_hi").and_then(|v| v.parse::<f32>().ok()).unwrap_or(prev_current_offset);
+        let prev_voltage_offset = self.load_offset("voltage_offset");
+        match self.nvs.set_blob("prev_cur_offset", &prev_current_offset.to_le_bytes())
+            .and_then(|_| self.nvs.set_blob("prev_cur_off_hi", &prev_current_offset_hi.to_le_bytes()))
+            .and_then(|_| self.nvs.set_blob("prev_vol_offset", &prev_voltage_offset.to_le_bytes())) {
+            Ok(_) => {},
+            Err(e) => {
+                info!("Failed to save previous offsets to NVS: {:?}", e);
+            }
+        }
+        self.store_offsets(offsets);
+    }
+
+    fn store_offsets(&mut self, offsets: Offsets)
+    {
+        match self.nvs.set_blob("current_offset", &offsets.current.to_le_bytes())
+            .and_then(|_| self.nvs.set_blob(nvs_key("current_offset_hi"), &offsets.current_hi.to_le_bytes())) {
+            Ok(_) => {
+                info!("Current offsets saved to NVS: {:.6}A (163.84mV range {:.6}A)", offsets.current, offsets.current_hi);
+            },
+            Err(e) => {
+                info!("Failed to save current offset to NVS: {:?}", e);
+            }
+        }
+        match self.nvs.set_blob("voltage_offset", &offsets.voltage.to_le_bytes()) {
+            Ok(_) => {
+                info!("Voltage offset saved to NVS: {:.6}V", offsets.voltage);
+            },
+            Err(e) => {
+                info!("Failed to save voltage offset to NVS: {:?}", e);
+            }
+        }
+    }
+
+    // Restore the offsets replaced by the last calibration, None if there are none.
+    // Only one step back: the previous offsets are removed, a second undo returns None.
+    pub fn undo_calibration(&mut self) -> Option<Offsets> {
+        let mut current_buf = [0u8; 4];
+        let mut current_hi_buf = [0u8; 4];
+        let mut voltage_buf = [0u8; 4];
+        let current_offset = match self.nvs.get_blob("prev_cur_offset", &mut current_buf) {
+            Ok(Some(data)) if data.len() == 4 => f32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            _ => return None,
+        };
+        // Not kept by a calibration without the 163.84mV range offset
+        let current_offset_hi = match self.nvs.get_blob("prev_cur_off_hi", &mut current_hi_buf) {
+            Ok(Some(data)) if data.len() == 4 => f32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            _ => current_offset,
+        };
+        let voltage_offset = match self.nvs.get_blob("prev_vol_offset", &mut voltage_buf) {
+            Ok(Some(data)) if data.len() == 4 => f32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            _ => return None,
+        };
+        info!("Restoring previous calibration");
+        for key in ["prev_cur_offset", "prev_cur_off_hi", "prev_vol_offset"] {
+            if let Err(e) = self.nvs.remove(key) {
+                info!("Failed to remove {} from NVS: {:?}", key, e);
+            }
+        }
+        let offsets = Offsets { current: current_offset, current_hi: current_offset_hi, voltage: voltage_offset };
+        self.store_offsets(offsets);
+        Some(offsets)
+    }
+
+    // Get a setting as text, None if it is not stored in NVS
+    pub fn get(&self, key: &str) -> Option<String> {
+        if let Some((_, value)) = self.pending.iter().find(|(k, _)| k == key) {
+            return Some(value.clone());
+        }
+        match key {
+            "channel" => {
+                self.nvs.get_u8(key).ok().flatten().map(|v| v.to_string())
+            },
+            "current_offset" | "current_offset_hi" | "voltage_offset" => {
+                let mut buffer = [0u8; 4];
+                match self.nvs.get_blob(nvs_key(key), &mut buffer) {
+                    Ok(Some(data)) if data.len() == 4 => {
+                        Some(format!("{:.6}", f32::from_le_bytes([data[0], data[1], data[2], data[3]])))
+                    },
+                    _ => None,
+                }
+            },
+            "wifi_ssid" | "wifi_psk" | "current_limit" | "device_name" | "profile" | "sound_enabled" | "invert_current"
+                | "battery_divider" | "battery_scale" | "display_mode" | "timezone" | "channel_names" | "trigger_start" | "trigger_stop" => {
+                let mut buffer = [0u8; 65];
+                self.nvs.get_str(key, &mut buffer).ok().flatten().map(|v| v.to_string())
+            },
+            "wifi_eap_identity" | "wifi_eap_username" | "wifi_eap_password" | "schedule" | "influxdb_tags" | "output_rules" => {
+                let mut buffer = [0u8; 129];
+                self.nvs.get_str(nvs_key(key), &mut buffer).ok().flatten().map(|v| v.to_string())
+            },
+            "influxdb_server" | "influxdb_api_key" | "influxdb_api" => {
+                let mut buffer = [0u8; 257];
+                self.nvs.get_str(nvs_key(key), &mut buffer).ok().flatten().map(|v| v.to_string())
+            },
+            _ => None,
+        }
+    }
+
+    // Validate and store a setting
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let stored = validate(key, value)?;
+        self.store(key, &stored)
+    }
+
+    // Save a validated value, queued for a frequent setting
+    fn store(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let ret = match key {
+            key if FREQUENT_KEYS.contains(&key) => self.defer(key, value),
+            "current_offset" | "current_offset_hi" | "voltage_offset" => {
+                self.nvs.set_blob(nvs_key(key), &value.parse::<f32>().unwrap_or(0.0).to_le_bytes())
+            },
+            _ => self.nvs.set_str(nvs_key(key), value),
+        };
+        ret.map_err(|e| format!("failed to save {}: {:?}", key, e))?;
+        if FREQUENT_KEYS.contains(&key) {
+            info!("Setting {} queued for NVS", key);
+        }
+        else {
+            info!("Setting {} saved to NVS", key);
+        }
+        self.notify(key, value);
+        Ok(())
+    }
+
+    // All settings stored in NVS as a JSON object, including the WiFi password and API key
+    pub fn export_json(&self) -> String {
+        let items: Vec<String> = SETTING_KEYS.iter()
+            .filter_map(|key| self.get(key).map(|value| format!("\"{}\":\"{}\"", key, json_escape(&value))))
+            .collect();
+        format!("{{{}}}", items.join(","))
+    }
+
+    // Store the settings of an exported JSON object, all keys and values are checked before anything is saved.
+    // Returns the number of settings stored.
+    pub fn import_json(&mut self, text: &str) -> Result<usize, String> {
+        let items = parse_json_object(text)?;
+        if let Some((key, _)) = items.iter().find(|(key, _)| !SETTING_KEYS.contains(&key.as_str())) {
+            return Err(format!("unknown setting '{}'", key));
+        }
+        let mut validated = Vec::with_capacity(items.len());
+        for (key, value) in &items {
+            validated.push((key, validate(key, value)?));
+        }
+        for (key, value) in &validated {
+            self.store(key, value)?;
+        }
+        Ok(validated.len())
+    }
+}
+
+// Check a setting, returns the value as it is stored
+fn validate(key: &str, value: &str) -> Result<String, String> {
+    let stored = match key {
+        "channel" => {
+            match value.parse::<u8>() {
+                Ok(ch) if ch >= 1 && ch <= 4 => ch.to_string(),
+                _ => return Err("channel must be 1-4".to_string()),
+            }
+        },
+        "current_offset" | "current_offset_hi" | "voltage_offset" => {
+            match value.parse::<f32>() {
+                Ok(v) => v.to_string(),
+                Err(_) => return Err(format!("{} must be a number", key)),
+            }
+        },
+        "current_limit" => {
+            match value.parse::<f32>() {
+                Ok(v) if v >= 0.0 => value.to_string(),
+                _ => return Err("current_limit must be a number >= 0".to_string()),
+            }
+        },
+        "device_name" => {
+            // Used as an InfluxDB tag value without escaping
+            if value.is_empty() || value.len() > 32 || !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err("device_name must be 1-32 characters of a-z, 0-9, '-' or '_'".to_string());
+            }
+            value.to_string()
+        },
+        "profile" => {
+            match Profile::from_name(value) {
+                Some(profile) => profile.name().to_string(),
+                None => return Err("profile must be standard, precision, fast_transient or long_battery_life".to_string()),
+            }
+        },
+        "sound_enabled" => {
+            match value.parse::<bool>() {
+                Ok(_) => value.to_string(),
+                Err(_) => return Err("sound_enabled must be true or false".to_string()),
+            }
+        },
+        "invert_current" => {
+            match value.parse::<bool>() {
+                Ok(_) => value.to_string(),
+                Err(_) => return Err("invert_current must be true or false".to_string()),
+            }
+        },
+        "display_mode" => {
+            match value {
+                "on" | "off" | "button" => value.to_string(),
+                _ => return Err("display_mode must be on, off or button".to_string()),
+            }
+        },
+        "battery_divider" => {
+            match value.parse::<f32>() {
+                Ok(v) if v >= 1.0 && v <= 20.0 => value.to_string(),
+                _ => return Err("battery_divider must be a number 1-20".to_string()),
+            }
+        },
+        "battery_scale" => {
+            match value.parse::<f32>() {
+                Ok(v) if v >= BATTERY_SCALE_MIN && v <= BATTERY_SCALE_MAX => value.to_string(),
+                _ => return Err(format!("battery_scale must be a number {}-{}", BATTERY_SCALE_MIN, BATTERY_SCALE_MAX)),
+            }
+        },
+        "schedule" if value.len() <= 128 => {
+            match Schedule::parse(value) {
+                Ok(_) => value.to_string(),
+                Err(e) => return Err(format!("schedule: {}", e)),
+            }
+        },
+        "schedule" => return Err("schedule is too long".to_string()),
+        "influxdb_tags" if value.len() <= 128 => {
+            match transfer::parse_tags(value) {
+                Ok(_) => value.to_string(),
+                Err(e) => return Err(format!("influxdb_tags: {}", e)),
+            }
+        },
+        "influxdb_tags" => return Err("influxdb_tags is too long".to_string()),
+        "output_rules" if value.len() <= 128 => {
+            match outputrule::parse_rules(value) {
+                Ok(_) => value.to_string(),
+                Err(e) => return Err(format!("output_rules: {}", e)),
+            }
+        },
+        "output_rules" => return Err("output_rules is too long".to_string()),
+        "trigger_start" | "trigger_stop" if value.len() <= 64 => {
+            match trigger::check_condition(value, key == "trigger_stop") {
+                Ok(_) => value.to_string(),
+                Err(e) => return Err(format!("{}: {}", key, e)),
+            }
+        },
+        "trigger_start" | "trigger_stop" => return Err(format!("{} is too long", key)),
+        "channel_names" => {
+            match parse_channel_names(value) {
+                Ok(_) => value.to_string(),
+                Err(e) => return Err(e),
+            }
+        },
+        "timezone" => {
+            match schedule::valid_timezone(value) {
+                true => value.to_string(),
+                false => return Err("timezone must be a POSIX TZ string, e.g. JST-9".to_string()),
+            }
+        },
+        "wifi_ssid" if value.len() <= 32 => value.to_string(),
+        "wifi_psk" if value.len() <= 64 => value.to_string(),
+        "wifi_ssid" | "wifi_psk" => return Err(format!("{} is too long", key)),
+        "wifi_eap_identity" | "wifi_eap_username" | "wifi_eap_password" if value.len() <= 128 => value.to_string(),
+        "wifi_eap_identity" | "wifi_eap_username" | "wifi_eap_password" => return Err(format!("{} is too long", key)),
+        "influxdb_server" | "influxdb_api_key" | "influxdb_api" if value.len() <= 256 => value.to_string(),
+        "influxdb_server" | "influxdb_api_key" | "influxdb_api" => return Err(format!("{} is too long", key)),
+        _ => return Err(format!("unknown setting '{}'", key)),
+    };
+    Ok(stored)
+}
+
+// Flat JSON object of string, number or boolean values
+fn parse_json_object(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut chars = text.trim().chars().peekable();
+    let mut items = Vec::new();
+    if chars.next() != Some('{') {
+        return Err("JSON object expected".to_string());
+    }
+    loop {
+        skip_spaces(&mut chars);
+        match chars.next() {
+            Some('}') if items.is_empty() => break,
+            Some('"') => {},
+            _ => return Err("key expected".to_string()),
+        }
+        let key = parse_json_string(&mut chars)?;
+        skip_spaces(&mut chars);
+        if chars.next() != Some(':') {
+            return Err(format!("':' expected after '{}'", key));
+        }
+        skip_spaces(&mut chars);
+        let value = match chars.peek() {
+            Some('"') => {
+                chars.next();
+                parse_json_string(&mut chars)?
+            },
+            _ => {
+                let mut value = String::new();
+                while let Some(c) = chars.peek() {
+                    if *c == ',' || *c == '}' || c.is_whitespace() {
+                        break;
+                    }
+                    value.push(*c);
+                    chars.next();
+                }
+                if value.is_empty() {
+                    return Err(format!("value expected for '{}'", key));
+                }
+                value
+            }
+        };
+        items.push((key, value));
+        skip_spaces(&mut chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => break,
+            _ => return Err("',' or '}' expected".to_string()),
+        }
+    }
+    Ok(items)
+}
+
+fn skip_spaces(chars: &mut std::iter::Peekable<std::str::Chars>)
+{
+    while chars.peek().map_or(false, |c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+// String after the opening quote
+fn parse_json_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(text),
+            Some('\\') => {
+                match chars.next() {
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some('r') => text.push('\r'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => text.push(c),
+                            None => return Err(format!("invalid escape \\u{}", hex)),
+                        }
+                    },
+                    Some(c) => text.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            },
+            Some(c) => text.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// Simulator
+// Demo mode without an INA228: the registers of a simulated sensor on a battery-powered IoT load, so the
+// driver, range switching, sampling, display, logging and transfer run unchanged. The load sleeps, wakes
+// every few seconds with short radio bursts, and carries the ripple of a switching regulator and noise.
+// The result registers follow the CONFIG and SHUNT_CAL written by the driver like the real device.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use esp_idf_hal::delay::Ets;
+use crate::ina228::AdcConfig;
+
+// Load cycle: sleep, active phase with a radio burst, repeated every CYCLE_S seconds (A, s)
+const CYCLE_S: f32 = 5.0;
+const SLEEP_A: f32 = 0.00035;
+const ACTIVE_A: f32 = 0.042;
+const ACTIVE_S: f32 = 0.6;
+const BURST_A: f32 = 0.180;
+const BURST_START_S: f32 = 0.2;
+const BURST_S: f32 = 0.05;
+// Triangle ripple of the switching regulator, relative to the current
+const RIPPLE_HZ: f32 = 1000.0;
+const RIPPLE: f32 = 0.05;
+const NOISE: f32 = 0.005;
+// Battery falling from full over the run, with its internal resistance (V, V/s, Ohm)
+const BATTERY_FULL_V: f32 = 4.1;
+const BATTERY_EMPTY_V: f32 = 3.5;
+const BATTERY_DRAIN_V_PER_S: f32 = 0.00002;
+const BATTERY_RESISTANCE: f32 = 0.25;
+// Output of the regulator behind the second sensor
+const REGULATOR_V: f32 = 3.3;
+const REGULATOR_EFFICIENCY: f32 = 0.88;
+// Time of a register read at 400kHz, 9 clocks per byte with the address and register bytes
+const BYTE_TIME_US: u32 = 23;
+
+// Common time base, the sensors of both channels see the same load cycle
+static START: OnceLock<Instant> = OnceLock::new();
+
+pub struct Simulator {
+    shunt_resistance: f32,
+    regulator: bool,        // output of the regulator (second sensor), the battery side otherwise
+    config: u16,
+    adc_config: u16,
+    shunt_cal: u16,
+    shunt_tempco: u16,
+    diag_alrt: u16,
+    energy: f64,            // J
+    charge: f64,            // C
+    last: Instant,
+    random: u32,
+    conversion: Option<(Instant, Sample)>,  // result registers of the last conversion and its time
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    current: f32,
+    voltage: f32,
+    temperature: f32,
+}
+
+impl Simulator {
+    // Battery side of the load, the first sensor
+    pub fn new(shunt_resistance: f32) -> Simulator {
+        Simulator::with_side(shunt_resistance, false)
+    }
+
+    // Regulated 3.3V side of the same load, the second sensor
+    pub fn regulator(shunt_resistance: f32) -> Simulator {
+        Simulator::with_side(shunt_resistance, true)
+    }
+
+    fn with_side(shunt_resistance: f32, regulator: bool) -> Simulator {
+        START.get_or_init(Instant::now);
+        Simulator {
+            shunt_resistance: shunt_resistance,
+            regulator: regulator,
+            config: 0,
+            adc_config: 0xFB68,
+            shunt_cal: 0x1000,
+            shunt_tempco: 0,
+            diag_alrt: 0,
+            energy: 0.0,
+            charge: 0.0,
+            last: Instant::now(),
+            random: if regulator { 0x2545F491 } else { 0x9E3779B9 },
+            conversion: None,
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, value: u16)
+    {
+        match reg {
+            0x00 => {
+                // RSTACC clears the energy and charge accumulators
+                if value & 0x4000 != 0 {
+                    self.energy = 0.0;
+                    self.charge = 0.0;
+                }
+                self.config = value & !0xC000;
+            },
+            0x01 => self.adc_config = value,
+            0x02 => self.shunt_cal = value & 0x7FFF,
+            0x03 => self.shunt_tempco = value & 0x3FFF,
+            0x0B => self.diag_alrt = value,
+            _ => {},
+        }
+    }
+
+    // Registers from reg on as in a block read, waits as long as the transfer over the bus would take
+    pub fn read(&mut self, reg: u8, buf: &mut [u8])
+    {
+        let sample = self.converted();
+        let mut bytes = Vec::with_capacity(buf.len() + 4);
+        let mut reg = reg;
+        while bytes.len() < buf.len() {
+            bytes.extend_from_slice(&self.register(reg, &sample));
+            reg = reg.wrapping_add(1);
+        }
+        buf.copy_from_slice(&bytes[..buf.len()]);
+        Ets::delay_us(BYTE_TIME_US * (buf.len() as u32 + 3));
+    }
+
+    // Current LSB of the SHUNT_CAL written by the driver, in the range selected by ADCRANGE
+    fn current_lsb(&self) -> f32 {
+        let scale = if self.config & 0x0010 != 0 { 4.0 } else { 1.0 };
+        self.shunt_cal as f32 / (13107.2 * 1000_000.0 * self.shunt_resistance * scale)
+    }
+
+    fn register(&self, reg: u8, sample: &Sample) -> Vec<u8> {
+        let lsb = self.current_lsb();
+        let lsb_v = if self.config & 0x0010 != 0 { 78.125e-9 } else { 312.5e-9 };
+        match reg {
+            0x00 => self.config.to_be_bytes().to_vec(),
+            0x01 => self.adc_config.to_be_bytes().to_vec(),
+            0x02 => self.shunt_cal.to_be_bytes().to_vec(),
+            0x03 => self.shunt_tempco.to_be_bytes().to_vec(),
+            0x04 => be24(encode_signed20(sample.current * self.shunt_resistance / lsb_v)),
+            0x05 => be24(((sample.voltage / 195.3125e-6) as u32).min(0xFFFFF) << 4),
+            0x06 => ((sample.temperature / 7.8125e-3) as i16).to_be_bytes().to_vec(),
+            0x07 => be24(encode_signed20(divide(sample.current, lsb))),
+            0x08 => be24(divide(sample.voltage * sample.current.abs(), 3.2 * lsb).min(0xFFFFFF as f32) as u32),
+            0x09 => be40(divide(self.energy as f32, 16.0 * 3.2 * lsb) as u64),
+            0x0A => be40(divide(self.charge as f32, lsb) as i64 as u64),
+            // CNVRF, a conversion is always ready
+            0x0B => (self.diag_alrt | 0x0002).to_be_bytes().to_vec(),
+            _ => vec![0, 0],
+        }
+    }
+
+    // The result registers keep a conversion until the next one is complete, so the registers read one
+    // by one belong to the same sample as on the real sensor
+    fn converted(&mut self) -> Sample {
+        let period = Duration::from_micros(AdcConfig::from_register(self.adc_config).conversion_period_us());
+        match self.conversion {
+            Some((at, sample)) if at.elapsed() < period => sample,
+            _ => {
+                let sample = self.sample();
+                self.conversion = Some((Instant::now(), sample));
+                sample
+            }
+        }
+    }
+
+    // Load at the present time, accumulating energy and charge since the previous read
+    fn sample(&mut self) -> Sample {
+        let now = Instant::now();
+        let t = now.duration_since(*START.get_or_init(Instant::now)).as_secs_f32();
+        let noise = self.noise();
+        let load = load_current(t) * (1.0 + NOISE * noise);
+        let battery = (BATTERY_FULL_V - BATTERY_DRAIN_V_PER_S * t).max(BATTERY_EMPTY_V) - BATTERY_RESISTANCE * load;
+        let (current, voltage) = match self.regulator {
+            true => (battery * load * REGULATOR_EFFICIENCY / REGULATOR_V, REGULATOR_V * (1.0 + 0.002 * noise)),
+            false => (load, battery),
+        };
+        let dt = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.energy += (voltage * current) as f64 * dt;
+        self.charge += current as f64 * dt;
+        Sample {
+            current: current,
+            voltage: voltage,
+            temperature: 25.0 + 2.0 * (t * std::f32::consts::TAU / 600.0).sin() + 0.1 * noise,
+        }
+    }
+
+    // Uniform in -1..1, xorshift32
+    fn noise(&mut self) -> f32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+// Current of the load t seconds after the start, the battery side
+fn load_current(t: f32) -> f32 {
+    let phase = t % CYCLE_S;
+    let base = match phase {
+        p if p >= BURST_START_S && p < BURST_START_S + BURST_S => BURST_A,
+        p if p < ACTIVE_S => ACTIVE_A,
+        _ => SLEEP_A,
+    };
+    // Triangle from -1 to 1
+    let ripple = 4.0 * ((t * RIPPLE_HZ) % 1.0 - 0.5).abs() - 1.0;
+    base * (1.0 + RIPPLE * ripple)
+}
+
+fn divide(value: f32, lsb: f32) -> f32 {
+    if lsb > 0.0 { value / lsb } else { 0.0 }
+}
+
+// 20-bit two's complement left-aligned in 24 bits, clipped at full scale
+fn encode_signed20(value: f32) -> u32 {
+    let raw = (value as i32).clamp(-0x80000, 0x7FFFF);
+    ((raw << 4) as u32) & 0xFFFFFF
+}
+
+fn be24(value: u32) -> Vec<u8> {
+    value.to_be_bytes()[1..].to_vec()
+}
+
+fn be40(value: u64) -> Vec<u8> {
+    value.to_be_bytes()[3..].to_vec()
+}
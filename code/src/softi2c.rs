@@ -0,0 +1,102 @@
+// SoftI2c
+// Bit-banged I2C master for the display on a bus of its own. The ESP32-C3 has one I2C controller,
+// which stays with the sensors, so a refresh of the panel never waits for or delays a sensor read.
+// Write only, as the SSD1306 is never read. The clock is stretched when the thread is preempted,
+// which I2C allows. Both lines are open drain with the internal pull-ups, external ones are faster.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use esp_idf_hal::delay::Ets;
+use esp_idf_hal::gpio::{AnyIOPin, InputOutput, PinDriver, Pull};
+
+// Half of the SCL period, the GPIO calls add about as much again (about 250kHz)
+const HALF_PERIOD_US: u32 = 1;
+
+pub struct SoftI2c {
+    sda: PinDriver<'static, AnyIOPin, InputOutput>,
+    scl: PinDriver<'static, AnyIOPin, InputOutput>,
+}
+
+impl SoftI2c {
+    pub fn new(sda: i32, scl: i32) -> anyhow::Result<SoftI2c> {
+        let mut sda = PinDriver::input_output_od(unsafe { AnyIOPin::new(sda) })?;
+        let mut scl = PinDriver::input_output_od(unsafe { AnyIOPin::new(scl) })?;
+        sda.set_pull(Pull::Up)?;
+        scl.set_pull(Pull::Up)?;
+        sda.set_high()?;
+        scl.set_high()?;
+        Ok(SoftI2c { sda: sda, scl: scl })
+    }
+
+    fn delay(&self)
+    {
+        Ets::delay_us(HALF_PERIOD_US);
+    }
+
+    // SDA falls while SCL is high
+    fn start(&mut self) -> anyhow::Result<()> {
+        self.sda.set_high()?;
+        self.scl.set_high()?;
+        self.delay();
+        self.sda.set_low()?;
+        self.delay();
+        self.scl.set_low()?;
+        Ok(())
+    }
+
+    // SDA rises while SCL is high
+    fn stop(&mut self) -> anyhow::Result<()> {
+        self.sda.set_low()?;
+        self.delay();
+        self.scl.set_high()?;
+        self.delay();
+        self.sda.set_high()?;
+        self.delay();
+        Ok(())
+    }
+
+    // Eight bits MSB first, then the ninth clock for the acknowledge, false if the device did not pull SDA low
+    fn write_byte(&mut self, byte: u8) -> anyhow::Result<bool> {
+        for bit in (0..8).rev() {
+            if byte & (1 << bit) != 0 {
+                self.sda.set_high()?;
+            }
+            else {
+                self.sda.set_low()?;
+            }
+            self.delay();
+            self.scl.set_high()?;
+            self.delay();
+            self.scl.set_low()?;
+        }
+        self.sda.set_high()?;
+        self.delay();
+        self.scl.set_high()?;
+        self.delay();
+        let ack = self.sda.is_low();
+        self.scl.set_low()?;
+        Ok(ack)
+    }
+
+    pub fn write(&mut self, address: u8, bytes: &[u8]) -> anyhow::Result<()> {
+        self.start()?;
+        let mut result = Ok(());
+        for byte in std::iter::once(address << 1).chain(bytes.iter().copied()) {
+            if !self.write_byte(byte)? {
+                result = Err(anyhow::anyhow!("No acknowledge from 0x{:02X}", address));
+                break;
+            }
+        }
+        self.stop()?;
+        result
+    }
+}
+
+// The I2C trait of the SSD1306 driver
+impl embedded_hal_0_2::blocking::i2c::Write for SoftI2c {
+    type Error = ();
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        SoftI2c::write(self, address, bytes).map_err(|_| ())
+    }
+}
@@ -0,0 +1,125 @@
+// Stats
+// Running statistics of the measured values.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::time::{Duration, Instant};
+
+// An interval longer than GAP_FACTOR times the usual sample interval (or MAX_INTERVAL) is a gap
+const GAP_FACTOR: f32 = 5.0;
+const MIN_GAP: Duration = Duration::from_millis(50);
+const MAX_INTERVAL: Duration = Duration::from_secs(2);
+
+// Peak-hold of the instantaneous current and power, updated at the sensor rate
+pub struct PeakHold {
+    pub current: f32,
+    pub power: f32,
+}
+
+impl PeakHold {
+    pub fn new() -> PeakHold {
+        PeakHold { current: 0.0, power: 0.0 }
+    }
+
+    pub fn update(&mut self, current: f32, power: f32)
+    {
+        if current.abs() > self.current.abs() {
+            self.current = current;
+        }
+        if power.abs() > self.power.abs() {
+            self.power = power;
+        }
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.current = 0.0;
+        self.power = 0.0;
+    }
+}
+
+// Energy from the power samples: trapezoidal integration over the actual time between the samples.
+// Gaps (stalled or dropped samples) are not integrated across, the energy resumes at the next sample.
+// Next to the energy of this session, cumulative energy and charge totals are kept across reboots.
+pub struct EnergyIntegrator {
+    energy: f64,            // J, since boot
+    total_energy: f64,      // J, cumulative
+    total_charge: f64,      // C, cumulative
+    last: Option<(f32, f32, Instant)>,  // previous power and current sample
+    interval: Option<f32>,  // usual sample interval (s), averaged
+    gaps: u32,
+    gap_time: Duration,     // time not integrated
+}
+
+impl EnergyIntegrator {
+    pub fn new() -> EnergyIntegrator {
+        EnergyIntegrator { energy: 0.0, total_energy: 0.0, total_charge: 0.0, last: None, interval: None, gaps: 0, gap_time: Duration::ZERO }
+    }
+
+    pub fn update(&mut self, power: f32, current: f32, instant: Instant)
+    {
+        if let Some((last_power, last_current, last_instant)) = self.last {
+            let dt = instant.saturating_duration_since(last_instant);
+            let limit = match self.interval {
+                Some(interval) => Duration::from_secs_f32(interval * GAP_FACTOR).max(MIN_GAP).min(MAX_INTERVAL),
+                None => MAX_INTERVAL,
+            };
+            if dt > limit {
+                self.gaps += 1;
+                self.gap_time += dt;
+            }
+            else {
+                let dt = dt.as_secs_f32();
+                let energy = ((last_power + power) / 2.0 * dt) as f64;
+                self.energy += energy;
+                self.total_energy += energy;
+                self.total_charge += ((last_current + current) / 2.0 * dt) as f64;
+                self.interval = Some(match self.interval {
+                    Some(interval) => interval * 0.9 + dt * 0.1,
+                    None => dt,
+                });
+            }
+        }
+        self.last = Some((power, current, instant));
+    }
+
+    // Energy since boot
+    pub fn energy_wh(&self) -> f32 {
+        (self.energy / 3600.0) as f32
+    }
+
+    // Cumulative totals as (Wh, Ah)
+    pub fn totals(&self) -> (f64, f64) {
+        (self.total_energy / 3600.0, self.total_charge / 3600.0)
+    }
+
+    // Continue the totals checkpointed before the reboot
+    pub fn restore_totals(&mut self, energy_wh: f64, charge_ah: f64)
+    {
+        self.total_energy = energy_wh * 3600.0;
+        self.total_charge = charge_ah * 3600.0;
+    }
+
+    pub fn reset_totals(&mut self)
+    {
+        self.total_energy = 0.0;
+        self.total_charge = 0.0;
+    }
+
+    // Number of gaps and the time left out
+    pub fn gaps(&self) -> (u32, Duration) {
+        (self.gaps, self.gap_time)
+    }
+}
+
+// Final summary of a measurement session, sent on a graceful shutdown or at the end of a capture
+pub struct SessionSummary {
+    pub event: &'static str,    // "shutdown" or "capture"
+    pub uptime: u64,        // seconds since boot, or of the capture
+    pub records: u32,       // records logged since boot, or during the capture
+    pub dropped: u32,       // records dropped by the retention policy
+    pub energy: f32,        // Wh
+    pub peak_current: f32,  // A
+    pub peak_power: f32,    // W
+    pub clock: u128,
+}
@@ -0,0 +1,149 @@
+// StatusLed
+// Shows the device state on a WS2812 RGB LED or a simple LED, readable from across the room:
+// alarm (red, fast blink), uploads failing (orange, double blink), WiFi down (blue, slow blink),
+// logging (green, on) and idle (green, short blink every 2 seconds).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::{thread, sync::Arc, sync::Mutex};
+use std::time::{Duration, Instant};
+use esp_idf_hal::gpio::{PinDriver, AnyOutputPin, Output};
+use esp_idf_hal::rmt::{TxRmtDriver, FixedLengthSignal, PinState, Pulse, CHANNEL0};
+use esp_idf_hal::rmt::config::TransmitConfig;
+#[cfg(feature = "influx")]
+use crate::transfer::TransferStatus;
+use crate::tasks;
+
+const LED_TICK_MS: u64 = 50;
+// WS2812 full scale is too bright for an indicator
+const LED_BRIGHTNESS: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedType {
+    Ws2812,
+    Led,            // active high
+    LedInverted,    // active low
+}
+
+impl LedType {
+    pub fn from_str(text: &str) -> Option<LedType> {
+        match text.trim() {
+            "ws2812" => Some(LedType::Ws2812),
+            "led" => Some(LedType::Led),
+            "led_inverted" => Some(LedType::LedInverted),
+            _ => None,
+        }
+    }
+}
+
+// Device state set by the main loop, the upload failure comes from the upload task
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LedState {
+    Idle,
+    Logging,
+    WifiDown,
+    Alarm,
+}
+
+struct LedInputs {
+    state: LedState,
+    transfer_failing: bool,
+}
+
+enum LedOutput {
+    Rgb(TxRmtDriver<'static>),
+    Pin(PinDriver<'static, AnyOutputPin, Output>, bool),    // inverted
+}
+
+#[derive(Clone)]
+pub struct StatusLed {
+    inputs: Arc<Mutex<LedInputs>>,
+}
+
+impl StatusLed {
+    pub fn new() -> StatusLed {
+        StatusLed { inputs: Arc::new(Mutex::new(LedInputs { state: LedState::Idle, transfer_failing: false })) }
+    }
+
+    pub fn start(&mut self, channel: CHANNEL0, pin: AnyOutputPin, led_type: LedType) -> anyhow::Result<()>
+    {
+        let mut output = match led_type {
+            LedType::Ws2812 => LedOutput::Rgb(TxRmtDriver::new(channel, pin, &TransmitConfig::new().clock_divider(1))?),
+            LedType::Led | LedType::LedInverted => LedOutput::Pin(PinDriver::output(pin)?, led_type == LedType::LedInverted),
+        };
+        let inputs = self.inputs.clone();
+        let _th = tasks::spawn(&tasks::STATUS_LED, move || {
+            info!("Start Status LED Thread.");
+            let start = Instant::now();
+            let mut prev: Option<(u8, u8, u8)> = None;
+            loop {
+                let (state, transfer_failing) = {
+                    let lck = inputs.lock().unwrap();
+                    (lck.state, lck.transfer_failing)
+                };
+                let t = start.elapsed().as_millis() as u64;
+                // The alarm comes first, then failing uploads
+                let (color, lit) = match (state, transfer_failing) {
+                    (LedState::Alarm, _) => ((255, 0, 0), t % 200 < 100),
+                    (_, true) => ((255, 80, 0), t % 1500 < 100 || (t % 1500 >= 200 && t % 1500 < 300)),
+                    (LedState::WifiDown, false) => ((0, 0, 255), t % 1000 < 500),
+                    (LedState::Logging, false) => ((0, 255, 0), true),
+                    (LedState::Idle, false) => ((0, 255, 0), t % 2000 < 100),
+                };
+                let color = if lit { color } else { (0, 0, 0) };
+                if prev != Some(color) {
+                    if let Err(e) = write_led(&mut output, color) {
+                        info!("Status LED failed: {:?}", e);
+                    }
+                    prev = Some(color);
+                }
+                thread::sleep(Duration::from_millis(LED_TICK_MS));
+            }
+        });
+        Ok(())
+    }
+
+    pub fn set_state(&mut self, state: LedState)
+    {
+        let mut lck = self.inputs.lock().unwrap();
+        lck.state = state;
+    }
+
+    #[cfg(feature = "influx")]
+    pub fn set_transfer_status(&mut self, status: TransferStatus)
+    {
+        let mut lck = self.inputs.lock().unwrap();
+        lck.transfer_failing = status != TransferStatus::Ok;
+    }
+}
+
+fn write_led(output: &mut LedOutput, (r, g, b): (u8, u8, u8)) -> anyhow::Result<()> {
+    match output {
+        LedOutput::Pin(pin, inverted) => {
+            if (r | g | b != 0) != *inverted {
+                pin.set_high()?;
+            }
+            else {
+                pin.set_low()?;
+            }
+        },
+        LedOutput::Rgb(tx) => {
+            // 24 bits in GRB order, MSB first: 0 = 350ns high + 800ns low, 1 = 700ns high + 600ns low
+            let scale = |c: u8| c as u32 * LED_BRIGHTNESS / 255;
+            let grb = (scale(g) << 16) | (scale(r) << 8) | scale(b);
+            let ticks_hz = tx.counter_clock()?;
+            let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(350))?;
+            let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &Duration::from_nanos(800))?;
+            let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(700))?;
+            let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &Duration::from_nanos(600))?;
+            let mut signal = FixedLengthSignal::<24>::new();
+            for i in 0..24 {
+                let bit = grb & (1 << (23 - i)) != 0;
+                signal.set(i, &if bit { (t1h, t1l) } else { (t0h, t0l) })?;
+            }
+            tx.start_blocking(&signal)?;
+        },
+    }
+    Ok(())
+}
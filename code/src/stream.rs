@@ -0,0 +1,103 @@
+// Stream
+// Streams samples over the USB serial console so the meter can be used as a bench instrument.
+// CSV mode writes every sample, SCPI mode answers MEAS? queries with the latest sample.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::io::Write;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use crate::CurrentLog;
+use crate::tasks;
+
+// Lines waiting for the writer thread, samples are dropped instead of blocking when full
+const STREAM_QUEUE_SIZE: usize = 64;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum StreamMode {
+    Off,
+    Csv,
+    Scpi,
+}
+
+impl StreamMode {
+    pub fn from_str(mode: &str) -> Option<StreamMode> {
+        match mode {
+            "off" => Some(StreamMode::Off),
+            "csv" => Some(StreamMode::Csv),
+            "scpi" => Some(StreamMode::Scpi),
+            _ => None,
+        }
+    }
+}
+
+pub struct Streamer {
+    mode: StreamMode,
+    tx: SyncSender<String>,
+    dropped: u32,
+}
+
+impl Streamer {
+    pub fn new(mode: StreamMode) -> Streamer {
+        let (tx, rx) = sync_channel::<String>(STREAM_QUEUE_SIZE);
+        let _th = tasks::spawn(&tasks::STREAM, move || {
+            info!("Start stream thread.");
+            let mut out = std::io::stdout();
+            for line in rx {
+                let _ = out.write_all(line.as_bytes());
+                let _ = out.flush();
+            }
+        });
+        let mut streamer = Streamer { mode: StreamMode::Off, tx: tx, dropped: 0 };
+        streamer.set_mode(mode);
+        streamer
+    }
+
+    pub fn set_mode(&mut self, mode: StreamMode)
+    {
+        self.mode = mode;
+        // Keep the stream free of log lines while streaming CSV
+        match mode {
+            StreamMode::Csv => {
+                log::set_max_level(LevelFilter::Warn);
+                self.send("time,voltage,current,power,battery\n".to_string());
+            },
+            _ => {
+                log::set_max_level(LevelFilter::Info);
+            }
+        }
+    }
+
+    pub fn mode(&self) -> StreamMode {
+        self.mode
+    }
+
+    // Called for every sample
+    pub fn push(&mut self, data: &CurrentLog)
+    {
+        if self.mode != StreamMode::Csv {
+            return;
+        }
+        self.send(format!("{},{:.5},{:.5},{:.5},{:.2}\n", data.clock, data.voltage, data.current, data.power, data.battery));
+    }
+
+    // Response to MEAS?: voltage,current,power
+    pub fn respond(&mut self, data: &CurrentLog)
+    {
+        self.send(format!("{:.5},{:.5},{:.5}\n", data.voltage, data.current, data.power));
+    }
+
+    fn send(&mut self, line: String)
+    {
+        match self.tx.try_send(line) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped % 100 == 1 {
+                    warn!("Stream output is too slow, {} lines dropped", self.dropped);
+                }
+            },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}
@@ -0,0 +1,232 @@
+// Summary
+// Periodic summary reports: every hour or every day at local midnight the energy, the average and
+// peak current, the uptime and the error counts of the period are sent as a separate InfluxDB
+// measurement and, if summary_webhook is set, posted as JSON to a webhook.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::sync::mpsc::Sender;
+use std::time::SystemTime;
+#[cfg(feature = "wifi")]
+use std::sync::mpsc;
+#[cfg(feature = "wifi")]
+use std::time::Duration;
+#[cfg(feature = "wifi")]
+use embedded_svc::http::client::Client;
+#[cfg(feature = "wifi")]
+use embedded_svc::http::Method;
+#[cfg(feature = "wifi")]
+use esp_idf_svc::http::client::{EspHttpConnection, Configuration};
+use crate::CurrentLog;
+use crate::schedule;
+use crate::timestamp::Timestamp;
+#[cfg(feature = "wifi")]
+use crate::tasks;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SummaryInterval {
+    Off,
+    Hourly,
+    Daily,      // at local midnight
+}
+
+impl SummaryInterval {
+    pub fn from_str(interval: &str) -> Option<SummaryInterval> {
+        match interval {
+            "off" => Some(SummaryInterval::Off),
+            "hourly" => Some(SummaryInterval::Hourly),
+            "daily" => Some(SummaryInterval::Daily),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SummaryInterval::Off => "off",
+            SummaryInterval::Hourly => "hourly",
+            SummaryInterval::Daily => "daily",
+        }
+    }
+}
+
+pub struct SummaryReport {
+    pub period: &'static str,   // "hourly" or "daily"
+    pub energy: f32,            // Wh
+    pub average_current: f32,
+    pub peak_current: f32,
+    pub samples: u32,
+    pub uptime: u64,            // seconds since boot
+    pub sensor_errors: u32,     // failed sensor reads in the period
+    pub reconnects: u32,        // WiFi reconnects in the period
+    pub clock: u128,            // end of the period
+}
+
+impl SummaryReport {
+    pub fn to_json(&self) -> String {
+        format!("{{\"ts\":{},\"period\":\"{}\",\"energy\":{:.6},\"average_current\":{:.5},\"peak_current\":{:.5},\"samples\":{},\"uptime\":{},\"sensor_errors\":{},\"reconnects\":{}}}",
+            self.clock,
+            self.period,
+            self.energy,
+            self.average_current,
+            self.peak_current,
+            self.samples,
+            self.uptime,
+            self.sensor_errors,
+            self.reconnects,
+        )
+    }
+}
+
+pub struct Summary {
+    interval: SummaryInterval,
+    webhook: Option<Sender<String>>,
+    period: Option<u32>,        // local hour or day of the running period, None until the clock is set
+    current_sum: f64,
+    peak_current: f32,
+    samples: u32,
+    energy: f32,                // Wh since boot, and the error counts, at the start of the period
+    sensor_errors: u32,
+    reconnects: u32,
+    pending: Option<SummaryReport>,
+}
+
+impl Summary {
+    pub fn new(interval: SummaryInterval, webhook_url: &str) -> Summary {
+        let webhook = match interval != SummaryInterval::Off && !webhook_url.is_empty() {
+            true => start_webhook(webhook_url.to_string()),
+            false => None,
+        };
+        Summary {
+            interval: interval,
+            webhook: webhook,
+            period: None,
+            current_sum: 0.0,
+            peak_current: 0.0,
+            samples: 0,
+            energy: 0.0,
+            sensor_errors: 0,
+            reconnects: 0,
+            pending: None,
+        }
+    }
+
+    // Called with every sample
+    pub fn update(&mut self, data: &CurrentLog)
+    {
+        if self.interval == SummaryInterval::Off {
+            return;
+        }
+        self.current_sum += data.current as f64;
+        if data.current.abs() > self.peak_current.abs() {
+            self.peak_current = data.current;
+        }
+        self.samples += 1;
+    }
+
+    // Returns the report of the period that has ended, with the energy (Wh) and the error counts since boot.
+    // The report is kept until sent() is called.
+    pub fn poll(&mut self, energy: f32, sensor_errors: u32, reconnects: u32) -> Option<&SummaryReport> {
+        let clock = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        // Reports start once the clock has been set
+        if self.interval == SummaryInterval::Off || Timestamp::valid(clock.as_nanos()).is_none() {
+            return self.pending.as_ref();
+        }
+        let (weekday, minute) = schedule::local_time();
+        let period = match self.interval {
+            SummaryInterval::Hourly => weekday * 24 + minute as u32 / 60,
+            _ => weekday,
+        };
+        match self.period {
+            // The first period starts when the clock is set
+            None => {
+                self.period = Some(period);
+                self.reset(energy, sensor_errors, reconnects);
+            },
+            Some(last) if last != period => {
+                self.period = Some(period);
+                let report = self.report(clock.as_nanos(), energy, sensor_errors, reconnects);
+                info!("Summary: {:.6}Wh average {:.5}A peak {:.5}A", report.energy, report.average_current, report.peak_current);
+                if let Some(ref webhook) = self.webhook {
+                    let _ = webhook.send(report.to_json());
+                }
+                // A report that could not be sent is replaced by the newer one
+                self.pending = Some(report);
+                self.reset(energy, sensor_errors, reconnects);
+            },
+            Some(_) => {},
+        }
+        self.pending.as_ref()
+    }
+
+    pub fn sent(&mut self)
+    {
+        self.pending = None;
+    }
+
+    fn report(&self, clock: u128, energy: f32, sensor_errors: u32, reconnects: u32) -> SummaryReport {
+        let uptime_us = unsafe { esp_idf_sys::esp_timer_get_time() };
+        SummaryReport {
+            period: self.interval.name(),
+            energy: energy - self.energy,
+            average_current: if self.samples > 0 { (self.current_sum / self.samples as f64) as f32 } else { 0.0 },
+            peak_current: self.peak_current,
+            samples: self.samples,
+            uptime: (uptime_us / 1_000_000) as u64,
+            sensor_errors: sensor_errors.wrapping_sub(self.sensor_errors),
+            reconnects: reconnects.wrapping_sub(self.reconnects),
+            clock: clock,
+        }
+    }
+
+    fn reset(&mut self, energy: f32, sensor_errors: u32, reconnects: u32)
+    {
+        self.current_sum = 0.0;
+        self.peak_current = 0.0;
+        self.samples = 0;
+        self.energy = energy;
+        self.sensor_errors = sensor_errors;
+        self.reconnects = reconnects;
+    }
+}
+
+// Posts the reports to the webhook from its own thread, a failed post is logged and not repeated
+#[cfg(feature = "wifi")]
+fn start_webhook(url: String) -> Option<Sender<String>> {
+    let (tx, rx) = mpsc::channel::<String>();
+    let _th = tasks::spawn(&tasks::WEBHOOK, move || {
+        info!("Start summary webhook thread.");
+        for body in rx {
+            if let Err(e) = post(&url, &body) {
+                info!("Summary webhook failed: {:?}", e);
+            }
+        }
+    });
+    Some(tx)
+}
+
+#[cfg(not(feature = "wifi"))]
+fn start_webhook(url: String) -> Option<Sender<String>> {
+    info!("Summary webhook {} ignored, built without WiFi", url);
+    None
+}
+
+#[cfg(feature = "wifi")]
+fn post(url: &str, body: &str) -> anyhow::Result<()> {
+    let http = EspHttpConnection::new(&Configuration {
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    })?;
+    let mut client = Client::wrap(http);
+    let content_length = body.len().to_string();
+    let headers = [("Content-Type", "application/json"), ("Content-Length", content_length.as_str())];
+    let mut request = client.request(Method::Post, url, &headers)?;
+    request.write(body.as_bytes())?;
+    let response = request.submit()?;
+    match response.status() {
+        200..=299 => Ok(()),
+        status => Err(anyhow::anyhow!("HTTP status {}", status)),
+    }
+}
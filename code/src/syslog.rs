@@ -0,0 +1,165 @@
+// Syslog
+// Forwards the log output to a remote syslog server or an HTTP log collector, so units in the field
+// can be debugged without a serial cable:
+//   udp://<host>[:port]     one RFC 5424 message per line, port 514 by default
+//   http(s)://<host>/<path> POST of the lines of about a second as text/plain, one message per line
+// The logger hands the lines to a queue without blocking, a thread sends them and drops them while
+// the network is down. The thread must not log itself, its errors go to the serial port only.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::net::UdpSocket;
+use std::sync::OnceLock;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, RecvTimeoutError};
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, Utc};
+use log::{Level, Record};
+use embedded_svc::http::client::Client;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::client::{EspHttpConnection, Configuration};
+use crate::timebase;
+use crate::tasks;
+
+// Lines waiting for the sender thread, dropped instead of blocking when full
+const SYSLOG_QUEUE_SIZE: usize = 64;
+const SYSLOG_PORT: u16 = 514;
+// Lines collected for one POST to an HTTP collector
+const HTTP_BATCH_TIME: Duration = Duration::from_secs(1);
+const HTTP_BATCH_LINES: usize = 64;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// Facility local0
+const FACILITY: u8 = 16;
+const APP_NAME: &str = "mini-current-meter";
+
+#[derive(Clone, PartialEq)]
+pub enum SyslogTarget {
+    Udp(String),    // host:port
+    Http(String),   // URL
+}
+
+struct LogLine {
+    level: Level,
+    clock: SystemTime,
+    message: String,
+}
+
+static SINK: OnceLock<SyncSender<LogLine>> = OnceLock::new();
+
+// udp://host[:port] or an http(s) URL, None if the URL is not one of them
+pub fn parse_url(url: &str) -> Option<SyslogTarget> {
+    if let Some(rest) = url.strip_prefix("udp://") {
+        let host = rest.trim_end_matches('/');
+        return match host.rsplit_once(':') {
+            Some((name, port)) if !name.is_empty() && port.parse::<u16>().is_ok() => Some(SyslogTarget::Udp(host.to_string())),
+            Some(_) => None,
+            None if !host.is_empty() => Some(SyslogTarget::Udp(format!("{}:{}", host, SYSLOG_PORT))),
+            None => None,
+        };
+    }
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return Some(SyslogTarget::Http(url.to_string()));
+    }
+    None
+}
+
+// Called by the logger for every line, never blocks
+pub fn forward(record: &Record)
+{
+    if let Some(tx) = SINK.get() {
+        let _ = tx.try_send(LogLine { level: record.level(), clock: SystemTime::now(), message: record.args().to_string() });
+    }
+}
+
+pub fn start(target: SyslogTarget, hostname: &str)
+{
+    let (tx, rx) = sync_channel::<LogLine>(SYSLOG_QUEUE_SIZE);
+    let hostname = match hostname {
+        "" => "-".to_string(),
+        name => name.chars().filter(|c| c.is_ascii_graphic()).collect(),
+    };
+    let _th = tasks::spawn(&tasks::SYSLOG, move || {
+        match target {
+            SyslogTarget::Udp(addr) => send_udp(rx, &addr, &hostname),
+            SyslogTarget::Http(url) => send_http(rx, &url, &hostname),
+        }
+    });
+    if SINK.set(tx).is_err() {
+        println!("Syslog is already started");
+    }
+}
+
+// RFC 5424: <PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG, no timestamp before the clock is set
+fn format_line(line: &LogLine, hostname: &str) -> String {
+    let severity = match line.level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    let timestamp = match timebase::is_valid() {
+        true => DateTime::<Utc>::from(line.clock).format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        false => "-".to_string(),
+    };
+    format!("<{}>1 {} {} {} - - - {}", FACILITY * 8 + severity, timestamp, hostname, APP_NAME, line.message.replace('\n', " "))
+}
+
+fn send_udp(rx: Receiver<LogLine>, addr: &str, hostname: &str)
+{
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!("Syslog socket failed: {:?}", e);
+            return;
+        }
+    };
+    println!("Syslog to udp://{}", addr);
+    for line in rx {
+        // Fails while WiFi is down, the line is lost
+        let _ = socket.send_to(format_line(&line, hostname).as_bytes(), addr);
+    }
+}
+
+fn send_http(rx: Receiver<LogLine>, url: &str, hostname: &str)
+{
+    println!("Syslog to {}", url);
+    loop {
+        // Wait for a line, then collect the following ones for a moment
+        let mut body = match rx.recv() {
+            Ok(line) => format_line(&line, hostname) + "\n",
+            Err(_) => return,
+        };
+        let mut lines = 1;
+        while lines < HTTP_BATCH_LINES {
+            match rx.recv_timeout(HTTP_BATCH_TIME) {
+                Ok(line) => {
+                    body.push_str(&format_line(&line, hostname));
+                    body.push('\n');
+                    lines += 1;
+                },
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        if let Err(e) = post(url, &body) {
+            println!("Syslog POST failed, {} lines lost: {:?}", lines, e);
+        }
+    }
+}
+
+fn post(url: &str, body: &str) -> anyhow::Result<()> {
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(HTTP_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = Client::wrap(connection);
+    let length = body.len().to_string();
+    let mut request = client.post(url, &[("Content-Type", "text/plain"), ("Content-Length", &length)])?;
+    request.write_all(body.as_bytes())?;
+    request.flush()?;
+    let response = request.submit()?;
+    match response.status() {
+        200..=299 => Ok(()),
+        status => Err(anyhow::anyhow!("HTTP status {}", status)),
+    }
+}
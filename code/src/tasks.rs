@@ -0,0 +1,99 @@
+// Tasks
+// The threads of the firmware with their FreeRTOS priority and stack size. std::thread::spawn starts every
+// thread at the pthread default (priority 5, 30KB stack), so a busy upload could delay the sampling and a
+// thread close to its stack limit went unnoticed until it crashed. The sampler runs above the display,
+// the network path runs lowest, and the stack high-water marks are sent with the health telemetry.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::thread::{self, JoinHandle};
+use esp_idf_hal::task::thread::ThreadSpawnConfiguration;
+
+// Stack left below this is reported at every health record (bytes)
+const STACK_LOW_BYTES: u32 = 1024;
+
+pub struct TaskSpec {
+    pub name: &'static [u8],    // FreeRTOS task name, NUL terminated, at most 15 characters
+    pub priority: u8,
+    pub stack_size: usize,      // bytes
+}
+
+impl TaskSpec {
+    // Name without the NUL
+    pub fn label(&self) -> &'static str {
+        std::str::from_utf8(&self.name[..self.name.len() - 1]).unwrap_or("?")
+    }
+}
+
+// The main loop, raised from priority 1 by set_main_priority() above the network path that it feeds.
+// Its stack is CONFIG_ESP_MAIN_TASK_STACK_SIZE.
+pub const MAIN: TaskSpec = TaskSpec { name: b"main\0", priority: 3, stack_size: 50000 };
+pub const SAMPLER: TaskSpec = TaskSpec { name: b"sampler\0", priority: 10, stack_size: 8192 };
+pub const DISPLAY: TaskSpec = TaskSpec { name: b"display\0", priority: 5, stack_size: 16384 };
+pub const BUZZER: TaskSpec = TaskSpec { name: b"buzzer\0", priority: 4, stack_size: 4096 };
+pub const STATUS_LED: TaskSpec = TaskSpec { name: b"statusled\0", priority: 4, stack_size: 4096 };
+pub const CONSOLE: TaskSpec = TaskSpec { name: b"console\0", priority: 3, stack_size: 8192 };
+pub const MQTT: TaskSpec = TaskSpec { name: b"mqtt\0", priority: 3, stack_size: 8192 };
+pub const STREAM: TaskSpec = TaskSpec { name: b"stream\0", priority: 3, stack_size: 6144 };
+pub const SYSLOG: TaskSpec = TaskSpec { name: b"syslog\0", priority: 3, stack_size: 6144 };
+pub const WS_PUSH: TaskSpec = TaskSpec { name: b"wspush\0", priority: 3, stack_size: 8192 };
+pub const SCPI: TaskSpec = TaskSpec { name: b"scpi\0", priority: 3, stack_size: 6144 };
+// WiFi supervisor and uploads, below the sampler and the display
+pub const NETWORK: TaskSpec = TaskSpec { name: b"network\0", priority: 2, stack_size: 24576 };
+// Summary report webhook, a blocking HTTPS client
+pub const WEBHOOK: TaskSpec = TaskSpec { name: b"webhook\0", priority: 2, stack_size: 12288 };
+// Sets the DS3231 after each NTP sync, mostly waiting for its second edge
+pub const RTC: TaskSpec = TaskSpec { name: b"rtc\0", priority: 2, stack_size: 6144 };
+
+const TASKS: [&TaskSpec; 14] = [&MAIN, &SAMPLER, &DISPLAY, &BUZZER, &STATUS_LED, &CONSOLE, &MQTT, &STREAM, &SYSLOG, &WS_PUSH, &SCPI, &NETWORK, &WEBHOOK, &RTC];
+
+// Called from the main task before any thread is spawned
+pub fn set_main_priority()
+{
+    unsafe {
+        esp_idf_sys::vTaskPrioritySet(std::ptr::null_mut(), MAIN.priority as u32);
+    }
+}
+
+// Spawn the thread as the task, panics like thread::spawn when it cannot be created
+pub fn spawn<F, T>(task: &TaskSpec, f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    let conf = ThreadSpawnConfiguration {
+        name: Some(task.name),
+        stack_size: task.stack_size,
+        priority: task.priority,
+        ..Default::default()
+    };
+    if let Err(e) = conf.set() {
+        info!("Failed to configure the {} task: {:?}", task.label(), e);
+    }
+    let handle = thread::Builder::new()
+        .name(task.label().to_string())
+        .stack_size(task.stack_size)
+        .spawn(f)
+        .expect("failed to spawn thread");
+    // Threads spawned later by the ESP-IDF components keep the defaults
+    if let Err(e) = ThreadSpawnConfiguration::default().set() {
+        info!("Failed to reset the task configuration: {:?}", e);
+    }
+    handle
+}
+
+// Least free stack since the start of each running task (bytes), tasks that were not started are left out
+pub fn stack_high_water_marks() -> Vec<(&'static str, u32)> {
+    let mut marks: Vec<(&'static str, u32)> = Vec::new();
+    for task in TASKS.iter() {
+        let handle = unsafe { esp_idf_sys::xTaskGetHandle(task.name.as_ptr() as *const _) };
+        if handle.is_null() {
+            continue;
+        }
+        let free = unsafe { esp_idf_sys::uxTaskGetStackHighWaterMark(handle) } as u32;
+        if free < STACK_LOW_BYTES {
+            info!("Task {} stack is low: {} of {} bytes left", task.label(), free, task.stack_size);
+        }
+        marks.push((task.label(), free));
+    }
+    marks
+}
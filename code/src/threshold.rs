@@ -0,0 +1,72 @@
+// Threshold
+// Measurement condition shared by the logging trigger and the output rules: a quantity compared with
+// a value, e.g. power>5, optionally held for a time, e.g. current>0.5:100 (ms).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::time::Duration;
+use crate::CurrentLog;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantity {
+    Current,
+    Voltage,
+    Power,
+}
+
+impl Quantity {
+    pub fn from_name(name: &str) -> Option<Quantity> {
+        match name {
+            "current" => Some(Quantity::Current),
+            "voltage" => Some(Quantity::Voltage),
+            "power" => Some(Quantity::Power),
+            _ => None,
+        }
+    }
+
+    // The quantity of a sample
+    pub fn measure(&self, data: &CurrentLog) -> f32 {
+        match self {
+            Quantity::Current => data.current,
+            Quantity::Voltage => data.voltage,
+            Quantity::Power => data.power,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Threshold {
+    pub quantity: Quantity,
+    pub above: bool,        // op '>', '<' otherwise
+    pub value: f32,
+}
+
+impl Threshold {
+    pub fn is_met(&self, measured: f32) -> bool {
+        if self.above { measured > self.value } else { measured < self.value }
+    }
+}
+
+// "quantity>value" or "quantity<value" (quantity: current, voltage, power)
+pub fn parse(text: &str) -> Option<Threshold> {
+    let (name, above, value) = match text.split_once('>') {
+        Some((name, value)) => (name, true, value),
+        None => {
+            let (name, value) = text.split_once('<')?;
+            (name, false, value)
+        }
+    };
+    Some(Threshold {
+        quantity: Quantity::from_name(name.trim())?,
+        above: above,
+        value: value.trim().parse::<f32>().ok()?,
+    })
+}
+
+// The condition and the hold time after ':' in ms, 0 without one
+pub fn split_hold(text: &str) -> Option<(&str, Duration)> {
+    match text.split_once(':') {
+        Some((cond, hold)) => Some((cond, Duration::from_millis(hold.trim().parse::<u64>().ok()?))),
+        None => Some((text, Duration::ZERO)),
+    }
+}
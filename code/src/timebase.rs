@@ -0,0 +1,95 @@
+// TimeBase
+// Sample timestamps from the esp_timer microsecond counter, anchored to the wall clock at every SNTP sync.
+// Between the syncs the rate error of the counter measured over the previous sync interval is corrected,
+// so timestamps are evenly spaced and free of the scheduling jitter of SystemTime::now().
+// Before the first sync the clock can be set from the RTC.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::*;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
+
+// A larger rate error is a clock step or a bad sync, not drift (ppm)
+const MAX_DRIFT_PPM: f64 = 500.0;
+
+struct Anchor {
+    timer_us: i64,      // esp_timer time of the anchor
+    wall_ns: i128,      // wall clock at timer_us
+    synced: bool,       // set by SNTP, used for the next drift estimate
+    valid: bool,        // wall clock set by SNTP or the RTC
+    drift: f64,         // rate error of the counter, wall = timer * (1 + drift)
+}
+
+static ANCHOR: Mutex<Option<Anchor>> = Mutex::new(None);
+static SYNC_COUNT: AtomicU32 = AtomicU32::new(0);
+
+pub fn timer_us() -> i64 {
+    unsafe { esp_idf_sys::esp_timer_get_time() }
+}
+
+// Wall clock in ns at the given esp_timer time
+pub fn wall_ns(timer_us: i64) -> u128 {
+    let mut lck = ANCHOR.lock().unwrap();
+    // Until the first sync the system clock is the reference
+    let anchor = lck.get_or_insert_with(|| Anchor {
+        timer_us: self::timer_us(),
+        wall_ns: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as i128,
+        synced: false,
+        valid: false,
+        drift: 0.0,
+    });
+    let elapsed_ns = (timer_us - anchor.timer_us) as f64 * 1000.0;
+    (anchor.wall_ns + (elapsed_ns * (1.0 + anchor.drift)) as i128).max(0) as u128
+}
+
+pub fn now_ns() -> u128 {
+    wall_ns(timer_us())
+}
+
+// Called from the SNTP callback with the synchronized time
+pub fn sync(wall: Duration)
+{
+    let timer_us = timer_us();
+    let wall_ns = wall.as_nanos() as i128;
+    let mut lck = ANCHOR.lock().unwrap();
+    let mut drift = 0.0;
+    if let Some(ref anchor) = *lck {
+        drift = anchor.drift;
+        if anchor.synced && timer_us > anchor.timer_us {
+            let elapsed_ns = (timer_us - anchor.timer_us) as f64 * 1000.0;
+            let measured = (wall_ns - anchor.wall_ns) as f64 / elapsed_ns - 1.0;
+            if measured.abs() * 1e6 <= MAX_DRIFT_PPM {
+                drift = measured;
+            }
+            info!("Timer drift: {:.1}ppm over {}s", measured * 1e6, (timer_us - anchor.timer_us) / 1_000_000);
+        }
+    }
+    *lck = Some(Anchor { timer_us: timer_us, wall_ns: wall_ns, synced: true, valid: true, drift: drift });
+    SYNC_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+// Set the system clock from the RTC at boot, not used for a drift estimate
+pub fn set_clock(wall: Duration)
+{
+    let tv = esp_idf_sys::timeval { tv_sec: wall.as_secs() as _, tv_usec: wall.subsec_micros() as _ };
+    unsafe { esp_idf_sys::settimeofday(&tv, std::ptr::null()); }
+    let mut lck = ANCHOR.lock().unwrap();
+    *lck = Some(Anchor { timer_us: timer_us(), wall_ns: wall.as_nanos() as i128, synced: false, valid: true, drift: 0.0 });
+}
+
+// True once the wall clock has been set by SNTP
+pub fn is_synced() -> bool {
+    ANCHOR.lock().unwrap().as_ref().map_or(false, |anchor| anchor.synced)
+}
+
+// True once the wall clock has been set by SNTP or the RTC
+pub fn is_valid() -> bool {
+    ANCHOR.lock().unwrap().as_ref().map_or(false, |anchor| anchor.valid)
+}
+
+// SNTP syncs since boot
+pub fn sync_count() -> u32 {
+    SYNC_COUNT.load(Ordering::Relaxed)
+}
@@ -0,0 +1,135 @@
+// Timestamp
+// Time of an uploaded point. The clocks are kept as u128 ns since the Unix epoch, which the 32-bit
+// RISC-V core divides and formats in software and which `as u64` would silently wrap. A Timestamp
+// holds u64 ns (enough until 2554), converted with saturation, and is scaled to the precision of the
+// write API. Before the first SNTP sync the clock starts at the epoch; a point stamped in 1970 lands
+// far from its series, so times before 2020 are not valid and are not uploaded.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+// 2020-01-01T00:00:00Z, an earlier time is a clock that was never set (ns)
+pub const MIN_VALID_NS: u64 = 1_577_836_800_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precision {
+    Ns,
+    Us,
+    Ms,
+    S,
+}
+
+impl Precision {
+    pub fn from_str(precision: &str) -> Precision {
+        match precision {
+            "us" => Precision::Us,
+            "ms" => Precision::Ms,
+            "s" => Precision::S,
+            _ => Precision::Ns,
+        }
+    }
+
+    // ns per unit of this precision
+    fn divisor(&self) -> u64 {
+        match self {
+            Precision::Ns => 1,
+            Precision::Us => 1_000,
+            Precision::Ms => 1_000_000,
+            Precision::S => 1_000_000_000,
+        }
+    }
+
+    // Value of the precision query parameter, v1 and v2 APIs name ns/us differently
+    #[cfg(any(feature = "influx", test))]
+    pub fn query_value(&self, v2: bool) -> &'static str {
+        match (self, v2) {
+            (Precision::Ns, true) => "ns",
+            (Precision::Ns, false) => "n",
+            (Precision::Us, true) => "us",
+            (Precision::Us, false) => "u",
+            (Precision::Ms, _) => "ms",
+            (Precision::S, _) => "s",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    // ns since the epoch, saturating instead of wrapping
+    pub fn from_nanos(clock: u128) -> Timestamp {
+        Timestamp(u64::try_from(clock).unwrap_or(u64::MAX))
+    }
+
+    // None for a clock before 2020
+    pub fn valid(clock: u128) -> Option<Timestamp> {
+        let timestamp = Timestamp::from_nanos(clock);
+        timestamp.is_valid().then_some(timestamp)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 >= MIN_VALID_NS
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    // Whole units of the precision, the fraction is cut
+    pub fn to_precision(&self, precision: Precision) -> u64 {
+        self.0 / precision.divisor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2025-10-09T09:46:40.123456789Z
+    const SYNCED_NS: u128 = 1_760_003_200_123_456_789;
+
+    #[test]
+    fn pre_ntp_clock_is_invalid() {
+        // Time since boot before the first sync
+        for clock in [0u128, 1, 5_000_000_000, 86_400_000_000_000] {
+            assert!(Timestamp::valid(clock).is_none(), "{}", clock);
+            assert!(!Timestamp::from_nanos(clock).is_valid());
+        }
+    }
+
+    #[test]
+    fn validity_starts_in_2020() {
+        assert!(Timestamp::valid(MIN_VALID_NS as u128 - 1).is_none());
+        assert_eq!(Timestamp::valid(MIN_VALID_NS as u128).map(|t| t.as_nanos()), Some(MIN_VALID_NS));
+    }
+
+    #[test]
+    fn synced_clock_in_each_precision() {
+        let timestamp = Timestamp::valid(SYNCED_NS).unwrap();
+        assert_eq!(timestamp.to_precision(Precision::Ns), 1_760_003_200_123_456_789);
+        assert_eq!(timestamp.to_precision(Precision::Us), 1_760_003_200_123_456);
+        assert_eq!(timestamp.to_precision(Precision::Ms), 1_760_003_200_123);
+        assert_eq!(timestamp.to_precision(Precision::S), 1_760_003_200);
+    }
+
+    #[test]
+    fn conversion_saturates() {
+        assert_eq!(Timestamp::from_nanos(u64::MAX as u128).as_nanos(), u64::MAX);
+        assert_eq!(Timestamp::from_nanos(u64::MAX as u128 + 1).as_nanos(), u64::MAX);
+        assert_eq!(Timestamp::from_nanos(u128::MAX).as_nanos(), u64::MAX);
+        // A wrapping cast would turn 2^64 + a synced time into a time in 1970
+        assert_eq!(Timestamp::from_nanos((1u128 << 64) + SYNCED_NS).as_nanos(), u64::MAX);
+    }
+
+    #[test]
+    fn precision_names() {
+        assert_eq!(Precision::from_str("us"), Precision::Us);
+        assert_eq!(Precision::from_str("ms"), Precision::Ms);
+        assert_eq!(Precision::from_str("s"), Precision::S);
+        assert_eq!(Precision::from_str("ns"), Precision::Ns);
+        assert_eq!(Precision::from_str("bogus"), Precision::Ns);
+        assert_eq!(Precision::Ns.query_value(true), "ns");
+        assert_eq!(Precision::Ns.query_value(false), "n");
+        assert_eq!(Precision::Us.query_value(false), "u");
+    }
+}
@@ -3,20 +3,301 @@
 // Copyright (c) 2024 Hiroshi Nakajima
 
 use log::*;
-use std::{thread, sync::Arc, sync::Mutex};
-use esp_idf_hal::task;
-use std::io::Error;
-use std::time::Duration;
-use embedded_svc::http::client::Client;
-use embedded_svc::http::Method;
-use esp_idf_svc::http::client::{EspHttpConnection, Configuration};
-
-use anyhow::Result;
+use std::{sync::Arc, sync::Mutex};
+use std::borrow::Cow;
+#[cfg(feature = "influx")]
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime};
+use esp_idf_hal::task::asynch::Notification;
+#[cfg(feature = "wifi")]
+use esp_idf_svc::timer::EspTaskTimerService;
+#[cfg(feature = "influx")]
+use esp_idf_svc::timer::EspAsyncTimer;
+#[cfg(feature = "influx")]
+use edge_http::Method;
+#[cfg(feature = "influx")]
+use edge_http::io::client::Connection;
+#[cfg(feature = "influx")]
+use edge_nal::{AddrType, Dns};
+#[cfg(feature = "influx")]
+use edge_nal_std::Stack;
+#[cfg(feature = "influx")]
+use embedded_io_async::{Read, Write};
+#[cfg(feature = "influx")]
+use embassy_futures::select::{select, Either};
+
 use crate::CurrentLog;
+use crate::health::{HealthLog, HeartbeatLog};
+use crate::summary::SummaryReport;
+use crate::crash::CrashReport;
+use crate::marker::Marker;
+use crate::stats::SessionSummary;
+use crate::bufferalert::BufferEvent;
+use crate::ripple::RippleResult;
+use crate::pulse::PulseStats;
+use crate::iotprofile::{IotSummary, BAND_NAMES};
+use crate::eventlog::JournalEntry;
+use crate::version;
+use crate::codec;
+#[cfg(feature = "influx")]
+use crate::backlog::Backlog;
+#[cfg(feature = "influx")]
+use crate::settings::SettingsWatch;
+use crate::timestamp::{Precision, Timestamp};
+use crate::locale::{Msg, tr, tr_args};
+
+#[cfg(feature = "influx")]
+const MAX_RETRY: u32 = 5;
+// Settings the upload task applies to its server when they are changed
+#[cfg(feature = "influx")]
+pub const TASK_SETTINGS: [&str; 5] = ["influxdb_server", "influxdb_api_key", "influxdb_api", "device_name", "influxdb_tags"];
+// Response bodies are read to the end for the connection reuse, a longer one closes the connection
+#[cfg(feature = "influx")]
+const MAX_RESPONSE_DRAIN: usize = 16384;
+// Text of an error response logged, and kept to find the rejected lines of a partial write
+#[cfg(feature = "influx")]
+const RESPONSE_LOG_SIZE: usize = 512;
+#[cfg(feature = "influx")]
+const ERROR_RESPONSE_SIZE: usize = 2048;
+// A larger difference between the server and the local clock is reported by the startup check (s)
+#[cfg(feature = "influx")]
+const CLOCK_SKEW_LIMIT_S: i64 = 5;
+// Request bodies are written in chunks of this size, never assembled as a whole
+#[cfg(feature = "influx")]
+const WRITE_CHUNK_SIZE: usize = 1024;
+// Request and response headers of the HTTP connection
+#[cfg(feature = "influx")]
+const CONNECTION_BUFFER_SIZE: usize = 2048;
+// A request that takes longer is abandoned and the connection closed
+#[cfg(feature = "influx")]
+const REQUEST_TIMEOUT_MS: u64 = 10000;
+// Wait before resending a failed request, multiplied by the retry count (ms)
+#[cfg(feature = "influx")]
+const RETRY_DELAY_MS: u64 = 1000;
+// Memory budget of the data waiting for upload and the largest request body (bytes). A batch is cut
+// to fit, a body above it is never built, the records past it go with the next request.
+const MAX_BODY_BYTES: usize = 32768;
+// While uploads fail, the stored batches are tried again after this time unless new data comes first (ms)
+#[cfg(feature = "influx")]
+const BACKLOG_RETRY_MS: u64 = 30000;
+// Upload pacing by link quality: (records per upload, minimum interval between uploads in ms)
+const UPLOAD_GOOD: (usize, u64) = (64, 0);
+const UPLOAD_FAIR: (usize, u64) = (128, 2000);
+const UPLOAD_POOR: (usize, u64) = (256, 10000);
+const RSSI_GOOD: i32 = -67;
+const RSSI_FAIR: i32 = -75;
+const LATENCY_GOOD_MS: u32 = 500;
+const LATENCY_FAIR_MS: u32 = 2000;
+// Upper bounds of the round-trip time histogram buckets (ms), the last bucket takes the slower requests
+const RTT_BUCKETS_MS: [u32; 5] = [100, 250, 500, 1000, 2500];
+
+// Upload pipeline counters since boot, the health telemetry sends the difference over its interval
+#[derive(Clone, Copy, Default)]
+pub struct TransferStats {
+    pub batches: u32,       // record batches encoded
+    pub encode_us: u64,     // time spent encoding the batches (first attempt)
+    pub requests: u32,      // HTTP requests including retries
+    pub failures: u32,      // requests without a 2xx response
+    pub retries: u32,
+    pub dropped: u32,       // bodies dropped after MAX_RETRY
+    pub rejected: u32,      // points dropped because the server rejected them (partial write)
+    pub invalid_clock: u32, // records and entries not sent for a timestamp before 2020
+    pub over_budget: u32,   // records and entries refused because they exceed MAX_BODY_BYTES
+    pub peak_body: u32,     // largest request body since boot (bytes)
+    pub peak_memory: u32,   // largest data waiting for upload since boot, entries and records in RAM (bytes)
+    pub bytes_sent: u64,    // body bytes of successful requests
+    pub rtt_ms: u64,        // total round-trip time
+    pub rtt_histogram: [u32; RTT_BUCKETS_MS.len() + 1],
+}
+
+impl TransferStats {
+    #[cfg(feature = "influx")]
+    fn add_rtt(&mut self, rtt_ms: u32)
+    {
+        self.rtt_ms += rtt_ms as u64;
+        let bucket = RTT_BUCKETS_MS.iter().position(|&limit| rtt_ms <= limit).unwrap_or(RTT_BUCKETS_MS.len());
+        self.rtt_histogram[bucket] += 1;
+    }
+
+    // Counts since an earlier snapshot
+    pub fn since(&self, base: &TransferStats) -> TransferStats {
+        let mut rtt_histogram = self.rtt_histogram;
+        for (count, base) in rtt_histogram.iter_mut().zip(base.rtt_histogram.iter()) {
+            *count = count.wrapping_sub(*base);
+        }
+        TransferStats {
+            batches: self.batches.wrapping_sub(base.batches),
+            encode_us: self.encode_us.wrapping_sub(base.encode_us),
+            requests: self.requests.wrapping_sub(base.requests),
+            failures: self.failures.wrapping_sub(base.failures),
+            retries: self.retries.wrapping_sub(base.retries),
+            dropped: self.dropped.wrapping_sub(base.dropped),
+            rejected: self.rejected.wrapping_sub(base.rejected),
+            invalid_clock: self.invalid_clock.wrapping_sub(base.invalid_clock),
+            over_budget: self.over_budget.wrapping_sub(base.over_budget),
+            peak_body: self.peak_body,
+            peak_memory: self.peak_memory,
+            bytes_sent: self.bytes_sent.wrapping_sub(base.bytes_sent),
+            rtt_ms: self.rtt_ms.wrapping_sub(base.rtt_ms),
+            rtt_histogram: rtt_histogram,
+        }
+    }
+
+    // Health fields as (name, value): counts, averages per batch or request and the histogram
+    fn fields(&self, window_secs: u64) -> Vec<(String, u64)> {
+        let mut fields = vec![
+            ("upload_requests".to_string(), self.requests as u64),
+            ("upload_failures".to_string(), self.failures as u64),
+            ("upload_retries".to_string(), self.retries as u64),
+            ("upload_dropped".to_string(), self.dropped as u64),
+            ("upload_rejected".to_string(), self.rejected as u64),
+            ("upload_invalid_clock".to_string(), self.invalid_clock as u64),
+            ("upload_over_budget".to_string(), self.over_budget as u64),
+            ("upload_peak_body".to_string(), self.peak_body as u64),
+            ("upload_peak_memory".to_string(), self.peak_memory as u64),
+            ("upload_bytes".to_string(), self.bytes_sent),
+            ("upload_throughput".to_string(), self.bytes_sent / window_secs.max(1)),
+            ("encode_us".to_string(), self.encode_us / (self.batches.max(1) as u64)),
+            ("rtt_ms".to_string(), self.rtt_ms / (self.requests.max(1) as u64)),
+        ];
+        for (i, count) in self.rtt_histogram.iter().enumerate() {
+            let name = match RTT_BUCKETS_MS.get(i) {
+                Some(limit) => format!("rtt_le{}", limit),
+                None => format!("rtt_gt{}", RTT_BUCKETS_MS[RTT_BUCKETS_MS.len() - 1]),
+            };
+            fields.push((name, *count as u64));
+        }
+        fields
+    }
+}
+
+// Upload counters of this session for the network page and the status endpoint.
+// Only the upload task fills them in.
+#[cfg_attr(not(feature = "influx"), allow(dead_code))]
+#[derive(Clone, Copy, Default)]
+pub struct SessionCounters {
+    pub records_sent: u64,
+    pub failed_attempts: u32,
+    pub last_upload: Option<Instant>,   // last successful upload
+    pub last_upload_clock: u128,        // ns, 0 before the first successful upload
+    pub last_status: Option<TransferStatus>,    // result of the last upload, None before the first one
+    pub backlog_batches: usize,     // batches stored in flash for the replay
+}
 
 struct TransferData {
-    body: String,
+    body: String,           // encoded entries other than records (health, markers, ...)
+    records: Vec<CurrentLog>,   // records are encoded while the request body is written
     txreq: bool,
+    #[cfg(feature = "influx")]
+    retry: u32,
+    last_seq: Option<u32>,  // highest sequence number already queued for transfer
+    #[cfg(feature = "influx")]
+    probe_request: bool,    // startup check of the server requested
+    probe_result: Option<ProbeResult>,
+    latency_ms: u32,        // averaged request latency, 0 until the first request
+    record_bytes: usize,    // encoded size of a record, measured on the last body
+    stats: TransferStats,
+    session: SessionCounters,
+}
+
+// Result of the last upload for the display, set by the upload task
+#[cfg_attr(not(feature = "influx"), allow(dead_code))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum TransferStatus {
+    Ok,
+    Retrying(u32, u16),     // retry count, HTTP status (0: no response)
+    Failed(u16),            // dropped after MAX_RETRY, HTTP status (0: no response)
+}
+
+impl TransferStatus {
+    // Object of the status endpoint
+    pub fn to_json(&self) -> String {
+        match self {
+            TransferStatus::Ok => "{\"result\":\"ok\"}".to_string(),
+            TransferStatus::Retrying(retry, code) => format!("{{\"result\":\"retrying\",\"retry\":{},\"http_status\":{}}}", retry, code),
+            TransferStatus::Failed(code) => format!("{{\"result\":\"failed\",\"http_status\":{}}}", code),
+        }
+    }
+}
+
+// Result of the startup check of the server, run by the upload task
+#[cfg_attr(not(feature = "influx"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeResult {
+    Ok(Option<i64>),        // clock skew (s, server - local) if the server sent its time
+    Unreachable(String),
+    BadToken(u16),
+    BucketMissing(String),
+    ClockSkew(i64),
+    Status(u16),            // unexpected HTTP status
+}
+
+impl ProbeResult {
+    // A passed check with the server clock more than CLOCK_SKEW_LIMIT_S off becomes ClockSkew
+    #[cfg(feature = "influx")]
+    fn check_skew(self) -> ProbeResult {
+        match self {
+            ProbeResult::Ok(Some(skew)) if skew.abs() > CLOCK_SKEW_LIMIT_S => ProbeResult::ClockSkew(skew),
+            result => result,
+        }
+    }
+
+    // Problem for the display, None if the check passed
+    pub fn message(&self) -> Option<String> {
+        match self {
+            ProbeResult::Ok(_) => None,
+            ProbeResult::Unreachable(_) => Some(tr(Msg::ServerUnreachable).to_string()),
+            ProbeResult::BadToken(code) => Some(tr_args(Msg::BadToken, &[&code.to_string()])),
+            ProbeResult::BucketMissing(_) => Some(tr(Msg::BucketMissing).to_string()),
+            ProbeResult::ClockSkew(skew) => Some(tr_args(Msg::ClockSkew, &[&skew.to_string()])),
+            ProbeResult::Status(code) => Some(tr_args(Msg::HttpStatus, &[&code.to_string()])),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PayloadFormat {
+    Line,   // InfluxDB line protocol
+    Json,   // JSON array of records
+    Packed, // binary stream of codec.rs by HTTP POST
+    PackedUdp,  // binary stream of codec.rs in UDP datagrams
+}
+
+impl PayloadFormat {
+    pub fn from_str(format: &str) -> PayloadFormat {
+        match format {
+            "json" => PayloadFormat::Json,
+            "packed" => PayloadFormat::Packed,
+            "packed_udp" => PayloadFormat::PackedUdp,
+            _ => PayloadFormat::Line,
+        }
+    }
+
+    #[cfg(feature = "influx")]
+    fn content_type(&self) -> &'static str {
+        match self {
+            PayloadFormat::Line => "text/plain; charset=utf-8",
+            PayloadFormat::Json => "application/json",
+            PayloadFormat::Packed | PayloadFormat::PackedUdp => "application/octet-stream",
+        }
+    }
+
+    // Format of the entries other than records, the packed formats carry them as line protocol
+    fn entry_format(&self) -> PayloadFormat {
+        match self {
+            PayloadFormat::Json => PayloadFormat::Json,
+            _ => PayloadFormat::Line,
+        }
+    }
+}
+
+// Field value of a point: integer, float with the number of decimals written, boolean or string
+#[derive(Clone, Copy)]
+enum Field<'a> {
+    Int(i64),
+    Float(f64, usize),
+    Bool(bool),
+    Text(&'a str),
 }
 
 #[derive(Clone)]
@@ -26,6 +307,20 @@ pub struct ServerInfo {
     pub influxdb_api_key: String,
     pub influxdb_api: String,
     pub influxdb_tag: String,
+    pub device: String,     // device tag, tells the units sharing a bucket apart
+    pub channel_name: String,   // channel_name tag of the records, empty: none
+    pub tags: Vec<(String, String)>,    // static tags added to every point (location, project, ...)
+    pub health_measurement: String,
+    pub summary_measurement: String,
+    pub marker_measurement: String,
+    pub pulse_measurement: String,
+    pub payload_format: PayloadFormat,
+    pub precision: Precision,
+    pub http_user: String,      // Basic auth instead of the InfluxDB token when set
+    pub http_password: String,
+    pub http_headers: Vec<(String, String)>,    // added to every request, replacing defaults of the same name
+    pub differential: bool,     // add current_diff, loss_w and efficiency of the two sensors
+    pub demo: bool,             // readings of the simulated sensor, tagged demo=true
 }
 
 impl ServerInfo {
@@ -36,130 +331,1384 @@ impl ServerInfo {
             influxdb_api_key: api_key,
             influxdb_api: api,
             influxdb_tag: tag,
+            device: "".to_string(),
+            channel_name: "".to_string(),
+            tags: Vec::new(),
+            health_measurement: "meter_health".to_string(),
+            summary_measurement: "meter_summary".to_string(),
+            marker_measurement: "meter_marker".to_string(),
+            pulse_measurement: "meter_pulse".to_string(),
+            payload_format: PayloadFormat::Line,
+            precision: Precision::Ns,
+            http_user: "".to_string(),
+            http_password: "".to_string(),
+            http_headers: Vec::new(),
+            differential: false,
+            demo: false,
+        }
+    }
+
+    // Static tags, with demo=true in demo mode so simulated data is never taken for a measurement
+    pub fn set_tags(&mut self, mut tags: Vec<(String, String)>)
+    {
+        if self.demo && !tags.iter().any(|(key, _)| key == "demo") {
+            tags.push(("demo".to_string(), "true".to_string()));
+        }
+        self.tags = tags;
+    }
+
+    pub fn set_demo(&mut self, demo: bool)
+    {
+        self.demo = demo;
+        let tags = std::mem::take(&mut self.tags);
+        self.set_tags(tags);
+    }
+
+    // Measurement and tags of a line protocol point: the tag and device tags, the tags of the point,
+    // then the static tags. Tags with an empty value are left out, InfluxDB rejects them.
+    fn line_series(&self, measurement: &str, tags: &[(&str, &str)]) -> String {
+        let mut series = measurement_escape(measurement);
+        let fixed = [("tag", self.influxdb_tag.as_str()), ("device", self.device.as_str())];
+        let custom = self.tags.iter().map(|(key, value)| (key.as_str(), value.as_str()));
+        for (key, value) in fixed.iter().chain(tags.iter()).copied().chain(custom) {
+            if !value.is_empty() {
+                series.push_str(&format!(",{}={}", tag_escape(key), tag_escape(value)));
+            }
         }
+        series
+    }
+
+    // Point other than a record in the entry format: the line protocol line, or the JSON entry with the
+    // channel and device keys, the static tags and the tags of the point followed by the fields.
+    // A field named like one of these keys (channel) is written as meter_<name> in JSON.
+    fn encode_point(&self, measurement: &str, tags: &[(&str, &str)], fields: &[(&str, Field)], clock: u64) -> String {
+        match self.payload_format.entry_format() {
+            PayloadFormat::Line => {
+                let fields: Vec<String> = fields.iter().map(|(name, value)| {
+                    let value = match *value {
+                        Field::Int(v) => format!("{}i", v),
+                        Field::Float(v, decimals) => format!("{:.*}", decimals, v),
+                        Field::Bool(v) => v.to_string(),
+                        Field::Text(v) => format!("\"{}\"", field_escape(v)),
+                    };
+                    format!("{}={}", tag_escape(name), value)
+                }).collect();
+                format!("{} {} {}\n", self.line_series(measurement, tags), fields.join(","), clock)
+            },
+            PayloadFormat::Json => {
+                let mut entry = format!("{{\"ts\":{},\"measurement\":\"{}\",\"channel\":\"{}\",\"device\":\"{}\"{}",
+                    clock, json_escape(measurement), json_escape(&self.influxdb_tag), json_escape(&self.device), self.json_tags());
+                for (key, value) in tags.iter().filter(|(_, value)| !value.is_empty()) {
+                    entry.push_str(&format!(",\"{}\":\"{}\"", json_escape(key), json_escape(value)));
+                }
+                for (name, value) in fields {
+                    let name = match *name {
+                        "ts" | "measurement" | "channel" | "device" => format!("meter_{}", name),
+                        name => name.to_string(),
+                    };
+                    let value = match *value {
+                        Field::Int(v) => v.to_string(),
+                        Field::Float(v, decimals) => format!("{:.*}", decimals, v),
+                        Field::Bool(v) => v.to_string(),
+                        Field::Text(v) => format!("\"{}\"", json_escape(v)),
+                    };
+                    entry.push_str(&format!(",\"{}\":{}", json_escape(&name), value));
+                }
+                entry.push('}');
+                entry
+            },
+        }
+    }
+
+    // Static tags of a JSON entry
+    fn json_tags(&self) -> String {
+        if self.tags.is_empty() {
+            return String::new();
+        }
+        let tags: Vec<String> = self.tags.iter().map(|(key, value)| format!("\"{}\":\"{}\"", json_escape(key), json_escape(value))).collect();
+        format!(",\"tags\":{{{}}}", tags.join(","))
     }
 }
 
+// Longest measurement name or tag value accepted from the configuration
+pub const NAME_MAX_LENGTH: usize = 64;
+
+// Measurement names and tag values given by the user. Spaces, commas and equal signs are escaped,
+// control characters and backslashes cannot be written.
+pub fn valid_name(text: &str) -> bool {
+    !text.is_empty() && text.len() <= NAME_MAX_LENGTH && text.chars().all(|c| !c.is_control() && c != '\\')
+}
+
 pub struct Transfer {
     data: Arc<Mutex<TransferData>>,
     server: ServerInfo,
+    batch_size: usize,
+    interval_ms: u64,
+    min_pacing: (usize, u64),   // smallest batch size and interval of the logging profile
+    last_upload: Option<Instant>,
+    #[cfg(feature = "influx")]
+    on_status: Option<Box<dyn FnMut(TransferStatus) + Send>>,
+    wake: Arc<Notification>,    // queued data or a probe request for the upload task
+    #[cfg(feature = "influx")]
+    backlog: Option<Backlog>,   // moved to the upload task by start()
+    #[cfg(feature = "influx")]
+    settings: Option<SettingsWatch>,    // moved to the upload task by start()
+}
+
+// The upload task, run by network::start() on the network thread
+#[cfg(feature = "influx")]
+pub struct TransferTask {
+    data: Arc<Mutex<TransferData>>,
+    server: ServerInfo,
+    on_status: Option<Box<dyn FnMut(TransferStatus) + Send>>,
+    wake: Arc<Notification>,
+    backlog: Option<Backlog>,   // batches of failed uploads, None: they are dropped
+    settings: Option<SettingsWatch>,
 }
 
+// Without the influx feature there is no upload task, network::start() is given None
+#[cfg(all(feature = "wifi", not(feature = "influx")))]
+pub enum TransferTask {}
+
 impl Transfer {
     pub fn new(server: ServerInfo) -> Self {
         Transfer { data: Arc::new(Mutex::new(
-            TransferData { body: "".to_string(), txreq: false })),
-            server: server}
-    }
-
-    pub fn start(&mut self) -> Result<(), Error>
-    {
-        let data = self.data.clone();
-        let server_info = self.server.clone();
-        let _th = thread::spawn(move || -> anyhow::Result<()> {
-            info!("Start transfer thread.");
-
-            loop {
-                task::wait_notification(100);
-                let http = EspHttpConnection::new(
-                    &Configuration {
-                        use_global_ca_store: true,
-                        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
-                        timeout: Some(Duration::from_secs(10 as u64)),
-                        ..Default::default()
-                    })?;
-    
-                let mut client = Client::wrap(http);
-    
-                let mut lck = data.lock().unwrap();
-                if lck.txreq == false {
-                    drop(lck);
-                    continue;
-                }
-                let request = format!("{}", lck.body);
-                drop(lck);                
-                // info!("Transfer data: {}", request);                
-                let ret = Self::transfer(&mut client, &server_info, request);
-                lck = data.lock().unwrap();
-                match ret {
-                    Ok(()) => { lck.txreq = false; },
-                    Err(e) => { info!("{}", e) },
-                }
-                lck.body.clear();
-                drop(lck);
-            }
-        });
+            TransferData { body: "".to_string(), records: Vec::new(), txreq: false,
+                #[cfg(feature = "influx")]
+                retry: 0,
+                last_seq: None,
+                #[cfg(feature = "influx")]
+                probe_request: false,
+                probe_result: None, latency_ms: 0, record_bytes: estimate_record_bytes(&server),
+                stats: TransferStats::default(), session: SessionCounters::default() })),
+            server: server,
+            batch_size: UPLOAD_GOOD.0,
+            interval_ms: UPLOAD_GOOD.1,
+            min_pacing: (0, 0),
+            last_upload: None,
+            #[cfg(feature = "influx")]
+            on_status: None,
+            wake: Arc::new(Notification::new()),
+            #[cfg(feature = "influx")]
+            backlog: None,
+            #[cfg(feature = "influx")]
+            settings: None }
+    }
 
-        Ok(())
+    // Store the records of failed uploads in flash instead of dropping them, must be set before start()
+    #[cfg(feature = "influx")]
+    pub fn set_backlog(&mut self, backlog: Backlog)
+    {
+        self.data.lock().unwrap().session.backlog_batches = backlog.len();
+        self.backlog = Some(backlog);
     }
 
-    fn transfer(client: &mut Client<EspHttpConnection>, server_info: &ServerInfo, body_data: String) -> anyhow::Result<()>
+    // Changes of TASK_SETTINGS, applied by the upload task before its next request, must be set before start()
+    #[cfg(feature = "influx")]
+    pub fn set_settings_watch(&mut self, watch: SettingsWatch)
     {
-        let authorization = &format!("Token {}", server_info.influxdb_api_key);
-        let headers : [(&str, &str); 2] = [
-                ("Authorization", authorization),
-                ("Content-Type", "application/json"),
-            ];
-        let url = format!("http://{}{}", server_info.server, server_info.influxdb_api);
-        // info!("URL: {}", url);
-        let mut request = client.request(Method::Post, 
-               url.as_str(),
-                &headers)?;
-        let body = body_data.as_bytes();
-        request.write(body)?;
-        // info!("Body data {:?}", body_data);
-        let mut response = request.submit()?;
-        let res_status = response.status();
-        // info!("Response status: {:?}", res_status);
-        match res_status {
-            204 => {
-                return Ok(());
-            },
-            _ => {
-                let mut response_buf = [0u8; 4096];
-                response.read(&mut response_buf)?;
-                let res_str = std::str::from_utf8(&response_buf).unwrap_or("<invalid UTF-8>");        
-                info!("Response: {}", res_str);
-                return Err(anyhow::anyhow!("Failed to transfer data."));
-            }
+        self.settings = Some(watch);
+    }
+
+    // Called from the network thread after every upload, must be set before start()
+    #[cfg(feature = "influx")]
+    pub fn set_status_callback<F>(&mut self, callback: F)
+        where F: FnMut(TransferStatus) + Send + 'static
+    {
+        self.on_status = Some(Box::new(callback));
+    }
+
+    // The upload task for network::start()
+    #[cfg(feature = "influx")]
+    pub fn start(&mut self) -> TransferTask
+    {
+        TransferTask {
+            data: self.data.clone(),
+            server: self.server.clone(),
+            on_status: self.on_status.take(),
+            wake: self.wake.clone(),
+            backlog: self.backlog.take(),
+            settings: self.settings.take(),
         }
     }
 
+    // Check the server once before the first upload, the result is returned by take_probe_result()
+    #[cfg(feature = "influx")]
+    pub fn request_probe(&mut self)
+    {
+        self.data.lock().unwrap().probe_request = true;
+        self.wake.notify_lsb();
+    }
+
+    pub fn take_probe_result(&mut self) -> Option<ProbeResult> {
+        self.data.lock().unwrap().probe_result.take()
+    }
+
+    // True while a request is pending, new data is not accepted
+    pub fn is_busy(&self) -> bool {
+        self.data.lock().unwrap().txreq
+    }
 
-    pub fn set_transfer_data(&mut self, data: &Vec<CurrentLog>) -> usize
+    // Larger and less frequent uploads on a weak or slow link, small and frequent ones on a good link
+    pub fn adapt(&mut self, rssi: i32)
     {
-        if data.len() == 0 {
-            return 0;
+        let latency = self.data.lock().unwrap().latency_ms;
+        let (batch_size, interval_ms) = if rssi >= RSSI_GOOD && latency <= LATENCY_GOOD_MS {
+            UPLOAD_GOOD
+        }
+        else if rssi >= RSSI_FAIR && latency <= LATENCY_FAIR_MS {
+            UPLOAD_FAIR
+        }
+        else {
+            UPLOAD_POOR
+        };
+        let batch_size = batch_size.max(self.min_pacing.0);
+        let interval_ms = interval_ms.max(self.min_pacing.1);
+        if batch_size != self.batch_size || interval_ms != self.interval_ms {
+            info!("Upload pacing: {} records every {}ms (rssi {}dBm, latency {}ms)", batch_size, interval_ms, rssi, latency);
+            self.batch_size = batch_size;
+            self.interval_ms = interval_ms;
         }
+    }
+
+    // Lower bound of the adaptive pacing, (0, 0) for none
+    pub fn set_min_pacing(&mut self, batch_size: usize, interval_ms: u64)
+    {
+        self.min_pacing = (batch_size, interval_ms);
+    }
+
+    // True when the next upload can be queued
+    pub fn is_ready(&self) -> bool {
+        let due = match self.last_upload {
+            Some(last) => last.elapsed() >= Duration::from_millis(self.interval_ms),
+            None => true,
+        };
+        due && !self.is_busy()
+    }
+
+    // Records per upload, no more than fit in MAX_BODY_BYTES with the queued entries
+    pub fn get_batch_size(&self) -> usize {
+        let lck = self.data.lock().unwrap();
+        self.batch_size.min(MAX_BODY_BYTES.saturating_sub(lck.body.len()) / lck.record_bytes.max(1)).max(1)
+    }
+
+    // (records per upload, upload interval in ms, averaged latency in ms)
+    pub fn get_upload_stats(&self) -> (usize, u64, u32) {
+        (self.batch_size, self.interval_ms, self.data.lock().unwrap().latency_ms)
+    }
+
+    pub fn get_transfer_stats(&self) -> TransferStats {
+        self.data.lock().unwrap().stats
+    }
+
+    pub fn get_session_counters(&self) -> SessionCounters {
+        self.data.lock().unwrap().session
+    }
+
+    // Queue records for the next request, returns the number of records queued
+    pub fn set_transfer_data<I>(&mut self, data: I) -> usize
+        where I: Iterator<Item = CurrentLog>
+    {
         let mut lck = self.data.lock().unwrap();
         if lck.txreq == true {
             // info!("Transfer request is already pending.");
             return 0;
         }
-        let mut count = 0;
+        let mut queued = 0;
+        let mut invalid = 0;
+        let mut over_budget = 0;
+        let limit = MAX_BODY_BYTES.saturating_sub(lck.body.len()) / lck.record_bytes.max(1);
         for it in data {
-            lck.body.push_str(
-                &format!("{},tag={} current={:.5},voltage={:.5},power={:.5},bat={:.2} {}\n",
-                    self.server.influxdb_measurement,
-                    self.server.influxdb_tag,
-                    it.current,
-                    it.voltage,
-                    it.power,
-                    it.battery,
-                    it.clock,
-            ));
-            count += 1;
-            if count == 128 {
-                info!("Chunk data");
-                break;
+            // Skip records already queued (deduplicate by sequence number)
+            if let Some(last_seq) = lck.last_seq {
+                if it.seq <= last_seq {
+                    continue;
+                }
+            }
+            // A record stamped before the clock was set would corrupt the series, it is not sent.
+            // Uploads wait for a valid clock, so these are records restored from a previous boot.
+            if Timestamp::valid(it.clock).is_none() {
+                invalid += 1;
+                continue;
             }
+            if lck.records.len() >= limit {
+                over_budget += 1;
+                continue;
+            }
+            lck.last_seq = Some(it.seq);
+            lck.records.push(it);
+            queued += 1;
+        }
+        if invalid > 0 {
+            lck.stats.invalid_clock += invalid;
+            info!("{} records not sent, timestamp before 2020", invalid);
+        }
+        if over_budget > 0 {
+            lck.stats.over_budget += over_budget;
+            info!("{} records dropped, over the {} byte body budget", over_budget, MAX_BODY_BYTES);
         }
+        if queued > 0 {
+            lck.stats.batches += 1;
+            lck.txreq = true;
+            self.last_upload = Some(Instant::now());
+            self.wake.notify_lsb();
+        }
+        queued
+    }
+
+    // Timestamp of an entry in the configured precision, None (the entry is not sent) before 2020
+    fn entry_clock(&self, stats: &mut TransferStats, clock: u128, kind: &str) -> Option<u64> {
+        let timestamp = Timestamp::valid(clock).map(|it| it.to_precision(self.server.precision));
+        if timestamp.is_none() {
+            stats.invalid_clock += 1;
+            info!("{} entry not sent, invalid timestamp {}", kind, clock);
+        }
+        timestamp
+    }
+
+    // Queue an encoded entry and request the upload, an entry that does not fit in MAX_BODY_BYTES is dropped
+    fn queue_entry(&self, lck: &mut TransferData, entry: &str) -> bool {
+        if lck.body.len() + entry.len() > MAX_BODY_BYTES {
+            lck.stats.over_budget += 1;
+            info!("Entry of {} bytes dropped, over the {} byte body budget", entry.len(), MAX_BODY_BYTES);
+            return true;
+        }
+        push_entry(&mut lck.body, self.server.payload_format.entry_format(), entry);
         lck.txreq = true;
-        count as usize
+        self.wake.notify_lsb();
+        true
+    }
+
+    pub fn set_health_data(&mut self, data: &HealthLog) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, data.clock, "Health") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let pipeline = data.transfer.fields(data.window_secs);
+        let stacks: Vec<(String, u32)> = data.stacks.iter().map(|(name, free)| (format!("stack_{}", name), *free)).collect();
+        let mut fields = vec![
+            ("free_heap", Field::Int(data.free_heap as i64)),
+            ("min_free_heap", Field::Int(data.min_free_heap as i64)),
+            ("uptime", Field::Int(data.uptime as i64)),
+            ("rssi", Field::Int(data.rssi as i64)),
+            ("reconnects", Field::Int(data.reconnects as i64)),
+            ("reconnect_attempts", Field::Int(data.reconnect_attempts as i64)),
+            ("dropped_records", Field::Int(data.dropped_records as i64)),
+            ("config_error", Field::Bool(data.config_error)),
+            ("upload_batch", Field::Int(data.upload_batch as i64)),
+            ("upload_interval", Field::Int(data.upload_interval as i64)),
+            ("upload_latency", Field::Int(data.upload_latency as i64)),
+            ("jitter_mean_us", Field::Int(data.jitter.mean_us as i64)),
+            ("jitter_p95_us", Field::Int(data.jitter.p95_us as i64)),
+            ("jitter_p99_us", Field::Int(data.jitter.p99_us as i64)),
+            ("jitter_late", Field::Int(data.jitter.late as i64)),
+        ];
+        fields.extend(pipeline.iter().map(|(name, value)| (name.as_str(), Field::Int(*value as i64))));
+        fields.extend(stacks.iter().map(|(name, free)| (name.as_str(), Field::Int(*free as i64))));
+        if let Some(ref rtc) = data.rtc {
+            fields.push(("rtc_offset", Field::Int(rtc.offset_ms)));
+            if let Some(ppm) = rtc.drift_ppm {
+                fields.push(("rtc_drift", Field::Float(ppm as f64, 3)));
+            }
+        }
+        fields.push(("reset_reason", Field::Text(data.reset_reason)));
+        let entry = self.server.encode_point(&self.server.health_measurement, &[("version", version::VERSION), ("git", version::GIT_HASH)], &fields, clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_crash_data(&mut self, report: &CrashReport) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match report.clock {
+            0 => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos(),
+            clock => clock,
+        };
+        let clock = match self.entry_clock(&mut lck.stats, clock, "Crash") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.health_measurement, &[("event", "crash")], &[
+            ("message", Field::Text(&report.message)),
+            ("channel", Field::Int(report.channel as i64)),
+            ("logging", Field::Bool(report.logging)),
+            ("buffer", Field::Int(report.buffer as i64)),
+            ("seq", Field::Int(report.seq as i64)),
+            ("log", Field::Text(&report.log)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_session_data(&mut self, summary: &SessionSummary) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, summary.clock, "Session") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.health_measurement, &[("event", summary.event)], &[
+            ("uptime", Field::Int(summary.uptime as i64)),
+            ("records", Field::Int(summary.records as i64)),
+            ("dropped_records", Field::Int(summary.dropped as i64)),
+            ("energy", Field::Float(summary.energy as f64, 6)),
+            ("peak_current", Field::Float(summary.peak_current as f64, 5)),
+            ("peak_power", Field::Float(summary.peak_power as f64, 5)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_buffer_alert_data(&mut self, event: &BufferEvent) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, event.clock, "Buffer alert") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.health_measurement, &[("event", "buffer_alert")], &[
+            ("level", Field::Int(event.level as i64)),
+            ("fill", Field::Int(event.fill as i64)),
+            ("records", Field::Int(event.records as i64)),
+            ("capacity", Field::Int(event.capacity as i64)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_heartbeat_data(&mut self, beat: &HeartbeatLog) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, beat.clock, "Heartbeat") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.health_measurement, &[("event", "heartbeat")], &[
+            ("logging", Field::Bool(beat.logging)),
+            ("tripped", Field::Bool(beat.tripped)),
+            ("voltage", Field::Float(beat.voltage as f64, 5)),
+            ("current", Field::Float(beat.current as f64, 5)),
+            ("power", Field::Float(beat.power as f64, 5)),
+            ("battery", Field::Float(beat.battery as f64, 2)),
+            ("buffer", Field::Int(beat.buffer as i64)),
+            ("rssi", Field::Int(beat.rssi as i64)),
+            ("uptime", Field::Int(beat.uptime as i64)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_ripple_data(&mut self, ripple: &RippleResult) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, ripple.clock, "Ripple") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.health_measurement, &[("event", "ripple")], &[
+            ("ripple_frequency", Field::Float(ripple.frequency as f64, 2)),
+            ("ripple_amplitude", Field::Float(ripple.amplitude as f64, 6)),
+            ("ripple_pp", Field::Float(ripple.peak_to_peak as f64, 6)),
+            ("sample_rate", Field::Float(ripple.sample_rate as f64, 1)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_pulse_data(&mut self, pulse: &PulseStats) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, pulse.clock, "Pulse") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.pulse_measurement, &[("channel_name", &self.server.channel_name)], &[
+            ("pulses", Field::Int(pulse.count as i64)),
+            ("pulse_width", Field::Float(pulse.width_ms as f64, 3)),
+            ("duty_cycle", Field::Float(pulse.duty_cycle as f64, 2)),
+            ("pulse_charge", Field::Float(pulse.charge_mc as f64, 6)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_iot_data(&mut self, summary: &IotSummary) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, summary.clock, "IoT") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        // Time (s) and charge (mAh) of each band, the battery life only with a capacity
+        let names: Vec<(String, String)> = BAND_NAMES.iter().map(|name| (format!("{}_time", name), format!("{}_charge", name))).collect();
+        let mut fields = Vec::new();
+        for (i, (time, charge)) in names.iter().enumerate() {
+            fields.push((time.as_str(), Field::Float(summary.bands[i].time as f64, 1)));
+            fields.push((charge.as_str(), Field::Float(summary.charge_mah(i) as f64, 6)));
+        }
+        fields.push(("avg_current", Field::Float(summary.average_current as f64, 7)));
+        if let Some(hours) = summary.battery_life {
+            fields.push(("battery_life", Field::Float(hours as f64, 1)));
+        }
+        let entry = self.server.encode_point(&self.server.health_measurement, &[("event", "iot_profile")], &fields, clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_journal_data(&mut self, event: &JournalEntry) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, event.clock, "Journal") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.health_measurement, &[("event", "journal"), ("kind", event.kind.name())], &[
+            ("seq", Field::Int(event.seq as i64)),
+            ("detail", Field::Text(&event.detail)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_summary_data(&mut self, report: &SummaryReport) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, report.clock, "Summary") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.summary_measurement, &[("period", report.period)], &[
+            ("energy", Field::Float(report.energy as f64, 6)),
+            ("average_current", Field::Float(report.average_current as f64, 5)),
+            ("peak_current", Field::Float(report.peak_current as f64, 5)),
+            ("samples", Field::Int(report.samples as i64)),
+            ("uptime", Field::Int(report.uptime as i64)),
+            ("sensor_errors", Field::Int(report.sensor_errors as i64)),
+            ("reconnects", Field::Int(report.reconnects as i64)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
+    }
+
+    pub fn set_marker_data(&mut self, marker: &Marker) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq == true {
+            return false;
+        }
+        let clock = match self.entry_clock(&mut lck.stats, marker.clock, "Marker") {
+            Some(clock) => clock,
+            None => return true,
+        };
+        let entry = self.server.encode_point(&self.server.marker_measurement, &[], &[
+            ("count", Field::Int(marker.count as i64)),
+        ], clock);
+        self.queue_entry(&mut lck, &entry)
     }
 
     pub fn set_tag(&mut self, new_tag: String) {
         self.server.influxdb_tag = new_tag;
         info!("InfluxDB tag updated to: {}", self.server.influxdb_tag);
     }
+
+    pub fn set_device(&mut self, device: String) {
+        self.server.device = device;
+        info!("Device tag updated to: {}", self.server.device);
+    }
+
+    pub fn set_tags(&mut self, tags: Vec<(String, String)>) {
+        self.server.set_tags(tags);
+        info!("Static tags updated: {}", self.server.tags.len());
+    }
+
+    pub fn set_channel_name(&mut self, name: String) {
+        self.server.channel_name = name;
+        info!("Channel name updated to: {}", self.server.channel_name);
+    }
+}
+
+#[cfg(feature = "influx")]
+impl TransferTask {
+    // Waits for queued data instead of polling, a failed request is resent after a delay.
+    // The connection is kept between requests, so the server connection is reused (keep-alive).
+    pub async fn run(mut self, timer_service: &EspTaskTimerService) -> anyhow::Result<()>
+    {
+        info!("Start transfer task.");
+        let mut timer = timer_service.timer_async()?;
+        let stack = Stack::new();
+        let mut buf = [0u8; CONNECTION_BUFFER_SIZE];
+        // The address is set by connect() before the first request
+        let mut conn: Connection<'_, Stack> = Connection::new(&mut buf, &stack, SocketAddr::from(([0, 0, 0, 0], 80)));
+        let mut connected = false;
+        // Socket and address of the collector for packed_udp, opened by the first upload
+        let mut udp: Option<(UdpSocket, SocketAddr)> = None;
+        // Set when a batch went to the backlog: the next batch is tried once and the replay waits
+        let mut diverting = false;
+        loop {
+            // A new server is connected to by the next request
+            if self.apply_settings() {
+                connected = false;
+                udp = None;
+            }
+            let mut lck = self.data.lock().unwrap();
+            // The startup check runs before the first upload
+            if lck.probe_request {
+                lck.probe_request = false;
+                drop(lck);
+                let result = match self.server.payload_format {
+                    // UDP has no reply to check
+                    PayloadFormat::PackedUdp => ProbeResult::Ok(None),
+                    _ => match Self::connect(&mut conn, &mut connected, &stack, &self.server).await {
+                        Ok(_) => Self::with_timeout(&mut timer, Self::probe(&mut conn, &self.server)).await
+                            .unwrap_or_else(|e| ProbeResult::Unreachable(format!("{}", e))),
+                        Err(e) => ProbeResult::Unreachable(format!("{}", e)),
+                    },
+                };
+                if let ProbeResult::Unreachable(_) = result {
+                    connected = false;
+                }
+                self.data.lock().unwrap().probe_result = Some(result);
+                continue;
+            }
+            // The stored batches are replayed oldest first while no new data is queued
+            let stored = self.backlog.as_ref().map_or(0, |backlog| backlog.len());
+            let replay = match self.backlog {
+                Some(ref mut backlog) if !lck.txreq && !diverting && stored > 0 => backlog.oldest(),
+                _ => None,
+            };
+            if lck.txreq == false && replay.is_none() {
+                lck.session.backlog_batches = self.backlog.as_ref().map_or(0, |backlog| backlog.len());
+                drop(lck);
+                if stored > 0 {
+                    if let Either::Second(_) = select(self.wake.wait(), timer.after(Duration::from_millis(BACKLOG_RETRY_MS))).await {
+                        diverting = false;
+                    }
+                }
+                else {
+                    self.wake.wait().await;
+                }
+                continue;
+            }
+            let replaying = replay.is_some();
+            // Take the queued data out, so the lock is not held during the request
+            let entries = match replaying {
+                true => String::new(),
+                false => std::mem::take(&mut lck.body),
+            };
+            let mut records = match replay {
+                Some(records) => records,
+                None => std::mem::take(&mut lck.records),
+            };
+            let memory = entries.capacity() + records.capacity() * std::mem::size_of::<CurrentLog>();
+            lck.stats.peak_memory = lck.stats.peak_memory.max(memory as u32);
+            let record_bytes = lck.record_bytes.max(1);
+            drop(lck);
+            let start = Instant::now();
+            let mut bytes = RequestBody { server: &self.server, entries: &entries, records: &records }.content_length();
+            // The newest records past MAX_BODY_BYTES are held back for the next request
+            let mut held: Vec<CurrentLog> = Vec::new();
+            while !replaying && bytes > MAX_BODY_BYTES && !records.is_empty() {
+                let excess = (bytes - MAX_BODY_BYTES).div_ceil(record_bytes).clamp(1, records.len());
+                let mut newest = records.split_off(records.len() - excess);
+                newest.append(&mut held);
+                held = newest;
+                bytes = RequestBody { server: &self.server, entries: &entries, records: &records }.content_length();
+            }
+            if !held.is_empty() {
+                info!("Body over {} bytes, {} records held for the next request", MAX_BODY_BYTES, held.len());
+            }
+            let body = RequestBody { server: &self.server, entries: &entries, records: &records };
+            let bytes = bytes as u64;
+            let encode_us = start.elapsed().as_micros() as u64;
+            let start = Instant::now();
+            let ret = match self.server.payload_format {
+                PayloadFormat::PackedUdp => Self::send_datagrams(&mut udp, &stack, &self.server, &body).await,
+                _ => match Self::connect(&mut conn, &mut connected, &stack, &self.server).await {
+                    Ok(_) => Self::with_timeout(&mut timer, Self::transfer(&mut conn, &self.server, &body, bytes)).await,
+                    Err(e) => Err(e),
+                },
+            };
+            if ret.is_err() {
+                // The connection state is unknown, reconnect for the next request
+                connected = false;
+                udp = None;
+            }
+            // A malformed point fails the whole request with 400 and the error names its line,
+            // only the named points are dropped and the others resent at once
+            let partial = match &ret {
+                Ok((400, response)) if self.server.payload_format == PayloadFormat::Line => remove_lines(&entries, &records, &rejected_lines(response)),
+                _ => None,
+            };
+            let latency = start.elapsed().as_millis() as u32;
+            let mut lck = self.data.lock().unwrap();
+            if lck.retry == 0 {
+                lck.stats.encode_us += encode_us;
+            }
+            lck.latency_ms = match lck.latency_ms {
+                0 => latency,
+                average => (average * 3 + latency) / 4,
+            };
+            lck.stats.requests += 1;
+            lck.stats.add_rtt(latency);
+            lck.stats.peak_body = lck.stats.peak_body.max(bytes as u32);
+            if !records.is_empty() {
+                lck.record_bytes = (bytes as usize).saturating_sub(entries.len()).div_ceil(records.len());
+            }
+            if replaying {
+                match ret {
+                    Ok((code, _)) if (200..300).contains(&code) => {
+                        lck.stats.bytes_sent += bytes;
+                        lck.session.records_sent += records.len() as u64;
+                        lck.session.last_upload = Some(Instant::now());
+                        lck.session.last_upload_clock = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+                        if let Some(ref mut backlog) = self.backlog {
+                            backlog.pop();
+                        }
+                    },
+                    // The server will never take the batch, e.g. a field type conflict
+                    Ok((code, _)) if (400..500).contains(&code) && code != 408 && code != 429 => {
+                        info!("Stored batch of {} records rejected with status {}, removed", records.len(), code);
+                        lck.stats.failures += 1;
+                        lck.stats.rejected += records.len() as u32;
+                        if let Some(ref mut backlog) = self.backlog {
+                            backlog.pop();
+                        }
+                    },
+                    ret => {
+                        info!("Replay of a stored batch failed: {:?}", ret.map(|(code, _)| code));
+                        lck.stats.failures += 1;
+                        lck.session.failed_attempts += 1;
+                        diverting = true;
+                    },
+                }
+                lck.session.backlog_batches = self.backlog.as_ref().map_or(0, |backlog| backlog.len());
+                continue;
+            }
+            let status = match ret {
+                Ok((code, _)) if (200..300).contains(&code) => {
+                    lck.stats.bytes_sent += bytes;
+                    lck.session.records_sent += records.len() as u64;
+                    lck.session.last_upload = Some(Instant::now());
+                    lck.session.last_upload_clock = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+                    lck.txreq = !held.is_empty();
+                    lck.records = held;
+                    lck.retry = 0;
+                    diverting = false;
+                    TransferStatus::Ok
+                },
+                _ if partial.is_some() => {
+                    let (kept_entries, mut kept_records, rejected) = partial.unwrap();
+                    kept_records.append(&mut held);
+                    lck.stats.failures += 1;
+                    lck.stats.rejected += rejected;
+                    lck.session.failed_attempts += 1;
+                    info!("{} points rejected by the server, resending {} records", rejected, kept_records.len());
+                    if kept_entries.is_empty() && kept_records.is_empty() {
+                        lck.txreq = false;
+                        lck.retry = 0;
+                    }
+                    else {
+                        lck.body = kept_entries;
+                        lck.records = kept_records;
+                    }
+                    TransferStatus::Retrying(lck.retry, 400)
+                },
+                ret => {
+                    let code = match ret {
+                        Ok((code, _)) => code,
+                        Err(e) => {
+                            info!("{}", e);
+                            0
+                        }
+                    };
+                    // Keep the body and resend it, records are never queued twice
+                    lck.stats.failures += 1;
+                    lck.session.failed_attempts += 1;
+                    lck.retry += 1;
+                    info!("Failed to transfer data, status {} (retry {}/{})", code, lck.retry, MAX_RETRY);
+                    if lck.retry >= MAX_RETRY || (diverting && self.backlog.is_some()) {
+                        match self.backlog {
+                            // The records are kept for the replay, the other points are dropped
+                            Some(ref mut backlog) if !records.is_empty() => {
+                                info!("Transfer data stored in the backlog after {} attempts", lck.retry);
+                                lck.stats.dropped += backlog.push(&records);
+                                lck.session.backlog_batches = backlog.len();
+                                diverting = true;
+                            },
+                            _ => {
+                                info!("Transfer data dropped after {} attempts", lck.retry);
+                                lck.stats.dropped += 1;
+                            },
+                        }
+                        lck.txreq = !held.is_empty();
+                        lck.records = held;
+                        lck.retry = 0;
+                        TransferStatus::Failed(code)
+                    }
+                    else {
+                        records.append(&mut held);
+                        lck.body = entries;
+                        lck.records = records;
+                        lck.stats.retries += 1;
+                        TransferStatus::Retrying(lck.retry, code)
+                    }
+                },
+            };
+            lck.session.last_status = Some(status);
+            drop(lck);
+            if let Some(ref mut callback) = self.on_status {
+                callback(status);
+            }
+            if let TransferStatus::Retrying(retry, _) = status {
+                let _ = timer.after(Duration::from_millis(RETRY_DELAY_MS * retry as u64)).await;
+            }
+        }
+    }
+
+    // Returns true when the server changed
+    fn apply_settings(&mut self) -> bool {
+        let changes = match self.settings {
+            Some(ref watch) => watch.changes(),
+            None => return false,
+        };
+        let mut server_changed = false;
+        for (key, value) in changes {
+            info!("Upload setting {} changed", key);
+            match key.as_str() {
+                "influxdb_server" => {
+                    self.server.server = value;
+                    server_changed = true;
+                },
+                "influxdb_api_key" => self.server.influxdb_api_key = value,
+                "influxdb_api" => self.server.influxdb_api = value,
+                "device_name" => self.server.device = value,
+                "influxdb_tags" => self.server.set_tags(parse_tags(&value).unwrap_or_default()),
+                _ => {},
+            }
+        }
+        server_changed
+    }
+
+    // Resolve the server again after a failed request, the socket is opened by the next request
+    async fn connect(conn: &mut Connection<'_, Stack>, connected: &mut bool, stack: &Stack, server_info: &ServerInfo) -> anyhow::Result<()> {
+        if !*connected {
+            let addr = resolve(stack, &server_info.server).await?;
+            conn.reinitialize(addr).await.map_err(http_error)?;
+            *connected = true;
+        }
+        Ok(())
+    }
+
+    // The request fails when it is not finished within REQUEST_TIMEOUT_MS
+    async fn with_timeout<T, F>(timer: &mut EspAsyncTimer, request: F) -> anyhow::Result<T>
+        where F: core::future::Future<Output = anyhow::Result<T>>
+    {
+        match select(request, timer.after(Duration::from_millis(REQUEST_TIMEOUT_MS))).await {
+            Either::First(ret) => ret,
+            Either::Second(_) => anyhow::bail!("Request timed out after {}ms", REQUEST_TIMEOUT_MS),
+        }
+    }
+
+    // Returns the HTTP status of the response, 2xx on success (InfluxDB replies 204)
+    // Returns the HTTP status and the beginning of an error response
+    async fn transfer(conn: &mut Connection<'_, Stack>, server_info: &ServerInfo, body: &RequestBody<'_>, content_length: u64) -> anyhow::Result<(u16, String)>
+    {
+        let host = url_host(&server_info.server);
+        let authorization = authorization(server_info);
+        let content_length = content_length.to_string();
+        let mut headers : Vec<(&str, &str)> = vec![
+                ("Host", host.as_str()),
+                ("Authorization", authorization.as_str()),
+                ("Content-Type", server_info.payload_format.content_type()),
+            ];
+        for (name, value) in &server_info.http_headers {
+            headers.retain(|(default, _)| !default.eq_ignore_ascii_case(name));
+            headers.push((name.as_str(), value.as_str()));
+        }
+        // A known length, the body is not sent with chunked transfer encoding
+        headers.push(("Content-Length", content_length.as_str()));
+        let uri = api_with_precision(&server_info.influxdb_api, server_info.precision);
+        conn.initiate_request(true, Method::Post, &uri, &headers).await.map_err(http_error)?;
+        let mut chunk: Vec<u8> = Vec::with_capacity(WRITE_CHUNK_SIZE * 2);
+        for part in body.parts() {
+            chunk.extend_from_slice(&part);
+            if chunk.len() >= WRITE_CHUNK_SIZE {
+                conn.write_all(&chunk).await.map_err(http_error)?;
+                chunk.clear();
+            }
+        }
+        conn.write_all(&chunk).await.map_err(http_error)?;
+        conn.initiate_response().await.map_err(http_error)?;
+        let res_status = conn.headers().map_err(http_error)?.code;
+        // info!("Response status: {:?}", res_status);
+        if (200..300).contains(&res_status) {
+            read_response(conn, 0).await?;
+            return Ok((res_status, String::new()));
+        }
+        let response = read_response(conn, ERROR_RESPONSE_SIZE).await?;
+        info!("Response: {}", response.chars().take(RESPONSE_LOG_SIZE).collect::<String>());
+        Ok((res_status, response))
+    }
+
+    // UDP has no response, datagrams handed to the network stack count as delivered (status 204)
+    async fn send_datagrams(udp: &mut Option<(UdpSocket, SocketAddr)>, stack: &Stack, server_info: &ServerInfo, body: &RequestBody<'_>) -> anyhow::Result<(u16, String)>
+    {
+        if udp.is_none() {
+            let addr = resolve(stack, &server_info.server).await?;
+            let socket = UdpSocket::bind(if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })?;
+            *udp = Some((socket, addr));
+        }
+        let (socket, addr) = udp.as_ref().unwrap();
+        for datagram in body.datagrams() {
+            socket.send_to(&datagram, *addr)?;
+        }
+        Ok((204, String::new()))
+    }
+
+    // Startup check: the token and bucket with the buckets API of InfluxDB v2, otherwise a ping,
+    // and the server clock from the Date header of the response
+    async fn probe(conn: &mut Connection<'_, Stack>, server_info: &ServerInfo) -> anyhow::Result<ProbeResult>
+    {
+        let host = url_host(&server_info.server);
+        let authorization = authorization(server_info);
+        let headers = [("Host", host.as_str()), ("Authorization", authorization.as_str())];
+        let bucket = match server_info.http_user.is_empty() {
+            true => query_param(&server_info.influxdb_api, "bucket"),
+            false => None,
+        };
+        let uri = match bucket {
+            Some(ref bucket) => format!("/api/v2/buckets?name={}", bucket),
+            None => "/ping".to_string(),
+        };
+        conn.initiate_request(true, Method::Get, &uri, &headers).await.map_err(http_error)?;
+        conn.initiate_response().await.map_err(http_error)?;
+        let (status, server_time) = {
+            let response = conn.headers().map_err(http_error)?;
+            (response.code, response.headers.get("Date").and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok()))
+        };
+        let body = read_response(conn, MAX_RESPONSE_DRAIN).await?;
+        let skew = server_time.map(|t| t.timestamp() - chrono::Utc::now().timestamp());
+        info!("Server check: {}{} status {}, clock skew {:?}s", host, uri, status, skew);
+        let result = match (status, bucket) {
+            (401 | 403, _) => ProbeResult::BadToken(status),
+            (200..=299, Some(bucket)) if !body.contains(&format!("\"name\":\"{}\"", bucket)) => ProbeResult::BucketMissing(bucket),
+            (200..=299, Some(_)) => ProbeResult::Ok(skew),
+            (_, Some(_)) => ProbeResult::Status(status),
+            // Only the clock is checked without the v2 API, e.g. on a plain HTTP endpoint
+            (_, None) => ProbeResult::Ok(skew),
+        };
+        Ok(result.check_skew())
+    }
+}
+
+#[cfg(all(feature = "wifi", not(feature = "influx")))]
+impl TransferTask {
+    pub async fn run(self, _timer_service: &EspTaskTimerService) -> anyhow::Result<()>
+    {
+        match self {}
+    }
+}
+
+// Host part of the URL. A bare IPv6 literal (more than one ':') is bracketed,
+// "[addr]:port", IPv4 addresses and host names are used as they are.
+#[cfg(feature = "influx")]
+fn url_host(server: &str) -> String {
+    if !server.starts_with('[') && server.matches(':').count() > 1 {
+        format!("[{}]", server)
+    }
+    else {
+        server.to_string()
+    }
+}
+
+// Socket address of "host", "host:port", "[addr]:port" or a bare IPv6 literal, port 80 if none is given.
+// Host names are looked up with DNS.
+#[cfg(feature = "influx")]
+async fn resolve(stack: &Stack, server: &str) -> anyhow::Result<SocketAddr> {
+    let (host, port) = match server.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((addr, port)) => (addr, port.strip_prefix(':').unwrap_or("")),
+            None => anyhow::bail!("Invalid server address: {}", server),
+        },
+        None if server.matches(':').count() > 1 => (server, ""),
+        None => server.split_once(':').unwrap_or((server, "")),
+    };
+    let port = match port {
+        "" => 80,
+        port => port.parse::<u16>().map_err(|_| anyhow::anyhow!("Invalid server port: {}", server))?,
+    };
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => stack.get_host_by_name(host, AddrType::Either).await
+            .map_err(|e| anyhow::anyhow!("DNS lookup of {} failed: {:?}", host, e))?,
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+// Errors of the HTTP connection and the socket for anyhow
+#[cfg(feature = "influx")]
+fn http_error<E: core::fmt::Debug>(e: E) -> anyhow::Error {
+    anyhow::anyhow!("HTTP error: {:?}", e)
+}
+
+// Tag keys written by the firmware, static tags cannot replace them
+const RESERVED_TAGS: [&str; 8] = ["tag", "device", "channel_name", "event", "kind", "version", "git", "period"];
+// Static tags of a point, more make every line of the payload longer
+pub const MAX_TAGS: usize = 8;
+
+// Static tags "key=value,key=value", e.g. "location=lab,project=solar". Empty is none.
+pub fn parse_tags(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut tags: Vec<(String, String)> = Vec::new();
+    for item in text.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+        let (key, value) = match item.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return Err(format!("'{}' is not key=value", item)),
+        };
+        if !valid_name(key) || !valid_name(value) || value.contains('=') {
+            return Err(format!("'{}' has an invalid key or value", item));
+        }
+        if RESERVED_TAGS.contains(&key) || tags.iter().any(|(k, _)| k == key) {
+            return Err(format!("tag '{}' is reserved or given twice", key));
+        }
+        tags.push((key.to_string(), value.to_string()));
+    }
+    if tags.len() > MAX_TAGS {
+        return Err(format!("more than {} tags", MAX_TAGS));
+    }
+    Ok(tags)
+}
+
+// Extra request headers, one "Name: value" per line. Returns None if a line has no name.
+pub fn parse_headers(text: &str) -> Option<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    for line in text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        match line.split_once(':') {
+            Some((name, value)) if !name.trim().is_empty() => headers.push((name.trim().to_string(), value.trim().to_string())),
+            _ => return None,
+        }
+    }
+    Some(headers)
+}
+
+// Standard base64 with padding for the Basic auth credentials
+#[cfg(feature = "influx")]
+fn base64_encode(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | (b[2] as u32);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(TABLE[((n >> (18 - 6 * i)) & 0x3F) as usize] as char);
+            }
+            else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Request body: the queued entries followed by the records, which are encoded one at a time
+struct RequestBody<'a> {
+    server: &'a ServerInfo,
+    entries: &'a str,
+    records: &'a [CurrentLog],
+}
+
+impl<'a> RequestBody<'a> {
+    // The parts of the body in order, including the JSON array framing and separators
+    // or the header of the packed format
+    fn parts(&self) -> impl Iterator<Item = Cow<'a, [u8]>> + '_ {
+        let json = self.server.payload_format == PayloadFormat::Json;
+        let packed = self.server.payload_format == PayloadFormat::Packed;
+        let records = self.records.iter().enumerate().flat_map(move |(i, it)| {
+            let separator = json && (i > 0 || !self.entries.is_empty());
+            let record = match packed {
+                true => codec::encode_record(i.checked_sub(1).map(|p| &self.records[p]), it),
+                false => encode_record(self.server, it).into_bytes(),
+            };
+            separator.then_some(Cow::Borrowed(&b","[..])).into_iter()
+                .chain(std::iter::once(Cow::Owned(record)))
+        });
+        let header = packed.then(|| Cow::Owned(codec::encode_header(&self.server.influxdb_tag, &self.server.device,
+            &self.server.channel_name, &self.server.tags, self.entries.len())));
+        json.then_some(Cow::Borrowed(&b"["[..])).into_iter()
+            .chain(header)
+            .chain(std::iter::once(Cow::Borrowed(self.entries.as_bytes())))
+            .chain(packed.then(|| Cow::Owned(codec::encode_records_start(self.records))))
+            .chain(records)
+            .chain(json.then_some(Cow::Borrowed(&b"]"[..])))
+    }
+
+    // Complete packed messages of the body for UDP
+    fn datagrams(&self) -> Vec<Vec<u8>> {
+        codec::encode_datagrams(&self.server.influxdb_tag, &self.server.device, &self.server.channel_name,
+            &self.server.tags, self.entries, self.records)
+    }
+
+    // Encodes the body once to count its bytes
+    fn content_length(&self) -> usize {
+        match self.server.payload_format {
+            PayloadFormat::PackedUdp => self.datagrams().iter().map(|datagram| datagram.len()).sum(),
+            _ => self.parts().map(|part| part.len()).sum(),
+        }
+    }
+}
+
+// Line numbers of the points rejected by InfluxDB, from an error such as
+// "failed to parse line protocol: errors encountered on line(s): line 3: ... line 7: ..."
+#[cfg(feature = "influx")]
+fn rejected_lines(response: &str) -> Vec<usize> {
+    let mut lines = Vec::new();
+    let mut rest = response;
+    while let Some(pos) = rest.find("line ") {
+        rest = &rest[pos + 5..];
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 && rest[digits..].starts_with(':') {
+            if let Ok(line) = rest[..digits].parse::<usize>() {
+                lines.push(line);
+            }
+        }
+    }
+    lines
+}
+
+// Remove the lines (1-based) of a line protocol body: the entries come first, one line each, then the records.
+// Returns the remaining entries and records and the number of points removed, None if no line matched.
+#[cfg(feature = "influx")]
+fn remove_lines(entries: &str, records: &[CurrentLog], lines: &[usize]) -> Option<(String, Vec<CurrentLog>, u32)> {
+    let entry_lines = entries.lines().count();
+    let mut kept_entries = String::with_capacity(entries.len());
+    let mut removed = 0;
+    for (i, line) in entries.lines().enumerate() {
+        if lines.contains(&(i + 1)) {
+            removed += 1;
+        }
+        else {
+            kept_entries.push_str(line);
+            kept_entries.push('\n');
+        }
+    }
+    let mut kept_records = Vec::with_capacity(records.len());
+    for (i, it) in records.iter().enumerate() {
+        if lines.contains(&(entry_lines + i + 1)) {
+            removed += 1;
+        }
+        else {
+            kept_records.push(*it);
+        }
+    }
+    (removed > 0).then_some((kept_entries, kept_records, removed))
+}
+
+// Authorization header, Basic auth when a user is set, otherwise the InfluxDB token
+#[cfg(feature = "influx")]
+fn authorization(server_info: &ServerInfo) -> String {
+    if !server_info.http_user.is_empty() {
+        format!("Basic {}", base64_encode(format!("{}:{}", server_info.http_user, server_info.http_password).as_bytes()))
+    }
+    else {
+        format!("Token {}", server_info.influxdb_api_key)
+    }
+}
+
+// Read the response to the end, the connection is only reused when nothing is left unread.
+// Returns up to keep bytes of the body as text.
+#[cfg(feature = "influx")]
+async fn read_response(conn: &mut Connection<'_, Stack>, keep: usize) -> anyhow::Result<String> {
+    let mut response_buf = [0u8; 512];
+    let mut text = String::new();
+    let mut drained = 0;
+    loop {
+        let len = conn.read(&mut response_buf).await.map_err(http_error)?;
+        if len == 0 {
+            break;
+        }
+        drained += len;
+        if drained > MAX_RESPONSE_DRAIN {
+            anyhow::bail!("Response body over {} bytes", MAX_RESPONSE_DRAIN);
+        }
+        if text.len() < keep {
+            text.push_str(&String::from_utf8_lossy(&response_buf[..len]));
+        }
+    }
+    conn.complete().await.map_err(http_error)?;
+    Ok(text)
+}
+
+// One record in the payload format, encoded while the request body is written
+fn encode_record(server: &ServerInfo, it: &CurrentLog) -> String {
+    match server.payload_format {
+        PayloadFormat::Line => format!("{} current={:.5},voltage={:.5},power={:.5},bat={:.2},seq={}i,crc={}i{}{}{}{}{} {}\n",
+            server.line_series(&server.influxdb_measurement, &[("channel_name", &server.channel_name)]),
+            it.current,
+            it.voltage,
+            it.power,
+            it.battery,
+            it.seq,
+            it.crc,
+            if it.clock_step { ",clock_step=true" } else { "" },
+            match it.soc { Some(soc) => format!(",soc={:.2}", soc), None => "".to_string() },
+            match it.ac {
+                Some(ac) => format!(",vrms={:.5},irms={:.5},va={:.5},freq={:.2}", ac.vrms, ac.irms, ac.apparent_power, ac.frequency),
+                None => "".to_string(),
+            },
+            match it.second {
+                Some(s) => format!(",current2={:.5},voltage2={:.5},power2={:.5}", s.current, s.voltage, s.power),
+                None => "".to_string(),
+            },
+            match (server.differential, it.differential()) {
+                (true, Some(d)) => format!(",current_diff={:.5},loss_w={:.5}{}", d.current, d.loss,
+                    match d.efficiency { Some(e) => format!(",efficiency={:.2}", e), None => "".to_string() }),
+                _ => "".to_string(),
+            },
+            Timestamp::from_nanos(it.clock).to_precision(server.precision),
+        ),
+        PayloadFormat::Json => format!("{{\"ts\":{},\"voltage\":{:.5},\"current\":{:.5},\"power\":{:.5},\"battery\":{:.2},\"channel\":\"{}\",\"device\":\"{}\"{}{},\"seq\":{},\"crc\":{}{}{}{}{}{}}}",
+            Timestamp::from_nanos(it.clock).to_precision(server.precision),
+            it.voltage,
+            it.current,
+            it.power,
+            it.battery,
+            json_escape(&server.influxdb_tag),
+            json_escape(&server.device),
+            match server.channel_name.as_str() { "" => "".to_string(), name => format!(",\"channel_name\":\"{}\"", json_escape(name)) },
+            server.json_tags(),
+            it.seq,
+            it.crc,
+            if it.clock_step { ",\"clock_step\":true" } else { "" },
+            match it.soc { Some(soc) => format!(",\"soc\":{:.2}", soc), None => "".to_string() },
+            match it.ac {
+                Some(ac) => format!(",\"vrms\":{:.5},\"irms\":{:.5},\"va\":{:.5},\"freq\":{:.2}", ac.vrms, ac.irms, ac.apparent_power, ac.frequency),
+                None => "".to_string(),
+            },
+            match it.second {
+                Some(s) => format!(",\"current2\":{:.5},\"voltage2\":{:.5},\"power2\":{:.5}", s.current, s.voltage, s.power),
+                None => "".to_string(),
+            },
+            match (server.differential, it.differential()) {
+                (true, Some(d)) => format!(",\"current_diff\":{:.5},\"loss_w\":{:.5}{}", d.current, d.loss,
+                    match d.efficiency { Some(e) => format!(",\"efficiency\":{:.2}", e), None => "".to_string() }),
+                _ => "".to_string(),
+            },
+        ),
+    }
+}
+
+// Encoded size of a record with the longest clock, sequence number and CRC, until a body is measured
+fn estimate_record_bytes(server: &ServerInfo) -> usize {
+    let mut it = CurrentLog::default();
+    it.clock = u64::MAX as u128;
+    it.seq = u32::MAX;
+    it.crc = u32::MAX;
+    RequestBody { server: server, entries: "", records: &[it] }.content_length()
+}
+
+// Append an encoded entry to the body, JSON objects are comma separated
+fn push_entry(body: &mut String, format: PayloadFormat, entry: &str) {
+    if format == PayloadFormat::Json && !body.is_empty() {
+        body.push(',');
+    }
+    body.push_str(entry);
+}
+
+// Value of a query parameter of the API path
+#[cfg(feature = "influx")]
+fn query_param(api: &str, name: &str) -> Option<String> {
+    let (_, query) = api.split_once('?')?;
+    query.split('&')
+        .filter_map(|p| p.split_once('='))
+        .find(|(key, value)| *key == name && !value.is_empty())
+        .map(|(_, value)| value.to_string())
+}
+
+// Set the precision query parameter of the write API path, replacing any given one
+#[cfg(feature = "influx")]
+fn api_with_precision(api: &str, precision: Precision) -> String {
+    let (path, query) = match api.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (api, ""),
+    };
+    let mut params: Vec<String> = query.split('&')
+        .filter(|p| !p.is_empty() && !p.starts_with("precision="))
+        .map(|p| p.to_string())
+        .collect();
+    params.push(format!("precision={}", precision.query_value(path.contains("/api/v2/"))));
+    format!("{}?{}", path, params.join("&"))
+}
+
+// Measurement of the line protocol, commas and spaces are escaped
+fn measurement_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == ',' || c == ' ' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Tag key, tag value or field key of the line protocol, commas, equal signs and spaces are escaped
+fn tag_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == ',' || c == '=' || c == ' ' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Line protocol string field value, newlines are not allowed
+fn field_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str(" | "),
+            c if (c as u32) < 0x20 => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
 }
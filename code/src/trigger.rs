@@ -0,0 +1,124 @@
+// Trigger
+// Trigger engine to start logging when a condition is met and stop it after a duration
+// or a stop condition, turning the meter into an event recorder.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+//
+// Condition format (cfg.toml or config set trigger_start / trigger_stop):
+//   current>0.5:100   current above 0.5A for 100ms (quantity: current, voltage, power; op: > or <)
+//   voltage<3.0       voltage below 3.0V (hold time 0ms)
+//   gpio              falling edge on trigger_gpio
+//   duration:10000    10 seconds after the start (stop condition only)
+
+use log::*;
+use std::time::{Duration, Instant};
+use crate::CurrentLog;
+use crate::threshold::{self, Threshold};
+
+#[derive(Clone, Copy)]
+enum Condition {
+    Threshold { threshold: Threshold, hold: Duration },
+    Gpio,
+    Duration(Duration),
+}
+
+pub enum TriggerEvent {
+    Start,
+    Stop,
+}
+
+pub struct Trigger {
+    start: Option<Condition>,
+    stop: Option<Condition>,
+    capturing: Option<Instant>,     // start time of the current capture
+    met_since: Option<Instant>,
+}
+
+impl Trigger {
+    pub fn new(start: &str, stop: &str) -> Trigger {
+        let start_cond = parse_condition(start);
+        let stop_cond = parse_condition(stop);
+        if !start.is_empty() && start_cond.is_none() {
+            info!("Invalid trigger start condition '{}', trigger disabled", start);
+        }
+        if !stop.is_empty() && stop_cond.is_none() {
+            info!("Invalid trigger stop condition '{}'", stop);
+        }
+        Trigger { start: start_cond, stop: stop_cond, capturing: None, met_since: None }
+    }
+
+    // Logging is controlled by the trigger only when a start condition is set
+    pub fn is_enabled(&self) -> bool {
+        self.start.is_some()
+    }
+
+    pub fn update(&mut self, data: &CurrentLog, gpio_edge: bool) -> Option<TriggerEvent> {
+        let start = self.start?;
+        match self.capturing {
+            None => {
+                if self.check(start, data, gpio_edge, None) {
+                    info!("Trigger start condition met");
+                    self.capturing = Some(Instant::now());
+                    self.met_since = None;
+                    return Some(TriggerEvent::Start);
+                }
+            },
+            Some(since) => {
+                if let Some(stop) = self.stop {
+                    if self.check(stop, data, gpio_edge, Some(since)) {
+                        info!("Trigger stop condition met after {}ms", since.elapsed().as_millis());
+                        self.capturing = None;
+                        self.met_since = None;
+                        return Some(TriggerEvent::Stop);
+                    }
+                }
+            },
+        }
+        None
+    }
+
+    fn check(&mut self, cond: Condition, data: &CurrentLog, gpio_edge: bool, capture_start: Option<Instant>) -> bool {
+        match cond {
+            Condition::Threshold { threshold, hold } => {
+                if !threshold.is_met(threshold.quantity.measure(data)) {
+                    self.met_since = None;
+                    return false;
+                }
+                let since = *self.met_since.get_or_insert_with(Instant::now);
+                since.elapsed() >= hold
+            },
+            Condition::Gpio => gpio_edge,
+            Condition::Duration(duration) => {
+                match capture_start {
+                    Some(start) => start.elapsed() >= duration,
+                    None => false,
+                }
+            },
+        }
+    }
+}
+
+// Check a start (stop false) or stop condition, an empty one is valid
+pub fn check_condition(text: &str, stop: bool) -> Result<(), String> {
+    match parse_condition(text) {
+        _ if text.trim().is_empty() => Ok(()),
+        Some(Condition::Duration(_)) if !stop => Err("duration is a stop condition only".to_string()),
+        Some(_) => Ok(()),
+        None => Err(format!("'{}' is not quantity>value[:hold_ms], gpio or duration:ms", text)),
+    }
+}
+
+fn parse_condition(text: &str) -> Option<Condition> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    if text == "gpio" {
+        return Some(Condition::Gpio);
+    }
+    if let Some(ms) = text.strip_prefix("duration:") {
+        return ms.parse::<u64>().ok().map(|ms| Condition::Duration(Duration::from_millis(ms)));
+    }
+    let (cond, hold) = threshold::split_hold(text)?;
+    Some(Condition::Threshold { threshold: threshold::parse(cond)?, hold: hold })
+}
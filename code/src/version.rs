@@ -0,0 +1,28 @@
+// Version
+// Firmware version and build information, set by build.rs at compile time.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use chrono::{DateTime, Utc};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("BUILD_GIT_HASH");
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");   // seconds since the epoch
+
+// Build time in UTC, e.g. "2025-08-01 12:34"
+pub fn build_time() -> String {
+    match BUILD_TIMESTAMP.parse::<i64>().ok().and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0)) {
+        Some(dt) => format!("{}", dt.format("%Y-%m-%d %H:%M")),
+        None => "unknown".to_string(),
+    }
+}
+
+// One line summary for the log and the console
+pub fn summary() -> String {
+    format!("v{} ({}) built {}", VERSION, GIT_HASH, build_time())
+}
+
+// Boot screen text
+pub fn boot_text() -> String {
+    format!("mini-current-meter\nv{}\n{}\n{}", VERSION, GIT_HASH, build_time())
+}
@@ -1,30 +1,82 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread;
+use std::ffi::CString;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use esp_idf_hal::peripheral;
+use esp_idf_hal::task::asynch::Notification;
 use esp_idf_svc::{eventloop::EspSystemEventLoop, wifi::EspWifi};
-use esp_idf_svc::wifi::{ClientConfiguration, Configuration};
-use anyhow::bail;
+use esp_idf_svc::wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, WifiEvent};
+use esp_idf_svc::netif::IpEvent;
+use esp_idf_svc::timer::EspTaskTimerService;
+use embassy_futures::select::select;
+use esp_idf_svc::wifi::config::ScanConfig;
 use anyhow::Result;
 use log::*;
 
-pub fn wifi_connect<'d> (
+// Reconnect backoff: the first retry after 10s, doubled per failed attempt up to 5 minutes, plus up to 25% jitter
+// so that meters losing the same access point do not retry in lockstep
+const RECONNECT_BASE_MS: u64 = 10000;
+const RECONNECT_MAX_MS: u64 = 300000;
+const RECONNECT_JITTER_PERCENT: u64 = 25;
+// The supervisor wakes on WiFi and IP events, this is only the fallback check while connected
+const SUPERVISOR_IDLE_MS: u64 = 60000;
+// The fallback access point stays up for 10 minutes and as long as a phone is connected, then the station
+// tries the configured network again
+const AP_SESSION_MS: u64 = 600000;
+const AP_CHECK_MS: u64 = 10000;
+const AP_CHANNEL: u8 = 1;
+const AP_MAX_CONNECTIONS: u16 = 2;
+
+// WPA2-Enterprise (PEAP, EAP-TTLS) credentials
+pub struct EapCredentials {
+    pub identity: String,       // outer identity, the username if empty
+    pub username: String,
+    pub password: String,
+    pub ca_cert: &'static str,  // PEM of the RADIUS server CA, the server is not verified if empty
+}
+
+// Access point started when the network has been out of reach for a while, so the buffered records can be
+// downloaded with a phone through the data API
+pub struct ApFallback {
+    pub after: Duration,    // without a connection
+    pub ssid: String,
+    pub psk: String,        // open network if empty
+}
+
+// Start the station and connect in the background. The WifiSupervisor watches the connection
+// and retries, so a missing access point does not delay sampling.
+pub fn wifi_start(
     modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
-    ssid: &'d str,
-    pass: &'d str,
-) -> Result<Box<EspWifi<'d>>> {
+    sys_event_loop: &EspSystemEventLoop,
+    ssid: &str,
+    pass: &str,
+    eap: Option<&EapCredentials>,
+) -> Result<Box<EspWifi<'static>>> {
   
-    let sys_event_loop = EspSystemEventLoop::take().unwrap();
     let mut wifi = Box::new(EspWifi::new(modem, sys_event_loop.clone(), None).unwrap());
 
     info!("Setting WiFi configuration...");
     
     // Set configuration first, then start
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.try_into().map_err(|_| anyhow::anyhow!("Failed to convert SSID"))?,
-        password: pass.try_into().map_err(|_| anyhow::anyhow!("Failed to convert password"))?,
-        ..Default::default()
-    })).map_err(|e| anyhow::anyhow!("Failed to set WiFi configuration: {:?}", e))?;
+    match eap {
+        Some(eap) => {
+            wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+                ssid: ssid.try_into().map_err(|_| anyhow::anyhow!("Failed to convert SSID"))?,
+                auth_method: AuthMethod::WPA2Enterprise,
+                ..Default::default()
+            })).map_err(|e| anyhow::anyhow!("Failed to set WiFi configuration: {:?}", e))?;
+            set_enterprise(eap)?;
+        },
+        None => {
+            wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+                ssid: ssid.try_into().map_err(|_| anyhow::anyhow!("Failed to convert SSID"))?,
+                password: pass.try_into().map_err(|_| anyhow::anyhow!("Failed to convert password"))?,
+                ..Default::default()
+            })).map_err(|e| anyhow::anyhow!("Failed to set WiFi configuration: {:?}", e))?;
+        }
+    }
 
     info!("Starting WiFi...");
     wifi.start().map_err(|e| anyhow::anyhow!("Failed to start WiFi: {:?}", e))?;
@@ -33,22 +85,29 @@ pub fn wifi_connect<'d> (
     thread::sleep(Duration::from_millis(100));
     
     info!("Connecting to WiFi network: {}", ssid);
+    if let Err(e) = wifi.connect() {
+        info!("Failed to connect to WiFi: {:?}", e);
+    }
+    Ok(wifi)
+}
 
-    info!("Connecting to WiFi network: {}", ssid);
-    wifi.connect().map_err(|e| anyhow::anyhow!("Failed to connect to WiFi: {:?}", e))?;
-    
-    let mut timeout = 0;
-    while !wifi.is_connected().map_err(|e| anyhow::anyhow!("Failed to check WiFi status: {:?}", e))? {
-        thread::sleep(Duration::from_secs(1));
-        timeout += 1;
-        info!("Waiting for WiFi connection... ({}/30)", timeout);
-        if timeout > 30 {
-            bail!("WiFi connection timeout after 30 seconds");
+// Pass the EAP credentials to the supplicant, must be called before connecting
+fn set_enterprise(eap: &EapCredentials) -> Result<()> {
+    let identity = if eap.identity.is_empty() { &eap.username } else { &eap.identity };
+    info!("WPA2-Enterprise: identity {}, CA certificate {}", identity, if eap.ca_cert.is_empty() { "not set" } else { "set" });
+    unsafe {
+        esp_idf_sys::esp!(esp_idf_sys::esp_eap_client_set_identity(identity.as_ptr(), identity.len() as i32))?;
+        esp_idf_sys::esp!(esp_idf_sys::esp_eap_client_set_username(eap.username.as_ptr(), eap.username.len() as i32))?;
+        esp_idf_sys::esp!(esp_idf_sys::esp_eap_client_set_password(eap.password.as_ptr(), eap.password.len() as i32))?;
+        if !eap.ca_cert.is_empty() {
+            // The supplicant keeps the pointer, the PEM parser needs the terminating NUL
+            let pem: &'static std::ffi::CStr = Box::leak(CString::new(eap.ca_cert)?.into_boxed_c_str());
+            let pem = pem.to_bytes_with_nul();
+            esp_idf_sys::esp!(esp_idf_sys::esp_eap_client_set_ca_cert(pem.as_ptr(), pem.len() as i32))?;
         }
+        esp_idf_sys::esp!(esp_idf_sys::esp_wifi_sta_enterprise_enable())?;
     }
-
-    info!("WiFi connected successfully");
-    Ok(wifi)
+    Ok(())
 }
 
 pub fn get_rssi() -> i32 {
@@ -59,7 +118,291 @@ pub fn get_rssi() -> i32 {
     }
 }
 
+// IPv4 address of the station, None while not connected
+pub fn get_ip() -> Option<Ipv4Addr> {
+    netif_ip(b"WIFI_STA_DEF\0")
+}
+
+// IPv4 address of the fallback access point, None while it is not started
+pub fn get_ap_ip() -> Option<Ipv4Addr> {
+    netif_ip(b"WIFI_AP_DEF\0")
+}
+
+fn netif_ip(ifkey: &[u8]) -> Option<Ipv4Addr> {
+    unsafe {
+        let netif = esp_idf_sys::esp_netif_get_handle_from_ifkey(ifkey.as_ptr() as *const _);
+        if netif.is_null() {
+            return None;
+        }
+        let mut info: esp_idf_sys::esp_netif_ip_info_t = std::mem::zeroed();
+        if esp_idf_sys::esp_netif_get_ip_info(netif, &mut info) != esp_idf_sys::ESP_OK || info.ip.addr == 0 {
+            return None;
+        }
+        // Stored in network byte order
+        Some(Ipv4Addr::from(u32::from_be(info.ip.addr)))
+    }
+}
+
 pub fn stop_wifi(wifi: &mut EspWifi) -> Result<()> {
     wifi.stop().map_err(|e| anyhow::anyhow!("Failed to stop WiFi: {:?}", e))?;
     Ok(())
-}
\ No newline at end of file
+}
+
+// Apply new credentials at runtime. The connection is established asynchronously,
+// the main loop keeps sampling and the WifiSupervisor retries if it fails.
+pub fn change_credentials(wifi: &mut EspWifi, ssid: &str, pass: &str) -> Result<()> {
+    info!("Changing WiFi network to: {}", ssid);
+    if let Err(e) = wifi.disconnect() {
+        info!("WiFi disconnect failed: {:?}", e);
+    }
+    // A PSK network, stop using the enterprise credentials
+    unsafe {
+        esp_idf_sys::esp_wifi_sta_enterprise_disable();
+    }
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: ssid.try_into().map_err(|_| anyhow::anyhow!("Failed to convert SSID"))?,
+        password: pass.try_into().map_err(|_| anyhow::anyhow!("Failed to convert password"))?,
+        ..Default::default()
+    })).map_err(|e| anyhow::anyhow!("Failed to set WiFi configuration: {:?}", e))?;
+    wifi.connect().map_err(|e| anyhow::anyhow!("Failed to connect to WiFi: {:?}", e))?;
+    Ok(())
+}
+
+// Replace the station by the access point, returns the station configuration to restore
+fn start_access_point(wifi: &mut EspWifi, ap: &ApFallback) -> Result<Configuration> {
+    let client = wifi.get_configuration().map_err(|e| anyhow::anyhow!("Failed to get WiFi configuration: {:?}", e))?;
+    if let Err(e) = wifi.disconnect() {
+        info!("WiFi disconnect failed: {:?}", e);
+    }
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ap.ssid.as_str().try_into().map_err(|_| anyhow::anyhow!("Failed to convert SSID"))?,
+        password: ap.psk.as_str().try_into().map_err(|_| anyhow::anyhow!("Failed to convert password"))?,
+        auth_method: if ap.psk.is_empty() { AuthMethod::None } else { AuthMethod::WPA2Personal },
+        channel: AP_CHANNEL,
+        max_connections: AP_MAX_CONNECTIONS,
+        ..Default::default()
+    })).map_err(|e| anyhow::anyhow!("Failed to set access point configuration: {:?}", e))?;
+    info!("WiFi access point {} started", ap.ssid);
+    Ok(client)
+}
+
+// Back to the station with its configuration
+fn stop_access_point(wifi: &mut EspWifi, client: &Configuration) -> Result<()> {
+    wifi.set_configuration(client).map_err(|e| anyhow::anyhow!("Failed to set WiFi configuration: {:?}", e))?;
+    info!("WiFi access point stopped, reconnecting");
+    wifi.connect().map_err(|e| anyhow::anyhow!("Failed to connect to WiFi: {:?}", e))?;
+    Ok(())
+}
+
+// Phones connected to the access point
+fn ap_stations() -> usize {
+    let mut list: esp_idf_sys::wifi_sta_list_t = unsafe { std::mem::zeroed() };
+    match unsafe { esp_idf_sys::esp_wifi_ap_get_sta_list(&mut list) } {
+        esp_idf_sys::ESP_OK => list.num as usize,
+        _ => 0,
+    }
+}
+
+// Create the IPv6 link-local address once the station is connected.
+// Global addresses are assigned by SLAAC (CONFIG_LWIP_IPV6_AUTOCONFIG).
+pub fn enable_ipv6(wifi: &EspWifi) {
+    let ret = unsafe { esp_idf_sys::esp_netif_create_ip6_linklocal(wifi.sta_netif().handle()) };
+    if ret != esp_idf_sys::ESP_OK {
+        info!("Failed to create IPv6 link-local address: {}", ret);
+    }
+}
+
+// Scan access points, returns (SSID, RSSI) sorted by signal strength
+pub fn scan(wifi: &mut EspWifi) -> Result<Vec<(String, i8)>> {
+    wifi.start_scan(&ScanConfig::default(), true).map_err(|e| anyhow::anyhow!("Failed to scan WiFi: {:?}", e))?;
+    let aps = wifi.get_scan_result().map_err(|e| anyhow::anyhow!("Failed to get scan result: {:?}", e))?;
+    let mut result: Vec<(String, i8)> = aps.iter().map(|ap| (ap.ssid.to_string(), ap.signal_strength)).collect();
+    result.sort_by(|a, b| b.1.cmp(&a.1));
+    Ok(result)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WifiStats {
+    pub connected: bool,
+    pub reconnects: u32,    // connections restored since boot
+    pub attempts: u32,      // reconnect attempts since boot
+    pub access_point: bool, // the fallback access point is up
+}
+
+struct SupervisorState {
+    stats: WifiStats,
+    paused: bool,           // WiFi stopped on purpose, no reconnects
+    retry_now: bool,        // new credentials, restart the backoff
+    ap_fallback: Option<Arc<ApFallback>>,
+}
+
+// Reconnects the station with exponential backoff. It runs as a task of the network thread
+// (network::start) and is woken by WiFi and IP events, so connect() is never called from the
+// main loop and a lost access point does not stall the readings.
+#[derive(Clone)]
+pub struct WifiSupervisor {
+    wifi: Arc<Mutex<Box<EspWifi<'static>>>>,
+    state: Arc<Mutex<SupervisorState>>,
+    sys_event_loop: EspSystemEventLoop,
+    wake: Arc<Notification>,
+}
+
+impl WifiSupervisor {
+    pub fn new(wifi: Box<EspWifi<'static>>, sys_event_loop: EspSystemEventLoop) -> WifiSupervisor {
+        WifiSupervisor {
+            wifi: Arc::new(Mutex::new(wifi)),
+            state: Arc::new(Mutex::new(SupervisorState { stats: WifiStats::default(), paused: false, retry_now: false, ap_fallback: None })),
+            sys_event_loop: sys_event_loop,
+            wake: Arc::new(Notification::new()),
+        }
+    }
+
+    pub async fn run(&self, timer_service: &EspTaskTimerService) -> Result<()>
+    {
+        info!("Start WiFi Supervisor Task.");
+        let mut timer = timer_service.timer_async()?;
+        // Any connection change wakes the task, the state is read from the driver afterwards
+        let wake = self.wake.clone();
+        let _wifi_events = self.sys_event_loop.subscribe::<WifiEvent, _>(move |_| {
+            wake.notify_lsb();
+        })?;
+        let wake = self.wake.clone();
+        let _ip_events = self.sys_event_loop.subscribe::<IpEvent, _>(move |_| {
+            wake.notify_lsb();
+        })?;
+        let mut failures: u32 = 0;          // attempts since the connection was lost
+        let mut ever_connected = false;
+        let mut lost_at = Instant::now();
+        // wifi_start() has already started the first connection
+        let mut next_attempt = Instant::now() + Duration::from_millis(RECONNECT_BASE_MS);
+        // Start of the fallback access point and the station configuration to restore
+        let mut access_point: Option<(Instant, Configuration)> = None;
+        loop {
+            let (paused, retry_now, was_connected, ap_fallback) = {
+                let mut lck = self.state.lock().unwrap();
+                (lck.paused, std::mem::take(&mut lck.retry_now), lck.stats.connected, lck.ap_fallback.clone())
+            };
+            if retry_now {
+                failures = 0;
+                next_attempt = Instant::now() + Duration::from_millis(RECONNECT_BASE_MS);
+            }
+            let connected = !paused && get_rssi() != 0;
+            if paused {
+                // Nothing to do until the WiFi is resumed
+            }
+            else if connected && !was_connected {
+                info!("WiFi connected after {} attempts, {}s offline", failures, lost_at.elapsed().as_secs());
+                let mut lck = self.state.lock().unwrap();
+                lck.stats.connected = true;
+                if ever_connected {
+                    lck.stats.reconnects += 1;
+                }
+                ever_connected = true;
+                failures = 0;
+            }
+            else if !connected && was_connected {
+                info!("WiFi disconnected, reconnecting");
+                self.state.lock().unwrap().stats.connected = false;
+                lost_at = Instant::now();
+                next_attempt = Instant::now();
+            }
+            // Access point fallback, the station is not retried while it is up
+            if let Some((since, ref client)) = access_point {
+                // New credentials have already replaced the access point
+                let done = retry_now || (since.elapsed() >= Duration::from_millis(AP_SESSION_MS) && ap_stations() == 0);
+                if done && !retry_now {
+                    if let Err(e) = stop_access_point(&mut self.wifi.lock().unwrap(), client) {
+                        info!("{:?}", e);
+                    }
+                }
+                if done {
+                    access_point = None;
+                    self.state.lock().unwrap().stats.access_point = false;
+                    lost_at = Instant::now();
+                    failures = 0;
+                    next_attempt = Instant::now() + Duration::from_millis(RECONNECT_BASE_MS);
+                }
+            }
+            else if let Some(ref ap) = ap_fallback {
+                if !paused && !connected && lost_at.elapsed() >= ap.after {
+                    info!("WiFi unreachable for {}s, starting the access point", lost_at.elapsed().as_secs());
+                    match start_access_point(&mut self.wifi.lock().unwrap(), ap) {
+                        Ok(client) => {
+                            access_point = Some((Instant::now(), client));
+                            self.state.lock().unwrap().stats.access_point = true;
+                        },
+                        Err(e) => {
+                            info!("{:?}", e);
+                            self.state.lock().unwrap().ap_fallback = None;
+                        },
+                    }
+                }
+            }
+            if !paused && !connected && access_point.is_none() && Instant::now() >= next_attempt {
+                failures += 1;
+                self.state.lock().unwrap().stats.attempts += 1;
+                let delay = backoff_ms(failures);
+                let result = {
+                    let mut wifi = self.wifi.lock().unwrap();
+                    unsafe {
+                        esp_idf_sys::esp_wifi_start();
+                    }
+                    wifi.connect()
+                };
+                match result {
+                    Ok(_) => info!("WiFi reconnect attempt {}, next in {}s", failures, delay / 1000),
+                    Err(e) => info!("WiFi reconnect attempt {} failed: {:?}, next in {}s", failures, e, delay / 1000),
+                }
+                next_attempt = Instant::now() + Duration::from_millis(delay);
+            }
+            // Sleep until the next attempt is due or an event arrives
+            let wait = match (paused || connected, access_point.is_some()) {
+                (true, _) => Duration::from_millis(SUPERVISOR_IDLE_MS),
+                (false, true) => Duration::from_millis(AP_CHECK_MS),
+                (false, false) => {
+                    let wait = next_attempt.saturating_duration_since(Instant::now());
+                    match ap_fallback {
+                        Some(ref ap) => wait.min((lost_at + ap.after).saturating_duration_since(Instant::now())),
+                        None => wait,
+                    }
+                },
+            };
+            select(self.wake.wait(), timer.after(wait)).await;
+        }
+    }
+
+    // The station for scans and configuration changes, the supervisor waits meanwhile
+    pub fn lock(&self) -> MutexGuard<'_, Box<EspWifi<'static>>> {
+        self.wifi.lock().unwrap()
+    }
+
+    pub fn stats(&self) -> WifiStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub fn set_paused(&self, paused: bool)
+    {
+        self.state.lock().unwrap().paused = paused;
+        self.wake.notify_lsb();
+    }
+
+    // Start an access point when the network cannot be reached for a while, must be set before run()
+    pub fn set_ap_fallback(&self, ap: ApFallback)
+    {
+        info!("WiFi access point {} after {}s without a connection", ap.ssid, ap.after.as_secs());
+        self.state.lock().unwrap().ap_fallback = Some(Arc::new(ap));
+    }
+
+    // Credentials were changed, give the new connection time before retrying
+    pub fn restart_backoff(&self)
+    {
+        self.state.lock().unwrap().retry_now = true;
+        self.wake.notify_lsb();
+    }
+}
+// Delay after the n-th failed attempt with jitter
+fn backoff_ms(failures: u32) -> u64 {
+    let delay = (RECONNECT_BASE_MS << failures.saturating_sub(1).min(5)).min(RECONNECT_MAX_MS);
+    let jitter = unsafe { esp_idf_sys::esp_random() } as u64 % (delay * RECONNECT_JITTER_PERCENT / 100 + 1);
+    delay + jitter
+}
@@ -0,0 +1,94 @@
+// WsPush
+// Pushes samples in real time to a WebSocket server (e.g. a browser dashboard or Grafana Live)
+// as JSON frames, with sub-second latency instead of the batched InfluxDB writes.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+//
+// Frame format (one frame per main loop):
+//   {"channel":"ch1","samples":[{"ts":<ns>,"voltage":<V>,"current":<A>,"power":<W>,"battery":<V>},...]}
+
+use log::*;
+use std::time::Duration;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use esp_idf_svc::ws::FrameType;
+use esp_idf_svc::ws::client::{EspWebSocketClient, EspWebSocketClientConfig, WebSocketEventType};
+use crate::CurrentLog;
+use crate::transfer::json_escape;
+use crate::tasks;
+
+// Frames waiting for the sender thread, dropped instead of blocking when full
+const WS_QUEUE_SIZE: usize = 16;
+const WS_TIMEOUT_MS: u64 = 1000;
+
+pub struct WsPush {
+    tx: SyncSender<String>,
+    samples: Vec<String>,
+    dropped: u32,
+}
+
+impl WsPush {
+    pub fn new(url: &str) -> WsPush {
+        let (tx, rx) = sync_channel::<String>(WS_QUEUE_SIZE);
+        let url = url.to_string();
+        let _th = tasks::spawn(&tasks::WS_PUSH, move || {
+            info!("Start WebSocket push thread: {}", url);
+            let config = EspWebSocketClientConfig {
+                reconnect_timeout_ms: Duration::from_secs(10),
+                ..Default::default()
+            };
+            let mut client = match EspWebSocketClient::new(&url, &config, Duration::from_millis(WS_TIMEOUT_MS), |event| {
+                match event {
+                    Ok(event) => match event.event_type {
+                        WebSocketEventType::Connected => info!("WebSocket connected"),
+                        WebSocketEventType::Disconnected => info!("WebSocket disconnected"),
+                        _ => {},
+                    },
+                    Err(e) => info!("WebSocket error: {:?}", e),
+                }
+            }) {
+                Ok(client) => client,
+                Err(e) => {
+                    info!("Failed to create WebSocket client: {:?}", e);
+                    return;
+                }
+            };
+            for frame in rx {
+                // Frames are only useful live, drop them while disconnected
+                if !client.is_connected() {
+                    continue;
+                }
+                if let Err(e) = client.send(FrameType::Text(false), frame.as_bytes()) {
+                    info!("WebSocket send failed: {:?}", e);
+                }
+            }
+        });
+        WsPush { tx: tx, samples: Vec::new(), dropped: 0 }
+    }
+
+    // Called for every sample
+    pub fn push(&mut self, data: &CurrentLog)
+    {
+        self.samples.push(format!("{{\"ts\":{},\"voltage\":{:.5},\"current\":{:.5},\"power\":{:.5},\"battery\":{:.2}}}",
+            data.clock, data.voltage, data.current, data.power, data.battery));
+    }
+
+    // Send the samples pushed since the last call as one frame
+    pub fn flush(&mut self, channel: &str)
+    {
+        if self.samples.is_empty() {
+            return;
+        }
+        let frame = format!("{{\"channel\":\"{}\",\"samples\":[{}]}}", json_escape(channel), self.samples.join(","));
+        self.samples.clear();
+        match self.tx.try_send(frame) {
+            Ok(()) => {},
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped % 100 == 1 {
+                    info!("WebSocket push is too slow, {} frames dropped", self.dropped);
+                }
+            },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}